@@ -0,0 +1,250 @@
+use crate::{
+    storage::{LoadError, LoadTree, StoreError, StoreTree, StrongReference},
+    storage_protocol::SerializedTree,
+    tree::{BlobDigest, HashedTree, TreeSerializationError},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use tracing::info;
+
+/// Every archive starts with these bytes followed by [ARCHIVE_VERSION] as a big-endian u32.
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"NLOSTREE";
+/// Version 2 tags every digest with its algorithm.
+pub const ARCHIVE_VERSION: u32 = 2;
+
+/// A record contains at most one tree, so anything longer than this can only be the result of corruption.
+const MAX_RECORD_LENGTH: u32 = 256 * 1024;
+
+/// After the header, an archive is a sequence of records, each prefixed with its length as a big-endian u32.
+/// Trees come in dependency order (children before their parents), so they can be imported while streaming.
+/// The last record is always [ArchiveRecord::End].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum ArchiveRecord {
+    Tree {
+        digest: BlobDigest,
+        tree: SerializedTree,
+    },
+    End {
+        root: BlobDigest,
+        tree_count: u64,
+    },
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(std::io::Error),
+    Postcard(postcard::Error),
+    Load(LoadError),
+    Store(StoreError),
+    TreeSerialization(TreeSerializationError),
+    /// The archive doesn't start with [ARCHIVE_MAGIC].
+    NotAnArchive,
+    UnsupportedVersion(u32),
+    /// The archive ended before the [ArchiveRecord::End] record.
+    Truncated,
+    /// A record read from the archive or about to be written is longer than a record with a single tree can be.
+    RecordTooLong(u64),
+    /// The content of the tree with this digest has been modified.
+    DigestMismatch(BlobDigest),
+    /// A tree refers to this child, but the child did not appear earlier in the archive.
+    ChildNotInArchive(BlobDigest),
+    RootNotInArchive(BlobDigest),
+    TreeCountMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    TrailingData,
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+fn write_record(
+    writer: &mut (dyn std::io::Write + Send),
+    record: &ArchiveRecord,
+) -> Result<(), ArchiveError> {
+    let serialized = postcard::to_stdvec(record).map_err(ArchiveError::Postcard)?;
+    let length = match u32::try_from(serialized.len()) {
+        Ok(length) if length <= MAX_RECORD_LENGTH => length,
+        _ => return Err(ArchiveError::RecordTooLong(serialized.len() as u64)),
+    };
+    writer
+        .write_all(&length.to_be_bytes())
+        .map_err(ArchiveError::Io)?;
+    writer.write_all(&serialized).map_err(ArchiveError::Io)?;
+    Ok(())
+}
+
+fn read_exact_or_truncated(
+    reader: &mut (dyn std::io::Read + Send),
+    buffer: &mut [u8],
+) -> Result<(), ArchiveError> {
+    reader
+        .read_exact(buffer)
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => ArchiveError::Truncated,
+            _ => ArchiveError::Io(error),
+        })
+}
+
+fn read_record(reader: &mut (dyn std::io::Read + Send)) -> Result<ArchiveRecord, ArchiveError> {
+    let mut length_bytes = [0u8; 4];
+    read_exact_or_truncated(reader, &mut length_bytes)?;
+    let length = u32::from_be_bytes(length_bytes);
+    if length > MAX_RECORD_LENGTH {
+        return Err(ArchiveError::RecordTooLong(length as u64));
+    }
+    let mut buffer = vec![0u8; length as usize];
+    read_exact_or_truncated(reader, &mut buffer)?;
+    postcard::from_bytes(&buffer).map_err(ArchiveError::Postcard)
+}
+
+struct Exporter<'t> {
+    load_tree: &'t (dyn LoadTree + Send + Sync),
+    writer: &'t mut (dyn std::io::Write + Send),
+    written: BTreeSet<BlobDigest>,
+}
+
+impl Exporter<'_> {
+    async fn export_tree(&mut self, digest: &BlobDigest) -> Result<(), ArchiveError> {
+        if self.written.contains(digest) {
+            return Ok(());
+        }
+        let loaded = self
+            .load_tree
+            .load_tree(digest)
+            .await
+            .map_err(ArchiveError::Load)?;
+        let hashed_tree = match loaded.hash() {
+            Some(success) => success,
+            None => {
+                return Err(ArchiveError::Load(LoadError::Inconsistency(
+                    *digest,
+                    "Tree hash does not match the digest".to_string(),
+                )))
+            }
+        };
+        let tree = hashed_tree.hashed_tree().tree();
+        for child in tree.children().references() {
+            Box::pin(self.export_tree(child.digest())).await?;
+        }
+        write_record(
+            self.writer,
+            &ArchiveRecord::Tree {
+                digest: *digest,
+                tree: SerializedTree::from_tree(tree),
+            },
+        )?;
+        self.written.insert(*digest);
+        Ok(())
+    }
+}
+
+/// Writes `root` and everything reachable from it into a self-contained archive. Returns the number of trees written.
+/// Trees referenced multiple times are written only once.
+pub async fn export_archive(
+    root: &BlobDigest,
+    load_tree: &(dyn LoadTree + Send + Sync),
+    writer: &mut (dyn std::io::Write + Send),
+) -> Result<u64, ArchiveError> {
+    writer.write_all(ARCHIVE_MAGIC).map_err(ArchiveError::Io)?;
+    writer
+        .write_all(&ARCHIVE_VERSION.to_be_bytes())
+        .map_err(ArchiveError::Io)?;
+    let mut exporter = Exporter {
+        load_tree,
+        writer,
+        written: BTreeSet::new(),
+    };
+    exporter.export_tree(root).await?;
+    let tree_count = exporter.written.len() as u64;
+    write_record(
+        exporter.writer,
+        &ArchiveRecord::End {
+            root: *root,
+            tree_count,
+        },
+    )?;
+    exporter.writer.flush().map_err(ArchiveError::Io)?;
+    info!("Exported {} trees of root {}", tree_count, root);
+    Ok(tree_count)
+}
+
+/// Reads an archive written by [export_archive] and stores all of its trees. Every tree is verified against its digest
+/// before it is stored, so a tampered archive is rejected as soon as the first modified tree is encountered.
+/// Trees stored before an error is detected are not removed, but nothing keeps them alive either.
+pub async fn import_archive(
+    reader: &mut (dyn std::io::Read + Send),
+    store_tree: &(dyn StoreTree + Send + Sync),
+) -> Result<StrongReference, ArchiveError> {
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => ArchiveError::NotAnArchive,
+            _ => ArchiveError::Io(error),
+        })?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(ArchiveError::NotAnArchive);
+    }
+    let mut version_bytes = [0u8; 4];
+    read_exact_or_truncated(reader, &mut version_bytes)?;
+    let version = u32::from_be_bytes(version_bytes);
+    if version != ARCHIVE_VERSION {
+        return Err(ArchiveError::UnsupportedVersion(version));
+    }
+    let mut imported: BTreeMap<BlobDigest, StrongReference> = BTreeMap::new();
+    loop {
+        match read_record(reader)? {
+            ArchiveRecord::Tree { digest, tree } => {
+                let mut children = Vec::with_capacity(tree.children.len());
+                for child in &tree.children {
+                    match imported.get(child) {
+                        Some(reference) => children.push(reference.clone()),
+                        None => return Err(ArchiveError::ChildNotInArchive(*child)),
+                    }
+                }
+                let tree = tree
+                    .to_tree(children)
+                    .map_err(ArchiveError::TreeSerialization)?;
+                let hashed = HashedTree::from_with_algorithm(Arc::new(tree), digest.algorithm());
+                if hashed.digest() != &digest {
+                    return Err(ArchiveError::DigestMismatch(digest));
+                }
+                let reference = store_tree
+                    .store_tree(&hashed)
+                    .await
+                    .map_err(ArchiveError::Store)?;
+                imported.insert(digest, reference);
+            }
+            ArchiveRecord::End { root, tree_count } => {
+                let actual_count = imported.len() as u64;
+                if tree_count != actual_count {
+                    return Err(ArchiveError::TreeCountMismatch {
+                        expected: tree_count,
+                        actual: actual_count,
+                    });
+                }
+                let root_reference = match imported.remove(&root) {
+                    Some(reference) => reference,
+                    None => return Err(ArchiveError::RootNotInArchive(root)),
+                };
+                let mut trailing = [0u8; 1];
+                match reader.read(&mut trailing).map_err(ArchiveError::Io)? {
+                    0 => {}
+                    _ => return Err(ArchiveError::TrailingData),
+                }
+                info!("Imported {} trees of root {}", actual_count, root);
+                return Ok(root_reference);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{
+    archive::{export_archive, import_archive, ArchiveError, ARCHIVE_MAGIC},
+    deep_tree::DeepTree,
+    in_memory_storage::InMemoryTreeStorage,
+    storage::{LoadError, StoreTree, StrongReference},
+    tree::{HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+fn make_tree(content: &'static str, children: Vec<StrongReference>) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from(content)).unwrap(),
+        TreeChildren::try_from(children).unwrap(),
+    )))
+}
+
+async fn make_archive(storage: &InMemoryTreeStorage) -> (StrongReference, Vec<u8>) {
+    let leaf = storage
+        .store_tree(&make_tree("leaf", vec![]))
+        .await
+        .unwrap();
+    let middle = storage
+        .store_tree(&make_tree("middle", vec![leaf.clone()]))
+        .await
+        .unwrap();
+    let root = storage
+        .store_tree(&make_tree("root", vec![middle, leaf]))
+        .await
+        .unwrap();
+    let mut archive = Vec::new();
+    assert_eq!(
+        3,
+        export_archive(root.digest(), storage, &mut archive)
+            .await
+            .unwrap()
+    );
+    (root, archive)
+}
+
+#[test_log::test(tokio::test)]
+async fn test_archive_round_trip() {
+    let source = InMemoryTreeStorage::empty();
+    let (root, archive) = make_archive(&source).await;
+    let destination = InMemoryTreeStorage::empty();
+    let imported = import_archive(&mut archive.as_slice(), &destination)
+        .await
+        .unwrap();
+    assert_eq!(root.digest(), imported.digest());
+    assert_eq!(3, destination.number_of_trees().await);
+    assert_eq!(
+        DeepTree::deserialize(root.digest(), &source).await,
+        DeepTree::deserialize(imported.digest(), &destination).await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_archive_export_missing_tree() {
+    let source = InMemoryTreeStorage::empty();
+    let digest = *make_tree("missing", vec![]).digest();
+    let mut archive = Vec::new();
+    match export_archive(&digest, &source, &mut archive).await {
+        Err(ArchiveError::Load(LoadError::TreeNotFound(not_found))) => {
+            assert_eq!(digest, not_found)
+        }
+        other => panic!("Unexpected result: {other:?}"),
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_archive_truncated() {
+    let source = InMemoryTreeStorage::empty();
+    let (_root, archive) = make_archive(&source).await;
+    for length in ARCHIVE_MAGIC.len()..archive.len() {
+        let destination = InMemoryTreeStorage::empty();
+        match import_archive(&mut &archive[..length], &destination).await {
+            Err(ArchiveError::Truncated) => {}
+            other => panic!("Unexpected result for length {length}: {other:?}"),
+        }
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_archive_tampered() {
+    let source = InMemoryTreeStorage::empty();
+    let (_root, archive) = make_archive(&source).await;
+    let position = archive
+        .windows(4)
+        .position(|window| window == b"leaf")
+        .unwrap();
+    let mut tampered = archive.clone();
+    tampered[position] = b'L';
+    let destination = InMemoryTreeStorage::empty();
+    match import_archive(&mut tampered.as_slice(), &destination).await {
+        Err(ArchiveError::DigestMismatch(digest)) => {
+            assert_eq!(make_tree("leaf", vec![]).digest(), &digest);
+        }
+        other => panic!("Unexpected result: {other:?}"),
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_archive_not_an_archive() {
+    let destination = InMemoryTreeStorage::empty();
+    for input in [&b""[..], &b"NLOS"[..], &b"something else entirely"[..]] {
+        match import_archive(&mut &input[..], &destination).await {
+            Err(ArchiveError::NotAnArchive) => {}
+            other => panic!("Unexpected result: {other:?}"),
+        }
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_archive_unsupported_version() {
+    // Version 1 archives contained untagged digests.
+    for version in [1u32, 3] {
+        let mut input = ARCHIVE_MAGIC.to_vec();
+        input.extend_from_slice(&version.to_be_bytes());
+        let destination = InMemoryTreeStorage::empty();
+        match import_archive(&mut input.as_slice(), &destination).await {
+            Err(ArchiveError::UnsupportedVersion(actual)) if actual == version => {}
+            other => panic!("Unexpected result: {other:?}"),
+        }
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_archive_trailing_data() {
+    let source = InMemoryTreeStorage::empty();
+    let (_root, mut archive) = make_archive(&source).await;
+    archive.push(0);
+    let destination = InMemoryTreeStorage::empty();
+    match import_archive(&mut archive.as_slice(), &destination).await {
+        Err(ArchiveError::TrailingData) => {}
+        other => panic!("Unexpected result: {other:?}"),
+    }
+}
@@ -0,0 +1,112 @@
+use crate::tree::TREE_BLOB_MAX_LENGTH;
+use std::collections::BTreeMap;
+use tracing::warn;
+
+/// How [crate::sqlite_storage::SQLiteStorage] compresses new tree blobs. Loading supports every codec regardless of this choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobCodec {
+    None,
+    Lz4,
+    /// Uses the newest dictionary in the database if there is one (see [crate::sqlite_storage::SQLiteStorage::train_compression_dictionary]).
+    Zstd {
+        level: i32,
+    },
+}
+
+impl BlobCodec {
+    /// The value of the `codec` column. These are stored in databases, so they must never change.
+    pub fn id(&self) -> i64 {
+        match self {
+            BlobCodec::None => 0,
+            BlobCodec::Lz4 => 1,
+            BlobCodec::Zstd { .. } => 2,
+        }
+    }
+}
+
+pub const ZSTD_DEFAULT_LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// The contents of the `compression_dictionary` table by ID.
+pub(crate) type CompressionDictionaries = BTreeMap<i64, Vec<u8>>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct EncodedBlob {
+    pub data: Vec<u8>,
+    pub codec: i64,
+    pub dictionary: Option<i64>,
+}
+
+/// Compresses the blob, but only if it's beneficial.
+pub(crate) fn encode_tree_blob(
+    original_blob: &[u8],
+    codec: BlobCodec,
+    dictionary: Option<(i64, &[u8])>,
+) -> EncodedBlob {
+    let compressed = match codec {
+        BlobCodec::None => None,
+        BlobCodec::Lz4 => Some((lz4_flex::compress_prepend_size(original_blob), None)),
+        BlobCodec::Zstd { level } => {
+            let result = match dictionary {
+                Some((id, content)) => zstd::bulk::Compressor::with_dictionary(level, content)
+                    .and_then(|mut compressor| compressor.compress(original_blob))
+                    .map(|data| (data, Some(id))),
+                None => zstd::bulk::compress(original_blob, level).map(|data| (data, None)),
+            };
+            match result {
+                Ok(compressed) => Some(compressed),
+                Err(error) => {
+                    warn!(
+                        "Failed to compress tree blob using zstd, storing it uncompressed: {error}"
+                    );
+                    None
+                }
+            }
+        }
+    };
+    match compressed {
+        Some((data, dictionary)) if data.len() < original_blob.len() => EncodedBlob {
+            data,
+            codec: codec.id(),
+            dictionary,
+        },
+        // Compression doesn't help, store uncompressed to save CPU time on loading
+        _ => EncodedBlob {
+            data: original_blob.to_vec(),
+            codec: BlobCodec::None.id(),
+            dictionary: None,
+        },
+    }
+}
+
+/// Undoes [encode_tree_blob]. The error is a human-readable description of the problem.
+pub(crate) fn decode_tree_blob(
+    tree_blob_raw: Vec<u8>,
+    codec: i64,
+    dictionary: Option<i64>,
+    dictionaries: &CompressionDictionaries,
+) -> std::result::Result<Vec<u8>, String> {
+    match (codec, dictionary) {
+        (0, None) => Ok(tree_blob_raw),
+        (1, None) => lz4_flex::decompress_size_prepended(&tree_blob_raw)
+            .map_err(|error| format!("Failed to decompress tree blob using lz4: {error:?}")),
+        (2, None) => zstd::bulk::decompress(&tree_blob_raw, TREE_BLOB_MAX_LENGTH)
+            .map_err(|error| format!("Failed to decompress tree blob using zstd: {error}")),
+        (2, Some(id)) => {
+            let content = dictionaries
+                .get(&id)
+                .ok_or_else(|| format!("Unknown compression dictionary: {id}"))?;
+            zstd::bulk::Decompressor::with_dictionary(content)
+                .and_then(|mut decompressor| {
+                    decompressor.decompress(&tree_blob_raw, TREE_BLOB_MAX_LENGTH)
+                })
+                .map_err(|error| format!("Failed to decompress tree blob using zstd: {error}"))
+        }
+        (_, None) => Err(format!("Unknown codec: {codec}")),
+        (_, Some(id)) => Err(format!("Codec {codec} can't use dictionary {id}")),
+    }
+}
+
+/// Trains a zstd dictionary of at most `max_size` bytes. zstd fails if there are too few samples.
+pub(crate) fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, String> {
+    zstd::dict::from_samples(samples, max_size).map_err(|error| error.to_string())
+}
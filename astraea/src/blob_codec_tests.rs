@@ -0,0 +1,96 @@
+use crate::blob_codec::{
+    decode_tree_blob, encode_tree_blob, train_dictionary, BlobCodec, CompressionDictionaries,
+    EncodedBlob, ZSTD_DEFAULT_LEVEL,
+};
+use pretty_assertions::assert_eq;
+
+fn compressible_blob(seed: u8) -> Vec<u8> {
+    format!("{{\"name\": \"file {seed}\", \"size\": {seed}, \"kind\": \"regular file\"}}")
+        .repeat(20)
+        .into_bytes()
+}
+
+#[test_log::test]
+fn test_round_trip_all_codecs() {
+    let original = compressible_blob(1);
+    let dictionaries = CompressionDictionaries::new();
+    for codec in [
+        BlobCodec::None,
+        BlobCodec::Lz4,
+        BlobCodec::Zstd {
+            level: ZSTD_DEFAULT_LEVEL,
+        },
+    ] {
+        let encoded = encode_tree_blob(&original, codec, None);
+        assert_eq!(codec.id(), encoded.codec);
+        assert_eq!(None, encoded.dictionary);
+        if codec != BlobCodec::None {
+            assert!(encoded.data.len() < original.len());
+        }
+        assert_eq!(
+            Ok(original.clone()),
+            decode_tree_blob(encoded.data, encoded.codec, None, &dictionaries)
+        );
+    }
+}
+
+#[test_log::test]
+fn test_incompressible_blob_is_stored_uncompressed() {
+    let original = vec![1u8, 2, 3];
+    assert_eq!(
+        EncodedBlob {
+            data: original.clone(),
+            codec: BlobCodec::None.id(),
+            dictionary: None,
+        },
+        encode_tree_blob(&original, BlobCodec::Zstd { level: 19 }, None)
+    );
+}
+
+#[test_log::test]
+fn test_zstd_with_dictionary() {
+    let samples: Vec<Vec<u8>> = (0..100).map(compressible_blob).collect();
+    let dictionary = train_dictionary(&samples, 4096).unwrap();
+    let original = compressible_blob(200);
+    let encoded = encode_tree_blob(
+        &original,
+        BlobCodec::Zstd {
+            level: ZSTD_DEFAULT_LEVEL,
+        },
+        Some((7, &dictionary)),
+    );
+    assert_eq!(Some(7), encoded.dictionary);
+    let mut dictionaries = CompressionDictionaries::new();
+    assert_eq!(
+        Err("Unknown compression dictionary: 7".to_string()),
+        decode_tree_blob(
+            encoded.data.clone(),
+            encoded.codec,
+            encoded.dictionary,
+            &dictionaries
+        )
+    );
+    dictionaries.insert(7, dictionary);
+    assert_eq!(
+        Ok(original),
+        decode_tree_blob(
+            encoded.data,
+            encoded.codec,
+            encoded.dictionary,
+            &dictionaries
+        )
+    );
+}
+
+#[test_log::test]
+fn test_decode_invalid_codec() {
+    let dictionaries = CompressionDictionaries::new();
+    assert_eq!(
+        Err("Unknown codec: 3".to_string()),
+        decode_tree_blob(vec![], 3, None, &dictionaries)
+    );
+    assert_eq!(
+        Err("Codec 1 can't use dictionary 1".to_string()),
+        decode_tree_blob(vec![], 1, Some(1), &dictionaries)
+    );
+}
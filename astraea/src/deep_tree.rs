@@ -2,8 +2,12 @@ use crate::{
     storage::{LoadError, LoadTree, StoreError, StoreTree, StrongReference},
     tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeSerializationError},
 };
+use futures_util::{stream, StreamExt, TryStreamExt};
 use std::sync::Arc;
 
+/// How many trees [DeepTree::serialize] and [DeepTree::deserialize] store or load at the same time.
+pub const MAX_CONCURRENT_OPERATIONS: usize = 32;
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DeepTreeChildren {
     references: Vec<DeepTree>,
@@ -61,42 +65,111 @@ impl DeepTree {
         &self.children
     }
 
+    /// Loads the tree `root` and everything below it. The trees are loaded level by level, so the depth of the tree doesn't
+    /// matter, and up to [MAX_CONCURRENT_OPERATIONS] trees of a level are loaded at the same time.
+    /// Everything ends up in memory, so large trees are better accessed with [LazyDeepTree](crate::lazy_deep_tree::LazyDeepTree).
     pub async fn deserialize(
         root: &BlobDigest,
         load_tree: &dyn LoadTree,
     ) -> std::result::Result<DeepTree, LoadError> {
-        let tree = match load_tree.load_tree(root).await?.hash() {
-            Some(hashed_tree) => hashed_tree,
-            None => {
-                return Err(LoadError::TreeNotFound(*root));
-            }
-        };
-        let blob = tree.hashed_tree().tree().blob();
-        let mut references = Vec::new();
-        for reference in tree.hashed_tree().tree().children().references() {
-            let deep_tree = Box::pin(DeepTree::deserialize(reference.digest(), load_tree)).await?;
-            references.push(deep_tree);
+        let mut levels: Vec<Vec<Arc<Tree>>> = Vec::new();
+        let mut next_level = vec![*root];
+        while !next_level.is_empty() {
+            let level: Vec<Arc<Tree>> = stream::iter(next_level.iter().map(|digest| async move {
+                match load_tree.load_tree(digest).await?.hash() {
+                    Some(hashed_tree) => Ok(hashed_tree.hashed_tree().tree().clone()),
+                    None => Err(LoadError::TreeNotFound(*digest)),
+                }
+            }))
+            .buffered(MAX_CONCURRENT_OPERATIONS)
+            .try_collect()
+            .await?;
+            next_level = level
+                .iter()
+                .flat_map(|tree| tree.children().references())
+                .map(|child| *child.digest())
+                .collect();
+            levels.push(level);
         }
-        Ok(DeepTree::new(
-            blob.clone(),
-            DeepTreeChildren::try_from(references)
-                .expect("Max child count enforced by TreeChildren"),
-        ))
+        // The children of each level are the next level in the same order, so the levels are put together from the bottom.
+        let mut below: Vec<DeepTree> = Vec::new();
+        for level in levels.iter().rev() {
+            let mut children = below.into_iter();
+            below = level
+                .iter()
+                .map(|tree| {
+                    DeepTree::new(
+                        tree.blob().clone(),
+                        DeepTreeChildren::try_from(
+                            children
+                                .by_ref()
+                                .take(tree.children().references().len())
+                                .collect(),
+                        )
+                        .expect("Max child count enforced by TreeChildren"),
+                    )
+                })
+                .collect();
+        }
+        Ok(below.pop().expect("The first level is the root"))
     }
 
+    /// Stores this tree and everything below it level by level, starting with the deepest one because children have to be
+    /// stored before their parents. Up to [MAX_CONCURRENT_OPERATIONS] trees of a level are stored at the same time.
     pub async fn serialize(
         &self,
         store_tree: &dyn StoreTree,
     ) -> Result<StrongReference, StoreError> {
-        let mut references = Vec::new();
-        for reference in self.children().references() {
-            references.push(Box::pin(reference.serialize(store_tree)).await?);
+        let mut levels: Vec<Vec<&DeepTree>> = vec![vec![self]];
+        loop {
+            let next_level: Vec<&DeepTree> = levels
+                .last()
+                .expect("There is at least the root")
+                .iter()
+                .flat_map(|tree| tree.children().references())
+                .collect();
+            if next_level.is_empty() {
+                break;
+            }
+            levels.push(next_level);
+        }
+        let algorithm = store_tree.digest_algorithm();
+        let mut below: Vec<StrongReference> = Vec::new();
+        for level in levels.iter().rev() {
+            let mut children = below.into_iter();
+            let trees: Vec<HashedTree> = level
+                .iter()
+                .map(|tree| {
+                    HashedTree::from_with_algorithm(
+                        Arc::new(Tree::new(
+                            tree.blob.clone(),
+                            crate::tree::TreeChildren::try_from(
+                                children
+                                    .by_ref()
+                                    .take(tree.children().references().len())
+                                    .collect::<Vec<_>>(),
+                            )
+                            .expect("Max child count enforced by DeepTreeChildren"),
+                        )),
+                        algorithm,
+                    )
+                })
+                .collect();
+            below = stream::iter(trees.iter().map(|tree| store_tree.store_tree(tree)))
+                .buffered(MAX_CONCURRENT_OPERATIONS)
+                .try_collect()
+                .await?;
+        }
+        Ok(below.pop().expect("The first level is the root"))
+    }
+}
+
+impl Drop for DeepTree {
+    /// The children are dropped one after another instead of recursively, so that very deep trees don't overflow the stack.
+    fn drop(&mut self) {
+        let mut pending = std::mem::take(&mut self.children.references);
+        while let Some(mut tree) = pending.pop() {
+            pending.append(&mut tree.children.references);
         }
-        let tree = Arc::new(Tree::new(
-            self.blob.clone(),
-            crate::tree::TreeChildren::try_from(references)
-                .expect("Max child count enforced by DeepTreeChildren"),
-        ));
-        store_tree.store_tree(&HashedTree::from(tree)).await
     }
 }
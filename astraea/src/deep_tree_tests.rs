@@ -1,11 +1,12 @@
 use crate::{
     deep_tree::{DeepTree, DeepTreeChildren},
+    fault_injecting_storage::{Fault, FaultInjectingStorage, FaultOperation, FaultRule},
     in_memory_storage::InMemoryTreeStorage,
     storage::{LoadError, StoreTree},
     tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
 };
 use pretty_assertions::assert_eq;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 #[test_log::test(tokio::test)]
 async fn test_deep_tree_deserialize_simple_tree() {
@@ -82,3 +83,117 @@ async fn test_deep_tree_deserialize_not_found() {
     let result = DeepTree::deserialize(&digest, &storage).await;
     assert_eq!(Err(LoadError::TreeNotFound(digest)), result);
 }
+
+fn deep_tree(content: &str, children: Vec<DeepTree>) -> DeepTree {
+    DeepTree::new(
+        TreeBlob::try_from(bytes::Bytes::copy_from_slice(content.as_bytes())).unwrap(),
+        DeepTreeChildren::try_from(children).unwrap(),
+    )
+}
+
+#[test_log::test(tokio::test)]
+async fn test_deep_tree_serialize_round_trip() {
+    let storage = InMemoryTreeStorage::empty();
+    // Different numbers of children per tree so that a mix-up between the levels would be noticed.
+    let original = deep_tree(
+        "root",
+        vec![
+            deep_tree("a", vec![deep_tree("a0", vec![]), deep_tree("a1", vec![])]),
+            deep_tree("b", vec![]),
+            deep_tree("c", vec![deep_tree("c0", vec![deep_tree("c00", vec![])])]),
+            deep_tree("a", vec![deep_tree("a0", vec![]), deep_tree("a1", vec![])]),
+        ],
+    );
+    let reference = original.serialize(&storage).await.unwrap();
+    assert_eq!(8, storage.number_of_trees().await);
+    assert_eq!(
+        Ok(original),
+        DeepTree::deserialize(reference.digest(), &storage).await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_deep_tree_very_deep() {
+    let depth = 20_000;
+    let mut original = deep_tree("leaf", vec![]);
+    for _ in 0..depth {
+        original = deep_tree("", vec![original]);
+    }
+    let storage = InMemoryTreeStorage::empty();
+    let reference = original.serialize(&storage).await.unwrap();
+    assert_eq!(depth + 1, storage.number_of_trees().await);
+    let deserialized = DeepTree::deserialize(reference.digest(), &storage)
+        .await
+        .unwrap();
+    // Comparing the trees directly would recurse, so the digests are compared instead.
+    let other_storage = InMemoryTreeStorage::empty();
+    assert_eq!(
+        reference.digest(),
+        deserialized
+            .serialize(&other_storage)
+            .await
+            .unwrap()
+            .digest()
+    );
+    let mut current = &deserialized;
+    let mut current_depth = 0;
+    while let Some(child) = current.children().references().first() {
+        current = child;
+        current_depth += 1;
+    }
+    assert_eq!(depth, current_depth);
+    assert_eq!(b"leaf", current.blob().as_slice());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_deep_tree_stores_and_loads_siblings_concurrently() {
+    let storage = FaultInjectingStorage::new(Arc::new(InMemoryTreeStorage::empty()));
+    let delay = Duration::from_millis(50);
+    storage.add_rule(FaultRule::always(
+        FaultOperation::Store,
+        Fault::Delay(delay),
+    ));
+    storage.add_rule(FaultRule::always(FaultOperation::Load, Fault::Delay(delay)));
+    let original = deep_tree(
+        "root",
+        (0..16)
+            .map(|index| deep_tree(&format!("{index}"), vec![]))
+            .collect(),
+    );
+    let started = std::time::Instant::now();
+    let reference = original.serialize(&storage).await.unwrap();
+    // One after another, this would take 17 delays.
+    assert!(started.elapsed() < delay * 8);
+    let started = std::time::Instant::now();
+    assert_eq!(
+        Ok(original),
+        DeepTree::deserialize(reference.digest(), &storage).await
+    );
+    assert!(started.elapsed() < delay * 8);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_deep_tree_deserialize_missing_child() {
+    let storage = FaultInjectingStorage::new(Arc::new(InMemoryTreeStorage::empty()));
+    let child = storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(bytes::Bytes::from("child")).unwrap(),
+            TreeChildren::empty(),
+        ))))
+        .await
+        .unwrap();
+    let child_digest = *child.digest();
+    let reference = deep_tree("root", vec![deep_tree("child", vec![])])
+        .serialize(&storage)
+        .await
+        .unwrap();
+    storage.add_rule(FaultRule::once(
+        FaultOperation::Load,
+        child_digest,
+        Fault::NotFound,
+    ));
+    assert_eq!(
+        Err(LoadError::TreeNotFound(child_digest)),
+        DeepTree::deserialize(reference.digest(), &storage).await
+    );
+}
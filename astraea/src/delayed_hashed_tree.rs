@@ -33,6 +33,14 @@ impl DelayedHashedTree {
         }
     }
 
+    /// The tree without checking its digest.
+    pub fn tree(&self) -> &Arc<Tree> {
+        match &self.alternatives {
+            DelayedHashedTreeAlternatives::Delayed(tree, _) => tree,
+            DelayedHashedTreeAlternatives::Immediate(hashed_tree) => hashed_tree.tree(),
+        }
+    }
+
     //#[instrument(skip_all)]
     // TODO: Why does this return Option instead of Result? What does None signify - hash mismatch?
     // Should hash verification failure be an error type instead of None for better error handling?
@@ -40,7 +48,8 @@ impl DelayedHashedTree {
     pub fn hash(self) -> Option<HashedTree> {
         match self.alternatives {
             DelayedHashedTreeAlternatives::Delayed(tree, expected_digest) => {
-                let hashed_tree = HashedTree::from(tree);
+                let hashed_tree =
+                    HashedTree::from_with_algorithm(tree, expected_digest.algorithm());
                 if hashed_tree.digest() == &expected_digest {
                     Some(hashed_tree)
                 } else {
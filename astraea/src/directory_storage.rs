@@ -0,0 +1,456 @@
+use crate::{
+    delayed_hashed_tree::DelayedHashedTree,
+    storage::{
+        CollectGarbage, CommitChanges, CompareAndSwapResult, GarbageCollectionStats, LoadError,
+        LoadRoot, LoadStoreTree, LoadTree, MeasureStoredSize, ReplaceTree, StoreError, StoreTree,
+        StrongDelayedHashedTree, StrongReference, StrongReferenceTrait, UpdateRoot,
+    },
+    storage_protocol::SerializedTree,
+    tree::{BlobDigest, HashedTree, TreeSerializationError},
+};
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+};
+use tokio::sync::Mutex;
+use tracing::{debug, info, instrument};
+
+#[derive(Debug)]
+struct DirectoryStrongReferenceImpl {}
+
+impl StrongReferenceTrait for DirectoryStrongReferenceImpl {}
+
+#[derive(Debug)]
+struct DirectoryState {
+    /// Trees kept alive by strong references in memory, like in [crate::sqlite_storage::SQLiteStorage].
+    additional_roots: BTreeMap<BlobDigest, Weak<DirectoryStrongReferenceImpl>>,
+    writes_since_commit: u64,
+    /// Directories in which files were created or deleted since the last commit.
+    modified_directories: BTreeSet<PathBuf>,
+}
+
+impl DirectoryState {
+    fn require_additional_root(&mut self, digest: &BlobDigest) -> StrongReference {
+        if let Some(existing) = self
+            .additional_roots
+            .get(digest)
+            .and_then(|existing| existing.upgrade())
+        {
+            return StrongReference::new(Some(existing), *digest);
+        }
+        let reference_counter = Arc::new(DirectoryStrongReferenceImpl {});
+        self.additional_roots
+            .insert(*digest, Arc::downgrade(&reference_counter));
+        StrongReference::new(Some(reference_counter), *digest)
+    }
+}
+
+/// Stores every tree as a file `trees/ab/cd/<digest>` in a directory, where `ab` and `cd` are the first bytes of the digest
+/// in hex, not counting the algorithm tag. A tree file contains the blob and the child digests serialized with postcard. A
+/// root is a file in `roots/` named after the hex-encoded root name, containing the hex digest of its target. All files are written to `tmp/` first and
+/// then renamed, so no reader ever sees a partially written file.
+///
+/// Only one process may use a directory at a time.
+#[derive(Debug)]
+pub struct DirectoryStorage {
+    directory: PathBuf,
+    next_temporary_file: AtomicU64,
+    state: Mutex<DirectoryState>,
+}
+
+fn io_error_message(path: &Path, error: &std::io::Error) -> String {
+    format!("{}: {}", path.display(), error)
+}
+
+fn store_io_error(path: &Path) -> impl Fn(std::io::Error) -> StoreError + '_ {
+    move |error| StoreError::Io(io_error_message(path, &error))
+}
+
+fn load_io_error(path: &Path) -> impl Fn(std::io::Error) -> LoadError + '_ {
+    move |error| LoadError::Io(io_error_message(path, &error))
+}
+
+fn parse_root_file(path: &Path, content: &str) -> Result<BlobDigest, String> {
+    BlobDigest::parse_hex_string(content.trim())
+        .ok_or_else(|| format!("Root file {} does not contain a digest", path.display()))
+}
+
+fn read_tree_file(path: &Path, digest: &BlobDigest) -> Result<Option<SerializedTree>, LoadError> {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(LoadError::Io(io_error_message(path, &error))),
+    };
+    postcard::from_bytes(&content).map(Some).map_err(|error| {
+        LoadError::Deserialization(*digest, TreeSerializationError::Postcard(error))
+    })
+}
+
+impl DirectoryStorage {
+    /// Creates the directory layout if necessary. Temporary files left over by a crash are deleted.
+    pub fn open(directory: &Path) -> std::io::Result<DirectoryStorage> {
+        for subdirectory in ["trees", "roots", "tmp"] {
+            std::fs::create_dir_all(directory.join(subdirectory))?;
+        }
+        for leftover in std::fs::read_dir(directory.join("tmp"))? {
+            let leftover = leftover?.path();
+            info!("Deleting leftover temporary file {}", leftover.display());
+            std::fs::remove_file(&leftover)?;
+        }
+        Ok(DirectoryStorage {
+            directory: directory.to_path_buf(),
+            next_temporary_file: AtomicU64::new(0),
+            state: Mutex::new(DirectoryState {
+                additional_roots: BTreeMap::new(),
+                writes_since_commit: 0,
+                modified_directories: BTreeSet::new(),
+            }),
+        })
+    }
+
+    pub fn tree_path(&self, digest: &BlobDigest) -> PathBuf {
+        // Sharding by the tag would put all trees of an algorithm into the same directory.
+        let hex = hex::encode(digest.as_bytes());
+        self.directory
+            .join("trees")
+            .join(&hex[0..2])
+            .join(&hex[2..4])
+            .join(digest.to_string())
+    }
+
+    pub fn root_path(&self, name: &str) -> PathBuf {
+        // Root names can contain anything, including slashes, but hex is always a valid file name.
+        self.directory.join("roots").join(hex::encode(name))
+    }
+
+    fn write_atomically(
+        &self,
+        state: &mut DirectoryState,
+        path: &Path,
+        content: &[u8],
+    ) -> std::io::Result<()> {
+        let temporary = self.directory.join("tmp").join(format!(
+            "{}",
+            self.next_temporary_file.fetch_add(1, Ordering::Relaxed)
+        ));
+        {
+            let mut file = std::fs::File::create(&temporary)?;
+            file.write_all(content)?;
+            // Without this, a crash could leave an empty file behind after the rename became durable.
+            file.sync_all()?;
+        }
+        let parent = path
+            .parent()
+            .expect("Tree and root files always have a parent");
+        std::fs::create_dir_all(parent)?;
+        std::fs::rename(&temporary, path)?;
+        // The shard directories may have just been created, so their parents have to be synced as well.
+        for directory in parent
+            .ancestors()
+            .take_while(|directory| directory.starts_with(&self.directory))
+        {
+            state.modified_directories.insert(directory.to_path_buf());
+        }
+        state.writes_since_commit += 1;
+        Ok(())
+    }
+
+    fn tree_files(&self) -> std::io::Result<Vec<(BlobDigest, PathBuf)>> {
+        let mut result = Vec::new();
+        for first in std::fs::read_dir(self.directory.join("trees"))? {
+            for second in std::fs::read_dir(first?.path())? {
+                for file in std::fs::read_dir(second?.path())? {
+                    let path = file?.path();
+                    let digest = path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(BlobDigest::parse_hex_string);
+                    match digest {
+                        Some(digest) => result.push((digest, path)),
+                        None => debug!("Ignoring unexpected file {}", path.display()),
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn root_targets(&self) -> Result<Vec<BlobDigest>, StoreError> {
+        let roots_directory = self.directory.join("roots");
+        let mut result = Vec::new();
+        for file in std::fs::read_dir(&roots_directory).map_err(store_io_error(&roots_directory))? {
+            let path = file.map_err(store_io_error(&roots_directory))?.path();
+            let content = std::fs::read_to_string(&path).map_err(store_io_error(&path))?;
+            result.push(parse_root_file(&path, &content).map_err(StoreError::CorruptedStorage)?);
+        }
+        Ok(result)
+    }
+}
+
+impl DirectoryStorage {
+    /// An existing tree file is kept unless `replace` is set.
+    fn store_tree_locked(
+        &self,
+        state: &mut DirectoryState,
+        tree: &HashedTree,
+        replace: bool,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let digest = tree.digest();
+        let path = self.tree_path(digest);
+        if replace || !path.exists() {
+            for child in tree.tree().children().references() {
+                if !self.tree_path(child.digest()).exists() {
+                    return Err(StoreError::TreeMissing(LoadError::TreeNotFound(
+                        *child.digest(),
+                    )));
+                }
+            }
+            let content =
+                postcard::to_stdvec(&SerializedTree::from_tree(tree.tree())).map_err(|error| {
+                    StoreError::TreeSerializationError(TreeSerializationError::Postcard(error))
+                })?;
+            self.write_atomically(state, &path, &content)
+                .map_err(store_io_error(&path))?;
+        }
+        Ok(state.require_additional_root(digest))
+    }
+}
+
+#[async_trait]
+impl StoreTree for DirectoryStorage {
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        self.store_tree_locked(&mut state_locked, tree, false)
+    }
+}
+
+#[async_trait]
+impl ReplaceTree for DirectoryStorage {
+    async fn replace_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        self.store_tree_locked(&mut state_locked, tree, true)
+    }
+}
+
+#[async_trait]
+impl LoadTree for DirectoryStorage {
+    async fn load_tree(
+        &self,
+        reference: &BlobDigest,
+    ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+        let mut state_locked = self.state.lock().await;
+        let serialized = match read_tree_file(&self.tree_path(reference), reference)? {
+            Some(serialized) => serialized,
+            None => return Err(LoadError::TreeNotFound(*reference)),
+        };
+        let root_reference = state_locked.require_additional_root(reference);
+        let children = serialized
+            .children
+            .iter()
+            .map(|child| state_locked.require_additional_root(child))
+            .collect();
+        let tree = serialized
+            .to_tree(children)
+            .map_err(|error| LoadError::Deserialization(*reference, error))?;
+        Ok(StrongDelayedHashedTree::new(
+            root_reference,
+            DelayedHashedTree::delayed(Arc::new(tree), *reference),
+        ))
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        let _state_locked = self.state.lock().await;
+        let trees_directory = self.directory.join("trees");
+        Ok(self
+            .tree_files()
+            .map_err(store_io_error(&trees_directory))?
+            .len() as u64)
+    }
+}
+
+impl LoadStoreTree for DirectoryStorage {}
+
+#[async_trait]
+impl MeasureStoredSize for DirectoryStorage {
+    /// The size of the tree files, which also contain the digests of the children.
+    async fn stored_sizes(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<Option<u64>>, LoadError> {
+        let _state_locked = self.state.lock().await;
+        digests
+            .iter()
+            .map(|digest| {
+                let path = self.tree_path(digest);
+                match std::fs::metadata(&path) {
+                    Ok(metadata) => Ok(Some(metadata.len())),
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(error) => Err(load_io_error(&path)(error)),
+                }
+            })
+            .collect()
+    }
+}
+
+impl DirectoryStorage {
+    fn update_root_locked(
+        &self,
+        state: &mut DirectoryState,
+        name: &str,
+        target: &StrongReference,
+    ) -> std::result::Result<(), StoreError> {
+        if !self.tree_path(target.digest()).exists() {
+            return Err(StoreError::TreeMissing(LoadError::TreeNotFound(
+                *target.digest(),
+            )));
+        }
+        let path = self.root_path(name);
+        self.write_atomically(state, &path, format!("{}\n", target.digest()).as_bytes())
+            .map_err(store_io_error(&path))
+    }
+}
+
+#[async_trait]
+impl UpdateRoot for DirectoryStorage {
+    async fn update_root(
+        &self,
+        name: &str,
+        target: &StrongReference,
+    ) -> std::result::Result<(), StoreError> {
+        info!("Update root {} to {}", name, target);
+        let mut state_locked = self.state.lock().await;
+        self.update_root_locked(&mut state_locked, name, target)
+    }
+
+    async fn compare_and_swap_root(
+        &self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &StrongReference,
+    ) -> std::result::Result<CompareAndSwapResult, StoreError> {
+        info!("Compare and swap root {} to {}", name, new);
+        let mut state_locked = self.state.lock().await;
+        let path = self.root_path(name);
+        let actual = match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                Some(parse_root_file(&path, &content).map_err(StoreError::CorruptedStorage)?)
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+            Err(error) => return Err(store_io_error(&path)(error)),
+        };
+        if actual.as_ref() != expected {
+            return Ok(CompareAndSwapResult::Conflict { actual });
+        }
+        self.update_root_locked(&mut state_locked, name, new)?;
+        Ok(CompareAndSwapResult::Swapped)
+    }
+}
+
+#[async_trait]
+impl LoadRoot for DirectoryStorage {
+    async fn load_root(
+        &self,
+        name: &str,
+    ) -> std::result::Result<Option<StrongReference>, LoadError> {
+        let mut state_locked = self.state.lock().await;
+        let path = self.root_path(name);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(load_io_error(&path)(error)),
+        };
+        let digest = parse_root_file(&path, &content).map_err(LoadError::Io)?;
+        // Like SQLiteStorage, a root pointing to a missing tree is treated as not existing.
+        if !self.tree_path(&digest).exists() {
+            return Ok(None);
+        }
+        Ok(Some(state_locked.require_additional_root(&digest)))
+    }
+}
+
+#[async_trait]
+impl CollectGarbage for DirectoryStorage {
+    /// Unlike [crate::sqlite_storage::SQLiteStorage], this collects all garbage at once: everything that isn't reachable from a root
+    /// or from a tree that is still referenced in memory is deleted.
+    #[instrument(skip_all)]
+    async fn collect_some_garbage(
+        &self,
+    ) -> std::result::Result<GarbageCollectionStats, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        state_locked
+            .additional_roots
+            .retain(|_, reference_counter| reference_counter.upgrade().is_some());
+        let mut pending: Vec<BlobDigest> = self.root_targets()?;
+        pending.extend(state_locked.additional_roots.keys());
+        let mut reachable: BTreeSet<BlobDigest> = BTreeSet::new();
+        while let Some(digest) = pending.pop() {
+            if !reachable.insert(digest) {
+                continue;
+            }
+            let path = self.tree_path(&digest);
+            match read_tree_file(&path, &digest) {
+                Ok(Some(serialized)) => pending.extend(serialized.children),
+                Ok(None) => {}
+                Err(error) => {
+                    return Err(StoreError::CorruptedStorage(format!(
+                        "Could not read {} during garbage collection: {}",
+                        path.display(),
+                        error
+                    )))
+                }
+            }
+        }
+        let trees_directory = self.directory.join("trees");
+        let mut trees_collected = 0;
+        for (digest, path) in self
+            .tree_files()
+            .map_err(store_io_error(&trees_directory))?
+        {
+            if reachable.contains(&digest) {
+                continue;
+            }
+            std::fs::remove_file(&path).map_err(store_io_error(&path))?;
+            let parent = path.parent().expect("Tree files always have a parent");
+            state_locked
+                .modified_directories
+                .insert(parent.to_path_buf());
+            trees_collected += 1;
+        }
+        debug!(
+            "Garbage collection deleted {} unreachable trees",
+            trees_collected
+        );
+        Ok(GarbageCollectionStats { trees_collected })
+    }
+}
+
+#[async_trait]
+impl CommitChanges for DirectoryStorage {
+    /// Every file is already complete when it appears, but the renames only become durable when the directories are synced.
+    /// Returns the number of files written since the last commit.
+    #[instrument(skip_all)]
+    async fn commit_changes(&self) -> Result<u64, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        for directory in std::mem::take(&mut state_locked.modified_directories) {
+            std::fs::File::open(&directory)
+                .and_then(|file| file.sync_all())
+                .map_err(store_io_error(&directory))?;
+        }
+        let writes = state_locked.writes_since_commit;
+        state_locked.writes_since_commit = 0;
+        info!("Committed {} writes", writes);
+        Ok(writes)
+    }
+}
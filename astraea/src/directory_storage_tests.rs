@@ -0,0 +1,263 @@
+use crate::{
+    directory_storage::DirectoryStorage,
+    storage::{
+        CollectGarbage, CommitChanges, CompareAndSwapResult, GarbageCollectionStats, LoadError,
+        LoadRoot, LoadTree, ReplaceTree, StoreError, StoreTree, StrongReference, UpdateRoot,
+    },
+    tree::{HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+fn make_tree(content: &'static [u8], children: Vec<StrongReference>) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from_static(content)).unwrap(),
+        TreeChildren::try_from(children).unwrap(),
+    )))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_and_load() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    assert_eq!(0, storage.approximate_tree_count().await.unwrap());
+    let child = make_tree(b"child", vec![]);
+    let child_reference = storage.store_tree(&child).await.unwrap();
+    assert_eq!(child.digest(), child_reference.digest());
+    let parent = make_tree(b"parent", vec![child_reference]);
+    let parent_reference = storage.store_tree(&parent).await.unwrap();
+    // Storing the same tree again doesn't write anything.
+    storage.store_tree(&parent).await.unwrap();
+    assert_eq!(2, storage.commit_changes().await.unwrap());
+    assert_eq!(0, storage.commit_changes().await.unwrap());
+    assert_eq!(2, storage.approximate_tree_count().await.unwrap());
+
+    let hex = hex::encode(child.digest().as_bytes());
+    assert!(workspace
+        .path()
+        .join("trees")
+        .join(&hex[0..2])
+        .join(&hex[2..4])
+        .join(child.digest().to_string())
+        .is_file());
+
+    let loaded = storage
+        .load_tree(parent_reference.digest())
+        .await
+        .unwrap()
+        .hash()
+        .unwrap();
+    assert_eq!(&parent, loaded.hashed_tree());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_load_tree_not_found() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    let digest = *make_tree(b"missing", vec![]).digest();
+    assert_eq!(
+        LoadError::TreeNotFound(digest),
+        storage.load_tree(&digest).await.unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_with_missing_child() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    let child = make_tree(b"child", vec![]);
+    let parent = make_tree(b"parent", vec![StrongReference::from_weak(*child.digest())]);
+    assert_eq!(
+        StoreError::TreeMissing(LoadError::TreeNotFound(*child.digest())),
+        storage.store_tree(&parent).await.unwrap_err()
+    );
+    assert_eq!(0, storage.commit_changes().await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_roots_survive_reopening() {
+    let workspace = tempfile::tempdir().unwrap();
+    let tree = make_tree(b"root", vec![]);
+    {
+        let storage = DirectoryStorage::open(workspace.path()).unwrap();
+        assert_eq!(None, storage.load_root("a/b").await.unwrap());
+        let reference = storage.store_tree(&tree).await.unwrap();
+        storage.update_root("a/b", &reference).await.unwrap();
+        assert_eq!(2, storage.commit_changes().await.unwrap());
+        assert_eq!(
+            format!("{}\n", tree.digest()),
+            std::fs::read_to_string(storage.root_path("a/b")).unwrap()
+        );
+    }
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    let loaded = storage.load_root("a/b").await.unwrap().unwrap();
+    assert_eq!(tree.digest(), loaded.digest());
+    assert_eq!(None, storage.load_root("a").await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_update_root_missing_tree() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    let digest = *make_tree(b"missing", vec![]).digest();
+    assert_eq!(
+        StoreError::TreeMissing(LoadError::TreeNotFound(digest)),
+        storage
+            .update_root("test", &StrongReference::from_weak(digest))
+            .await
+            .unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_collect_garbage() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    let rooted_child = make_tree(b"rooted child", vec![]);
+    let rooted_parent = {
+        let child = storage.store_tree(&rooted_child).await.unwrap();
+        storage
+            .store_tree(&make_tree(b"rooted parent", vec![child]))
+            .await
+            .unwrap()
+    };
+    storage.update_root("test", &rooted_parent).await.unwrap();
+    drop(rooted_parent);
+    let held = storage
+        .store_tree(&make_tree(b"held", vec![]))
+        .await
+        .unwrap();
+    let garbage = {
+        let child = storage
+            .store_tree(&make_tree(b"garbage child", vec![]))
+            .await
+            .unwrap();
+        *storage
+            .store_tree(&make_tree(b"garbage parent", vec![child]))
+            .await
+            .unwrap()
+            .digest()
+    };
+    assert_eq!(5, storage.approximate_tree_count().await.unwrap());
+
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 2 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(3, storage.approximate_tree_count().await.unwrap());
+    assert_eq!(
+        LoadError::TreeNotFound(garbage),
+        storage.load_tree(&garbage).await.unwrap_err()
+    );
+    assert!(storage.load_tree(rooted_child.digest()).await.is_ok());
+    assert!(storage.load_tree(held.digest()).await.is_ok());
+
+    drop(held);
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 1 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 0 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    storage.commit_changes().await.unwrap();
+}
+
+#[test_log::test(tokio::test)]
+async fn test_modified_tree_file_is_detected() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    let original = make_tree(b"original", vec![]);
+    let reference = storage.store_tree(&original).await.unwrap();
+    let other = make_tree(b"modified", vec![]);
+    let other_reference = storage.store_tree(&other).await.unwrap();
+    std::fs::copy(
+        storage.tree_path(other_reference.digest()),
+        storage.tree_path(reference.digest()),
+    )
+    .unwrap();
+    let loaded = storage.load_tree(reference.digest()).await.unwrap();
+    assert!(loaded.hash().is_none());
+
+    std::fs::write(storage.tree_path(reference.digest()), [0xff]).unwrap();
+    assert!(matches!(
+        storage.load_tree(reference.digest()).await.unwrap_err(),
+        LoadError::Deserialization(digest, _) if &digest == reference.digest()
+    ));
+}
+
+#[test_log::test(tokio::test)]
+async fn test_replace_tree_overwrites_modified_file() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    let original = make_tree(b"original", vec![]);
+    let reference = storage.store_tree(&original).await.unwrap();
+    std::fs::write(storage.tree_path(reference.digest()), [0xff]).unwrap();
+    storage.store_tree(&original).await.unwrap();
+    assert!(storage.load_tree(reference.digest()).await.is_err());
+
+    storage.replace_tree(&original).await.unwrap();
+    let loaded = storage.load_tree(reference.digest()).await.unwrap();
+    assert_eq!(&original, loaded.hash().unwrap().hashed_tree());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_leftover_temporary_files_are_deleted() {
+    let workspace = tempfile::tempdir().unwrap();
+    drop(DirectoryStorage::open(workspace.path()).unwrap());
+    let leftover = workspace.path().join("tmp").join("123");
+    std::fs::write(&leftover, b"half written").unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    assert!(!leftover.exists());
+    assert_eq!(0, storage.approximate_tree_count().await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_compare_and_swap_root() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = DirectoryStorage::open(workspace.path()).unwrap();
+    let first = storage
+        .store_tree(&make_tree(b"first", vec![]))
+        .await
+        .unwrap();
+    let second = storage
+        .store_tree(&make_tree(b"second", vec![]))
+        .await
+        .unwrap();
+    assert_eq!(
+        CompareAndSwapResult::Conflict { actual: None },
+        storage
+            .compare_and_swap_root("test", Some(first.digest()), &second)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("test", None, &first)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Conflict {
+            actual: Some(*first.digest())
+        },
+        storage
+            .compare_and_swap_root("test", Some(second.digest()), &second)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("test", Some(first.digest()), &second)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        second.digest(),
+        storage.load_root("test").await.unwrap().unwrap().digest()
+    );
+}
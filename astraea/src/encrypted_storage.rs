@@ -0,0 +1,464 @@
+use crate::{
+    delayed_hashed_tree::DelayedHashedTree,
+    storage::{
+        LoadError, LoadStoreTree, LoadTree, StoreError, StoreTree, StrongDelayedHashedTree,
+        StrongReference, StrongReferenceTrait,
+    },
+    tree::{
+        BlobDigest, DigestAlgorithm, HashedTree, Tree, TreeBlob, TreeChildren,
+        TreeSerializationError, TREE_BLOB_MAX_LENGTH,
+    },
+};
+use async_trait::async_trait;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+};
+
+/// The ciphertext follows the tag directly in the blob of the outer tree. The children of the outer tree are the
+/// encrypted children.
+const TAG_INLINE: u8 = 0;
+/// The ciphertext didn't fit into a single blob. The first child of the outer tree holds the encrypted children,
+/// the remaining children are leaves containing consecutive pieces of the ciphertext.
+const TAG_CHUNKED: u8 = 1;
+/// See [EncryptedStorage::seal].
+const TAG_ENVELOPE: u8 = 2;
+
+const NONCE_LENGTH: usize = 12;
+
+/// How many ciphertext digests of trees that nobody holds a reference to anymore are remembered, so that these trees can
+/// still be loaded by their plaintext digest for a while.
+pub const MAX_RECENTLY_RELEASED_DIGESTS: usize = 10_000;
+
+/// What gets encrypted for every tree. The plaintext digests of the children are needed to derive their keys when loading.
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    blob: Vec<u8>,
+    children: Vec<BlobDigest>,
+}
+
+/// Keeps the ciphertext tree alive in the inner storage as long as the plaintext reference exists.
+struct EncryptedStrongReferenceImpl {
+    plaintext_digest: BlobDigest,
+    outer: StrongReference,
+    digests: Weak<std::sync::Mutex<OuterDigests>>,
+}
+
+impl StrongReferenceTrait for EncryptedStrongReferenceImpl {}
+
+impl Drop for EncryptedStrongReferenceImpl {
+    fn drop(&mut self) {
+        let digests = match self.digests.upgrade() {
+            Some(digests) => digests,
+            None => return,
+        };
+        let mut digests_locked = digests.lock().unwrap();
+        match digests_locked.alive.get(&self.plaintext_digest) {
+            Some(existing) if existing.strong_count() > 0 => {
+                // Someone created a new reference for the same digest in the meantime.
+            }
+            _ => {
+                digests_locked.alive.remove(&self.plaintext_digest);
+                digests_locked.release(self.plaintext_digest, *self.outer.digest());
+            }
+        }
+    }
+}
+
+/// Ciphertext digests by plaintext digest.
+#[derive(Default)]
+struct OuterDigests {
+    /// Trees that are referenced. Every reference carries its ciphertext digest.
+    alive: BTreeMap<BlobDigest, Weak<EncryptedStrongReferenceImpl>>,
+    /// At most [MAX_RECENTLY_RELEASED_DIGESTS] trees that were referenced before.
+    recently_released: BTreeMap<BlobDigest, BlobDigest>,
+    /// The keys of `recently_released`, oldest first.
+    release_order: VecDeque<BlobDigest>,
+}
+
+impl OuterDigests {
+    fn release(&mut self, plaintext_digest: BlobDigest, outer_digest: BlobDigest) {
+        if self
+            .recently_released
+            .insert(plaintext_digest, outer_digest)
+            .is_none()
+        {
+            self.release_order.push_back(plaintext_digest);
+        }
+        while self.release_order.len() > MAX_RECENTLY_RELEASED_DIGESTS {
+            let oldest = self
+                .release_order
+                .pop_front()
+                .expect("The queue is not empty");
+            self.recently_released.remove(&oldest);
+        }
+    }
+}
+
+/// Convergent encryption on top of another storage: every tree is encrypted with a key derived from its plaintext digest,
+/// so equal trees still result in equal ciphertext trees and are deduplicated by the inner storage. The inner storage only
+/// ever sees ciphertext, and the plaintext digests of the children are hidden inside of it.
+///
+/// The `convergence_secret` is mixed into every key. Without it, anyone who can guess the plaintext of a tree could
+/// confirm that it's stored. Deduplication only works between storages using the same secret.
+///
+/// A tree can only be loaded after its ciphertext digest became known by storing it, loading its parent, or [EncryptedStorage::unseal].
+/// The digest is known as long as a reference to the tree exists, and for the last [MAX_RECENTLY_RELEASED_DIGESTS] trees
+/// after that. The sealed roots are what persists the mapping in the inner storage: every other tree can be found again by
+/// loading its parents starting from a root.
+pub struct EncryptedStorage {
+    inner: Arc<dyn LoadStoreTree + Send + Sync>,
+    convergence_secret: [u8; 32],
+    outer_digests: Arc<std::sync::Mutex<OuterDigests>>,
+}
+
+impl std::fmt::Debug for EncryptedStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The secret must never end up in a log.
+        f.debug_struct("EncryptedStorage")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn LoadStoreTree + Send + Sync>, convergence_secret: [u8; 32]) -> Self {
+        Self {
+            inner,
+            convergence_secret,
+            outer_digests: Arc::new(std::sync::Mutex::new(OuterDigests::default())),
+        }
+    }
+
+    fn derive_key(&self, purpose: &[u8], digest: Option<&BlobDigest>) -> [u8; 32] {
+        let mut hasher = Sha3_256::new();
+        hasher.update(self.convergence_secret);
+        hasher.update(purpose);
+        if let Some(digest) = digest {
+            hasher.update(digest.to_tagged_bytes());
+        }
+        hasher.finalize().into()
+    }
+
+    fn tree_cipher(&self, plaintext_digest: &BlobDigest) -> ChaCha20Poly1305 {
+        let key = self.derive_key(b"tree", Some(plaintext_digest));
+        ChaCha20Poly1305::new(Key::from_slice(&key))
+    }
+
+    fn outer_digest(&self, plaintext_digest: &BlobDigest) -> Option<BlobDigest> {
+        match self.find_outer(plaintext_digest) {
+            Some(Ok(outer)) => Some(*outer.digest()),
+            Some(Err(outer_digest)) => Some(outer_digest),
+            None => None,
+        }
+    }
+
+    /// Returns the reference to the ciphertext tree if the plaintext tree is referenced, or only the digest of the
+    /// ciphertext tree if it was released recently.
+    fn find_outer(
+        &self,
+        plaintext_digest: &BlobDigest,
+    ) -> Option<Result<StrongReference, BlobDigest>> {
+        let digests_locked = self.outer_digests.lock().unwrap();
+        let alive = digests_locked
+            .alive
+            .get(plaintext_digest)
+            .and_then(Weak::upgrade);
+        let result = match &alive {
+            Some(existing) => Some(Ok(existing.outer.clone())),
+            None => digests_locked
+                .recently_released
+                .get(plaintext_digest)
+                .map(|outer_digest| Err(*outer_digest)),
+        };
+        // The reference may be the last one, and dropping it needs the lock.
+        drop(digests_locked);
+        result
+    }
+
+    /// A reference to the ciphertext tree that keeps it alive in the inner storage.
+    async fn require_outer(
+        &self,
+        plaintext_digest: &BlobDigest,
+    ) -> Result<StrongReference, LoadError> {
+        match self.find_outer(plaintext_digest) {
+            Some(Ok(outer)) => Ok(outer),
+            Some(Err(outer_digest)) => match self.inner.load_tree(&outer_digest).await {
+                Ok(loaded) => Ok(loaded.reference().clone()),
+                Err(LoadError::TreeNotFound(_)) => Err(LoadError::TreeNotFound(*plaintext_digest)),
+                Err(error) => Err(error),
+            },
+            None => Err(LoadError::TreeNotFound(*plaintext_digest)),
+        }
+    }
+
+    /// `outer` is the ciphertext tree that the plaintext tree is loaded from, and it's kept alive by the result.
+    fn wrap_reference(
+        &self,
+        plaintext_digest: &BlobDigest,
+        outer: StrongReference,
+    ) -> StrongReference {
+        let mut digests_locked = self.outer_digests.lock().unwrap();
+        if let Some(existing) = digests_locked
+            .alive
+            .get(plaintext_digest)
+            .and_then(|existing| existing.upgrade())
+        {
+            return StrongReference::new(Some(existing), *plaintext_digest);
+        }
+        let reference_impl = Arc::new(EncryptedStrongReferenceImpl {
+            plaintext_digest: *plaintext_digest,
+            outer,
+            digests: Arc::downgrade(&self.outer_digests),
+        });
+        digests_locked
+            .alive
+            .insert(*plaintext_digest, Arc::downgrade(&reference_impl));
+        StrongReference::new(Some(reference_impl), *plaintext_digest)
+    }
+
+    async fn store_outer(
+        &self,
+        blob: &[u8],
+        children: Vec<StrongReference>,
+    ) -> Result<StrongReference, StoreError> {
+        let blob = TreeBlob::try_from(bytes::Bytes::copy_from_slice(blob))
+            .map_err(StoreError::TreeSerializationError)?;
+        let children = TreeChildren::try_from(children).ok_or(
+            StoreError::TreeSerializationError(TreeSerializationError::TooManyChildren),
+        )?;
+        self.inner
+            .store_tree(&HashedTree::from_with_algorithm(
+                Arc::new(Tree::new(blob, children)),
+                self.inner.digest_algorithm(),
+            ))
+            .await
+    }
+
+    async fn load_outer(
+        &self,
+        outer_digest: &BlobDigest,
+    ) -> Result<(StrongReference, Arc<Tree>), LoadError> {
+        let loaded = self.inner.load_tree(outer_digest).await?;
+        match loaded.hash() {
+            Some(hashed) => Ok((
+                hashed.reference().clone(),
+                hashed.hashed_tree().tree().clone(),
+            )),
+            None => Err(LoadError::Inconsistency(
+                *outer_digest,
+                "Ciphertext tree hash does not match the digest".to_string(),
+            )),
+        }
+    }
+
+    /// Stores an unencrypted tree that refers to `target` and contains its plaintext digest, encrypted with a key derived
+    /// only from the convergence secret. The returned reference can be saved as a root in the inner storage, and
+    /// [EncryptedStorage::unseal] gets the plaintext reference back, even in a new process.
+    pub async fn seal(&self, target: &StrongReference) -> Result<StrongReference, StoreError> {
+        let outer_target = self
+            .require_outer(target.digest())
+            .await
+            .map_err(StoreError::TreeMissing)?;
+        let key = self.derive_key(b"envelope", None);
+        let nonce_source = self.derive_key(b"envelope nonce", Some(target.digest()));
+        let nonce = Nonce::from_slice(&nonce_source[..NONCE_LENGTH]);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(nonce, &target.digest().to_tagged_bytes()[..])
+            .expect("Encrypting a digest can't fail");
+        let mut blob = vec![TAG_ENVELOPE];
+        blob.extend_from_slice(nonce);
+        blob.extend_from_slice(&ciphertext);
+        self.store_outer(&blob, vec![outer_target]).await
+    }
+
+    /// Reverses [EncryptedStorage::seal].
+    pub async fn unseal(&self, envelope: &BlobDigest) -> Result<StrongReference, LoadError> {
+        let (_, tree) = self.load_outer(envelope).await?;
+        let blob = tree.blob().as_slice();
+        let outer_target = match (blob.first(), tree.children().references()) {
+            (Some(&TAG_ENVELOPE), [outer_target]) if blob.len() > 1 + NONCE_LENGTH => outer_target,
+            _ => {
+                return Err(LoadError::Inconsistency(
+                    *envelope,
+                    "Not an envelope".to_string(),
+                ))
+            }
+        };
+        let key = self.derive_key(b"envelope", None);
+        let nonce = Nonce::from_slice(&blob[1..1 + NONCE_LENGTH]);
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(nonce, &blob[1 + NONCE_LENGTH..])
+            .map_err(|_| {
+                LoadError::Inconsistency(*envelope, "Could not decrypt the envelope".to_string())
+            })?;
+        let target = BlobDigest::from_tagged_bytes(&plaintext).ok_or_else(|| {
+            LoadError::Inconsistency(*envelope, "Envelope contains no digest".to_string())
+        })?;
+        // The envelope was loaded from the inner storage, so its child is a real reference to the ciphertext target.
+        Ok(self.wrap_reference(&target, outer_target.clone()))
+    }
+
+    async fn decrypt_tree(
+        &self,
+        plaintext_digest: &BlobDigest,
+        outer_digest: &BlobDigest,
+    ) -> Result<(StrongReference, Tree), LoadError> {
+        let inconsistency =
+            |message: &str| LoadError::Inconsistency(*plaintext_digest, message.to_string());
+        let (outer_reference, outer) = self.load_outer(outer_digest).await?;
+        let (ciphertext, outer_children): (Vec<u8>, Vec<StrongReference>) =
+            match outer.blob().as_slice().split_first() {
+                Some((&TAG_INLINE, ciphertext)) => {
+                    (ciphertext.to_vec(), outer.children().references().to_vec())
+                }
+                Some((&TAG_CHUNKED, [])) => {
+                    let (holder, chunks) = match outer.children().references().split_first() {
+                        Some(success) => success,
+                        None => return Err(inconsistency("Chunked tree without children")),
+                    };
+                    let mut ciphertext = Vec::new();
+                    for chunk in chunks {
+                        let (_, chunk) = self.load_outer(chunk.digest()).await?;
+                        ciphertext.extend_from_slice(chunk.blob().as_slice());
+                    }
+                    let (_, holder) = self.load_outer(holder.digest()).await?;
+                    (ciphertext, holder.children().references().to_vec())
+                }
+                _ => return Err(inconsistency("Unknown ciphertext tree format")),
+            };
+        let plaintext = self
+            .tree_cipher(plaintext_digest)
+            .decrypt(&Nonce::default(), &ciphertext[..])
+            .map_err(|_| inconsistency("Could not decrypt the tree"))?;
+        let payload: Payload = postcard::from_bytes(&plaintext)
+            .map_err(|_| inconsistency("Could not deserialize the decrypted tree"))?;
+        if payload.children.len() != outer_children.len() {
+            return Err(inconsistency(
+                "Number of children differs between plaintext and ciphertext",
+            ));
+        }
+        let mut children = Vec::with_capacity(payload.children.len());
+        for (child, outer_child) in payload.children.iter().zip(outer_children) {
+            children.push(self.wrap_reference(child, outer_child));
+        }
+        let blob = TreeBlob::try_from(payload.blob.into())
+            .map_err(|error| LoadError::Deserialization(*plaintext_digest, error))?;
+        let children = TreeChildren::try_from(children).ok_or(LoadError::Deserialization(
+            *plaintext_digest,
+            TreeSerializationError::TooManyChildren,
+        ))?;
+        Ok((outer_reference, Tree::new(blob, children)))
+    }
+}
+
+#[async_trait]
+impl LoadTree for EncryptedStorage {
+    async fn load_tree(
+        &self,
+        reference: &BlobDigest,
+    ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+        let outer_digest = match self.outer_digest(reference) {
+            Some(outer) => outer,
+            None => return Err(LoadError::TreeNotFound(*reference)),
+        };
+        let (outer_reference, tree) = self.decrypt_tree(reference, &outer_digest).await?;
+        // Anyone can write a ciphertext tree that decrypts successfully with a key derived from a digest they know,
+        // so the decrypted tree has to be checked like any other untrusted content.
+        let hashed = HashedTree::from_with_algorithm(Arc::new(tree), reference.algorithm());
+        if hashed.digest() != reference {
+            return Err(LoadError::Inconsistency(
+                *reference,
+                "Decrypted tree hash does not match the digest".to_string(),
+            ));
+        }
+        Ok(StrongDelayedHashedTree::new(
+            self.wrap_reference(reference, outer_reference),
+            DelayedHashedTree::immediate(hashed),
+        ))
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        // Large trees take more than one ciphertext tree, so this may be a bit too high.
+        self.inner.approximate_tree_count().await
+    }
+
+    async fn find_existing_trees(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<bool>, LoadError> {
+        let outer_digests: Vec<Option<BlobDigest>> = digests
+            .iter()
+            .map(|digest| self.outer_digest(digest))
+            .collect();
+        let known: Vec<BlobDigest> = outer_digests.iter().flatten().copied().collect();
+        let mut existing = self.inner.find_existing_trees(&known).await?.into_iter();
+        Ok(outer_digests
+            .iter()
+            .map(|outer| match outer {
+                Some(_) => existing.next().unwrap_or(false),
+                None => false,
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl StoreTree for EncryptedStorage {
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let plaintext_digest = tree.digest();
+        let mut outer_children = Vec::new();
+        for child in tree.tree().children().references() {
+            outer_children.push(
+                self.require_outer(child.digest())
+                    .await
+                    .map_err(StoreError::TreeMissing)?,
+            );
+        }
+        let payload = Payload {
+            blob: tree.tree().blob().as_slice().to_vec(),
+            children: tree
+                .tree()
+                .children()
+                .references()
+                .iter()
+                .map(|child| *child.digest())
+                .collect(),
+        };
+        let plaintext = postcard::to_stdvec(&payload).map_err(|error| {
+            StoreError::TreeSerializationError(TreeSerializationError::Postcard(error))
+        })?;
+        // Every key encrypts exactly one plaintext, so a constant nonce is fine, and it's required to make the ciphertext
+        // deterministic.
+        let ciphertext = self
+            .tree_cipher(plaintext_digest)
+            .encrypt(&Nonce::default(), &plaintext[..])
+            .expect("Encrypting in memory can't fail");
+        // One byte of the blob is needed for the tag.
+        let outer = if ciphertext.len() < TREE_BLOB_MAX_LENGTH {
+            let mut blob = vec![TAG_INLINE];
+            blob.extend_from_slice(&ciphertext);
+            self.store_outer(&blob, outer_children).await?
+        } else {
+            let holder = self.store_outer(&[], outer_children).await?;
+            let mut children = vec![holder];
+            for chunk in ciphertext.chunks(TREE_BLOB_MAX_LENGTH) {
+                children.push(self.store_outer(chunk, Vec::new()).await?);
+            }
+            self.store_outer(&[TAG_CHUNKED], children).await?
+        };
+        Ok(self.wrap_reference(plaintext_digest, outer))
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.inner.digest_algorithm()
+    }
+}
+
+impl LoadStoreTree for EncryptedStorage {}
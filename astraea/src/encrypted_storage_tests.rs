@@ -0,0 +1,257 @@
+use crate::{
+    encrypted_storage::{EncryptedStorage, MAX_RECENTLY_RELEASED_DIGESTS},
+    in_memory_storage::InMemoryTreeStorage,
+    sqlite_storage::SQLiteStorage,
+    storage::{LoadError, LoadStoreTree, LoadTree, StoreTree, StrongReference},
+    tree::{
+        BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren, TREE_BLOB_MAX_LENGTH,
+        TREE_MAX_CHILDREN,
+    },
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+const SECRET: [u8; 32] = [7; 32];
+
+fn leaf(content: &[u8]) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::copy_from_slice(content)).unwrap(),
+        TreeChildren::empty(),
+    )))
+}
+
+fn parent(content: &[u8], children: Vec<StrongReference>) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::copy_from_slice(content)).unwrap(),
+        TreeChildren::try_from(children).unwrap(),
+    )))
+}
+
+async fn load_hashed(storage: &(dyn LoadTree + Send + Sync), digest: &BlobDigest) -> HashedTree {
+    storage
+        .load_tree(digest)
+        .await
+        .unwrap()
+        .hash()
+        .unwrap()
+        .hashed_tree()
+        .clone()
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_and_load() {
+    let inner = Arc::new(InMemoryTreeStorage::empty());
+    let storage = EncryptedStorage::new(inner.clone(), SECRET);
+    let child = leaf(b"secret content");
+    let child_reference = storage.store_tree(&child).await.unwrap();
+    assert_eq!(child.digest(), child_reference.digest());
+    let root = parent(b"secret parent", vec![child_reference.clone()]);
+    let root_reference = storage.store_tree(&root).await.unwrap();
+    assert_eq!(root.digest(), root_reference.digest());
+    assert_eq!(2, inner.approximate_tree_count().await.unwrap());
+
+    assert_eq!(root, load_hashed(&storage, root.digest()).await);
+    assert_eq!(child, load_hashed(&storage, child.digest()).await);
+
+    // The inner storage never sees the plaintext.
+    assert_eq!(
+        vec![false, false],
+        inner
+            .find_existing_trees(&[*root.digest(), *child.digest()])
+            .await
+            .unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_inner_storage_sees_only_ciphertext() {
+    let inner = Arc::new(InMemoryTreeStorage::empty());
+    let storage = EncryptedStorage::new(inner.clone(), SECRET);
+    let child = leaf(b"secret content");
+    let child_reference = storage.store_tree(&child).await.unwrap();
+    let root = parent(b"secret parent", vec![child_reference]);
+    let root_reference = storage.store_tree(&root).await.unwrap();
+    let envelope = storage.seal(&root_reference).await.unwrap();
+
+    let envelope_tree = load_hashed(inner.as_ref(), envelope.digest()).await;
+    let outer_root_digest = *envelope_tree.tree().children().references()[0].digest();
+    let outer_root = load_hashed(inner.as_ref(), &outer_root_digest).await;
+    let outer_child_digest = *outer_root.tree().children().references()[0].digest();
+    assert_ne!(child.digest(), &outer_child_digest);
+    let outer_child = load_hashed(inner.as_ref(), &outer_child_digest).await;
+    for (outer, plaintext) in [
+        (&envelope_tree, root.digest()),
+        (&outer_root, child.digest()),
+    ] {
+        let blob = outer.tree().blob().as_slice();
+        let digest = plaintext.as_bytes();
+        assert!(!blob.windows(digest.len()).any(|window| window == digest));
+    }
+    for (outer, plaintext) in [(&outer_root, &root), (&outer_child, &child)] {
+        let blob = outer.tree().blob().as_slice();
+        let content = plaintext.tree().blob().as_slice();
+        assert!(!blob.windows(content.len()).any(|window| window == content));
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_deduplication() {
+    let inner = Arc::new(InMemoryTreeStorage::empty());
+    let first = EncryptedStorage::new(inner.clone(), SECRET);
+    let second = EncryptedStorage::new(inner.clone(), SECRET);
+    let tree = leaf(b"same content");
+    let first_reference = first.store_tree(&tree).await.unwrap();
+    let second_reference = second.store_tree(&tree).await.unwrap();
+    assert_eq!(first_reference, second_reference);
+    assert_eq!(1, inner.approximate_tree_count().await.unwrap());
+
+    // A different secret results in different ciphertext.
+    let other = EncryptedStorage::new(inner.clone(), [8; 32]);
+    other.store_tree(&tree).await.unwrap();
+    assert_eq!(2, inner.approximate_tree_count().await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_large_tree_is_chunked() {
+    let inner = Arc::new(InMemoryTreeStorage::empty());
+    let storage = EncryptedStorage::new(inner.clone(), SECRET);
+    let mut children = Vec::new();
+    for index in 0..TREE_MAX_CHILDREN {
+        children.push(
+            storage
+                .store_tree(&leaf(&(index as u32).to_be_bytes()))
+                .await
+                .unwrap(),
+        );
+    }
+    let content: Vec<u8> = (0..TREE_BLOB_MAX_LENGTH).map(|index| index as u8).collect();
+    let root = parent(&content, children);
+    let root_reference = storage.store_tree(&root).await.unwrap();
+    assert_eq!(root.digest(), root_reference.digest());
+    // 1000 children, the holder of their ciphertext, 3 chunks and the root
+    assert_eq!(
+        TREE_MAX_CHILDREN as u64 + 5,
+        inner.approximate_tree_count().await.unwrap()
+    );
+    assert_eq!(root, load_hashed(&storage, root.digest()).await);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_seal_and_unseal() {
+    let inner: Arc<dyn LoadStoreTree + Send + Sync> = Arc::new(InMemoryTreeStorage::empty());
+    let envelope = {
+        let storage = EncryptedStorage::new(inner.clone(), SECRET);
+        let child = storage.store_tree(&leaf(b"child")).await.unwrap();
+        let root = storage
+            .store_tree(&parent(b"root", vec![child]))
+            .await
+            .unwrap();
+        storage.seal(&root).await.unwrap()
+    };
+
+    // A new instance doesn't know any ciphertext digests yet.
+    let storage = EncryptedStorage::new(inner.clone(), SECRET);
+    let child = leaf(b"child");
+    let expected_root = parent(b"root", vec![StrongReference::from_weak(*child.digest())]);
+    assert_eq!(
+        LoadError::TreeNotFound(*expected_root.digest()),
+        storage.load_tree(expected_root.digest()).await.unwrap_err()
+    );
+    let root = storage.unseal(envelope.digest()).await.unwrap();
+    assert_eq!(expected_root.digest(), root.digest());
+    assert_eq!(expected_root, load_hashed(&storage, root.digest()).await);
+    assert_eq!(child, load_hashed(&storage, child.digest()).await);
+
+    let wrong_secret = EncryptedStorage::new(inner.clone(), [8; 32]);
+    assert_eq!(
+        LoadError::Inconsistency(
+            *envelope.digest(),
+            "Could not decrypt the envelope".to_string()
+        ),
+        wrong_secret.unseal(envelope.digest()).await.unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_find_existing_trees() {
+    let inner = Arc::new(InMemoryTreeStorage::empty());
+    let storage = EncryptedStorage::new(inner.clone(), SECRET);
+    let stored = leaf(b"stored");
+    let _stored_reference = storage.store_tree(&stored).await.unwrap();
+    let unknown = leaf(b"unknown");
+    assert_eq!(
+        vec![false, true, false],
+        storage
+            .find_existing_trees(&[*unknown.digest(), *stored.digest(), *unknown.digest()])
+            .await
+            .unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_with_unknown_child() {
+    let inner = Arc::new(InMemoryTreeStorage::empty());
+    let storage = EncryptedStorage::new(inner.clone(), SECRET);
+    let child = leaf(b"not stored");
+    let root = parent(b"root", vec![StrongReference::from_weak(*child.digest())]);
+    assert_eq!(
+        crate::storage::StoreError::TreeMissing(LoadError::TreeNotFound(*child.digest())),
+        storage.store_tree(&root).await.unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_released_digests_are_bounded() {
+    // Unlike in memory, the ciphertext trees stay in SQLite without references.
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let inner = Arc::new(SQLiteStorage::from(connection).unwrap());
+    let storage = EncryptedStorage::new(inner.clone(), SECRET);
+    let kept = leaf(b"kept");
+    let _kept_reference = storage.store_tree(&kept).await.unwrap();
+    let released = leaf(b"released");
+    drop(storage.store_tree(&released).await.unwrap());
+    assert_eq!(released, load_hashed(&storage, released.digest()).await);
+
+    let mut last = None;
+    for index in 0..MAX_RECENTLY_RELEASED_DIGESTS {
+        let tree = leaf(&(index as u32).to_be_bytes());
+        drop(storage.store_tree(&tree).await.unwrap());
+        last = Some(tree);
+    }
+    // The ciphertext still exists in the inner storage, but its digest has been forgotten.
+    assert_eq!(
+        LoadError::TreeNotFound(*released.digest()),
+        storage.load_tree(released.digest()).await.unwrap_err()
+    );
+    assert_eq!(kept, load_hashed(&storage, kept.digest()).await);
+    let last = last.unwrap();
+    assert_eq!(last, load_hashed(&storage, last.digest()).await);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_parent_keeps_ciphertext_child_alive() {
+    let inner = Arc::new(InMemoryTreeStorage::empty());
+    let storage = EncryptedStorage::new(inner.clone(), SECRET);
+    let child = leaf(b"child");
+    let root = {
+        let child_reference = storage.store_tree(&child).await.unwrap();
+        storage
+            .store_tree(&parent(b"root", vec![child_reference]))
+            .await
+            .unwrap()
+    };
+    assert_eq!(2, inner.number_of_trees().await);
+    let envelope = storage.seal(&root).await.unwrap();
+    drop(root);
+    assert_eq!(3, inner.number_of_trees().await);
+
+    let root = storage.unseal(envelope.digest()).await.unwrap();
+    let loaded_root = load_hashed(&storage, root.digest()).await;
+    assert_eq!(
+        child.digest(),
+        loaded_root.tree().children().references()[0].digest()
+    );
+    assert_eq!(child, load_hashed(&storage, child.digest()).await);
+}
@@ -0,0 +1,241 @@
+use crate::{
+    delayed_hashed_tree::DelayedHashedTree,
+    storage::{
+        LoadError, LoadStoreTree, LoadTree, StoreError, StoreTree, StrongDelayedHashedTree,
+        StrongReference,
+    },
+    tree::{BlobDigest, DigestAlgorithm, HashedTree, Tree, TreeBlob},
+};
+use async_trait::async_trait;
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultOperation {
+    Load,
+    Store,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails with [LoadError::Io] or [StoreError::Io].
+    Fail,
+    /// Loading fails with [LoadError::TreeNotFound]. Storing fails with [StoreError::TreeMissing] as if the first child was
+    /// missing. A tree without children can't miss one, so it is stored normally.
+    NotFound,
+    /// Loading returns the tree with a modified blob, so [DelayedHashedTree::hash] fails. Storing succeeds, but every later
+    /// load of the tree returns it modified, like a disk that damaged it.
+    Corrupt,
+    /// The operation is delayed, then continues normally or with the next matching fault.
+    Delay(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultRule {
+    pub operation: FaultOperation,
+    /// The rule only applies to this tree, or to every tree if None.
+    pub digest: Option<BlobDigest>,
+    pub fault: Fault,
+    /// How often the rule applies before it is removed, or forever if None.
+    pub times: Option<u64>,
+}
+
+impl FaultRule {
+    pub fn always(operation: FaultOperation, fault: Fault) -> Self {
+        Self {
+            operation,
+            digest: None,
+            fault,
+            times: None,
+        }
+    }
+
+    pub fn once(operation: FaultOperation, digest: BlobDigest, fault: Fault) -> Self {
+        Self {
+            operation,
+            digest: Some(digest),
+            fault,
+            times: Some(1),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    rules: Vec<FaultRule>,
+    /// Trees that were damaged by [Fault::Corrupt] while being stored.
+    corrupted: BTreeSet<BlobDigest>,
+    faults_injected: u64,
+}
+
+impl FaultState {
+    /// Applies the matching rules in the order they were added. All delays are summed up and the first other fault wins.
+    fn take_faults(
+        &mut self,
+        operation: FaultOperation,
+        digest: &BlobDigest,
+    ) -> (Duration, Option<Fault>) {
+        let mut delay = Duration::ZERO;
+        let mut result = None;
+        for rule in self.rules.iter_mut() {
+            if rule.operation != operation
+                || rule
+                    .digest
+                    .is_some_and(|rule_digest| &rule_digest != digest)
+            {
+                continue;
+            }
+            match rule.fault {
+                Fault::Delay(duration) => delay += duration,
+                _ if result.is_some() => continue,
+                fault => result = Some(fault),
+            }
+            if let Some(ref mut times) = rule.times {
+                *times -= 1;
+            }
+            self.faults_injected += 1;
+            debug!(
+                "Injecting {:?} into {:?} of {}",
+                rule.fault, operation, digest
+            );
+        }
+        self.rules.retain(|rule| rule.times != Some(0));
+        (delay, result)
+    }
+}
+
+/// Wraps another storage and makes loads and stores fail, return garbage or take longer according to a list of
+/// [FaultRule]s. This is meant for testing how code deals with storage problems.
+#[derive(Debug)]
+pub struct FaultInjectingStorage {
+    inner: Arc<dyn LoadStoreTree + Send + Sync>,
+    state: Mutex<FaultState>,
+}
+
+impl FaultInjectingStorage {
+    pub fn new(inner: Arc<dyn LoadStoreTree + Send + Sync>) -> Self {
+        Self {
+            inner,
+            state: Mutex::new(FaultState::default()),
+        }
+    }
+
+    pub fn add_rule(&self, rule: FaultRule) {
+        self.state.lock().unwrap().rules.push(rule);
+    }
+
+    /// Removes all rules. Trees that were corrupted while being stored stay corrupted.
+    pub fn clear_rules(&self) {
+        self.state.lock().unwrap().rules.clear();
+    }
+
+    /// How many times a rule was applied so far.
+    pub fn faults_injected(&self) -> u64 {
+        self.state.lock().unwrap().faults_injected
+    }
+
+    fn take_faults(
+        &self,
+        operation: FaultOperation,
+        digest: &BlobDigest,
+    ) -> (Duration, Option<Fault>) {
+        self.state.lock().unwrap().take_faults(operation, digest)
+    }
+
+    fn corrupt(tree: &Tree) -> Arc<Tree> {
+        let mut content = tree.blob().as_slice().to_vec();
+        match content.first_mut() {
+            Some(first) => *first ^= 0xff,
+            None => content.push(0),
+        }
+        Arc::new(Tree::new(
+            TreeBlob::try_from(bytes::Bytes::from(content))
+                .expect("Flipping a byte keeps the length and an empty blob only gets one byte"),
+            tree.children().clone(),
+        ))
+    }
+}
+
+#[async_trait]
+impl StoreTree for FaultInjectingStorage {
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let (delay, fault) = self.take_faults(FaultOperation::Store, tree.digest());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        match fault {
+            Some(Fault::Fail) => Err(StoreError::Io(format!(
+                "Injected failure while storing {}",
+                tree.digest()
+            ))),
+            Some(Fault::NotFound) => match tree.tree().children().references().first() {
+                Some(child) => Err(StoreError::TreeMissing(LoadError::TreeNotFound(
+                    *child.digest(),
+                ))),
+                None => self.inner.store_tree(tree).await,
+            },
+            Some(Fault::Corrupt) => {
+                let reference = self.inner.store_tree(tree).await?;
+                self.state.lock().unwrap().corrupted.insert(*tree.digest());
+                Ok(reference)
+            }
+            Some(Fault::Delay(_)) => unreachable!("Delays are summed up separately"),
+            None => self.inner.store_tree(tree).await,
+        }
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.inner.digest_algorithm()
+    }
+}
+
+#[async_trait]
+impl LoadTree for FaultInjectingStorage {
+    async fn load_tree(
+        &self,
+        reference: &BlobDigest,
+    ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+        let (delay, fault) = self.take_faults(FaultOperation::Load, reference);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        let is_corrupted = match fault {
+            Some(Fault::Fail) => {
+                return Err(LoadError::Io(format!(
+                    "Injected failure while loading {}",
+                    reference
+                )))
+            }
+            Some(Fault::NotFound) => return Err(LoadError::TreeNotFound(*reference)),
+            Some(Fault::Corrupt) => true,
+            Some(Fault::Delay(_)) => unreachable!("Delays are summed up separately"),
+            None => self.state.lock().unwrap().corrupted.contains(reference),
+        };
+        let loaded = self.inner.load_tree(reference).await?;
+        if !is_corrupted {
+            return Ok(loaded);
+        }
+        let tree = match loaded.delayed_tree().clone().hash() {
+            Some(hashed) => hashed.tree().clone(),
+            // Already broken, nothing to do.
+            None => return Ok(loaded),
+        };
+        Ok(StrongDelayedHashedTree::new(
+            loaded.reference().clone(),
+            DelayedHashedTree::delayed(Self::corrupt(&tree), *reference),
+        ))
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        self.inner.approximate_tree_count().await
+    }
+}
+
+impl LoadStoreTree for FaultInjectingStorage {}
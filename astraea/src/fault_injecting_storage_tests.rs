@@ -0,0 +1,159 @@
+use crate::{
+    fault_injecting_storage::{Fault, FaultInjectingStorage, FaultOperation, FaultRule},
+    in_memory_storage::InMemoryTreeStorage,
+    storage::{LoadError, LoadTree, StoreError, StoreTree},
+    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::{sync::Arc, time::Duration};
+
+fn leaf(content: &'static str) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from(content)).unwrap(),
+        TreeChildren::empty(),
+    )))
+}
+
+async fn load_hashed(
+    storage: &FaultInjectingStorage,
+    digest: &BlobDigest,
+) -> Result<Option<HashedTree>, LoadError> {
+    storage
+        .load_tree(digest)
+        .await
+        .map(|loaded| loaded.hash().map(|hashed| hashed.hashed_tree().clone()))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_load_faults() {
+    let storage = FaultInjectingStorage::new(Arc::new(InMemoryTreeStorage::empty()));
+    let tree = leaf("tree");
+    let reference = storage.store_tree(&tree).await.unwrap();
+    let digest = *reference.digest();
+
+    storage.add_rule(FaultRule::once(FaultOperation::Load, digest, Fault::Fail));
+    assert_eq!(
+        Err(LoadError::Io(format!(
+            "Injected failure while loading {digest}"
+        ))),
+        load_hashed(&storage, &digest).await
+    );
+    assert_eq!(Ok(Some(tree.clone())), load_hashed(&storage, &digest).await);
+
+    storage.add_rule(FaultRule::once(
+        FaultOperation::Load,
+        digest,
+        Fault::NotFound,
+    ));
+    assert_eq!(
+        Err(LoadError::TreeNotFound(digest)),
+        load_hashed(&storage, &digest).await
+    );
+
+    storage.add_rule(FaultRule::once(
+        FaultOperation::Load,
+        digest,
+        Fault::Corrupt,
+    ));
+    assert_eq!(Ok(None), load_hashed(&storage, &digest).await);
+    assert_eq!(Ok(Some(tree)), load_hashed(&storage, &digest).await);
+    assert_eq!(3, storage.faults_injected());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_rules_only_apply_to_their_digest() {
+    let storage = FaultInjectingStorage::new(Arc::new(InMemoryTreeStorage::empty()));
+    let first = storage.store_tree(&leaf("first")).await.unwrap();
+    let second = storage.store_tree(&leaf("second")).await.unwrap();
+    storage.add_rule(FaultRule {
+        operation: FaultOperation::Load,
+        digest: Some(*first.digest()),
+        fault: Fault::NotFound,
+        times: None,
+    });
+    for _ in 0..3 {
+        assert_eq!(
+            Err(LoadError::TreeNotFound(*first.digest())),
+            load_hashed(&storage, first.digest()).await
+        );
+        assert!(load_hashed(&storage, second.digest())
+            .await
+            .unwrap()
+            .is_some());
+    }
+    storage.clear_rules();
+    assert!(load_hashed(&storage, first.digest())
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(3, storage.faults_injected());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_faults() {
+    let storage = FaultInjectingStorage::new(Arc::new(InMemoryTreeStorage::empty()));
+    storage.add_rule(FaultRule::always(FaultOperation::Store, Fault::Fail));
+    let tree = leaf("tree");
+    assert_eq!(
+        StoreError::Io(format!("Injected failure while storing {}", tree.digest())),
+        storage.store_tree(&tree).await.unwrap_err()
+    );
+    storage.clear_rules();
+
+    // A tree without children can't miss one.
+    storage.add_rule(FaultRule::once(
+        FaultOperation::Store,
+        *tree.digest(),
+        Fault::NotFound,
+    ));
+    let child = storage.store_tree(&tree).await.unwrap();
+    let parent = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from("parent")).unwrap(),
+        TreeChildren::try_from(vec![child]).unwrap(),
+    )));
+    storage.add_rule(FaultRule::once(
+        FaultOperation::Store,
+        *parent.digest(),
+        Fault::NotFound,
+    ));
+    assert_eq!(
+        StoreError::TreeMissing(LoadError::TreeNotFound(*tree.digest())),
+        storage.store_tree(&parent).await.unwrap_err()
+    );
+
+    // The damage happens at rest, so every load sees it, even without rules.
+    storage.add_rule(FaultRule::once(
+        FaultOperation::Store,
+        *tree.digest(),
+        Fault::Corrupt,
+    ));
+    let reference = storage.store_tree(&tree).await.unwrap();
+    storage.clear_rules();
+    for _ in 0..2 {
+        assert_eq!(Ok(None), load_hashed(&storage, reference.digest()).await);
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_delays_add_up_and_combine_with_faults() {
+    let storage = FaultInjectingStorage::new(Arc::new(InMemoryTreeStorage::empty()));
+    let reference = storage.store_tree(&leaf("slow")).await.unwrap();
+    let digest = *reference.digest();
+    let delay = Duration::from_millis(20);
+    storage.add_rule(FaultRule::always(FaultOperation::Load, Fault::Delay(delay)));
+    storage.add_rule(FaultRule::always(FaultOperation::Load, Fault::Delay(delay)));
+    storage.add_rule(FaultRule::once(
+        FaultOperation::Load,
+        digest,
+        Fault::NotFound,
+    ));
+    let started = std::time::Instant::now();
+    assert_eq!(
+        Err(LoadError::TreeNotFound(digest)),
+        load_hashed(&storage, &digest).await
+    );
+    assert!(started.elapsed() >= delay * 2);
+    assert!(load_hashed(&storage, &digest).await.unwrap().is_some());
+    assert_eq!(5, storage.faults_injected());
+}
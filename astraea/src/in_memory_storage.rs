@@ -1,10 +1,12 @@
 use crate::{
     delayed_hashed_tree::DelayedHashedTree,
     storage::{
-        CollectGarbage, GarbageCollectionStats, LoadError, LoadStoreTree, LoadTree, StoreError,
-        StoreTree, StrongDelayedHashedTree, StrongReference, StrongReferenceTrait,
+        root_change_stream, CollectGarbage, CompareAndSwapResult, GarbageCollectionStats,
+        LoadError, LoadRoot, LoadStoreTree, LoadTree, MeasureStoredSize, ReplaceTree, RootChange,
+        RootChangeStream, StoreError, StoreTree, StrongDelayedHashedTree, StrongReference,
+        StrongReferenceTrait, UpdateRoot, WatchRoots, ROOT_CHANGE_CHANNEL_CAPACITY,
     },
-    tree::{BlobDigest, HashedTree},
+    tree::{BlobDigest, DigestAlgorithm, HashedTree},
 };
 use async_trait::async_trait;
 use std::{
@@ -30,16 +32,30 @@ struct InMemoryTreeEntry {
 pub struct InMemoryTreeStorage {
     // TODO: automatic garbage collection when the number of trees exceeds a certain threshold
     reference_to_tree: Mutex<BTreeMap<BlobDigest, InMemoryTreeEntry>>,
+    // Always locked before reference_to_tree. The references keep the targets alive.
+    roots: Mutex<BTreeMap<String, StrongReference>>,
+    root_changes: tokio::sync::broadcast::Sender<RootChange>,
+    digest_algorithm: DigestAlgorithm,
 }
 
 impl InMemoryTreeStorage {
     pub fn empty() -> InMemoryTreeStorage {
+        Self::with_digest_algorithm(DigestAlgorithm::Sha3_512)
+    }
+
+    /// See [StoreTree::digest_algorithm].
+    pub fn with_digest_algorithm(digest_algorithm: DigestAlgorithm) -> InMemoryTreeStorage {
         Self {
             reference_to_tree: Mutex::new(BTreeMap::new()),
+            roots: Mutex::new(BTreeMap::new()),
+            root_changes: tokio::sync::broadcast::channel(ROOT_CHANGE_CHANNEL_CAPACITY).0,
+            digest_algorithm,
         }
     }
 
     pub async fn clear(&self) {
+        let mut roots = self.roots.lock().await;
+        roots.clear();
         self.reference_to_tree.lock().await.clear();
     }
 
@@ -57,49 +73,100 @@ impl InMemoryTreeStorage {
     }
 }
 
-#[async_trait]
-impl StoreTree for InMemoryTreeStorage {
-    async fn store_tree(
-        &self,
-        tree: &HashedTree,
-    ) -> std::result::Result<StrongReference, StoreError> {
-        let mut children = Vec::new();
-        for child_digest in tree.tree().children().references() {
-            let child_tree = match self.load_tree(child_digest.digest()).await {
-                Ok(success) => success,
-                Err(error) => return Err(StoreError::TreeMissing(error)),
-            };
-            children.push(child_tree.reference().clone());
+/// An existing tree with the same digest is kept unless `replace` is set.
+fn store_tree_locked(
+    lock: &mut BTreeMap<BlobDigest, InMemoryTreeEntry>,
+    tree: &HashedTree,
+    replace: bool,
+) -> std::result::Result<StrongReference, StoreError> {
+    let mut children = Vec::new();
+    for child_digest in tree.tree().children().references() {
+        let child_tree = match load_tree_locked(lock, child_digest.digest()) {
+            Ok(success) => success,
+            Err(error) => return Err(StoreError::TreeMissing(error)),
+        };
+        children.push(child_tree.reference().clone());
+    }
+    let digest = *tree.digest();
+    let impl_ = match lock.entry(digest) {
+        std::collections::btree_map::Entry::Vacant(vacant_entry) => {
+            let impl_ = Arc::new(InMemoryStrongReferenceImpl {});
+            vacant_entry.insert(InMemoryTreeEntry {
+                tree: tree.clone(),
+                strong_reference_impl: Arc::<InMemoryStrongReferenceImpl>::downgrade(&impl_),
+                _children: children,
+            });
+            impl_
         }
-        let mut lock = self.reference_to_tree.lock().await;
-        let digest = *tree.digest();
-        let impl_ = match lock.entry(digest) {
-            std::collections::btree_map::Entry::Vacant(vacant_entry) => {
-                let impl_ = Arc::new(InMemoryStrongReferenceImpl {});
-                vacant_entry.insert(InMemoryTreeEntry {
-                    tree: tree.clone(),
-                    strong_reference_impl: Arc::<InMemoryStrongReferenceImpl>::downgrade(&impl_),
-                    _children: children,
-                });
-                impl_
-            }
-            std::collections::btree_map::Entry::Occupied(mut occupied_entry) => occupied_entry
-                .get()
-                .strong_reference_impl
-                .upgrade()
-                .unwrap_or_else(|| {
-                    let impl_ = Arc::new(InMemoryStrongReferenceImpl {});
+        std::collections::btree_map::Entry::Occupied(mut occupied_entry) => {
+            let existing = occupied_entry.get().strong_reference_impl.upgrade();
+            match existing {
+                Some(impl_) if !replace => impl_,
+                existing => {
+                    let impl_ =
+                        existing.unwrap_or_else(|| Arc::new(InMemoryStrongReferenceImpl {}));
+                    let stored = if replace {
+                        tree.clone()
+                    } else {
+                        occupied_entry.get().tree.clone()
+                    };
                     occupied_entry.insert(InMemoryTreeEntry {
-                        tree: occupied_entry.get().tree.clone(),
+                        tree: stored,
                         strong_reference_impl: Arc::<InMemoryStrongReferenceImpl>::downgrade(
                             &impl_,
                         ),
                         _children: children,
                     });
                     impl_
-                }),
-        };
-        Ok(StrongReference::new(Some(impl_), digest))
+                }
+            }
+        }
+    };
+    Ok(StrongReference::new(Some(impl_), digest))
+}
+
+fn load_tree_locked(
+    lock: &mut BTreeMap<BlobDigest, InMemoryTreeEntry>,
+    reference: &BlobDigest,
+) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+    match lock.get(reference) {
+        Some(found) => match found.strong_reference_impl.upgrade() {
+            Some(impl_) => Ok(StrongDelayedHashedTree::new(
+                StrongReference::new(Some(impl_), *reference),
+                DelayedHashedTree::immediate(found.tree.clone()),
+            )),
+            None => {
+                lock.remove(reference);
+                Err(LoadError::TreeNotFound(*reference))
+            }
+        },
+        None => Err(LoadError::TreeNotFound(*reference)),
+    }
+}
+
+#[async_trait]
+impl StoreTree for InMemoryTreeStorage {
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let mut lock = self.reference_to_tree.lock().await;
+        store_tree_locked(&mut lock, tree, false)
+    }
+
+    async fn store_trees(
+        &self,
+        trees: &[HashedTree],
+    ) -> std::result::Result<Vec<StrongReference>, StoreError> {
+        let mut lock = self.reference_to_tree.lock().await;
+        trees
+            .iter()
+            .map(|tree| store_tree_locked(&mut lock, tree, false))
+            .collect()
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
     }
 }
 
@@ -110,29 +177,57 @@ impl LoadTree for InMemoryTreeStorage {
         reference: &BlobDigest,
     ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
         let mut lock = self.reference_to_tree.lock().await;
-        match lock.get(reference) {
-            Some(found) => match found.strong_reference_impl.upgrade() {
-                Some(impl_) => Ok(StrongDelayedHashedTree::new(
-                    StrongReference::new(Some(impl_), *reference),
-                    DelayedHashedTree::immediate(found.tree.clone()),
-                )),
-                None => {
-                    lock.remove(reference);
-                    Err(LoadError::TreeNotFound(*reference))
-                }
-            },
-            None => Err(LoadError::TreeNotFound(*reference)),
-        }
+        load_tree_locked(&mut lock, reference)
     }
 
     async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
         let lock = self.reference_to_tree.lock().await;
         Ok(lock.len() as u64)
     }
+
+    async fn load_trees(
+        &self,
+        references: &[BlobDigest],
+    ) -> std::result::Result<Vec<StrongDelayedHashedTree>, LoadError> {
+        let mut lock = self.reference_to_tree.lock().await;
+        references
+            .iter()
+            .map(|reference| load_tree_locked(&mut lock, reference))
+            .collect()
+    }
 }
 
 impl LoadStoreTree for InMemoryTreeStorage {}
 
+#[async_trait]
+impl ReplaceTree for InMemoryTreeStorage {
+    async fn replace_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let mut lock = self.reference_to_tree.lock().await;
+        store_tree_locked(&mut lock, tree, true)
+    }
+}
+
+#[async_trait]
+impl MeasureStoredSize for InMemoryTreeStorage {
+    /// Nothing is compressed in memory.
+    async fn stored_sizes(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<Option<u64>>, LoadError> {
+        let lock = self.reference_to_tree.lock().await;
+        Ok(digests
+            .iter()
+            .map(|digest| {
+                lock.get(digest)
+                    .map(|entry| entry.tree.tree().blob().len() as u64)
+            })
+            .collect())
+    }
+}
+
 #[async_trait]
 impl CollectGarbage for InMemoryTreeStorage {
     async fn collect_some_garbage(
@@ -148,3 +243,73 @@ impl CollectGarbage for InMemoryTreeStorage {
         })
     }
 }
+
+impl InMemoryTreeStorage {
+    async fn require_tree(
+        &self,
+        digest: &BlobDigest,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let mut lock = self.reference_to_tree.lock().await;
+        match load_tree_locked(&mut lock, digest) {
+            Ok(found) => Ok(found.reference().clone()),
+            Err(error) => Err(StoreError::TreeMissing(error)),
+        }
+    }
+
+    fn publish_root_change(&self, name: &str, target: &BlobDigest) {
+        // Nobody may be subscribed, which is fine.
+        let _ = self.root_changes.send(RootChange {
+            name: name.to_string(),
+            target: *target,
+        });
+    }
+}
+
+#[async_trait]
+impl UpdateRoot for InMemoryTreeStorage {
+    async fn update_root(
+        &self,
+        name: &str,
+        target: &StrongReference,
+    ) -> std::result::Result<(), StoreError> {
+        let mut roots = self.roots.lock().await;
+        let reference = self.require_tree(target.digest()).await?;
+        roots.insert(name.to_string(), reference);
+        self.publish_root_change(name, target.digest());
+        Ok(())
+    }
+
+    async fn compare_and_swap_root(
+        &self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &StrongReference,
+    ) -> std::result::Result<CompareAndSwapResult, StoreError> {
+        let mut roots = self.roots.lock().await;
+        let actual = roots.get(name).map(|reference| *reference.digest());
+        if actual.as_ref() != expected {
+            return Ok(CompareAndSwapResult::Conflict { actual });
+        }
+        let reference = self.require_tree(new.digest()).await?;
+        roots.insert(name.to_string(), reference);
+        self.publish_root_change(name, new.digest());
+        Ok(CompareAndSwapResult::Swapped)
+    }
+}
+
+#[async_trait]
+impl LoadRoot for InMemoryTreeStorage {
+    async fn load_root(
+        &self,
+        name: &str,
+    ) -> std::result::Result<Option<StrongReference>, LoadError> {
+        Ok(self.roots.lock().await.get(name).cloned())
+    }
+}
+
+/// There are no transactions, so every update is published immediately.
+impl WatchRoots for InMemoryTreeStorage {
+    fn watch_roots(&self) -> RootChangeStream {
+        root_change_stream(self.root_changes.subscribe())
+    }
+}
@@ -1,9 +1,16 @@
 use crate::{
     in_memory_storage::InMemoryTreeStorage,
-    storage::{LoadTree, StoreTree},
+    storage::{
+        CollectGarbage, CompareAndSwapResult, LoadRoot, LoadTree, RootChange, StoreTree,
+        StrongReference, UpdateRoot, WatchRoots, WatchRootsError, ROOT_CHANGE_CHANNEL_CAPACITY,
+    },
     tree::{HashedTree, Tree, TreeBlob, TreeChildren},
 };
+use bytes::Bytes;
+use futures_util::FutureExt;
+use pretty_assertions::assert_eq;
 use std::sync::Arc;
+use tokio_stream::StreamExt;
 
 #[test_log::test(tokio::test)]
 async fn test_approximate_tree_count() {
@@ -18,3 +25,125 @@ async fn test_approximate_tree_count() {
         .unwrap();
     assert_eq!(storage.approximate_tree_count().await.unwrap(), 1);
 }
+
+#[test_log::test(tokio::test)]
+async fn test_store_trees_and_load_trees() {
+    let storage = InMemoryTreeStorage::empty();
+    let leaf = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from_static(b"leaf")).unwrap(),
+        TreeChildren::empty(),
+    )));
+    let parent = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::empty(),
+        TreeChildren::try_from(vec![StrongReference::from_weak(*leaf.digest())]).unwrap(),
+    )));
+    let references = storage
+        .store_trees(&[leaf.clone(), parent.clone()])
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![*leaf.digest(), *parent.digest()],
+        references
+            .iter()
+            .map(|reference| *reference.digest())
+            .collect::<Vec<_>>()
+    );
+    let loaded: Vec<HashedTree> = storage
+        .load_trees(&[*parent.digest(), *leaf.digest()])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|loaded| loaded.hash().unwrap().hashed_tree().clone())
+        .collect();
+    assert_eq!(vec![parent, leaf], loaded);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_compare_and_swap_root() {
+    let storage = InMemoryTreeStorage::empty();
+    let first = storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(Bytes::from_static(b"first")).unwrap(),
+            TreeChildren::empty(),
+        ))))
+        .await
+        .unwrap();
+    let second = storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(Bytes::from_static(b"second")).unwrap(),
+            TreeChildren::empty(),
+        ))))
+        .await
+        .unwrap();
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("test", None, &first)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Conflict {
+            actual: Some(*first.digest())
+        },
+        storage
+            .compare_and_swap_root("test", Some(second.digest()), &second)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        Some(first.clone()),
+        storage.load_root("test").await.unwrap()
+    );
+
+    // The root keeps its target alive.
+    let first_digest = *first.digest();
+    drop(first);
+    storage.collect_some_garbage().await.unwrap();
+    assert!(storage.load_tree(&first_digest).await.is_ok());
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("test", Some(&first_digest), &second)
+            .await
+            .unwrap()
+    );
+    storage.collect_some_garbage().await.unwrap();
+    assert!(storage.load_tree(&first_digest).await.is_err());
+    assert_eq!(None, storage.load_root("other").await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_watch_roots() {
+    let storage = InMemoryTreeStorage::empty();
+    let reference = storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::empty(),
+            TreeChildren::empty(),
+        ))))
+        .await
+        .unwrap();
+    let mut changes = storage.watch_roots();
+    storage.update_root("test", &reference).await.unwrap();
+    assert_eq!(
+        Some(Ok(RootChange {
+            name: "test".to_string(),
+            target: *reference.digest(),
+        })),
+        changes.next().await
+    );
+    assert!(changes.next().now_or_never().is_none());
+
+    // A subscriber that doesn't keep up is told how many changes it missed.
+    for _ in 0..(ROOT_CHANGE_CHANNEL_CAPACITY + 2) {
+        storage.update_root("test", &reference).await.unwrap();
+    }
+    assert_eq!(Some(Err(WatchRootsError::Lagged(2))), changes.next().await);
+    assert_eq!(
+        Some(Ok(RootChange {
+            name: "test".to_string(),
+            target: *reference.digest(),
+        })),
+        changes.next().await
+    );
+}
@@ -0,0 +1,111 @@
+use crate::{
+    storage::{LoadError, LoadTree, StrongReference},
+    storage_protocol::SerializedTree,
+    tree::{calculate_reference_with, BlobDigest, TreeSerializationError},
+};
+use serde::{Deserialize, Serialize};
+
+/// Shows that a path of child indices leads from a root to a certain digest. Contains every tree on the path except the last
+/// one, starting with the root. Each of them is needed completely to calculate its digest, but nothing below the path is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    pub trees: Vec<SerializedTree>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum InclusionProofError {
+    Load(LoadError),
+    HashMismatch(BlobDigest),
+    /// The tree at this depth of the path has fewer children than the index requires.
+    NoSuchChild {
+        depth: usize,
+        index: usize,
+    },
+    /// The proof has to contain exactly one tree per path element.
+    WrongTreeCount {
+        expected: usize,
+        actual: usize,
+    },
+    /// The tree at this depth of the proof doesn't have the digest its parent or the root says it has.
+    DigestMismatch {
+        depth: usize,
+    },
+    Deserialization(TreeSerializationError),
+}
+
+impl std::fmt::Display for InclusionProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for InclusionProofError {}
+
+/// Loads the trees along `path` below `root` and returns them as a proof together with the digest the path leads to.
+/// An empty path leads to the root itself and needs no trees.
+pub async fn prove_inclusion(
+    storage: &(dyn LoadTree + Send + Sync),
+    root: &BlobDigest,
+    path: &[usize],
+) -> Result<(InclusionProof, BlobDigest), InclusionProofError> {
+    let mut trees = Vec::with_capacity(path.len());
+    let mut current = *root;
+    for (depth, index) in path.iter().enumerate() {
+        let loaded = storage
+            .load_tree(&current)
+            .await
+            .map_err(InclusionProofError::Load)?;
+        let hashed = loaded
+            .hash()
+            .ok_or(InclusionProofError::HashMismatch(current))?;
+        let tree = hashed.hashed_tree().tree();
+        current = *tree
+            .children()
+            .references()
+            .get(*index)
+            .ok_or(InclusionProofError::NoSuchChild {
+                depth,
+                index: *index,
+            })?
+            .digest();
+        trees.push(SerializedTree::from_tree(tree));
+    }
+    Ok((InclusionProof { trees }, current))
+}
+
+/// Checks that `proof` shows a path from `root` along `path` and returns the digest it leads to. Only the digests of the
+/// trees in the proof are calculated, so the verifier doesn't need access to any storage.
+pub fn verify_inclusion(
+    root: &BlobDigest,
+    path: &[usize],
+    proof: &InclusionProof,
+) -> Result<BlobDigest, InclusionProofError> {
+    if proof.trees.len() != path.len() {
+        return Err(InclusionProofError::WrongTreeCount {
+            expected: path.len(),
+            actual: proof.trees.len(),
+        });
+    }
+    let mut current = *root;
+    for (depth, (serialized, index)) in proof.trees.iter().zip(path).enumerate() {
+        let children = serialized
+            .children
+            .iter()
+            .map(|child| StrongReference::from_weak(*child))
+            .collect();
+        let tree = serialized
+            .to_tree(children)
+            .map_err(InclusionProofError::Deserialization)?;
+        if calculate_reference_with(&tree, current.algorithm()) != current {
+            return Err(InclusionProofError::DigestMismatch { depth });
+        }
+        current = *serialized
+            .children
+            .get(*index)
+            .ok_or(InclusionProofError::NoSuchChild {
+                depth,
+                index: *index,
+            })?;
+    }
+    Ok(current)
+}
@@ -0,0 +1,129 @@
+use crate::{
+    in_memory_storage::InMemoryTreeStorage,
+    inclusion_proof::{prove_inclusion, verify_inclusion, InclusionProofError},
+    storage::{LoadError, StoreTree, StrongReference},
+    tree::{BlobDigest, DigestAlgorithm, HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+async fn store(
+    storage: &InMemoryTreeStorage,
+    content: &'static str,
+    children: Vec<StrongReference>,
+    algorithm: DigestAlgorithm,
+) -> StrongReference {
+    storage
+        .store_tree(&HashedTree::from_with_algorithm(
+            Arc::new(Tree::new(
+                TreeBlob::try_from(Bytes::from(content)).unwrap(),
+                TreeChildren::try_from(children).unwrap(),
+            )),
+            algorithm,
+        ))
+        .await
+        .unwrap()
+}
+
+/// root -> (left -> (a, b), right)
+async fn make_tree(
+    storage: &InMemoryTreeStorage,
+    algorithm: DigestAlgorithm,
+) -> Vec<StrongReference> {
+    let a = store(storage, "a", vec![], algorithm).await;
+    let b = store(storage, "b", vec![], algorithm).await;
+    let left = store(storage, "left", vec![a.clone(), b.clone()], algorithm).await;
+    let right = store(storage, "right", vec![], algorithm).await;
+    let root = store(
+        storage,
+        "root",
+        vec![left.clone(), right.clone()],
+        algorithm,
+    )
+    .await;
+    vec![root, left, right, a, b]
+}
+
+#[test_log::test(tokio::test)]
+async fn test_prove_and_verify() {
+    for algorithm in [DigestAlgorithm::Sha3_512, DigestAlgorithm::Blake3] {
+        let storage = InMemoryTreeStorage::empty();
+        let trees = make_tree(&storage, algorithm).await;
+        let [root, left, right, a, b] = [0, 1, 2, 3, 4].map(|index| *trees[index].digest());
+        for (path, expected) in [
+            (vec![], root),
+            (vec![0], left),
+            (vec![1], right),
+            (vec![0, 0], a),
+            (vec![0, 1], b),
+        ] {
+            let (proof, target) = prove_inclusion(&storage, &root, &path).await.unwrap();
+            assert_eq!(expected, target);
+            assert_eq!(path.len(), proof.trees.len());
+            assert_eq!(Ok(expected), verify_inclusion(&root, &path, &proof));
+        }
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_prove_invalid_path() {
+    let storage = InMemoryTreeStorage::empty();
+    let trees = make_tree(&storage, DigestAlgorithm::Sha3_512).await;
+    let root = *trees[0].digest();
+    assert_eq!(
+        Err(InclusionProofError::NoSuchChild { depth: 1, index: 2 }),
+        prove_inclusion(&storage, &root, &[0, 2]).await
+    );
+    // The leaf at the end of the path has no children.
+    assert_eq!(
+        Err(InclusionProofError::NoSuchChild { depth: 2, index: 0 }),
+        prove_inclusion(&storage, &root, &[0, 1, 0]).await
+    );
+    let missing = BlobDigest::hash(b"missing");
+    assert_eq!(
+        Err(InclusionProofError::Load(LoadError::TreeNotFound(missing))),
+        prove_inclusion(&storage, &missing, &[0]).await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_verify_rejects_tampering() {
+    let storage = InMemoryTreeStorage::empty();
+    let trees = make_tree(&storage, DigestAlgorithm::Sha3_512).await;
+    let root = *trees[0].digest();
+    let path = [0, 1];
+    let (proof, _target) = prove_inclusion(&storage, &root, &path).await.unwrap();
+
+    let mut changed_blob = proof.clone();
+    changed_blob.trees[1].blob.push(0);
+    assert_eq!(
+        Err(InclusionProofError::DigestMismatch { depth: 1 }),
+        verify_inclusion(&root, &path, &changed_blob)
+    );
+
+    // Claiming a different child requires changing the parent, which changes its digest.
+    let mut changed_child = proof.clone();
+    changed_child.trees[1].children[1] = BlobDigest::hash(b"forged");
+    assert_eq!(
+        Err(InclusionProofError::DigestMismatch { depth: 1 }),
+        verify_inclusion(&root, &path, &changed_child)
+    );
+
+    assert_eq!(
+        Err(InclusionProofError::DigestMismatch { depth: 0 }),
+        verify_inclusion(trees[2].digest(), &path, &proof)
+    );
+    assert_eq!(
+        Err(InclusionProofError::WrongTreeCount {
+            expected: 1,
+            actual: 2
+        }),
+        verify_inclusion(&root, &[0], &proof)
+    );
+    // A proof for one path doesn't work for another one of the same length.
+    assert_eq!(
+        Err(InclusionProofError::DigestMismatch { depth: 1 }),
+        verify_inclusion(&root, &[1, 0], &proof)
+    );
+}
@@ -0,0 +1,276 @@
+use crate::{
+    prometheus_text::{LatencyHistogram, PrometheusText},
+    storage::{
+        LoadError, LoadStoreTree, LoadTree, StoreError, StoreTree, StrongDelayedHashedTree,
+        StrongReference,
+    },
+    tree::{BlobDigest, DigestAlgorithm, HashedTree},
+};
+use async_trait::async_trait;
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tracing::debug;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OperationMetrics {
+    /// Calls of the operation. A batch counts once.
+    pub calls: u64,
+    pub errors: u64,
+    /// Trees loaded, stored or looked up by successful calls.
+    pub trees: u64,
+    /// Sum of the blob sizes of the trees, without the child digests.
+    pub bytes: u64,
+    pub latency: LatencyHistogram,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StorageMetrics {
+    pub load: OperationMetrics,
+    pub store: OperationMetrics,
+    pub find_existing: OperationMetrics,
+    /// Stored trees that already existed before they were stored. Only counted by [InstrumentedStorage::with_dedup_hits].
+    pub dedup_hits: u64,
+}
+
+impl StorageMetrics {
+    /// Adds the metrics to `text`. The `storage` label distinguishes multiple instrumented storages on the same page.
+    pub fn write_prometheus(&self, storage: &str, text: &mut PrometheusText) {
+        let operations = [
+            ("load", &self.load),
+            ("store", &self.store),
+            ("find_existing", &self.find_existing),
+        ];
+        let labels: Vec<[(&str, &str); 2]> = operations
+            .iter()
+            .map(|(operation, _)| [("storage", storage), ("operation", *operation)])
+            .collect();
+        let samples = |value: &dyn Fn(&OperationMetrics) -> u64| -> Vec<(&[(&str, &str)], u64)> {
+            labels
+                .iter()
+                .zip(operations.iter())
+                .map(|(labels, (_, metrics))| (labels.as_slice(), value(metrics)))
+                .collect()
+        };
+        text.counter(
+            "astraea_storage_calls_total",
+            "Calls of a storage operation. A batch counts once.",
+            &samples(&|metrics| metrics.calls),
+        );
+        text.counter(
+            "astraea_storage_errors_total",
+            "Calls of a storage operation that failed.",
+            &samples(&|metrics| metrics.errors),
+        );
+        text.counter(
+            "astraea_storage_trees_total",
+            "Trees loaded, stored or looked up.",
+            &samples(&|metrics| metrics.trees),
+        );
+        text.counter(
+            "astraea_storage_bytes_total",
+            "Blob bytes of the trees loaded or stored.",
+            &samples(&|metrics| metrics.bytes),
+        );
+        text.counter(
+            "astraea_storage_dedup_hits_total",
+            "Stored trees that already existed.",
+            &[(&[("storage", storage)], self.dedup_hits)],
+        );
+        let histograms: Vec<(&[(&str, &str)], &LatencyHistogram)> = labels
+            .iter()
+            .zip(operations.iter())
+            .map(|(labels, (_, metrics))| (labels.as_slice(), &metrics.latency))
+            .collect();
+        text.histogram(
+            "astraea_storage_latency_seconds",
+            "Duration of the calls of a storage operation.",
+            &histograms,
+        );
+    }
+}
+
+/// Counts what goes through it to another storage.
+#[derive(Debug)]
+pub struct InstrumentedStorage {
+    inner: Arc<dyn LoadStoreTree + Send + Sync>,
+    metrics: Mutex<StorageMetrics>,
+    count_dedup_hits: bool,
+}
+
+impl InstrumentedStorage {
+    pub fn new(inner: Arc<dyn LoadStoreTree + Send + Sync>) -> Self {
+        Self {
+            inner,
+            metrics: Mutex::new(StorageMetrics::default()),
+            count_dedup_hits: false,
+        }
+    }
+
+    /// Also counts [StorageMetrics::dedup_hits]. To find out whether a store is a dedup hit, the inner storage is asked
+    /// which of the trees it already has before they are stored, which costs one additional call per store.
+    pub fn with_dedup_hits(inner: Arc<dyn LoadStoreTree + Send + Sync>) -> Self {
+        Self {
+            count_dedup_hits: true,
+            ..Self::new(inner)
+        }
+    }
+
+    pub fn metrics(&self) -> StorageMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    fn record<T, E>(
+        &self,
+        operation: fn(&mut StorageMetrics) -> &mut OperationMetrics,
+        started: Instant,
+        result: &Result<T, E>,
+        trees: u64,
+        bytes: u64,
+    ) {
+        let mut metrics_locked = self.metrics.lock().unwrap();
+        let metrics = operation(&mut metrics_locked);
+        metrics.calls += 1;
+        metrics.latency.observe(started.elapsed());
+        match result {
+            Ok(_) => {
+                metrics.trees += trees;
+                metrics.bytes += bytes;
+            }
+            Err(_) => metrics.errors += 1,
+        }
+    }
+
+    /// How many of the trees the inner storage already has. They only count as dedup hits once the store succeeded.
+    async fn find_dedup_hits(&self, trees: &[HashedTree]) -> u64 {
+        if !self.count_dedup_hits {
+            return 0;
+        }
+        let digests: Vec<BlobDigest> = trees.iter().map(|tree| *tree.digest()).collect();
+        match self.inner.find_existing_trees(&digests).await {
+            Ok(existing) => existing.iter().filter(|exists| **exists).count() as u64,
+            Err(error) => {
+                debug!("Could not check for dedup hits: {}", error);
+                0
+            }
+        }
+    }
+
+    fn record_dedup_hits<T>(&self, hits: u64, result: &Result<T, StoreError>) {
+        if result.is_ok() {
+            self.metrics.lock().unwrap().dedup_hits += hits;
+        }
+    }
+}
+
+fn blob_bytes(trees: &[HashedTree]) -> u64 {
+    trees
+        .iter()
+        .map(|tree| tree.tree().blob().len() as u64)
+        .sum()
+}
+
+fn loaded_bytes(trees: &[StrongDelayedHashedTree]) -> u64 {
+    trees
+        .iter()
+        .map(|tree| tree.delayed_tree().tree().blob().len() as u64)
+        .sum()
+}
+
+#[async_trait]
+impl LoadTree for InstrumentedStorage {
+    async fn load_tree(
+        &self,
+        reference: &BlobDigest,
+    ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+        let started = Instant::now();
+        let result = self.inner.load_tree(reference).await;
+        let bytes = result
+            .as_ref()
+            .map(|loaded| loaded_bytes(std::slice::from_ref(loaded)))
+            .unwrap_or(0);
+        self.record(|metrics| &mut metrics.load, started, &result, 1, bytes);
+        result
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        self.inner.approximate_tree_count().await
+    }
+
+    async fn load_trees(
+        &self,
+        references: &[BlobDigest],
+    ) -> std::result::Result<Vec<StrongDelayedHashedTree>, LoadError> {
+        let started = Instant::now();
+        let result = self.inner.load_trees(references).await;
+        let bytes = result
+            .as_ref()
+            .map(|loaded| loaded_bytes(loaded))
+            .unwrap_or(0);
+        self.record(
+            |metrics| &mut metrics.load,
+            started,
+            &result,
+            references.len() as u64,
+            bytes,
+        );
+        result
+    }
+
+    async fn find_existing_trees(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<bool>, LoadError> {
+        let started = Instant::now();
+        let result = self.inner.find_existing_trees(digests).await;
+        self.record(
+            |metrics| &mut metrics.find_existing,
+            started,
+            &result,
+            digests.len() as u64,
+            0,
+        );
+        result
+    }
+}
+
+#[async_trait]
+impl StoreTree for InstrumentedStorage {
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let dedup_hits = self.find_dedup_hits(std::slice::from_ref(tree)).await;
+        let started = Instant::now();
+        let result = self.inner.store_tree(tree).await;
+        let bytes = blob_bytes(std::slice::from_ref(tree));
+        self.record(|metrics| &mut metrics.store, started, &result, 1, bytes);
+        self.record_dedup_hits(dedup_hits, &result);
+        result
+    }
+
+    async fn store_trees(
+        &self,
+        trees: &[HashedTree],
+    ) -> std::result::Result<Vec<StrongReference>, StoreError> {
+        let dedup_hits = self.find_dedup_hits(trees).await;
+        let started = Instant::now();
+        let result = self.inner.store_trees(trees).await;
+        self.record(
+            |metrics| &mut metrics.store,
+            started,
+            &result,
+            trees.len() as u64,
+            blob_bytes(trees),
+        );
+        self.record_dedup_hits(dedup_hits, &result);
+        result
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.inner.digest_algorithm()
+    }
+}
+
+impl LoadStoreTree for InstrumentedStorage {}
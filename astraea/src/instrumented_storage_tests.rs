@@ -0,0 +1,125 @@
+use crate::{
+    fault_injecting_storage::{Fault, FaultInjectingStorage, FaultOperation, FaultRule},
+    in_memory_storage::InMemoryTreeStorage,
+    instrumented_storage::InstrumentedStorage,
+    prometheus_text::PrometheusText,
+    storage::{LoadTree, StoreTree},
+    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+fn leaf(content: &'static str) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from(content)).unwrap(),
+        TreeChildren::empty(),
+    )))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_counts_operations() {
+    let storage = InstrumentedStorage::with_dedup_hits(Arc::new(InMemoryTreeStorage::empty()));
+    let first = storage.store_tree(&leaf("first")).await.unwrap();
+    let again = storage
+        .store_trees(&[leaf("first"), leaf("second!")])
+        .await
+        .unwrap();
+    storage.load_tree(first.digest()).await.unwrap();
+    storage
+        .load_trees(&[*again[0].digest(), *again[1].digest()])
+        .await
+        .unwrap();
+    let missing = BlobDigest::hash(b"missing");
+    storage.load_tree(&missing).await.unwrap_err();
+    assert_eq!(
+        vec![true, false],
+        storage
+            .find_existing_trees(&[*first.digest(), missing])
+            .await
+            .unwrap()
+    );
+
+    let metrics = storage.metrics();
+    assert_eq!(1, metrics.dedup_hits);
+    assert_eq!(2, metrics.store.calls);
+    assert_eq!(3, metrics.store.trees);
+    assert_eq!(5 + 5 + 7, metrics.store.bytes);
+    assert_eq!(0, metrics.store.errors);
+    assert_eq!(3, metrics.load.calls);
+    assert_eq!(3, metrics.load.trees);
+    assert_eq!(5 + 5 + 7, metrics.load.bytes);
+    assert_eq!(1, metrics.load.errors);
+    assert_eq!(3, metrics.load.latency.count);
+    assert_eq!(1, metrics.find_existing.calls);
+    assert_eq!(2, metrics.find_existing.trees);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_counts_store_errors() {
+    let inner = Arc::new(FaultInjectingStorage::new(Arc::new(
+        InMemoryTreeStorage::empty(),
+    )));
+    inner.add_rule(FaultRule::always(FaultOperation::Store, Fault::Fail));
+    let storage = InstrumentedStorage::with_dedup_hits(inner);
+    storage.store_tree(&leaf("tree")).await.unwrap_err();
+    let metrics = storage.metrics();
+    assert_eq!(1, metrics.store.calls);
+    assert_eq!(1, metrics.store.errors);
+    assert_eq!(0, metrics.store.trees);
+    assert_eq!(0, metrics.store.bytes);
+    assert_eq!(0, metrics.dedup_hits);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_failed_store_is_no_dedup_hit() {
+    let inner = Arc::new(FaultInjectingStorage::new(Arc::new(
+        InMemoryTreeStorage::empty(),
+    )));
+    let storage = InstrumentedStorage::with_dedup_hits(inner.clone());
+    let _reference = storage.store_tree(&leaf("tree")).await.unwrap();
+    inner.add_rule(FaultRule::always(FaultOperation::Store, Fault::Fail));
+    storage.store_tree(&leaf("tree")).await.unwrap_err();
+    storage.store_trees(&[leaf("tree")]).await.unwrap_err();
+    let metrics = storage.metrics();
+    assert_eq!(3, metrics.store.calls);
+    assert_eq!(2, metrics.store.errors);
+    assert_eq!(0, metrics.dedup_hits);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_no_additional_calls_by_default() {
+    let inner = Arc::new(InstrumentedStorage::new(Arc::new(
+        InMemoryTreeStorage::empty(),
+    )));
+    let storage = InstrumentedStorage::new(inner.clone());
+    storage.store_tree(&leaf("tree")).await.unwrap();
+    storage.store_tree(&leaf("tree")).await.unwrap();
+    assert_eq!(0, storage.metrics().dedup_hits);
+    let inner_metrics = inner.metrics();
+    assert_eq!(2, inner_metrics.store.calls);
+    assert_eq!(0, inner_metrics.find_existing.calls);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_prometheus_text() {
+    let storage = InstrumentedStorage::with_dedup_hits(Arc::new(InMemoryTreeStorage::empty()));
+    let reference = storage.store_tree(&leaf("tree")).await.unwrap();
+    storage.store_tree(&leaf("tree")).await.unwrap();
+    storage.load_tree(reference.digest()).await.unwrap();
+    let mut text = PrometheusText::new();
+    storage.metrics().write_prometheus("main", &mut text);
+    let text = text.finish();
+    for expected in [
+        "# TYPE astraea_storage_calls_total counter\n",
+        "astraea_storage_calls_total{storage=\"main\",operation=\"store\"} 2\n",
+        "astraea_storage_bytes_total{storage=\"main\",operation=\"load\"} 4\n",
+        "astraea_storage_errors_total{storage=\"main\",operation=\"find_existing\"} 0\n",
+        "astraea_storage_dedup_hits_total{storage=\"main\"} 1\n",
+        "# TYPE astraea_storage_latency_seconds histogram\n",
+        "astraea_storage_latency_seconds_bucket{storage=\"main\",operation=\"load\",le=\"+Inf\"} 1\n",
+        "astraea_storage_latency_seconds_count{storage=\"main\",operation=\"store\"} 2\n",
+    ] {
+        assert!(text.contains(expected), "{expected} is missing in {text}");
+    }
+}
@@ -0,0 +1,117 @@
+use crate::{
+    deep_tree::DeepTree,
+    storage::{LoadError, LoadTree, StrongHashedTree},
+    tree::{BlobDigest, TreeBlob},
+};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+/// A tree whose children are only loaded from storage when they are accessed for the first time. Unlike [DeepTree], only
+/// the parts of a large graph that are actually looked at end up in memory. Loaded children are kept by their parent, so
+/// each of them is loaded at most once.
+#[derive(Debug)]
+pub struct LazyDeepTree {
+    // Keeps the tree and its children alive in the storage.
+    tree: StrongHashedTree,
+    children: Vec<OnceCell<Arc<LazyDeepTree>>>,
+    storage: Arc<dyn LoadTree + Send + Sync>,
+}
+
+impl LazyDeepTree {
+    /// Loads only `root` itself.
+    pub async fn load(
+        root: &BlobDigest,
+        storage: Arc<dyn LoadTree + Send + Sync>,
+    ) -> std::result::Result<LazyDeepTree, LoadError> {
+        let tree = match storage.load_tree(root).await?.hash() {
+            Some(hashed_tree) => hashed_tree,
+            None => {
+                return Err(LoadError::TreeNotFound(*root));
+            }
+        };
+        let children = tree
+            .hashed_tree()
+            .tree()
+            .children()
+            .references()
+            .iter()
+            .map(|_| OnceCell::new())
+            .collect();
+        Ok(LazyDeepTree {
+            tree,
+            children,
+            storage,
+        })
+    }
+
+    pub fn digest(&self) -> &BlobDigest {
+        self.tree.hashed_tree().digest()
+    }
+
+    pub fn blob(&self) -> &TreeBlob {
+        self.tree.hashed_tree().tree().blob()
+    }
+
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Available without loading the child.
+    pub fn child_digest(&self, index: usize) -> Option<&BlobDigest> {
+        self.tree
+            .hashed_tree()
+            .tree()
+            .children()
+            .references()
+            .get(index)
+            .map(|child| child.digest())
+    }
+
+    pub fn is_child_loaded(&self, index: usize) -> bool {
+        self.children
+            .get(index)
+            .is_some_and(|child| child.initialized())
+    }
+
+    /// Loads the child on the first call. Returns None if there is no child with this index. Concurrent calls for the same
+    /// child load it only once.
+    pub async fn child(
+        &self,
+        index: usize,
+    ) -> std::result::Result<Option<Arc<LazyDeepTree>>, LoadError> {
+        let (Some(cell), Some(digest)) = (self.children.get(index), self.child_digest(index))
+        else {
+            return Ok(None);
+        };
+        let child = cell
+            .get_or_try_init(|| async {
+                LazyDeepTree::load(digest, self.storage.clone())
+                    .await
+                    .map(Arc::new)
+            })
+            .await?;
+        Ok(Some(child.clone()))
+    }
+
+    /// Loads everything below this tree into memory.
+    pub async fn to_deep_tree(&self) -> std::result::Result<DeepTree, LoadError> {
+        DeepTree::deserialize(self.digest(), self.storage.as_ref()).await
+    }
+}
+
+impl Drop for LazyDeepTree {
+    /// Like [DeepTree], the loaded children are dropped one after another instead of recursively, so that very deep trees
+    /// don't overflow the stack. Children that are still shared with someone else are left alone.
+    fn drop(&mut self) {
+        let mut pending: Vec<Arc<LazyDeepTree>> = self
+            .children
+            .iter_mut()
+            .filter_map(OnceCell::take)
+            .collect();
+        while let Some(tree) = pending.pop() {
+            if let Some(mut tree) = Arc::into_inner(tree) {
+                pending.extend(tree.children.iter_mut().filter_map(OnceCell::take));
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{
+    deep_tree::{DeepTree, DeepTreeChildren},
+    fault_injecting_storage::{Fault, FaultInjectingStorage, FaultOperation, FaultRule},
+    in_memory_storage::InMemoryTreeStorage,
+    instrumented_storage::InstrumentedStorage,
+    lazy_deep_tree::LazyDeepTree,
+    storage::LoadError,
+    tree::TreeBlob,
+};
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+fn deep_tree(content: &str, children: Vec<DeepTree>) -> DeepTree {
+    DeepTree::new(
+        TreeBlob::try_from(bytes::Bytes::copy_from_slice(content.as_bytes())).unwrap(),
+        DeepTreeChildren::try_from(children).unwrap(),
+    )
+}
+
+fn example() -> DeepTree {
+    deep_tree(
+        "root",
+        vec![
+            deep_tree("a", vec![deep_tree("a0", vec![])]),
+            deep_tree("b", vec![]),
+        ],
+    )
+}
+
+#[test_log::test(tokio::test)]
+async fn test_children_are_loaded_on_first_access() {
+    let storage = Arc::new(InstrumentedStorage::new(Arc::new(
+        InMemoryTreeStorage::empty(),
+    )));
+    let original = example();
+    let reference = original.serialize(storage.as_ref()).await.unwrap();
+    let root = LazyDeepTree::load(reference.digest(), storage.clone())
+        .await
+        .unwrap();
+    assert_eq!(reference.digest(), root.digest());
+    assert_eq!(b"root", root.blob().as_slice());
+    assert_eq!(2, root.child_count());
+    assert_eq!(1, storage.metrics().load.trees);
+    assert!(!root.is_child_loaded(0));
+
+    let first = root.child(0).await.unwrap().unwrap();
+    assert!(root.is_child_loaded(0));
+    assert!(!root.is_child_loaded(1));
+    assert_eq!(Some(first.digest()), root.child_digest(0));
+    assert_eq!(b"a", first.blob().as_slice());
+    assert_eq!(1, first.child_count());
+    assert_eq!(2, storage.metrics().load.trees);
+
+    // The child is kept, so it isn't loaded again.
+    let again = root.child(0).await.unwrap().unwrap();
+    assert!(Arc::ptr_eq(&first, &again));
+    assert_eq!(2, storage.metrics().load.trees);
+
+    assert_eq!(None, root.child(2).await.unwrap().map(|_| ()));
+    assert_eq!(None, root.child_digest(2));
+    assert_eq!(Ok(original), root.to_deep_tree().await);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_failed_child_load_is_retried() {
+    let storage = Arc::new(FaultInjectingStorage::new(Arc::new(
+        InMemoryTreeStorage::empty(),
+    )));
+    let reference = example().serialize(storage.as_ref()).await.unwrap();
+    let root = LazyDeepTree::load(reference.digest(), storage.clone())
+        .await
+        .unwrap();
+    let child_digest = *root.child_digest(1).unwrap();
+    storage.add_rule(FaultRule::once(
+        FaultOperation::Load,
+        child_digest,
+        Fault::NotFound,
+    ));
+    assert_eq!(
+        Some(LoadError::TreeNotFound(child_digest)),
+        root.child(1).await.err()
+    );
+    assert!(!root.is_child_loaded(1));
+    let child = root.child(1).await.unwrap().unwrap();
+    assert_eq!(b"b", child.blob().as_slice());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_very_deep_tree_is_dropped() {
+    let depth = 20_000;
+    let mut original = deep_tree("leaf", vec![]);
+    for _ in 0..depth {
+        original = deep_tree("", vec![original]);
+    }
+    let storage = Arc::new(InMemoryTreeStorage::empty());
+    let reference = original.serialize(storage.as_ref()).await.unwrap();
+    let root = Arc::new(
+        LazyDeepTree::load(reference.digest(), storage.clone())
+            .await
+            .unwrap(),
+    );
+    let mut current = root.clone();
+    let mut current_depth = 0;
+    while let Some(child) = current.child(0).await.unwrap() {
+        current = child;
+        current_depth += 1;
+    }
+    assert_eq!(depth, current_depth);
+    assert_eq!(b"leaf", current.blob().as_slice());
+    drop(current);
+    // Every level is loaded now, so dropping them recursively would overflow the stack.
+    drop(root);
+}
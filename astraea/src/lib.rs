@@ -15,14 +15,106 @@ pub mod sqlite_storage;
 #[cfg(test)]
 mod sqlite_storage_tests;
 
+pub mod sqlite_integrity;
+
+#[cfg(test)]
+mod sqlite_integrity_tests;
+
+pub mod blob_codec;
+
+#[cfg(test)]
+mod blob_codec_tests;
+
 pub mod in_memory_storage;
 
 #[cfg(test)]
 mod in_memory_storage_tests;
 
+pub mod directory_storage;
+
+#[cfg(test)]
+mod directory_storage_tests;
+
 pub mod load_cache_storage;
 
+#[cfg(test)]
+mod load_cache_storage_tests;
+
 pub mod delayed_hashed_tree;
 
 #[cfg(test)]
 mod delayed_hashed_tree_tests;
+
+pub mod storage_protocol;
+
+pub mod storage_server;
+
+pub mod remote_storage;
+
+#[cfg(test)]
+mod remote_storage_tests;
+
+pub mod replication;
+
+#[cfg(test)]
+mod replication_tests;
+
+pub mod archive;
+
+#[cfg(test)]
+mod archive_tests;
+
+pub mod encrypted_storage;
+
+#[cfg(test)]
+mod encrypted_storage_tests;
+
+pub mod tree_diff;
+
+#[cfg(test)]
+mod tree_diff_tests;
+
+pub mod traversal;
+
+#[cfg(test)]
+mod traversal_tests;
+
+pub mod usage_report;
+
+#[cfg(test)]
+mod usage_report_tests;
+
+pub mod mirrored_storage;
+
+#[cfg(test)]
+mod mirrored_storage_tests;
+
+pub mod fault_injecting_storage;
+
+#[cfg(test)]
+mod fault_injecting_storage_tests;
+
+pub mod inclusion_proof;
+
+#[cfg(test)]
+mod inclusion_proof_tests;
+
+pub mod prometheus_text;
+
+#[cfg(test)]
+mod prometheus_text_tests;
+
+pub mod instrumented_storage;
+
+#[cfg(test)]
+mod instrumented_storage_tests;
+
+pub mod lazy_deep_tree;
+
+#[cfg(test)]
+mod lazy_deep_tree_tests;
+
+pub mod tree_serde;
+
+#[cfg(test)]
+mod tree_serde_tests;
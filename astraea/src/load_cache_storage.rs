@@ -4,11 +4,10 @@ use crate::{
         LoadError, LoadStoreTree, LoadTree, StoreError, StoreTree, StrongDelayedHashedTree,
         StrongReference,
     },
-    tree::{BlobDigest, HashedTree},
+    tree::{BlobDigest, DigestAlgorithm, HashedTree},
 };
 use async_trait::async_trait;
-use cached::Cached;
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 use tokio::sync::Mutex;
 
 #[derive(Debug, Clone)]
@@ -17,17 +16,121 @@ struct CacheValue {
     reference: StrongReference,
 }
 
+#[derive(Debug)]
+struct CacheEntry {
+    value: CacheValue,
+    size: u64,
+    last_use: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LoadCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries that were removed to make room for new ones.
+    pub evictions: u64,
+    pub entries: u64,
+    /// Current size of all entries as counted by [LoadCache::entry_size].
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    entries: BTreeMap<BlobDigest, CacheEntry>,
+    /// The keys of `entries` by the time of their last use. The first one is evicted first.
+    by_last_use: BTreeMap<u64, BlobDigest>,
+    next_use: u64,
+    max_bytes: u64,
+    stats: LoadCacheStats,
+}
+
+impl CacheState {
+    fn get(&mut self, digest: &BlobDigest) -> Option<CacheValue> {
+        let next_use = self.next_use;
+        match self.entries.get_mut(digest) {
+            Some(entry) => {
+                self.by_last_use.remove(&entry.last_use);
+                self.by_last_use.insert(next_use, *digest);
+                entry.last_use = next_use;
+                self.next_use += 1;
+                self.stats.hits += 1;
+                Some(entry.value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, digest: BlobDigest, value: CacheValue) {
+        let size = LoadCache::entry_size(&value.tree);
+        if size > self.max_bytes {
+            return;
+        }
+        if let Some(previous) = self.entries.remove(&digest) {
+            self.by_last_use.remove(&previous.last_use);
+            self.stats.bytes -= previous.size;
+        }
+        while self.stats.bytes + size > self.max_bytes {
+            let (_, evicted) = self
+                .by_last_use
+                .pop_first()
+                .expect("The cache can't be over budget without entries");
+            let evicted = self
+                .entries
+                .remove(&evicted)
+                .expect("Every key in by_last_use has an entry");
+            self.stats.bytes -= evicted.size;
+            self.stats.evictions += 1;
+        }
+        self.by_last_use.insert(self.next_use, digest);
+        self.entries.insert(
+            digest,
+            CacheEntry {
+                value,
+                size,
+                last_use: self.next_use,
+            },
+        );
+        self.next_use += 1;
+        self.stats.bytes += size;
+    }
+}
+
+/// Keeps recently loaded and stored trees in memory. The least recently used trees are evicted when the total size
+/// exceeds `max_bytes`. Every cached tree holds a [StrongReference], so it stays alive in `next` while it is cached.
 #[derive(Debug)]
 pub struct LoadCache {
     next: Arc<dyn LoadStoreTree + Send + Sync>,
-    entries: Mutex<cached::stores::SizedCache<BlobDigest, CacheValue>>,
+    state: Mutex<CacheState>,
 }
 
 impl LoadCache {
-    pub fn new(next: Arc<dyn LoadStoreTree + Send + Sync>, max_entries: usize) -> Self {
+    pub fn new(next: Arc<dyn LoadStoreTree + Send + Sync>, max_bytes: u64) -> Self {
         Self {
             next,
-            entries: Mutex::new(cached::stores::SizedCache::with_size(max_entries)),
+            state: Mutex::new(CacheState {
+                entries: BTreeMap::new(),
+                by_last_use: BTreeMap::new(),
+                next_use: 0,
+                max_bytes,
+                stats: LoadCacheStats::default(),
+            }),
+        }
+    }
+
+    /// How much of the budget a tree uses: the length of its blob plus its child digests.
+    pub fn entry_size(tree: &HashedTree) -> u64 {
+        let children = tree.tree().children().references().len();
+        tree.tree().blob().len() as u64 + (children * std::mem::size_of::<BlobDigest>()) as u64
+    }
+
+    pub async fn stats(&self) -> LoadCacheStats {
+        let state_locked = self.state.lock().await;
+        LoadCacheStats {
+            entries: state_locked.entries.len() as u64,
+            ..state_locked.stats
         }
     }
 
@@ -36,9 +139,9 @@ impl LoadCache {
         reference: &BlobDigest,
     ) -> std::result::Result<CacheValue, LoadError> {
         {
-            let mut entries_locked = self.entries.lock().await;
-            if let Some(found) = entries_locked.cache_get(reference) {
-                return Ok(found.clone());
+            let mut state_locked = self.state.lock().await;
+            if let Some(found) = state_locked.get(reference) {
+                return Ok(found);
             }
         }
         let loaded = match self.next.load_tree(reference).await {
@@ -48,12 +151,12 @@ impl LoadCache {
         let maybe_hashed_tree = loaded.hash();
         match maybe_hashed_tree {
             Some(success) => {
-                let mut entries_locked = self.entries.lock().await;
+                let mut state_locked = self.state.lock().await;
                 let result = CacheValue {
                     tree: success.hashed_tree().clone(),
                     reference: success.reference().clone(),
                 };
-                entries_locked.cache_set(*reference, result.clone());
+                state_locked.insert(*reference, result.clone());
                 Ok(result)
             }
             None => Err(LoadError::TreeNotFound(*reference)),
@@ -83,7 +186,38 @@ impl StoreTree for LoadCache {
         &self,
         tree: &HashedTree,
     ) -> std::result::Result<StrongReference, StoreError> {
-        self.next.store_tree(tree).await
+        let reference = self.next.store_tree(tree).await?;
+        let mut state_locked = self.state.lock().await;
+        state_locked.insert(
+            *reference.digest(),
+            CacheValue {
+                tree: tree.clone(),
+                reference: reference.clone(),
+            },
+        );
+        Ok(reference)
+    }
+
+    async fn store_trees(
+        &self,
+        trees: &[HashedTree],
+    ) -> std::result::Result<Vec<StrongReference>, StoreError> {
+        let references = self.next.store_trees(trees).await?;
+        let mut state_locked = self.state.lock().await;
+        for (tree, reference) in trees.iter().zip(&references) {
+            state_locked.insert(
+                *reference.digest(),
+                CacheValue {
+                    tree: tree.clone(),
+                    reference: reference.clone(),
+                },
+            );
+        }
+        Ok(references)
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.next.digest_algorithm()
     }
 }
 
@@ -0,0 +1,113 @@
+use crate::{
+    in_memory_storage::InMemoryTreeStorage,
+    load_cache_storage::{LoadCache, LoadCacheStats},
+    storage::{LoadTree, StoreTree, StrongReference},
+    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+fn make_tree(content: &'static str, children: Vec<StrongReference>) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from(content)).unwrap(),
+        TreeChildren::try_from(children).unwrap(),
+    )))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_hits_and_misses() {
+    let next = Arc::new(InMemoryTreeStorage::empty());
+    let stored = next.store_tree(&make_tree("test", vec![])).await.unwrap();
+    let cache = LoadCache::new(next, 1000);
+    for _ in 0..2 {
+        let loaded = cache.load_tree(stored.digest()).await.unwrap();
+        assert_eq!(stored.digest(), loaded.hash().unwrap().reference().digest());
+    }
+    assert_eq!(
+        LoadCacheStats {
+            hits: 1,
+            misses: 1,
+            evictions: 0,
+            entries: 1,
+            bytes: 4,
+        },
+        cache.stats().await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_byte_budget_evicts_least_recently_used() {
+    let next = Arc::new(InMemoryTreeStorage::empty());
+    let mut digests: Vec<BlobDigest> = Vec::new();
+    let mut references = Vec::new();
+    for content in ["aaaa", "bbbb", "cccc"] {
+        let reference = next.store_tree(&make_tree(content, vec![])).await.unwrap();
+        digests.push(*reference.digest());
+        references.push(reference);
+    }
+    let cache = LoadCache::new(next, 10);
+    cache.load_tree(&digests[0]).await.unwrap();
+    cache.load_tree(&digests[1]).await.unwrap();
+    // Makes the first tree the most recently used one.
+    cache.load_tree(&digests[0]).await.unwrap();
+    cache.load_tree(&digests[2]).await.unwrap();
+    assert_eq!(
+        LoadCacheStats {
+            hits: 1,
+            misses: 3,
+            evictions: 1,
+            entries: 2,
+            bytes: 8,
+        },
+        cache.stats().await
+    );
+    cache.load_tree(&digests[0]).await.unwrap();
+    cache.load_tree(&digests[2]).await.unwrap();
+    cache.load_tree(&digests[1]).await.unwrap();
+    assert_eq!(
+        LoadCacheStats {
+            hits: 3,
+            misses: 4,
+            evictions: 2,
+            entries: 2,
+            bytes: 8,
+        },
+        cache.stats().await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_entry_size_counts_children() {
+    let next = Arc::new(InMemoryTreeStorage::empty());
+    let cache = LoadCache::new(next, 1000);
+    let child = cache.store_tree(&make_tree("", vec![])).await.unwrap();
+    let parent = make_tree("parent", vec![child]);
+    assert_eq!(
+        6 + std::mem::size_of::<BlobDigest>() as u64,
+        LoadCache::entry_size(&parent)
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_stored_trees_are_cached() {
+    let next = Arc::new(InMemoryTreeStorage::empty());
+    let cache = LoadCache::new(next, 10);
+    let small = cache.store_tree(&make_tree("small", vec![])).await.unwrap();
+    let stored = cache
+        .store_trees(&[make_tree("larger than the budget", vec![])])
+        .await
+        .unwrap();
+    cache.load_tree(small.digest()).await.unwrap();
+    cache.load_tree(stored[0].digest()).await.unwrap();
+    assert_eq!(
+        LoadCacheStats {
+            hits: 1,
+            misses: 1,
+            evictions: 0,
+            entries: 1,
+            bytes: 5,
+        },
+        cache.stats().await
+    );
+}
@@ -0,0 +1,463 @@
+use crate::{
+    delayed_hashed_tree::DelayedHashedTree,
+    replication::{replicate, ReplicationError},
+    storage::{
+        CompareAndSwapResult, LoadError, LoadRoot, LoadStoreTree, LoadTree, ReplaceTree,
+        StoreError, StoreTree, StrongDelayedHashedTree, StrongReference, StrongReferenceTrait,
+        UpdateRoot,
+    },
+    tree::{BlobDigest, DigestAlgorithm, HashedTree},
+};
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Everything a storage has to support to be a member of a [MirroredStorage].
+pub trait MirrorMember: LoadStoreTree + ReplaceTree + UpdateRoot + LoadRoot + Send + Sync {}
+
+impl<T> MirrorMember for T where T: LoadStoreTree + ReplaceTree + UpdateRoot + LoadRoot + Send + Sync
+{}
+
+/// Keeps the tree alive in every member that it was stored in.
+struct MirroredStrongReferenceImpl {
+    _members: Vec<StrongReference>,
+}
+
+impl StrongReferenceTrait for MirroredStrongReferenceImpl {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemberHealth {
+    /// Whether the last request to this member worked. Problems with a single tree don't count, only failures of the
+    /// member itself like I/O or network errors.
+    pub is_healthy: bool,
+    /// Moving average of the time it took to load a tree, or None if nothing has been loaded yet.
+    pub average_load_latency: Option<Duration>,
+    /// Trees that were missing or corrupted in this member and were stored again from another member.
+    pub trees_repaired: u64,
+    /// Roots that this member failed to update and that were updated again when they were loaded.
+    pub roots_repaired: u64,
+}
+
+impl MemberHealth {
+    fn record_latency(&mut self, latency: Duration) {
+        self.average_load_latency = Some(match self.average_load_latency {
+            Some(average) => (average * 7 + latency) / 8,
+            None => latency,
+        });
+    }
+}
+
+/// Writes every tree and root to all members and reads from the fastest healthy member. Trees that a member reports as
+/// missing, or returns with a wrong digest, are loaded from the next member and stored again in the members that failed.
+///
+/// Root updates and compare and swap are decided by the first member. The other members follow its result. Members that
+/// fail to follow are marked unhealthy and remembered in [MirroredStorage::lagging_roots]. Roots are only loaded from the
+/// first member, and loading a root updates it again in the members that lag behind.
+pub struct MirroredStorage {
+    members: Vec<Arc<dyn MirrorMember>>,
+    health: std::sync::Mutex<Vec<MemberHealth>>,
+    /// For every root name, the members that missed the last update of that root.
+    lagging_roots: std::sync::Mutex<BTreeMap<String, BTreeSet<usize>>>,
+}
+
+impl std::fmt::Debug for MirroredStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MirroredStorage")
+            .field("members", &self.members)
+            .finish_non_exhaustive()
+    }
+}
+
+enum MemberRead {
+    Found(StrongReference, HashedTree),
+    /// The member doesn't have the tree or it's corrupted, so it should be repaired.
+    Bad(LoadError),
+    /// The member itself failed.
+    Failed(LoadError),
+}
+
+impl MirroredStorage {
+    pub fn new(members: Vec<Arc<dyn MirrorMember>>) -> Self {
+        assert!(!members.is_empty(), "A mirror needs at least one member");
+        let health = vec![
+            MemberHealth {
+                is_healthy: true,
+                average_load_latency: None,
+                trees_repaired: 0,
+                roots_repaired: 0,
+            };
+            members.len()
+        ];
+        Self {
+            members,
+            health: std::sync::Mutex::new(health),
+            lagging_roots: std::sync::Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// One entry per member in the order they were passed to [MirroredStorage::new].
+    pub fn member_health(&self) -> Vec<MemberHealth> {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// The indices of the members that failed to follow the last update of each root and still have an older target.
+    pub fn lagging_roots(&self) -> BTreeMap<String, BTreeSet<usize>> {
+        self.lagging_roots.lock().unwrap().clone()
+    }
+
+    /// Sets the root in the members that missed an update of it.
+    async fn repair_lagging_root(&self, name: &str, root: &StrongReference) {
+        let lagging: Vec<usize> = match self.lagging_roots.lock().unwrap().get(name) {
+            Some(lagging) => lagging.iter().copied().collect(),
+            None => return,
+        };
+        for index in lagging {
+            match self.members[index].update_root(name, root).await {
+                Ok(()) => {
+                    info!("Repaired root {} in mirror member {}", name, index);
+                    self.set_healthy(index, true);
+                    self.health.lock().unwrap()[index].roots_repaired += 1;
+                    let mut lagging_roots = self.lagging_roots.lock().unwrap();
+                    if let Some(lagging) = lagging_roots.get_mut(name) {
+                        lagging.remove(&index);
+                        if lagging.is_empty() {
+                            lagging_roots.remove(name);
+                        }
+                    }
+                }
+                Err(error) => {
+                    warn!(
+                        "Could not repair root {} in mirror member {}: {}",
+                        name, index, &error
+                    );
+                    self.set_healthy(index, false);
+                }
+            }
+        }
+    }
+
+    /// Sets the root in every member except the first one, which has already been updated.
+    async fn follow_root_update(&self, name: &str, target: &StrongReference) {
+        let results = join_all(
+            self.members[1..]
+                .iter()
+                .map(|member| member.update_root(name, target)),
+        )
+        .await;
+        let mut lagging_roots = self.lagging_roots.lock().unwrap();
+        let lagging = lagging_roots.entry(name.to_string()).or_default();
+        for (offset, result) in results.into_iter().enumerate() {
+            let index = offset + 1;
+            match result {
+                Ok(()) => {
+                    lagging.remove(&index);
+                }
+                Err(error) => {
+                    warn!(
+                        "Mirror member {} failed to follow an update of root {}: {}",
+                        index, name, &error
+                    );
+                    self.set_healthy(index, false);
+                    lagging.insert(index);
+                }
+            }
+        }
+        if lagging.is_empty() {
+            lagging_roots.remove(name);
+        }
+    }
+
+    /// Healthy members first, then by their average latency. Members that haven't been measured yet are tried early so
+    /// that they get measured.
+    fn read_order(&self) -> Vec<usize> {
+        let health = self.health.lock().unwrap();
+        let mut order: Vec<usize> = (0..self.members.len()).collect();
+        order.sort_by_key(|index| {
+            let member = &health[*index];
+            (
+                !member.is_healthy,
+                member.average_load_latency.unwrap_or(Duration::ZERO),
+            )
+        });
+        order
+    }
+
+    fn set_healthy(&self, index: usize, is_healthy: bool) {
+        let mut health = self.health.lock().unwrap();
+        if health[index].is_healthy != is_healthy {
+            if is_healthy {
+                info!("Mirror member {} is healthy again", index);
+            } else {
+                warn!("Mirror member {} failed", index);
+            }
+        }
+        health[index].is_healthy = is_healthy;
+    }
+
+    async fn read_from_member(&self, index: usize, digest: &BlobDigest) -> MemberRead {
+        let started = Instant::now();
+        let loaded = self.members[index].load_tree(digest).await;
+        let latency = started.elapsed();
+        let result = match loaded {
+            Ok(loaded) => match loaded.hash() {
+                Some(hashed) => {
+                    MemberRead::Found(hashed.reference().clone(), hashed.hashed_tree().clone())
+                }
+                None => MemberRead::Bad(LoadError::Inconsistency(
+                    *digest,
+                    "Tree hash does not match the digest".to_string(),
+                )),
+            },
+            Err(
+                error @ (LoadError::TreeNotFound(_)
+                | LoadError::Deserialization(_, _)
+                | LoadError::Inconsistency(_, _)),
+            ) => MemberRead::Bad(error),
+            Err(error) => MemberRead::Failed(error),
+        };
+        let is_healthy = !matches!(result, MemberRead::Failed(_));
+        self.set_healthy(index, is_healthy);
+        if is_healthy {
+            self.health.lock().unwrap()[index].record_latency(latency);
+        }
+        result
+    }
+
+    /// Copies the children that `target` is missing from `source`, then overwrites the tree itself. The repair only counts
+    /// once `target` returns the tree with the right digest.
+    async fn repair(
+        &self,
+        tree: &HashedTree,
+        source: usize,
+        target: usize,
+    ) -> Result<StrongReference, StoreError> {
+        let source_member: &(dyn LoadTree + Send + Sync) = self.members[source].as_ref();
+        let target_member: &(dyn LoadStoreTree + Send + Sync) = self.members[target].as_ref();
+        // The children have to stay alive until their parent has been stored.
+        let mut children = Vec::with_capacity(tree.tree().children().references().len());
+        for child in tree.tree().children().references() {
+            let (reference, _progress) =
+                replicate(child.digest(), source_member, target_member, &|_| {})
+                    .await
+                    .map_err(|error| match error {
+                        ReplicationError::Load(error) => StoreError::TreeMissing(error),
+                        ReplicationError::Store(error) => error,
+                        ReplicationError::HashMismatch(digest) => StoreError::CorruptedStorage(
+                            format!("Tree {} has a wrong digest in the source member", digest),
+                        ),
+                    })?;
+            children.push(reference);
+        }
+        let reference = self.members[target].replace_tree(tree).await?;
+        let reloaded = target_member
+            .load_tree(tree.digest())
+            .await
+            .map_err(StoreError::TreeMissing)?;
+        if reloaded.hash().is_none() {
+            return Err(StoreError::CorruptedStorage(format!(
+                "Tree {} still has a wrong digest after it was replaced",
+                tree.digest()
+            )));
+        }
+        self.health.lock().unwrap()[target].trees_repaired += 1;
+        Ok(reference)
+    }
+
+    fn combine_references(
+        digest: &BlobDigest,
+        references: Vec<StrongReference>,
+    ) -> StrongReference {
+        StrongReference::new(
+            Some(Arc::new(MirroredStrongReferenceImpl {
+                _members: references,
+            })),
+            *digest,
+        )
+    }
+
+    /// Returns the first error after all members have been asked.
+    fn require_all<T>(&self, results: Vec<Result<T, StoreError>>) -> Result<Vec<T>, StoreError> {
+        let mut values = Vec::with_capacity(results.len());
+        let mut first_error = None;
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => values.push(value),
+                Err(error) => {
+                    warn!("Mirror member {} failed to store: {}", index, &error);
+                    self.set_healthy(index, false);
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(values),
+        }
+    }
+}
+
+#[async_trait]
+impl LoadTree for MirroredStorage {
+    async fn load_tree(
+        &self,
+        reference: &BlobDigest,
+    ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+        let mut needs_repair = Vec::new();
+        let mut first_error = None;
+        for index in self.read_order() {
+            match self.read_from_member(index, reference).await {
+                MemberRead::Found(member_reference, tree) => {
+                    let mut references = vec![member_reference];
+                    for target in needs_repair {
+                        match self.repair(&tree, index, target).await {
+                            Ok(repaired) => references.push(repaired),
+                            Err(error) => {
+                                warn!(
+                                    "Could not repair tree {} in mirror member {}: {}",
+                                    reference, target, &error
+                                );
+                            }
+                        }
+                    }
+                    return Ok(StrongDelayedHashedTree::new(
+                        Self::combine_references(reference, references),
+                        DelayedHashedTree::immediate(tree),
+                    ));
+                }
+                MemberRead::Bad(error) => {
+                    needs_repair.push(index);
+                    first_error.get_or_insert(error);
+                }
+                MemberRead::Failed(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+        Err(first_error.expect("There is at least one member"))
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        let mut first_error = None;
+        for index in self.read_order() {
+            match self.members[index].approximate_tree_count().await {
+                Ok(count) => return Ok(count),
+                Err(error) => {
+                    self.set_healthy(index, false);
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+        Err(first_error.expect("There is at least one member"))
+    }
+}
+
+#[async_trait]
+impl StoreTree for MirroredStorage {
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let results = join_all(self.members.iter().map(|member| member.store_tree(tree))).await;
+        let references = self.require_all(results)?;
+        Ok(Self::combine_references(tree.digest(), references))
+    }
+
+    async fn store_trees(
+        &self,
+        trees: &[HashedTree],
+    ) -> std::result::Result<Vec<StrongReference>, StoreError> {
+        let results = join_all(self.members.iter().map(|member| member.store_trees(trees))).await;
+        let mut by_member: Vec<std::vec::IntoIter<StrongReference>> = self
+            .require_all(results)?
+            .into_iter()
+            .map(|references| references.into_iter())
+            .collect();
+        Ok(trees
+            .iter()
+            .map(|tree| {
+                let references = by_member
+                    .iter_mut()
+                    .map(|references| {
+                        references
+                            .next()
+                            .expect("Every member returns one reference per tree")
+                    })
+                    .collect();
+                Self::combine_references(tree.digest(), references)
+            })
+            .collect())
+    }
+
+    /// The first member decides, so that all members get the same digests.
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.members[0].digest_algorithm()
+    }
+}
+
+impl LoadStoreTree for MirroredStorage {}
+
+#[async_trait]
+impl UpdateRoot for MirroredStorage {
+    async fn update_root(
+        &self,
+        name: &str,
+        target: &StrongReference,
+    ) -> std::result::Result<(), StoreError> {
+        if let Err(error) = self.members[0].update_root(name, target).await {
+            self.set_healthy(0, false);
+            return Err(error);
+        }
+        self.follow_root_update(name, target).await;
+        Ok(())
+    }
+
+    async fn compare_and_swap_root(
+        &self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &StrongReference,
+    ) -> std::result::Result<CompareAndSwapResult, StoreError> {
+        let result = match self.members[0]
+            .compare_and_swap_root(name, expected, new)
+            .await
+        {
+            Ok(result) => result,
+            Err(error) => {
+                self.set_healthy(0, false);
+                return Err(error);
+            }
+        };
+        // The swap already happened in the first member, so it has to be reported even if the others fail to follow.
+        if result == CompareAndSwapResult::Swapped {
+            self.follow_root_update(name, new).await;
+        }
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl LoadRoot for MirroredStorage {
+    async fn load_root(
+        &self,
+        name: &str,
+    ) -> std::result::Result<Option<StrongReference>, LoadError> {
+        // The other members may lag behind, so only the first one is asked.
+        let root = match self.members[0].load_root(name).await {
+            Ok(root) => root,
+            Err(error) => {
+                self.set_healthy(0, false);
+                return Err(error);
+            }
+        };
+        self.set_healthy(0, true);
+        if let Some(root) = &root {
+            self.repair_lagging_root(name, root).await;
+        }
+        Ok(root)
+    }
+}
@@ -0,0 +1,538 @@
+use crate::{
+    delayed_hashed_tree::DelayedHashedTree,
+    directory_storage::DirectoryStorage,
+    in_memory_storage::InMemoryTreeStorage,
+    mirrored_storage::{MirrorMember, MirroredStorage},
+    sqlite_storage::SQLiteStorage,
+    storage::{
+        CommitChanges, CompareAndSwapResult, LoadError, LoadRoot, LoadStoreTree, LoadTree,
+        ReplaceTree, StoreError, StoreTree, StrongDelayedHashedTree, StrongReference, UpdateRoot,
+    },
+    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+fn leaf(content: &[u8]) -> HashedTree {
+    parent(content, vec![])
+}
+
+fn parent(content: &[u8], children: Vec<StrongReference>) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::copy_from_slice(content)).unwrap(),
+        TreeChildren::try_from(children).unwrap(),
+    )))
+}
+
+/// An in-memory storage that can be told to fail, to be slow or to return garbage for some trees.
+#[derive(Debug)]
+struct TestMember {
+    inner: InMemoryTreeStorage,
+    /// Returned with the wrong content until they are replaced.
+    corrupted: std::sync::Mutex<BTreeSet<BlobDigest>>,
+    is_failing: AtomicBool,
+    load_delay: Duration,
+    loads: AtomicU64,
+}
+
+impl TestMember {
+    fn new(load_delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            inner: InMemoryTreeStorage::empty(),
+            corrupted: std::sync::Mutex::new(BTreeSet::new()),
+            is_failing: AtomicBool::new(false),
+            load_delay,
+            loads: AtomicU64::new(0),
+        })
+    }
+
+    fn corrupt(&self, digest: &BlobDigest) {
+        self.corrupted.lock().unwrap().insert(*digest);
+    }
+
+    fn check_failing(&self) -> Result<(), String> {
+        if self.is_failing.load(Ordering::SeqCst) {
+            Err("The disk is on fire".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn has_tree(&self, digest: &BlobDigest) -> bool {
+        match self.inner.load_tree(digest).await {
+            Ok(loaded) => {
+                loaded.hash().is_some() && !self.corrupted.lock().unwrap().contains(digest)
+            }
+            Err(LoadError::TreeNotFound(_)) => false,
+            Err(error) => panic!("Unexpected error: {error}"),
+        }
+    }
+}
+
+#[async_trait]
+impl LoadTree for TestMember {
+    async fn load_tree(
+        &self,
+        reference: &BlobDigest,
+    ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(self.load_delay).await;
+        self.check_failing().map_err(LoadError::Io)?;
+        let loaded = self.inner.load_tree(reference).await?;
+        if self.corrupted.lock().unwrap().contains(reference) {
+            return Ok(StrongDelayedHashedTree::new(
+                loaded.reference().clone(),
+                DelayedHashedTree::delayed(leaf(b"garbage").tree().clone(), *reference),
+            ));
+        }
+        Ok(loaded)
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        self.check_failing().map_err(StoreError::Io)?;
+        self.inner.approximate_tree_count().await
+    }
+}
+
+#[async_trait]
+impl StoreTree for TestMember {
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        self.check_failing().map_err(StoreError::Io)?;
+        self.inner.store_tree(tree).await
+    }
+}
+
+impl LoadStoreTree for TestMember {}
+
+#[async_trait]
+impl ReplaceTree for TestMember {
+    async fn replace_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        self.check_failing().map_err(StoreError::Io)?;
+        self.corrupted.lock().unwrap().remove(tree.digest());
+        self.inner.replace_tree(tree).await
+    }
+}
+
+#[async_trait]
+impl UpdateRoot for TestMember {
+    async fn update_root(
+        &self,
+        name: &str,
+        target: &StrongReference,
+    ) -> std::result::Result<(), StoreError> {
+        self.check_failing().map_err(StoreError::Io)?;
+        self.inner.update_root(name, target).await
+    }
+
+    async fn compare_and_swap_root(
+        &self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &StrongReference,
+    ) -> std::result::Result<CompareAndSwapResult, StoreError> {
+        self.check_failing().map_err(StoreError::Io)?;
+        self.inner.compare_and_swap_root(name, expected, new).await
+    }
+}
+
+#[async_trait]
+impl LoadRoot for TestMember {
+    async fn load_root(
+        &self,
+        name: &str,
+    ) -> std::result::Result<Option<StrongReference>, LoadError> {
+        self.check_failing().map_err(LoadError::Io)?;
+        self.inner.load_root(name).await
+    }
+}
+
+fn mirror(members: &[Arc<TestMember>]) -> MirroredStorage {
+    MirroredStorage::new(
+        members
+            .iter()
+            .map(|member| member.clone() as Arc<dyn MirrorMember>)
+            .collect(),
+    )
+}
+
+async fn load_hashed(storage: &MirroredStorage, digest: &BlobDigest) -> HashedTree {
+    storage
+        .load_tree(digest)
+        .await
+        .unwrap()
+        .hash()
+        .unwrap()
+        .hashed_tree()
+        .clone()
+}
+
+#[test_log::test(tokio::test)]
+async fn test_writes_go_to_all_members() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let child = storage.store_tree(&leaf(b"child")).await.unwrap();
+    let root = storage
+        .store_trees(&[parent(b"root", vec![child.clone()])])
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    storage.update_root("main", &root).await.unwrap();
+    for member in &members {
+        assert!(member.has_tree(child.digest()).await);
+        assert!(member.has_tree(root.digest()).await);
+        assert_eq!(
+            Some(*root.digest()),
+            member
+                .load_root("main")
+                .await
+                .unwrap()
+                .map(|root| *root.digest())
+        );
+    }
+    assert_eq!(
+        Some(*root.digest()),
+        storage
+            .load_root("main")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    assert_eq!(2, storage.approximate_tree_count().await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_missing_tree_is_repaired() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    // Only the second member has the trees, so the first one is asked first and has to be repaired.
+    let child = members[1].store_tree(&leaf(b"child")).await.unwrap();
+    let root_tree = parent(b"root", vec![child.clone()]);
+    let root = members[1].store_tree(&root_tree).await.unwrap();
+    let storage = mirror(&members);
+    // The repaired copy only stays in the in-memory member while the loaded reference exists.
+    let loaded = storage.load_tree(root.digest()).await.unwrap();
+    assert_eq!(root_tree, loaded.delayed_tree().clone().hash().unwrap());
+    assert!(members[0].has_tree(child.digest()).await);
+    assert!(members[0].has_tree(root.digest()).await);
+    let health = storage.member_health();
+    assert_eq!(1, health[0].trees_repaired);
+    assert_eq!(0, health[1].trees_repaired);
+    assert!(health.iter().all(|member| member.is_healthy));
+}
+
+#[test_log::test(tokio::test)]
+async fn test_corrupted_tree_is_repaired() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let tree = leaf(b"precious");
+    let reference = storage.store_tree(&tree).await.unwrap();
+    members[0].corrupt(reference.digest());
+    assert!(!members[0].has_tree(reference.digest()).await);
+    assert_eq!(tree, load_hashed(&storage, reference.digest()).await);
+    assert!(members[0].has_tree(reference.digest()).await);
+    assert_eq!(1, storage.member_health()[0].trees_repaired);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_corrupted_tree_is_repaired_on_disk() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let database = {
+        let connection = rusqlite::Connection::open(&database_path).unwrap();
+        SQLiteStorage::create_schema(&connection).unwrap();
+        Arc::new(SQLiteStorage::from(connection).unwrap())
+    };
+    let directory = Arc::new(DirectoryStorage::open(&workspace.path().join("directory")).unwrap());
+    let storage = MirroredStorage::new(vec![
+        database.clone() as Arc<dyn MirrorMember>,
+        directory.clone() as Arc<dyn MirrorMember>,
+    ]);
+    let child = storage.store_tree(&leaf(b"child")).await.unwrap();
+    let tree = parent(b"precious", vec![child.clone()]);
+    let reference = storage.store_tree(&tree).await.unwrap();
+    database.commit_changes().await.unwrap();
+    directory.commit_changes().await.unwrap();
+
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    assert_eq!(
+        1,
+        connection
+            .execute(
+                "UPDATE tree SET tree_blob = x'0102', codec = 0 WHERE digest = ?1",
+                (reference.digest().to_tagged_bytes(),),
+            )
+            .unwrap()
+    );
+    assert!(database
+        .load_tree(reference.digest())
+        .await
+        .unwrap()
+        .hash()
+        .is_none());
+
+    assert_eq!(tree, load_hashed(&storage, reference.digest()).await);
+    assert_eq!(
+        tree,
+        *database
+            .load_tree(reference.digest())
+            .await
+            .unwrap()
+            .hash()
+            .unwrap()
+            .hashed_tree()
+    );
+    database.commit_changes().await.unwrap();
+    let stored_blob: Vec<u8> = connection
+        .query_row(
+            "SELECT tree_blob FROM tree WHERE digest = ?1",
+            (reference.digest().to_tagged_bytes(),),
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_ne!(vec![1u8, 2], stored_blob);
+    let health = storage.member_health();
+    assert_eq!(1, health[0].trees_repaired);
+    assert_eq!(0, health[1].trees_repaired);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_tree_missing_everywhere() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let missing = BlobDigest::hash(b"missing");
+    assert_eq!(
+        LoadError::TreeNotFound(missing),
+        storage.load_tree(&missing).await.unwrap_err()
+    );
+    assert_eq!(0, storage.member_health()[0].trees_repaired);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_failing_member_is_avoided() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let tree = leaf(b"tree");
+    let reference = storage.store_tree(&tree).await.unwrap();
+    members[0].is_failing.store(true, Ordering::SeqCst);
+    assert_eq!(tree, load_hashed(&storage, reference.digest()).await);
+    assert!(!storage.member_health()[0].is_healthy);
+    assert!(storage.member_health()[1].is_healthy);
+
+    // The unhealthy member is only asked after the healthy ones.
+    assert_eq!(tree, load_hashed(&storage, reference.digest()).await);
+    assert_eq!(1, members[0].loads.load(Ordering::SeqCst));
+    assert_eq!(2, members[1].loads.load(Ordering::SeqCst));
+
+    // Writes have to reach every member.
+    assert_eq!(
+        StoreError::Io("The disk is on fire".to_string()),
+        storage.store_tree(&leaf(b"other")).await.unwrap_err()
+    );
+
+    members[0].is_failing.store(false, Ordering::SeqCst);
+    members[1].is_failing.store(true, Ordering::SeqCst);
+    assert_eq!(tree, load_hashed(&storage, reference.digest()).await);
+    assert!(storage.member_health()[0].is_healthy);
+    assert!(!storage.member_health()[1].is_healthy);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_fastest_member_is_preferred() {
+    let members = [
+        TestMember::new(Duration::from_millis(20)),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let tree = leaf(b"tree");
+    let reference = storage.store_tree(&tree).await.unwrap();
+    for _ in 0..4 {
+        assert_eq!(tree, load_hashed(&storage, reference.digest()).await);
+    }
+    // The slow member is only asked once before the fast one has been measured.
+    assert_eq!(1, members[0].loads.load(Ordering::SeqCst));
+    assert_eq!(3, members[1].loads.load(Ordering::SeqCst));
+    let health = storage.member_health();
+    assert!(health[0].average_load_latency.unwrap() > health[1].average_load_latency.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_compare_and_swap_root() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let first = storage.store_tree(&leaf(b"first")).await.unwrap();
+    let second = storage.store_tree(&leaf(b"second")).await.unwrap();
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("main", None, &first)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Conflict {
+            actual: Some(*first.digest())
+        },
+        storage
+            .compare_and_swap_root("main", Some(second.digest()), &second)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("main", Some(first.digest()), &second)
+            .await
+            .unwrap()
+    );
+    for member in &members {
+        assert_eq!(
+            Some(*second.digest()),
+            member
+                .load_root("main")
+                .await
+                .unwrap()
+                .map(|root| *root.digest())
+        );
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_compare_and_swap_root_with_failing_follower() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let first = storage.store_tree(&leaf(b"first")).await.unwrap();
+    let second = storage.store_tree(&leaf(b"second")).await.unwrap();
+    storage.update_root("main", &first).await.unwrap();
+    assert_eq!(BTreeMap::new(), storage.lagging_roots());
+    members[1].is_failing.store(true, Ordering::SeqCst);
+    // The first member decides, so the swap happened even though the follower failed.
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("main", Some(first.digest()), &second)
+            .await
+            .unwrap()
+    );
+    assert!(!storage.member_health()[1].is_healthy);
+    assert_eq!(
+        BTreeMap::from([("main".to_string(), BTreeSet::from([1]))]),
+        storage.lagging_roots()
+    );
+    members[1].is_failing.store(false, Ordering::SeqCst);
+    assert_eq!(
+        Some(*first.digest()),
+        members[1]
+            .load_root("main")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    // The next successful update brings the follower up to date.
+    storage.update_root("main", &first).await.unwrap();
+    assert_eq!(0, storage.member_health()[1].roots_repaired);
+    assert_eq!(BTreeMap::new(), storage.lagging_roots());
+    assert_eq!(
+        Some(*first.digest()),
+        members[1]
+            .load_root("main")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_lagging_root_is_repaired_when_loaded() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let first = storage.store_tree(&leaf(b"first")).await.unwrap();
+    let second = storage.store_tree(&leaf(b"second")).await.unwrap();
+    storage.update_root("main", &first).await.unwrap();
+    members[1].is_failing.store(true, Ordering::SeqCst);
+    storage.update_root("main", &second).await.unwrap();
+    members[1].is_failing.store(false, Ordering::SeqCst);
+    assert_eq!(
+        Some(*first.digest()),
+        members[1]
+            .load_root("main")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    assert_eq!(
+        Some(*second.digest()),
+        storage
+            .load_root("main")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    assert_eq!(
+        Some(*second.digest()),
+        members[1]
+            .load_root("main")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    assert_eq!(BTreeMap::new(), storage.lagging_roots());
+    let health = storage.member_health();
+    assert!(health[1].is_healthy);
+    assert_eq!(1, health[1].roots_repaired);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_root_is_not_loaded_from_followers() {
+    let members = [
+        TestMember::new(Duration::ZERO),
+        TestMember::new(Duration::ZERO),
+    ];
+    let storage = mirror(&members);
+    let root = storage.store_tree(&leaf(b"root")).await.unwrap();
+    storage.update_root("main", &root).await.unwrap();
+    members[0].is_failing.store(true, Ordering::SeqCst);
+    assert_eq!(
+        LoadError::Io("The disk is on fire".to_string()),
+        storage.load_root("main").await.unwrap_err()
+    );
+    assert!(!storage.member_health()[0].is_healthy);
+}
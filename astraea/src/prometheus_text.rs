@@ -0,0 +1,121 @@
+use std::{fmt::Write, time::Duration};
+
+/// Upper bounds of the latency histogram buckets in seconds. Everything slower ends up in the implicit `+Inf` bucket.
+pub const LATENCY_BUCKETS_SECONDS: [f64; 10] =
+    [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LatencyHistogram {
+    /// Number of observations per bucket of [LATENCY_BUCKETS_SECONDS], not cumulative. The last entry counts everything
+    /// slower than the largest bucket.
+    pub buckets: [u64; LATENCY_BUCKETS_SECONDS.len() + 1],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS_SECONDS
+            .iter()
+            .position(|upper_bound| seconds <= *upper_bound)
+            .unwrap_or(LATENCY_BUCKETS_SECONDS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+}
+
+/// Builds a page in the Prometheus text exposition format. Every metric is written with its `# HELP` and `# TYPE` lines
+/// followed by one sample per label set.
+#[derive(Debug, Default)]
+pub struct PrometheusText {
+    output: String,
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let formatted: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let escaped = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{escaped}\"")
+        })
+        .collect();
+    format!("{{{}}}", formatted.join(","))
+}
+
+impl PrometheusText {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        writeln!(self.output, "# HELP {name} {help}").unwrap();
+        writeln!(self.output, "# TYPE {name} {kind}").unwrap();
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, samples: &[(&[(&str, &str)], u64)]) {
+        self.header(name, help, "counter");
+        for (labels, value) in samples {
+            writeln!(self.output, "{name}{} {value}", format_labels(labels)).unwrap();
+        }
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, samples: &[(&[(&str, &str)], u64)]) {
+        self.header(name, help, "gauge");
+        for (labels, value) in samples {
+            writeln!(self.output, "{name}{} {value}", format_labels(labels)).unwrap();
+        }
+    }
+
+    pub fn histogram(
+        &mut self,
+        name: &str,
+        help: &str,
+        samples: &[(&[(&str, &str)], &LatencyHistogram)],
+    ) {
+        self.header(name, help, "histogram");
+        for (labels, histogram) in samples {
+            let mut cumulative = 0;
+            let upper_bounds = LATENCY_BUCKETS_SECONDS
+                .iter()
+                .map(|upper_bound| upper_bound.to_string())
+                .chain(std::iter::once("+Inf".to_string()));
+            for (upper_bound, count) in upper_bounds.zip(histogram.buckets) {
+                cumulative += count;
+                let mut bucket_labels = labels.to_vec();
+                bucket_labels.push(("le", &upper_bound));
+                writeln!(
+                    self.output,
+                    "{name}_bucket{} {cumulative}",
+                    format_labels(&bucket_labels)
+                )
+                .unwrap();
+            }
+            writeln!(
+                self.output,
+                "{name}_sum{} {}",
+                format_labels(labels),
+                histogram.sum.as_secs_f64()
+            )
+            .unwrap();
+            writeln!(
+                self.output,
+                "{name}_count{} {}",
+                format_labels(labels),
+                histogram.count
+            )
+            .unwrap();
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.output
+    }
+}
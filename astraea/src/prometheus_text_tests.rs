@@ -0,0 +1,61 @@
+use crate::prometheus_text::{LatencyHistogram, PrometheusText};
+use pretty_assertions::assert_eq;
+use std::time::Duration;
+
+#[test_log::test]
+fn test_counter_and_gauge() {
+    let mut text = PrometheusText::new();
+    text.counter(
+        "requests_total",
+        "Requests.",
+        &[(&[("path", "/a")], 3), (&[("path", "say \"hi\"\\\n")], 4)],
+    );
+    text.gauge("trees", "Trees.", &[(&[], 7)]);
+    assert_eq!(
+        "# HELP requests_total Requests.
+# TYPE requests_total counter
+requests_total{path=\"/a\"} 3
+requests_total{path=\"say \\\"hi\\\"\\\\\\n\"} 4
+# HELP trees Trees.
+# TYPE trees gauge
+trees 7
+",
+        text.finish()
+    );
+}
+
+#[test_log::test]
+fn test_histogram() {
+    let mut histogram = LatencyHistogram::default();
+    histogram.observe(Duration::from_micros(50));
+    histogram.observe(Duration::from_millis(1));
+    histogram.observe(Duration::from_millis(300));
+    histogram.observe(Duration::from_secs(10));
+    assert_eq!([1, 0, 1, 0, 0, 0, 0, 1, 0, 0, 1], histogram.buckets);
+    assert_eq!(4, histogram.count);
+    let mut text = PrometheusText::new();
+    text.histogram(
+        "latency_seconds",
+        "Latency.",
+        &[(&[("op", "x")], &histogram)],
+    );
+    assert_eq!(
+        "# HELP latency_seconds Latency.
+# TYPE latency_seconds histogram
+latency_seconds_bucket{op=\"x\",le=\"0.0001\"} 1
+latency_seconds_bucket{op=\"x\",le=\"0.0005\"} 1
+latency_seconds_bucket{op=\"x\",le=\"0.001\"} 2
+latency_seconds_bucket{op=\"x\",le=\"0.005\"} 2
+latency_seconds_bucket{op=\"x\",le=\"0.01\"} 2
+latency_seconds_bucket{op=\"x\",le=\"0.05\"} 2
+latency_seconds_bucket{op=\"x\",le=\"0.1\"} 2
+latency_seconds_bucket{op=\"x\",le=\"0.5\"} 3
+latency_seconds_bucket{op=\"x\",le=\"1\"} 3
+latency_seconds_bucket{op=\"x\",le=\"5\"} 3
+latency_seconds_bucket{op=\"x\",le=\"+Inf\"} 4
+latency_seconds_sum{op=\"x\"} 10.30105
+latency_seconds_count{op=\"x\"} 4
+",
+        text.finish()
+    );
+}
@@ -0,0 +1,395 @@
+use crate::{
+    delayed_hashed_tree::DelayedHashedTree,
+    inclusion_proof::{verify_inclusion, InclusionProofError},
+    storage::{
+        CompareAndSwapResult, LoadError, LoadRoot, LoadStoreTree, LoadTree, StoreError, StoreTree,
+        StrongDelayedHashedTree, StrongReference, StrongReferenceTrait, UpdateRoot,
+    },
+    storage_protocol::{
+        read_message, write_message, Operation, ProtocolError, Request, Response, SerializedTree,
+    },
+    tree::{BlobDigest, HashedTree},
+};
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
+use tokio::{
+    io::{BufReader, BufWriter},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+    sync::Mutex,
+};
+
+/// Keeps track of which digests the client still holds strong references to, so that the server can be told
+/// when it may stop keeping a tree alive for us.
+#[derive(Debug, Default)]
+struct ReferenceTable {
+    alive: BTreeMap<BlobDigest, Weak<RemoteStrongReferenceImpl>>,
+    released: Vec<BlobDigest>,
+}
+
+#[derive(Debug)]
+struct RemoteStrongReferenceImpl {
+    digest: BlobDigest,
+    table: Weak<std::sync::Mutex<ReferenceTable>>,
+}
+
+impl StrongReferenceTrait for RemoteStrongReferenceImpl {}
+
+impl Drop for RemoteStrongReferenceImpl {
+    fn drop(&mut self) {
+        let table = match self.table.upgrade() {
+            Some(table) => table,
+            // The client is gone, and with it the connection that held the reference on the server.
+            None => return,
+        };
+        let mut table_locked = table.lock().unwrap();
+        match table_locked.alive.get(&self.digest) {
+            Some(existing) if existing.upgrade().is_some() => {
+                // Someone created a new reference for the same digest in the meantime.
+            }
+            _ => {
+                table_locked.alive.remove(&self.digest);
+                table_locked.released.push(self.digest);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    /// Set when an exchange failed. The stream may contain a part of a request or response then, so nothing can be sent
+    /// anymore. The server forgets our references when the connection is closed, so a new one would have to be made by
+    /// creating a new client.
+    is_broken: bool,
+}
+
+impl Connection {
+    async fn exchange(&mut self, request: &Request) -> Result<Response, ProtocolError> {
+        write_message(&mut self.writer, request).await?;
+        match read_message(&mut self.reader).await? {
+            Some(response) => Ok(response),
+            None => Err(ProtocolError::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The server closed the connection",
+            ))),
+        }
+    }
+}
+
+/// Client for a storage exposed by [crate::storage_server::serve_storage]. Trees stay alive on the server
+/// as long as this client holds a strong reference to them.
+#[derive(Debug)]
+pub struct RemoteTreeStorage {
+    connection: Arc<Mutex<Connection>>,
+    references: Arc<std::sync::Mutex<ReferenceTable>>,
+}
+
+impl RemoteTreeStorage {
+    pub async fn connect<A: ToSocketAddrs>(address: A) -> std::io::Result<RemoteTreeStorage> {
+        let stream = TcpStream::connect(address).await?;
+        // Every operation is a small request followed by waiting for the response, so Nagle's algorithm would only add latency.
+        stream.set_nodelay(true)?;
+        Ok(Self::from_stream(stream))
+    }
+
+    pub fn from_stream(stream: TcpStream) -> RemoteTreeStorage {
+        let (reader, writer) = stream.into_split();
+        RemoteTreeStorage {
+            connection: Arc::new(Mutex::new(Connection {
+                reader: BufReader::new(reader),
+                writer: BufWriter::new(writer),
+                is_broken: false,
+            })),
+            references: Arc::new(std::sync::Mutex::new(ReferenceTable::default())),
+        }
+    }
+
+    fn require_reference(&self, digest: &BlobDigest) -> StrongReference {
+        require_reference(&self.references, digest)
+    }
+
+    /// Returns the response together with references to every tree that the server started to hold for us while answering.
+    /// They are registered before the connection is unlocked, so no other request can send a release of an older reference
+    /// to the same tree, which would make the server drop the new hold. The caller has to keep them until it has created its
+    /// own references.
+    async fn send(
+        &self,
+        operation: Operation,
+    ) -> Result<(Response, Vec<StrongReference>), ProtocolError> {
+        let connection = self.connection.clone();
+        let references = self.references.clone();
+        // The exchange runs in its own task, so a caller that stops waiting for the response can't leave a part of the
+        // request or response in the stream.
+        tokio::spawn(async move {
+            let mut connection_locked = connection.lock().await;
+            if connection_locked.is_broken {
+                return Err(ProtocolError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "The connection broke during an earlier request",
+                )));
+            }
+            let released = std::mem::take(&mut references.lock().unwrap().released);
+            let request = Request {
+                released,
+                operation,
+            };
+            match connection_locked.exchange(&request).await {
+                Ok(response) => {
+                    let held = held_digests(&request.operation, &response)
+                        .iter()
+                        .map(|digest| require_reference(&references, digest))
+                        .collect();
+                    Ok((response, held))
+                }
+                Err(error) => {
+                    connection_locked.is_broken = true;
+                    // The server may not have seen the releases, so they must not be forgotten.
+                    let mut table_locked = references.lock().unwrap();
+                    for digest in request.released {
+                        let is_alive = table_locked
+                            .alive
+                            .get(&digest)
+                            .is_some_and(|existing| existing.upgrade().is_some());
+                        if !is_alive && !table_locked.released.contains(&digest) {
+                            table_locked.released.push(digest);
+                        }
+                    }
+                    Err(error)
+                }
+            }
+        })
+        .await
+        .map_err(|error| ProtocolError::Io(std::io::Error::other(error)))?
+    }
+}
+
+impl RemoteTreeStorage {
+    /// Asks the server to prove that `path` leads from `root` to some tree and verifies the proof locally, so the server
+    /// doesn't have to be trusted. Returns the digest the path leads to.
+    pub async fn prove_inclusion(
+        &self,
+        root: &BlobDigest,
+        path: &[usize],
+    ) -> Result<BlobDigest, InclusionProofError> {
+        let proof = match self
+            .send(Operation::ProveInclusion {
+                root: *root,
+                path: path.to_vec(),
+            })
+            .await
+            .map_err(|error| InclusionProofError::Load(LoadError::Network(error.to_string())))?
+            .0
+        {
+            Response::ProveInclusion(result) => result?,
+            other => {
+                return Err(InclusionProofError::Load(LoadError::Network(
+                    unexpected_response(&other),
+                )))
+            }
+        };
+        verify_inclusion(root, path, &proof)
+    }
+}
+
+fn require_reference(
+    references: &Arc<std::sync::Mutex<ReferenceTable>>,
+    digest: &BlobDigest,
+) -> StrongReference {
+    let mut table_locked = references.lock().unwrap();
+    // A release that hasn't been sent yet must not be sent anymore, otherwise the server would drop a tree we depend on.
+    table_locked.released.retain(|released| released != digest);
+    if let Some(existing) = table_locked
+        .alive
+        .get(digest)
+        .and_then(|existing| existing.upgrade())
+    {
+        return StrongReference::new(Some(existing), *digest);
+    }
+    let reference_impl = Arc::new(RemoteStrongReferenceImpl {
+        digest: *digest,
+        table: Arc::downgrade(references),
+    });
+    table_locked
+        .alive
+        .insert(*digest, Arc::downgrade(&reference_impl));
+    StrongReference::new(Some(reference_impl), *digest)
+}
+
+/// The trees that the server holds for us after sending `response` to `operation`.
+fn held_digests(operation: &Operation, response: &Response) -> Vec<BlobDigest> {
+    match (operation, response) {
+        (Operation::LoadTree(digest), Response::LoadTree(Ok(tree))) => {
+            let mut result = tree.children.clone();
+            result.push(*digest);
+            result
+        }
+        (_, Response::StoreTree(Ok(digest))) => vec![*digest],
+        (_, Response::LoadRoot(Ok(Some(digest)))) => vec![*digest],
+        _ => Vec::new(),
+    }
+}
+
+fn unexpected_response(response: &Response) -> String {
+    format!("Unexpected response from the server: {response:?}")
+}
+
+#[async_trait]
+impl LoadTree for RemoteTreeStorage {
+    async fn load_tree(
+        &self,
+        reference: &BlobDigest,
+    ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+        let (response, _held) = self
+            .send(Operation::LoadTree(*reference))
+            .await
+            .map_err(|error| LoadError::Network(error.to_string()))?;
+        let serialized = match response {
+            Response::LoadTree(result) => result?,
+            other => return Err(LoadError::Network(unexpected_response(&other))),
+        };
+        let children = serialized
+            .children
+            .iter()
+            .map(|child| self.require_reference(child))
+            .collect();
+        let tree = serialized
+            .to_tree(children)
+            .map_err(|error| LoadError::Deserialization(*reference, error))?;
+        Ok(StrongDelayedHashedTree::new(
+            self.require_reference(reference),
+            DelayedHashedTree::delayed(Arc::new(tree), *reference),
+        ))
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        match self
+            .send(Operation::ApproximateTreeCount)
+            .await
+            .map_err(|error| StoreError::Network(error.to_string()))?
+            .0
+        {
+            Response::ApproximateTreeCount(result) => result,
+            other => Err(StoreError::Network(unexpected_response(&other))),
+        }
+    }
+
+    async fn find_existing_trees(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<bool>, LoadError> {
+        let result = match self
+            .send(Operation::FindExistingTrees(digests.to_vec()))
+            .await
+            .map_err(|error| LoadError::Network(error.to_string()))?
+            .0
+        {
+            Response::FindExistingTrees(result) => result?,
+            other => return Err(LoadError::Network(unexpected_response(&other))),
+        };
+        if result.len() != digests.len() {
+            return Err(LoadError::Network(format!(
+                "Asked the server about {} trees, but got {} answers",
+                digests.len(),
+                result.len()
+            )));
+        }
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl StoreTree for RemoteTreeStorage {
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let (response, _held) = self
+            .send(Operation::StoreTree {
+                tree: SerializedTree::from_tree(tree.tree()),
+                algorithm: tree.digest().algorithm(),
+            })
+            .await
+            .map_err(|error| StoreError::Network(error.to_string()))?;
+        let digest = match response {
+            Response::StoreTree(result) => result?,
+            other => return Err(StoreError::Network(unexpected_response(&other))),
+        };
+        if &digest != tree.digest() {
+            return Err(StoreError::Network(format!(
+                "The server stored tree {} as {}",
+                tree.digest(),
+                digest
+            )));
+        }
+        Ok(self.require_reference(&digest))
+    }
+}
+
+impl LoadStoreTree for RemoteTreeStorage {}
+
+#[async_trait]
+impl UpdateRoot for RemoteTreeStorage {
+    async fn update_root(
+        &self,
+        name: &str,
+        target: &StrongReference,
+    ) -> std::result::Result<(), StoreError> {
+        match self
+            .send(Operation::UpdateRoot {
+                name: name.to_string(),
+                target: *target.digest(),
+            })
+            .await
+            .map_err(|error| StoreError::Network(error.to_string()))?
+            .0
+        {
+            Response::UpdateRoot(result) => result,
+            other => Err(StoreError::Network(unexpected_response(&other))),
+        }
+    }
+
+    async fn compare_and_swap_root(
+        &self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &StrongReference,
+    ) -> std::result::Result<CompareAndSwapResult, StoreError> {
+        match self
+            .send(Operation::CompareAndSwapRoot {
+                name: name.to_string(),
+                expected: expected.copied(),
+                new: *new.digest(),
+            })
+            .await
+            .map_err(|error| StoreError::Network(error.to_string()))?
+            .0
+        {
+            Response::CompareAndSwapRoot(result) => result,
+            other => Err(StoreError::Network(unexpected_response(&other))),
+        }
+    }
+}
+
+#[async_trait]
+impl LoadRoot for RemoteTreeStorage {
+    async fn load_root(
+        &self,
+        name: &str,
+    ) -> std::result::Result<Option<StrongReference>, LoadError> {
+        let (response, _held) = self
+            .send(Operation::LoadRoot(name.to_string()))
+            .await
+            .map_err(|error| LoadError::Network(error.to_string()))?;
+        match response {
+            Response::LoadRoot(result) => Ok(result?.map(|digest| self.require_reference(&digest))),
+            other => Err(LoadError::Network(unexpected_response(&other))),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    inclusion_proof::InclusionProofError,
+    remote_storage::RemoteTreeStorage,
+    sqlite_storage::SQLiteStorage,
+    storage::{
+        CollectGarbage, CompareAndSwapResult, GarbageCollectionStats, LoadError, LoadRoot,
+        LoadTree, StoreError, StoreTree, StrongReference, UpdateRoot,
+    },
+    storage_server::serve_storage,
+    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use futures_util::FutureExt;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+async fn start_server() -> (Arc<SQLiteStorage>, RemoteTreeStorage) {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = Arc::new(SQLiteStorage::from(connection).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve_storage(listener, storage.clone()));
+    let client = RemoteTreeStorage::connect(address).await.unwrap();
+    (storage, client)
+}
+
+fn blob_tree(content: &'static str) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from(content)).unwrap(),
+        TreeChildren::empty(),
+    )))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_store_and_load() {
+    let (_server_storage, client) = start_server().await;
+    let child = client.store_tree(&blob_tree("child")).await.unwrap();
+    let parent = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from("parent")).unwrap(),
+        TreeChildren::try_from(vec![child.clone()]).unwrap(),
+    )));
+    let parent_reference = client.store_tree(&parent).await.unwrap();
+    assert_eq!(parent.digest(), parent_reference.digest());
+    let loaded = client
+        .load_tree(parent_reference.digest())
+        .await
+        .unwrap()
+        .hash()
+        .unwrap();
+    assert_eq!(&parent, loaded.hashed_tree());
+    assert_eq!(2, client.approximate_tree_count().await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_load_not_found() {
+    let (_server_storage, client) = start_server().await;
+    let digest = *blob_tree("missing").digest();
+    assert_eq!(
+        LoadError::TreeNotFound(digest),
+        client.load_tree(&digest).await.unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_store_missing_child() {
+    let (_server_storage, client) = start_server().await;
+    let missing = *blob_tree("missing").digest();
+    let parent = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::empty(),
+        TreeChildren::try_from(vec![StrongReference::from_weak(missing)]).unwrap(),
+    )));
+    assert_eq!(
+        StoreError::TreeMissing(LoadError::TreeNotFound(missing)),
+        client.store_tree(&parent).await.unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_find_existing_trees() {
+    let (_server_storage, client) = start_server().await;
+    let existing = client.store_tree(&blob_tree("existing")).await.unwrap();
+    let missing = *blob_tree("missing").digest();
+    assert_eq!(
+        vec![false, true, false],
+        client
+            .find_existing_trees(&[missing, *existing.digest(), missing])
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        Vec::<bool>::new(),
+        client.find_existing_trees(&[]).await.unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_roots() {
+    let (server_storage, client) = start_server().await;
+    let name = "test";
+    assert_eq!(Ok(None), client.load_root(name).await);
+    let reference = client.store_tree(&blob_tree("root")).await.unwrap();
+    client.update_root(name, &reference).await.unwrap();
+    assert_eq!(Ok(Some(reference.clone())), client.load_root(name).await);
+    assert_eq!(Ok(Some(reference)), server_storage.load_root(name).await);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_compare_and_swap_root() {
+    let (server_storage, client) = start_server().await;
+    let first = client.store_tree(&blob_tree("first")).await.unwrap();
+    let second = client.store_tree(&blob_tree("second")).await.unwrap();
+    assert_eq!(
+        Ok(CompareAndSwapResult::Swapped),
+        client.compare_and_swap_root("test", None, &first).await
+    );
+    assert_eq!(
+        Ok(CompareAndSwapResult::Conflict {
+            actual: Some(*first.digest())
+        }),
+        client.compare_and_swap_root("test", None, &second).await
+    );
+    assert_eq!(
+        Ok(CompareAndSwapResult::Swapped),
+        client
+            .compare_and_swap_root("test", Some(first.digest()), &second)
+            .await
+    );
+    assert_eq!(Ok(Some(second)), server_storage.load_root("test").await);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_update_root_missing_tree() {
+    let (_server_storage, client) = start_server().await;
+    let missing = *blob_tree("missing").digest();
+    assert_eq!(
+        Err(StoreError::TreeMissing(LoadError::TreeNotFound(missing))),
+        client
+            .update_root("test", &StrongReference::from_weak(missing))
+            .await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_references_keep_trees_alive() {
+    let (server_storage, client) = start_server().await;
+    let reference = client.store_tree(&blob_tree("alive")).await.unwrap();
+    let digest: BlobDigest = *reference.digest();
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 0 },
+        server_storage.collect_some_garbage().await.unwrap()
+    );
+    drop(reference);
+    // The release is sent along with the next request.
+    assert_eq!(1, client.approximate_tree_count().await.unwrap());
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 1 },
+        server_storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        LoadError::TreeNotFound(digest),
+        client.load_tree(&digest).await.unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_release_while_storing_again() {
+    let (server_storage, client) = start_server().await;
+    let tree = blob_tree("stored twice");
+    let old_reference = client.store_tree(&tree).await.unwrap();
+    let mut storing = Box::pin(client.store_tree(&tree));
+    assert!((&mut storing).now_or_never().is_none());
+    // The connection is locked in order, so the second store has been answered once this returns.
+    assert_eq!(1, client.approximate_tree_count().await.unwrap());
+    // The release of the old reference would be sent before the new reference exists if the hold of the second store
+    // wasn't registered before the connection was unlocked.
+    drop(old_reference);
+    assert_eq!(1, client.approximate_tree_count().await.unwrap());
+    let new_reference = storing.await.unwrap();
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 0 },
+        server_storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        &tree,
+        client
+            .load_tree(new_reference.digest())
+            .await
+            .unwrap()
+            .hash()
+            .unwrap()
+            .hashed_tree()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_server_gone() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = RemoteTreeStorage::connect(address).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    drop(stream);
+    drop(listener);
+    let digest = *blob_tree("anything").digest();
+    match client.load_tree(&digest).await.unwrap_err() {
+        LoadError::Network(_) => {}
+        other => panic!("Unexpected error: {other:?}"),
+    }
+    assert_eq!(
+        LoadError::Network(
+            "Io(Custom { kind: NotConnected, error: \"The connection broke during an earlier request\" })"
+                .to_string()
+        ),
+        client.load_tree(&digest).await.unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_cancelled_request() {
+    let (_server_storage, client) = start_server().await;
+    let reference = client.store_tree(&blob_tree("stored")).await.unwrap();
+    // Gives up after polling once, so the request may have been sent, but the response can't have been read yet.
+    assert!(client.approximate_tree_count().now_or_never().is_none());
+    // The response to the cancelled request must not be taken for the response to the next one.
+    let missing = *blob_tree("missing").digest();
+    assert_eq!(
+        LoadError::TreeNotFound(missing),
+        client.load_tree(&missing).await.unwrap_err()
+    );
+    assert_eq!(
+        vec![true, false],
+        client
+            .find_existing_trees(&[*reference.digest(), missing])
+            .await
+            .unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_remote_prove_inclusion() {
+    let (_server_storage, client) = start_server().await;
+    let child = client.store_tree(&blob_tree("child")).await.unwrap();
+    let parent = client
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(Bytes::from("parent")).unwrap(),
+            TreeChildren::try_from(vec![child.clone()]).unwrap(),
+        ))))
+        .await
+        .unwrap();
+    assert_eq!(
+        Ok(*child.digest()),
+        client.prove_inclusion(parent.digest(), &[0]).await
+    );
+    assert_eq!(
+        Err(InclusionProofError::NoSuchChild { depth: 0, index: 1 }),
+        client.prove_inclusion(parent.digest(), &[1]).await
+    );
+    let missing = *blob_tree("missing").digest();
+    assert_eq!(
+        Err(InclusionProofError::Load(LoadError::TreeNotFound(missing))),
+        client.prove_inclusion(&missing, &[0]).await
+    );
+}
@@ -0,0 +1,136 @@
+use crate::{
+    storage::{LoadError, LoadStoreTree, LoadTree, StoreError, StrongReference},
+    tree::{BlobDigest, HashedTree},
+};
+use std::collections::BTreeMap;
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplicationProgress {
+    pub trees_transferred: u64,
+    /// Sum of the blob sizes of the transferred trees.
+    pub bytes_transferred: u64,
+    /// Number of subtrees that were not transferred because the destination already had them.
+    /// The trees below a skipped subtree are not counted because they are never looked at.
+    pub subtrees_skipped: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplicationError {
+    Load(LoadError),
+    Store(StoreError),
+    HashMismatch(BlobDigest),
+}
+
+impl std::fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for ReplicationError {}
+
+struct Replication<'t> {
+    from: &'t (dyn LoadTree + Send + Sync),
+    to: &'t (dyn LoadStoreTree + Send + Sync),
+    on_progress: &'t (dyn Fn(&ReplicationProgress) + Send + Sync),
+    progress: ReplicationProgress,
+    // Keeps everything we stored alive until the parents have been stored, and avoids asking the destination about the same tree twice.
+    stored: BTreeMap<BlobDigest, StrongReference>,
+}
+
+impl Replication<'_> {
+    async fn copy_tree(
+        &mut self,
+        digest: &BlobDigest,
+    ) -> Result<StrongReference, ReplicationError> {
+        let loaded = self
+            .from
+            .load_tree(digest)
+            .await
+            .map_err(ReplicationError::Load)?;
+        let hashed_tree = match loaded.hash() {
+            Some(success) => success,
+            None => return Err(ReplicationError::HashMismatch(*digest)),
+        };
+        let tree = hashed_tree.hashed_tree().tree().clone();
+
+        let mut unknown_children: Vec<BlobDigest> = Vec::new();
+        for child in tree.children().references() {
+            if !self.stored.contains_key(child.digest())
+                && !unknown_children.contains(child.digest())
+            {
+                unknown_children.push(*child.digest());
+            }
+        }
+        let existing = self
+            .to
+            .find_existing_trees(&unknown_children)
+            .await
+            .map_err(ReplicationError::Load)?;
+        for (child, exists) in unknown_children.iter().zip(existing) {
+            if exists {
+                self.progress.subtrees_skipped += 1;
+                (self.on_progress)(&self.progress);
+            } else if self.stored.contains_key(child) {
+                // A sibling we copied before already contained this child.
+            } else {
+                // Children have to be stored before their parent because store_tree checks that they exist.
+                let reference = Box::pin(self.copy_tree(child)).await?;
+                self.stored.insert(*child, reference);
+            }
+        }
+
+        let reference = self
+            .to
+            .store_tree(&HashedTree::from_with_algorithm(
+                tree.clone(),
+                digest.algorithm(),
+            ))
+            .await
+            .map_err(ReplicationError::Store)?;
+        if reference.digest() != digest {
+            return Err(ReplicationError::HashMismatch(*digest));
+        }
+        self.progress.trees_transferred += 1;
+        self.progress.bytes_transferred += tree.blob().len() as u64;
+        (self.on_progress)(&self.progress);
+        Ok(reference)
+    }
+}
+
+/// Copies the tree `root` and everything reachable from it from one storage to another. Subtrees that already exist in `to`
+/// are assumed to be complete and are neither loaded nor transferred. `on_progress` is called after every tree transferred or skipped.
+pub async fn replicate(
+    root: &BlobDigest,
+    from: &(dyn LoadTree + Send + Sync),
+    to: &(dyn LoadStoreTree + Send + Sync),
+    on_progress: &(dyn Fn(&ReplicationProgress) + Send + Sync),
+) -> Result<(StrongReference, ReplicationProgress), ReplicationError> {
+    let mut replication = Replication {
+        from,
+        to,
+        on_progress,
+        progress: ReplicationProgress::default(),
+        stored: BTreeMap::new(),
+    };
+    let root_exists = to
+        .find_existing_trees(&[*root])
+        .await
+        .map_err(ReplicationError::Load)?;
+    let reference = if root_exists == [true] {
+        debug!("Destination already has root {}", root);
+        replication.progress.subtrees_skipped += 1;
+        on_progress(&replication.progress);
+        // We need a strong reference to return, and loading is the only way to get one.
+        to.load_tree(root)
+            .await
+            .map_err(ReplicationError::Load)?
+            .reference()
+            .clone()
+    } else {
+        replication.copy_tree(root).await?
+    };
+    debug!("Replicated {}: {:?}", root, &replication.progress);
+    Ok((reference, replication.progress))
+}
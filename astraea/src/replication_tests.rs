@@ -0,0 +1,105 @@
+use crate::{
+    in_memory_storage::InMemoryTreeStorage,
+    replication::{replicate, ReplicationError, ReplicationProgress},
+    storage::{LoadError, LoadTree, StoreTree, StrongReference},
+    tree::{HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::{Arc, Mutex};
+
+fn make_tree(content: &'static str, children: Vec<StrongReference>) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from(content)).unwrap(),
+        TreeChildren::try_from(children).unwrap(),
+    )))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_replicate_everything() {
+    let from = InMemoryTreeStorage::empty();
+    let to = InMemoryTreeStorage::empty();
+    let leaf = from.store_tree(&make_tree("leaf", vec![])).await.unwrap();
+    let middle = from
+        .store_tree(&make_tree("middle", vec![leaf.clone()]))
+        .await
+        .unwrap();
+    let root_tree = make_tree("root", vec![middle.clone(), leaf.clone()]);
+    let root = from.store_tree(&root_tree).await.unwrap();
+    let reported = Mutex::new(Vec::new());
+    let (reference, progress) = replicate(root.digest(), &from, &to, &|progress| {
+        reported.lock().unwrap().push(*progress)
+    })
+    .await
+    .unwrap();
+    assert_eq!(root.digest(), reference.digest());
+    assert_eq!(
+        ReplicationProgress {
+            trees_transferred: 3,
+            bytes_transferred: 4 + 6 + 4,
+            subtrees_skipped: 0,
+        },
+        progress
+    );
+    assert_eq!(3, reported.lock().unwrap().len());
+    assert_eq!(3, to.number_of_trees().await);
+    let loaded = to.load_tree(root.digest()).await.unwrap().hash().unwrap();
+    assert_eq!(&root_tree, loaded.hashed_tree());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_replicate_skips_existing_subtrees() {
+    let from = InMemoryTreeStorage::empty();
+    let to = InMemoryTreeStorage::empty();
+    let leaf = from.store_tree(&make_tree("leaf", vec![])).await.unwrap();
+    let middle_tree = make_tree("middle", vec![leaf.clone()]);
+    let middle = from.store_tree(&middle_tree).await.unwrap();
+    let root = from
+        .store_tree(&make_tree("root", vec![middle.clone()]))
+        .await
+        .unwrap();
+    let _leaf_at_destination = to.store_tree(&make_tree("leaf", vec![])).await.unwrap();
+    let _middle_at_destination = to.store_tree(&middle_tree).await.unwrap();
+    // The source doesn't need the skipped subtree anymore, so it must not be loaded.
+    drop(middle);
+    drop(leaf);
+    let (reference, progress) = replicate(root.digest(), &from, &to, &|_| {}).await.unwrap();
+    assert_eq!(root.digest(), reference.digest());
+    assert_eq!(
+        ReplicationProgress {
+            trees_transferred: 1,
+            bytes_transferred: 4,
+            subtrees_skipped: 1,
+        },
+        progress
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_replicate_root_exists() {
+    let from = InMemoryTreeStorage::empty();
+    let to = InMemoryTreeStorage::empty();
+    let tree = make_tree("root", vec![]);
+    let root = to.store_tree(&tree).await.unwrap();
+    let (reference, progress) = replicate(root.digest(), &from, &to, &|_| {}).await.unwrap();
+    assert_eq!(root, reference);
+    assert_eq!(
+        ReplicationProgress {
+            trees_transferred: 0,
+            bytes_transferred: 0,
+            subtrees_skipped: 1,
+        },
+        progress
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_replicate_missing_in_source() {
+    let from = InMemoryTreeStorage::empty();
+    let to = InMemoryTreeStorage::empty();
+    let digest = *make_tree("missing", vec![]).digest();
+    assert_eq!(
+        Err(ReplicationError::Load(LoadError::TreeNotFound(digest))),
+        replicate(&digest, &from, &to, &|_| {}).await
+    );
+}
@@ -0,0 +1,336 @@
+use crate::{
+    blob_codec::{decode_tree_blob, CompressionDictionaries},
+    sqlite_storage::load_compression_dictionaries,
+    storage::StrongReference,
+    tree::{calculate_reference_with, BlobDigest, Tree, TreeBlob, TreeChildren},
+};
+use rusqlite::OptionalExtension;
+use std::collections::BTreeSet;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityProblem {
+    /// The blob could not be decompressed or is too long.
+    UndecodableBlob {
+        tree: BlobDigest,
+        message: String,
+    },
+    /// The content of the tree doesn't hash to the digest it is stored under.
+    DigestMismatch {
+        tree: BlobDigest,
+        actual: BlobDigest,
+    },
+    /// The child indices of the tree are not exactly 0, 1, 2, ...
+    NonContiguousChildren {
+        tree: BlobDigest,
+        indices: Vec<i64>,
+    },
+    TooManyChildren {
+        tree: BlobDigest,
+        count: usize,
+    },
+    /// A `reference` row points to a tree that doesn't exist.
+    MissingChild {
+        tree: BlobDigest,
+        child: BlobDigest,
+    },
+    /// A `root` row points to a tree that doesn't exist.
+    MissingRootTarget {
+        root: String,
+        target: BlobDigest,
+    },
+}
+
+impl IntegrityProblem {
+    /// The tree that has to be quarantined to fix this problem, if any.
+    pub fn broken_tree(&self) -> Option<&BlobDigest> {
+        match self {
+            IntegrityProblem::UndecodableBlob { tree, .. } => Some(tree),
+            IntegrityProblem::DigestMismatch { tree, .. } => Some(tree),
+            IntegrityProblem::NonContiguousChildren { tree, .. } => Some(tree),
+            IntegrityProblem::TooManyChildren { tree, .. } => Some(tree),
+            IntegrityProblem::MissingChild { tree, .. } => Some(tree),
+            IntegrityProblem::MissingRootTarget { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IntegrityReport {
+    pub trees_checked: u64,
+    pub references_checked: u64,
+    pub roots_checked: u64,
+    pub problems: Vec<IntegrityProblem>,
+    /// Trees that were moved into the `quarantined_tree` table by the repair mode.
+    pub quarantined: Vec<BlobDigest>,
+}
+
+impl IntegrityReport {
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+fn tree_exists(connection: &rusqlite::Connection, digest: &BlobDigest) -> rusqlite::Result<bool> {
+    let mut statement = connection.prepare_cached("SELECT 1 FROM tree WHERE digest = ?1")?;
+    Ok(statement
+        .query_row((digest,), |_row| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+fn check_tree(
+    connection: &rusqlite::Connection,
+    id: i64,
+    digest: &BlobDigest,
+    decoded_blob: Result<Vec<u8>, String>,
+    report: &mut IntegrityReport,
+) -> rusqlite::Result<()> {
+    let children: Vec<(i64, BlobDigest)> = {
+        let mut statement = connection.prepare_cached(
+            "SELECT zero_based_index, target FROM reference WHERE origin = ?1 ORDER BY zero_based_index ASC",
+        )?;
+        let rows = statement.query_map((id,), |row| {
+            let index: i64 = row.get(0)?;
+            let target: BlobDigest = row.get(1)?;
+            Ok((index, target))
+        })?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    report.references_checked += children.len() as u64;
+
+    let mut is_structurally_sound = true;
+    if children
+        .iter()
+        .enumerate()
+        .any(|(expected, (actual, _))| expected as i64 != *actual)
+    {
+        report
+            .problems
+            .push(IntegrityProblem::NonContiguousChildren {
+                tree: *digest,
+                indices: children.iter().map(|(index, _)| *index).collect(),
+            });
+        is_structurally_sound = false;
+    }
+    for (_, child) in &children {
+        if !tree_exists(connection, child)? {
+            report.problems.push(IntegrityProblem::MissingChild {
+                tree: *digest,
+                child: *child,
+            });
+        }
+    }
+
+    let blob = match decoded_blob.and_then(|data| {
+        TreeBlob::try_from(data.into()).map_err(|error| format!("Invalid tree blob: {error}"))
+    }) {
+        Ok(blob) => blob,
+        Err(message) => {
+            report.problems.push(IntegrityProblem::UndecodableBlob {
+                tree: *digest,
+                message,
+            });
+            return Ok(());
+        }
+    };
+    let child_count = children.len();
+    let children = match TreeChildren::try_from(
+        children
+            .into_iter()
+            .map(|(_, child)| StrongReference::from_weak(child))
+            .collect(),
+    ) {
+        Some(children) => children,
+        None => {
+            report.problems.push(IntegrityProblem::TooManyChildren {
+                tree: *digest,
+                count: child_count,
+            });
+            return Ok(());
+        }
+    };
+    if is_structurally_sound {
+        let actual = calculate_reference_with(&Tree::new(blob, children), digest.algorithm());
+        if &actual != digest {
+            report.problems.push(IntegrityProblem::DigestMismatch {
+                tree: *digest,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn check_all_trees(
+    connection: &rusqlite::Connection,
+    dictionaries: &CompressionDictionaries,
+    report: &mut IntegrityReport,
+) -> rusqlite::Result<()> {
+    let mut statement = connection
+        .prepare("SELECT id, digest, tree_blob, codec, dictionary FROM tree ORDER BY id")?;
+    let mut rows = statement.query(())?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        let digest: BlobDigest = row.get(1)?;
+        let tree_blob_raw: Vec<u8> = row.get(2)?;
+        let codec: i64 = row.get(3)?;
+        let dictionary: Option<i64> = row.get(4)?;
+        let decoded_blob = decode_tree_blob(tree_blob_raw, codec, dictionary, dictionaries);
+        check_tree(connection, id, &digest, decoded_blob, report)?;
+        report.trees_checked += 1;
+    }
+    Ok(())
+}
+
+fn check_roots(
+    connection: &rusqlite::Connection,
+    report: &mut IntegrityReport,
+) -> rusqlite::Result<()> {
+    let mut statement = connection.prepare(
+        "SELECT root.name, root.target FROM root WHERE NOT EXISTS (SELECT 1 FROM tree WHERE tree.digest = root.target)",
+    )?;
+    let missing: Vec<(String, BlobDigest)> = statement
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    for (root, target) in missing {
+        report
+            .problems
+            .push(IntegrityProblem::MissingRootTarget { root, target });
+    }
+    report.roots_checked =
+        connection.query_row("SELECT COUNT(*) FROM root", (), |row| row.get::<_, i64>(0))? as u64;
+    Ok(())
+}
+
+/// Finds trees that refer to children which don't exist (anymore), for example because we just quarantined them.
+fn find_trees_with_missing_children(
+    connection: &rusqlite::Connection,
+) -> rusqlite::Result<Vec<(BlobDigest, BlobDigest)>> {
+    let mut statement = connection.prepare(
+        "SELECT tree.digest, reference.target FROM reference, tree
+        WHERE reference.origin = tree.id
+        AND NOT EXISTS (SELECT 1 FROM tree AS child WHERE child.digest = reference.target)",
+    )?;
+    let result = statement
+        .query_map((), |row| {
+            let tree: BlobDigest = row.get(0)?;
+            let child: BlobDigest = row.get(1)?;
+            Ok((tree, child))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(result)
+}
+
+fn quarantine_tree(
+    connection: &rusqlite::Connection,
+    digest: &BlobDigest,
+    reason: &str,
+) -> rusqlite::Result<()> {
+    let children: Vec<u8> = {
+        let mut statement = connection.prepare_cached(
+            "SELECT reference.target FROM reference, tree WHERE reference.origin = tree.id AND tree.digest = ?1 ORDER BY reference.zero_based_index ASC",
+        )?;
+        let targets = statement
+            .query_map((digest,), |row| row.get::<_, Vec<u8>>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        targets.concat()
+    };
+    connection.execute(
+        "INSERT INTO quarantined_tree (digest, tree_blob, codec, dictionary, children, reason)
+        SELECT digest, tree_blob, codec, dictionary, ?2, ?3 FROM tree WHERE digest = ?1",
+        (digest, &children, reason),
+    )?;
+    // The references of the tree are deleted by ON DELETE CASCADE.
+    connection.execute("DELETE FROM tree WHERE digest = ?1", (digest,))?;
+    warn!("Quarantined tree {}: {}", digest, reason);
+    Ok(())
+}
+
+/// Part of [crate::sqlite_storage::SQLiteStorage] upgrading an old database. Must run inside of its transaction.
+pub(crate) fn upgrade_quarantine(
+    connection: &rusqlite::Connection,
+    tag_digests: bool,
+) -> rusqlite::Result<()> {
+    let table: Option<String> = connection
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'quarantined_tree'",
+            (),
+            |row| row.get(0),
+        )
+        .optional()?;
+    let Some(table) = table else {
+        return Ok(());
+    };
+    if table.contains("is_compressed") {
+        connection.execute_batch(
+            "ALTER TABLE quarantined_tree RENAME COLUMN is_compressed TO codec;
+            ALTER TABLE quarantined_tree ADD COLUMN dictionary INTEGER;",
+        )?;
+    }
+    if !tag_digests {
+        return Ok(());
+    }
+    let rows: Vec<(i64, Vec<u8>)> = connection
+        .prepare("SELECT id, children FROM quarantined_tree")?
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    for (id, untagged_children) in rows {
+        let children: Vec<u8> = untagged_children
+            .chunks_exact(64)
+            .flat_map(|child| BlobDigest::new(child.try_into().unwrap()).to_tagged_bytes())
+            .collect();
+        connection.execute(
+            "UPDATE quarantined_tree SET digest = CAST(x'14' || digest AS BLOB), children = ?2 WHERE id = ?1",
+            (id, &children),
+        )?;
+    }
+    Ok(())
+}
+
+/// Scans the whole database. With `repair`, broken trees are moved into the `quarantined_tree` table.
+/// Quarantining a tree breaks its parents, so they are quarantined too until no tree refers to a missing child anymore.
+/// Roots are never modified.
+pub(crate) fn check_integrity(
+    connection: &rusqlite::Connection,
+    repair: bool,
+) -> rusqlite::Result<IntegrityReport> {
+    let mut report = IntegrityReport::default();
+    let dictionaries = load_compression_dictionaries(connection)?;
+    check_all_trees(connection, &dictionaries, &mut report)?;
+    if repair {
+        let mut quarantined: BTreeSet<BlobDigest> = BTreeSet::new();
+        for problem in &report.problems {
+            if let Some(tree) = problem.broken_tree() {
+                if quarantined.insert(*tree) {
+                    quarantine_tree(connection, tree, &format!("{problem:?}"))?;
+                    report.quarantined.push(*tree);
+                }
+            }
+        }
+        loop {
+            let newly_broken = find_trees_with_missing_children(connection)?;
+            if newly_broken.is_empty() {
+                break;
+            }
+            for (tree, child) in newly_broken {
+                let problem = IntegrityProblem::MissingChild { tree, child };
+                if quarantined.insert(tree) {
+                    quarantine_tree(connection, &tree, &format!("{problem:?}"))?;
+                    report.quarantined.push(tree);
+                }
+                report.problems.push(problem);
+            }
+        }
+    }
+    check_roots(connection, &mut report)?;
+    info!(
+        "Integrity check: {} trees, {} references, {} roots, {} problems, {} trees quarantined",
+        report.trees_checked,
+        report.references_checked,
+        report.roots_checked,
+        report.problems.len(),
+        report.quarantined.len()
+    );
+    Ok(report)
+}
@@ -0,0 +1,264 @@
+use crate::{
+    sqlite_integrity::{IntegrityProblem, IntegrityReport},
+    sqlite_storage::SQLiteStorage,
+    storage::{CommitChanges, LoadError, LoadTree, StoreTree, StrongReference, UpdateRoot},
+    tree::{calculate_reference, BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::{path::Path, sync::Arc};
+
+fn open_storage(database_path: &Path) -> SQLiteStorage {
+    let connection = rusqlite::Connection::open(database_path).unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    SQLiteStorage::from(connection).unwrap()
+}
+
+async fn store_leaf(storage: &SQLiteStorage, content: &'static [u8]) -> StrongReference {
+    storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(Bytes::from_static(content)).unwrap(),
+            TreeChildren::empty(),
+        ))))
+        .await
+        .unwrap()
+}
+
+async fn store_parent(storage: &SQLiteStorage, children: Vec<StrongReference>) -> StrongReference {
+    storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(Bytes::from_static(b"parent")).unwrap(),
+            TreeChildren::try_from(children).unwrap(),
+        ))))
+        .await
+        .unwrap()
+}
+
+/// Modifies the database behind the back of the storage like a disk error or a buggy tool would.
+fn corrupt(database_path: &Path, sql: &str, digest: &BlobDigest) {
+    let connection = rusqlite::Connection::open(database_path).unwrap();
+    let digest_array = digest.to_tagged_bytes();
+    assert_eq!(1, connection.execute(sql, (&digest_array,)).unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_check_integrity_empty() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    assert_eq!(
+        IntegrityReport::default(),
+        storage.check_integrity(false).await.unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_check_integrity_healthy() {
+    let workspace = tempfile::tempdir().unwrap();
+    let storage = open_storage(&workspace.path().join("database.sqlite"));
+    let leaf_1 = store_leaf(&storage, b"leaf 1").await;
+    let leaf_2 = store_leaf(&storage, b"leaf 2").await;
+    let parent = store_parent(&storage, vec![leaf_1, leaf_2]).await;
+    storage.update_root("test", &parent).await.unwrap();
+    storage.commit_changes().await.unwrap();
+    let report = storage.check_integrity(false).await.unwrap();
+    assert!(report.is_healthy());
+    assert_eq!(
+        IntegrityReport {
+            trees_checked: 3,
+            references_checked: 2,
+            roots_checked: 1,
+            problems: vec![],
+            quarantined: vec![],
+        },
+        report
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_check_integrity_modified_blob() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let storage = open_storage(&database_path);
+    let leaf = store_leaf(&storage, b"leaf").await;
+    storage.commit_changes().await.unwrap();
+    corrupt(
+        &database_path,
+        "UPDATE tree SET tree_blob = x'0102', codec = 0 WHERE digest = ?1",
+        leaf.digest(),
+    );
+    let report = storage.check_integrity(false).await.unwrap();
+    let expected_actual = calculate_reference(&Tree::new(
+        TreeBlob::try_from(Bytes::from_static(&[1, 2])).unwrap(),
+        TreeChildren::empty(),
+    ));
+    assert_eq!(
+        vec![IntegrityProblem::DigestMismatch {
+            tree: *leaf.digest(),
+            actual: expected_actual,
+        }],
+        report.problems
+    );
+    // Checking without repair doesn't change anything.
+    assert_eq!(report, storage.check_integrity(false).await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_check_integrity_undecodable_blob() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let storage = open_storage(&database_path);
+    let leaf = store_leaf(&storage, b"leaf").await;
+    storage.commit_changes().await.unwrap();
+    corrupt(
+        &database_path,
+        "UPDATE tree SET tree_blob = x'00010203040506070809', codec = 1 WHERE digest = ?1",
+        leaf.digest(),
+    );
+    let report = storage.check_integrity(false).await.unwrap();
+    assert_eq!(
+        vec![IntegrityProblem::UndecodableBlob {
+            tree: *leaf.digest(),
+            message: "Failed to decompress tree blob using lz4: OffsetOutOfBounds".to_string(),
+        }],
+        report.problems
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_check_integrity_non_contiguous_children() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let storage = open_storage(&database_path);
+    let leaf_1 = store_leaf(&storage, b"leaf 1").await;
+    let leaf_2 = store_leaf(&storage, b"leaf 2").await;
+    let parent = store_parent(&storage, vec![leaf_1, leaf_2.clone()]).await;
+    storage.commit_changes().await.unwrap();
+    corrupt(
+        &database_path,
+        "UPDATE reference SET zero_based_index = 5 WHERE target = ?1",
+        leaf_2.digest(),
+    );
+    let report = storage.check_integrity(false).await.unwrap();
+    assert_eq!(
+        vec![IntegrityProblem::NonContiguousChildren {
+            tree: *parent.digest(),
+            indices: vec![0, 5],
+        }],
+        report.problems
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_check_integrity_missing_child_and_root_target() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let storage = open_storage(&database_path);
+    let leaf = store_leaf(&storage, b"leaf").await;
+    let parent = store_parent(&storage, vec![leaf.clone()]).await;
+    storage.update_root("leaf", &leaf).await.unwrap();
+    storage.commit_changes().await.unwrap();
+    corrupt(
+        &database_path,
+        "DELETE FROM tree WHERE digest = ?1",
+        leaf.digest(),
+    );
+    let report = storage.check_integrity(false).await.unwrap();
+    assert_eq!(
+        IntegrityReport {
+            trees_checked: 1,
+            references_checked: 1,
+            roots_checked: 1,
+            problems: vec![
+                IntegrityProblem::MissingChild {
+                    tree: *parent.digest(),
+                    child: *leaf.digest(),
+                },
+                IntegrityProblem::MissingRootTarget {
+                    root: "leaf".to_string(),
+                    target: *leaf.digest(),
+                },
+            ],
+            quarantined: vec![],
+        },
+        report
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_check_integrity_repair_quarantines_ancestors() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let storage = open_storage(&database_path);
+    let broken_leaf = store_leaf(&storage, b"broken").await;
+    let healthy_leaf = store_leaf(&storage, b"healthy").await;
+    let parent = store_parent(&storage, vec![broken_leaf.clone()]).await;
+    let grandparent = store_parent(&storage, vec![parent.clone(), healthy_leaf.clone()]).await;
+    storage.update_root("test", &grandparent).await.unwrap();
+    storage.commit_changes().await.unwrap();
+    corrupt(
+        &database_path,
+        "UPDATE tree SET tree_blob = x'0102', codec = 0 WHERE digest = ?1",
+        broken_leaf.digest(),
+    );
+
+    let report = storage.check_integrity(true).await.unwrap();
+    assert_eq!(
+        vec![
+            *broken_leaf.digest(),
+            *parent.digest(),
+            *grandparent.digest()
+        ],
+        report.quarantined
+    );
+    assert_eq!(
+        Some(&IntegrityProblem::MissingRootTarget {
+            root: "test".to_string(),
+            target: *grandparent.digest(),
+        }),
+        report.problems.last()
+    );
+    assert_eq!(3, storage.commit_changes().await.unwrap());
+
+    assert_eq!(
+        LoadError::TreeNotFound(*broken_leaf.digest()),
+        storage.load_tree(broken_leaf.digest()).await.unwrap_err()
+    );
+    assert!(storage.load_tree(healthy_leaf.digest()).await.is_ok());
+
+    // Only the dangling root remains.
+    let report = storage.check_integrity(false).await.unwrap();
+    assert_eq!(
+        IntegrityReport {
+            trees_checked: 1,
+            references_checked: 0,
+            roots_checked: 1,
+            problems: vec![IntegrityProblem::MissingRootTarget {
+                root: "test".to_string(),
+                target: *grandparent.digest(),
+            }],
+            quarantined: vec![],
+        },
+        report
+    );
+
+    {
+        let connection = rusqlite::Connection::open(&database_path).unwrap();
+        let quarantined_count: i64 = connection
+            .query_row("SELECT COUNT(*) FROM quarantined_tree", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(3, quarantined_count);
+    }
+
+    // The broken tree can be stored again with its correct content.
+    let restored = store_leaf(&storage, b"broken").await;
+    assert_eq!(broken_leaf.digest(), restored.digest());
+    assert!(storage
+        .load_tree(restored.digest())
+        .await
+        .unwrap()
+        .hash()
+        .is_some());
+}
@@ -1,21 +1,32 @@
 use crate::{
+    blob_codec::{
+        decode_tree_blob, encode_tree_blob, train_dictionary, BlobCodec, CompressionDictionaries,
+        EncodedBlob,
+    },
     delayed_hashed_tree::DelayedHashedTree,
+    prometheus_text::PrometheusText,
+    sqlite_integrity::{check_integrity, upgrade_quarantine, IntegrityReport},
     storage::{
-        CollectGarbage, CommitChanges, GarbageCollectionStats, LoadError, LoadRoot, LoadStoreTree,
-        LoadTree, StoreError, StoreTree, StrongDelayedHashedTree, StrongReference,
-        StrongReferenceTrait, UpdateRoot,
+        root_change_stream, CollectGarbage, CommitChanges, CompareAndSwapResult,
+        GarbageCollectionStats, LoadError, LoadRoot, LoadStoreTree, LoadTree, MeasureStoredSize,
+        Pin, PinTrees, ReplaceTree, RootChange, RootChangeStream, StoreError, StoreTree,
+        StrongDelayedHashedTree, StrongReference, StrongReferenceTrait, UpdateRoot, WatchRoots,
+        ROOT_CHANGE_CHANNEL_CAPACITY,
+    },
+    tree::{
+        BlobDigest, DigestAlgorithm, HashedTree, Tree, TreeBlob, TreeChildren,
+        TREE_BLOB_MAX_LENGTH, TREE_MAX_CHILDREN,
     },
-    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren, TREE_BLOB_MAX_LENGTH},
 };
 use async_trait::async_trait;
 use pretty_assertions::assert_eq;
 use rusqlite::OptionalExtension;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     sync::{Arc, Weak},
 };
 use tokio::sync::Mutex;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 
 #[derive(Debug)]
 struct TransactionStats {
@@ -27,43 +38,257 @@ struct SQLiteStrongReferenceImpl {}
 
 impl StrongReferenceTrait for SQLiteStrongReferenceImpl {}
 
-#[derive(Debug)]
-struct GarbageCollector {
-    additional_roots: BTreeMap<BlobDigest, (i64, Weak<SQLiteStrongReferenceImpl>)>,
-    last_gc_additional_roots_len: usize,
-    has_gc_new_tree_table: bool,
+pub type Clock = Arc<dyn Fn() -> std::time::SystemTime + Send + Sync>;
+
+#[derive(Clone)]
+pub struct SQLiteStorageConfiguration {
+    /// Every update of a root is recorded in the `root_history` table. The garbage collector keeps the targets of all entries that
+    /// were still current at some point within this window before now. Older entries are deleted.
+    /// With zero, only the current target of each root is kept, like without history.
+    pub root_history_retention: std::time::Duration,
+    /// Source of the timestamps of the root history.
+    pub clock: Clock,
+    /// Used for new trees and by [SQLiteStorage::recompress_trees].
+    pub codec: BlobCodec,
+    /// Number of additional read-only connections for [LoadTree], [LoadRoot] and [LoadTree::approximate_tree_count].
+    /// Trees and roots that the writer changed in its open transaction are still loaded by the writer, so callers always see
+    /// their own writes.
+    /// In-memory databases can't have readers.
+    pub reader_connections: usize,
+    /// Returned by [StoreTree::digest_algorithm], so this is what new trees for this storage get hashed with. Trees with
+    /// digests of any algorithm can be stored regardless.
+    pub digest_algorithm: DigestAlgorithm,
 }
 
-impl GarbageCollector {
-    fn new() -> Self {
+impl Default for SQLiteStorageConfiguration {
+    fn default() -> Self {
         Self {
-            additional_roots: BTreeMap::new(),
-            last_gc_additional_roots_len: 0,
-            has_gc_new_tree_table: false,
+            root_history_retention: std::time::Duration::ZERO,
+            clock: Arc::new(std::time::SystemTime::now),
+            codec: BlobCodec::Lz4,
+            reader_connections: 0,
+            digest_algorithm: DigestAlgorithm::Sha3_512,
         }
     }
+}
 
-    fn require_additional_root(
-        &mut self,
-        root: &BlobDigest,
-        root_tree_id: i64,
-        connection: &rusqlite::Connection,
-    ) -> rusqlite::Result<StrongReference> {
-        let result = self.require_additional_root_entry(root, root_tree_id)?;
-        self.check_automatic_collection(connection)?;
-        Ok(result)
+impl std::fmt::Debug for SQLiteStorageConfiguration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SQLiteStorageConfiguration")
+            .field("root_history_retention", &self.root_history_retention)
+            .field("codec", &self.codec)
+            .field("reader_connections", &self.reader_connections)
+            .field("digest_algorithm", &self.digest_algorithm)
+            .finish_non_exhaustive()
     }
+}
 
-    fn require_additional_root_entry(
-        &mut self,
-        root: &BlobDigest,
-        root_tree_id: i64,
-    ) -> rusqlite::Result<StrongReference> {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootHistoryEntry {
+    pub target: BlobDigest,
+    pub updated_at: std::time::SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecompressionStats {
+    pub trees_examined: u64,
+    pub trees_recompressed: u64,
+    /// Total size of the stored blobs before and after recompression.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Counted since the storage was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SQLiteStorageStats {
+    pub commits: u64,
+    /// Sum of the writes of all committed transactions.
+    pub writes_committed: u64,
+    /// Runs of the simple garbage collection, including the automatic ones.
+    pub garbage_collections: u64,
+    pub trees_collected: u64,
+    pub mark_and_sweep_cycles_finished: u64,
+    pub trees_swept: u64,
+}
+
+impl SQLiteStorageStats {
+    pub fn write_prometheus(&self, text: &mut PrometheusText) {
+        let counters = [
+            (
+                "astraea_sqlite_commits_total",
+                "Committed transactions.",
+                self.commits,
+            ),
+            (
+                "astraea_sqlite_writes_committed_total",
+                "Writes in committed transactions.",
+                self.writes_committed,
+            ),
+            (
+                "astraea_sqlite_garbage_collections_total",
+                "Runs of the simple garbage collection.",
+                self.garbage_collections,
+            ),
+            (
+                "astraea_sqlite_trees_collected_total",
+                "Trees deleted by the simple garbage collection.",
+                self.trees_collected,
+            ),
+            (
+                "astraea_sqlite_mark_and_sweep_cycles_total",
+                "Finished mark-and-sweep cycles.",
+                self.mark_and_sweep_cycles_finished,
+            ),
+            (
+                "astraea_sqlite_trees_swept_total",
+                "Trees deleted by mark-and-sweep.",
+                self.trees_swept,
+            ),
+        ];
+        for (name, help, value) in counters {
+            text.counter(name, help, &[(&[], value)]);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DictionaryTrainingError {
+    Store(StoreError),
+    /// zstd couldn't train a dictionary, for example because there were too few samples.
+    Training(String),
+}
+
+impl std::fmt::Display for DictionaryTrainingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for DictionaryTrainingError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkAndSweepPhase {
+    /// Following the references from the roots and the live [StrongReference]s.
+    Marking,
+    /// Deleting every tree that wasn't marked.
+    Sweeping,
+    /// The cycle is complete. The next call to [SQLiteStorage::mark_and_sweep] starts a new one.
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarkAndSweepProgress {
+    pub phase: MarkAndSweepPhase,
+    /// Trees known to be reachable so far in the current cycle.
+    pub trees_marked: u64,
+    /// Reachable trees whose children haven't been looked at yet.
+    pub trees_pending: u64,
+    /// Trees deleted so far in the current cycle.
+    pub trees_swept: u64,
+}
+
+/// Limits how much work a single call to [SQLiteStorage::mark_and_sweep] does. Without any limit, the whole cycle runs at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MarkAndSweepBudget {
+    /// Checked after every tree, so a call can take a little longer than this.
+    pub max_duration: Option<std::time::Duration>,
+    /// Number of trees marked or deleted.
+    pub max_trees: Option<u64>,
+}
+
+impl MarkAndSweepBudget {
+    fn is_exhausted(&self, started: std::time::Instant, trees: u64) -> bool {
+        // At least one tree per call, so that every call makes progress.
+        if trees == 0 {
+            return false;
+        }
+        self.max_trees.is_some_and(|max_trees| trees >= max_trees)
+            || self
+                .max_duration
+                .is_some_and(|max_duration| started.elapsed() >= max_duration)
+    }
+}
+
+/// The version of the schema that [SQLiteStorage::create_schema] creates. It is stored in `PRAGMA user_version`.
+pub const SCHEMA_VERSION: i32 = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum OpenError {
+    Rusqlite(rusqlite::Error),
+    /// The database was created by a newer version of this library, so we can't know what changed.
+    UnsupportedSchemaVersion(i32),
+}
+
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<rusqlite::Error> for OpenError {
+    fn from(error: rusqlite::Error) -> Self {
+        OpenError::Rusqlite(error)
+    }
+}
+
+/// The root history stores times as microseconds since the UNIX epoch.
+fn to_unix_micros(time: std::time::SystemTime) -> i64 {
+    match time.duration_since(std::time::UNIX_EPOCH) {
+        Ok(after) => i64::try_from(after.as_micros()).unwrap_or(i64::MAX),
+        Err(before) => -i64::try_from(before.duration().as_micros()).unwrap_or(i64::MAX),
+    }
+}
+
+fn from_unix_micros(micros: i64) -> std::time::SystemTime {
+    let magnitude = std::time::Duration::from_micros(micros.unsigned_abs());
+    if micros >= 0 {
+        std::time::UNIX_EPOCH + magnitude
+    } else {
+        std::time::UNIX_EPOCH - magnitude
+    }
+}
+
+#[derive(Debug)]
+struct MarkAndSweepCycle {
+    phase: MarkAndSweepPhase,
+    trees_marked: u64,
+    trees_swept: u64,
+    /// Trees are deleted in the order of their IDs.
+    last_swept_id: i64,
+}
+
+/// The part of the state that the readers need. Unlike [SQLiteState], it is only ever locked for a moment.
+#[derive(Debug)]
+struct SharedState {
+    additional_roots: BTreeMap<BlobDigest, (i64, Weak<SQLiteStrongReferenceImpl>)>,
+    /// Trees that got a [StrongReference] since the last step of the current mark-and-sweep cycle, None without a cycle.
+    /// They are marked before anything else is deleted because they may reference trees that looked unreachable so far.
+    newly_referenced: Option<BTreeSet<i64>>,
+    /// Odd while trees are being deleted. A reader only hands out [StrongReference]s if this didn't change while it was
+    /// reading, otherwise the trees it found may be gone already.
+    deletion_sequence: u64,
+    /// Trees the writer stored in its open transaction. The readers don't see them yet.
+    uncommitted_trees: BTreeSet<BlobDigest>,
+    /// Roots the writer updated in its open transaction. The readers still see the old targets.
+    uncommitted_roots: BTreeSet<String>,
+    /// Trees may have been deleted in the open transaction, so the readers may still see trees that are gone for the writer.
+    has_uncommitted_deletions: bool,
+    dictionaries: Arc<CompressionDictionaries>,
+    stats: SQLiteStorageStats,
+}
+
+impl SharedState {
+    fn require_additional_root(&mut self, root: &BlobDigest, root_tree_id: i64) -> StrongReference {
+        if let Some(newly_referenced) = &mut self.newly_referenced {
+            newly_referenced.insert(root_tree_id);
+        }
         match self.additional_roots.entry(*root) {
             std::collections::btree_map::Entry::Vacant(vacant_entry) => {
                 let reference_counter = Arc::new(SQLiteStrongReferenceImpl {});
                 vacant_entry.insert((root_tree_id, Arc::downgrade(&reference_counter)));
-                Ok(StrongReference::new(Some(reference_counter), *root))
+                StrongReference::new(Some(reference_counter), *root)
             }
             std::collections::btree_map::Entry::Occupied(mut occupied_entry) => {
                 match occupied_entry.get().1.upgrade() {
@@ -72,23 +297,138 @@ impl GarbageCollector {
                         if existing_tree_id != root_tree_id {
                             unreachable!("Inconsistency detected: The same root digest {} is associated with multiple tree IDs: existing tree ID {}, new tree ID {}", root, existing_tree_id, root_tree_id);
                         }
-                        Ok(StrongReference::new(Some(reference_counter), *root))
+                        StrongReference::new(Some(reference_counter), *root)
                     }
                     None => {
                         let reference_counter = Arc::new(SQLiteStrongReferenceImpl {});
                         occupied_entry.insert((root_tree_id, Arc::downgrade(&reference_counter)));
-                        Ok(StrongReference::new(Some(reference_counter), *root))
+                        StrongReference::new(Some(reference_counter), *root)
                     }
                 }
             }
         }
     }
 
+    /// The tree IDs of the additional roots that still have [StrongReference]s.
+    fn live_additional_roots(&mut self) -> Vec<i64> {
+        // All StrongReferences of the other entries have been dropped, so they don't protect their trees anymore.
+        self.additional_roots
+            .retain(|_, (_, reference_counter)| reference_counter.upgrade().is_some());
+        self.additional_roots
+            .values()
+            .map(|(tree_id, _)| *tree_id)
+            .collect()
+    }
+}
+
+/// Keeps [SharedState::deletion_sequence] odd while it exists.
+struct DeletionGuard {
+    shared: Arc<std::sync::Mutex<SharedState>>,
+}
+
+impl DeletionGuard {
+    fn new(shared: &Arc<std::sync::Mutex<SharedState>>) -> Self {
+        {
+            let mut shared_locked = shared.lock().unwrap();
+            shared_locked.deletion_sequence += 1;
+            shared_locked.has_uncommitted_deletions = true;
+        }
+        Self {
+            shared: shared.clone(),
+        }
+    }
+}
+
+impl Drop for DeletionGuard {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().deletion_sequence += 1;
+    }
+}
+
+#[derive(Debug)]
+struct GarbageCollector {
+    shared: Arc<std::sync::Mutex<SharedState>>,
+    last_gc_additional_roots_len: usize,
+    has_gc_new_tree_table: bool,
+    has_mark_and_sweep_tables: bool,
+    mark_and_sweep: Option<MarkAndSweepCycle>,
+    configuration: SQLiteStorageConfiguration,
+}
+
+impl GarbageCollector {
+    fn new(
+        configuration: SQLiteStorageConfiguration,
+        shared: Arc<std::sync::Mutex<SharedState>>,
+    ) -> Self {
+        Self {
+            shared,
+            last_gc_additional_roots_len: 0,
+            has_gc_new_tree_table: false,
+            has_mark_and_sweep_tables: false,
+            mark_and_sweep: None,
+            configuration,
+        }
+    }
+
+    /// Deletes the history entries that had already been replaced by a newer entry when the retention window began.
+    fn prune_root_history(&self, connection: &rusqlite::Connection) -> rusqlite::Result<usize> {
+        let now = (self.configuration.clock)();
+        let cutoff = now
+            .checked_sub(self.configuration.root_history_retention)
+            .unwrap_or(std::time::UNIX_EPOCH);
+        let mut statement = connection.prepare_cached(
+            "DELETE FROM root_history
+            WHERE EXISTS (
+                SELECT 1 FROM root_history AS newer
+                WHERE newer.name = root_history.name
+                AND newer.updated_at <= ?1
+                AND (newer.updated_at > root_history.updated_at
+                    OR (newer.updated_at = root_history.updated_at AND newer.id > root_history.id))
+            )",
+        )?;
+        statement.execute((to_unix_micros(cutoff),))
+    }
+
+    fn delete_expired_pins(&self, connection: &rusqlite::Connection) -> rusqlite::Result<usize> {
+        let now = to_unix_micros((self.configuration.clock)());
+        connection
+            .prepare_cached("DELETE FROM pin WHERE expires_at <= ?1")?
+            .execute((now,))
+    }
+
+    fn require_additional_root(
+        &mut self,
+        root: &BlobDigest,
+        root_tree_id: i64,
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<StrongReference> {
+        let result = self.require_additional_root_entry(root, root_tree_id);
+        self.check_automatic_collection(connection)?;
+        Ok(result)
+    }
+
+    fn require_additional_root_entry(
+        &mut self,
+        root: &BlobDigest,
+        root_tree_id: i64,
+    ) -> StrongReference {
+        self.shared
+            .lock()
+            .unwrap()
+            .require_additional_root(root, root_tree_id)
+    }
+
+    /// Must be called when a tree disappears from the database outside of garbage collection, otherwise the digest
+    /// could be associated with a different tree ID when it is stored again.
+    fn forget(&mut self, root: &BlobDigest) {
+        self.shared.lock().unwrap().additional_roots.remove(root);
+    }
+
     fn check_automatic_collection(
         &mut self,
         connection: &rusqlite::Connection,
     ) -> rusqlite::Result<()> {
-        let additional_roots_len = self.additional_roots.len();
+        let additional_roots_len = self.shared.lock().unwrap().additional_roots.len();
         // Not sure what's a good minimum here.
         let minimum_additional_roots_len_for_gc = 100;
         if (additional_roots_len >= minimum_additional_roots_len_for_gc)
@@ -100,7 +440,7 @@ impl GarbageCollector {
                 "Automatic garbage collection collected {} trees",
                 stats.trees_collected
             );
-            self.last_gc_additional_roots_len = self.additional_roots.len();
+            self.last_gc_additional_roots_len = self.shared.lock().unwrap().additional_roots.len();
         }
         Ok(())
     }
@@ -130,28 +470,24 @@ impl GarbageCollector {
         &mut self,
         connection: &rusqlite::Connection,
     ) -> rusqlite::Result<GarbageCollectionStats> {
+        let _deletion = DeletionGuard::new(&self.shared);
         self.require_gc_new_tree_table(connection)?;
         connection.execute("DELETE FROM gc_new_tree", ())?;
         {
             let mut statement = connection
                 .prepare_cached("INSERT OR IGNORE INTO gc_new_tree (tree_id) VALUES (?1)")?;
-            let mut sql_error: Option<rusqlite::Error> = None;
-            self.additional_roots
-                .retain(|_, (tree_id, reference_counter)| {
-                    if reference_counter.upgrade().is_none() {
-                        // All StrongReferences have been dropped, so we can remove this additional root
-                        // and not consider the tree it pointed to as a root for GC purposes anymore
-                        return false;
-                    }
-                    if let Err(err) = statement.execute((*tree_id,)) {
-                        sql_error = Some(err);
-                    }
-                    true
-                });
-            if let Some(err) = sql_error {
-                return Err(err);
+            let live_additional_roots = self.shared.lock().unwrap().live_additional_roots();
+            for tree_id in live_additional_roots {
+                statement.execute((tree_id,))?;
             }
         }
+        let pruned_history = self.prune_root_history(connection)?;
+        debug!(
+            "Garbage collection pruned {} root history entries",
+            pruned_history
+        );
+        let expired_pins = self.delete_expired_pins(connection)?;
+        debug!("Garbage collection deleted {} expired pins", expired_pins);
         let deleted_trees = connection.execute(
             "DELETE FROM tree
         WHERE NOT EXISTS (
@@ -165,6 +501,14 @@ impl GarbageCollector {
         AND NOT EXISTS (
             SELECT 1 FROM root
             WHERE root.target = tree.digest
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM root_history
+            WHERE root_history.target = tree.digest
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM pin
+            WHERE pin.target = tree.digest
         );",
             (),
         )?;
@@ -172,11 +516,226 @@ impl GarbageCollector {
             "Garbage collection deleted {} unreferenced trees",
             deleted_trees
         );
-        self.last_gc_additional_roots_len = self.additional_roots.len();
+        {
+            let mut shared = self.shared.lock().unwrap();
+            self.last_gc_additional_roots_len = shared.additional_roots.len();
+            shared.stats.garbage_collections += 1;
+            shared.stats.trees_collected += deleted_trees as u64;
+        }
         Ok(GarbageCollectionStats {
             trees_collected: deleted_trees as u64,
         })
     }
+
+    fn require_mark_and_sweep_tables(
+        &mut self,
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<()> {
+        if self.has_mark_and_sweep_tables {
+            return Ok(());
+        }
+        connection.execute(
+            "CREATE TEMP TABLE gc_mark (
+                tree_id INTEGER PRIMARY KEY NOT NULL
+            ) STRICT",
+            (),
+        )?;
+        connection.execute(
+            "CREATE TEMP TABLE gc_pending (
+                tree_id INTEGER PRIMARY KEY NOT NULL
+            ) STRICT",
+            (),
+        )?;
+        self.has_mark_and_sweep_tables = true;
+        Ok(())
+    }
+
+    /// Adds the unmarked targets of all roots, root history entries, pins and live [StrongReference]s to `gc_pending`.
+    fn add_unmarked_roots_to_pending(
+        &mut self,
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<usize> {
+        let mut added = connection.execute(
+            "INSERT OR IGNORE INTO gc_pending (tree_id)
+            SELECT tree.id FROM tree
+            WHERE tree.digest IN (
+                SELECT target FROM root UNION SELECT target FROM root_history UNION SELECT target FROM pin
+            )
+            AND tree.id NOT IN (SELECT tree_id FROM gc_mark)",
+            (),
+        )?;
+        let mut statement = connection.prepare_cached(
+            "INSERT OR IGNORE INTO gc_pending (tree_id)
+            SELECT ?1 WHERE ?1 NOT IN (SELECT tree_id FROM gc_mark)",
+        )?;
+        let live_additional_roots = self.shared.lock().unwrap().live_additional_roots();
+        for tree_id in live_additional_roots {
+            added += statement.execute((tree_id,))?;
+        }
+        Ok(added)
+    }
+
+    /// Marks the tree and adds its unmarked children to `gc_pending`.
+    fn mark_tree(connection: &rusqlite::Connection, tree_id: i64) -> rusqlite::Result<bool> {
+        connection
+            .prepare_cached("DELETE FROM gc_pending WHERE tree_id = ?1")?
+            .execute((tree_id,))?;
+        let newly_marked = connection
+            .prepare_cached("INSERT OR IGNORE INTO gc_mark (tree_id) VALUES (?1)")?
+            .execute((tree_id,))?
+            > 0;
+        // Trees stored during the cycle can reuse the ID of a tree that was marked before it was deleted, so the children
+        // are always looked at.
+        connection
+            .prepare_cached(
+                "INSERT OR IGNORE INTO gc_pending (tree_id)
+                SELECT tree.id FROM reference, tree
+                WHERE reference.origin = ?1
+                AND reference.target = tree.digest
+                AND tree.id NOT IN (SELECT tree_id FROM gc_mark)",
+            )?
+            .execute((tree_id,))?;
+        Ok(newly_marked)
+    }
+
+    fn count_pending(connection: &rusqlite::Connection) -> rusqlite::Result<u64> {
+        connection.query_row("SELECT COUNT(*) FROM gc_pending", (), |row| {
+            row.get::<_, i64>(0).map(|count| count as u64)
+        })
+    }
+
+    /// Does one part of a mark-and-sweep cycle and returns how many trees it deleted.
+    /// Unlike [Self::collect_garbage], this also deletes unreachable trees that are still referenced by other unreachable trees.
+    #[instrument(skip_all)]
+    fn mark_and_sweep(
+        &mut self,
+        connection: &rusqlite::Connection,
+        budget: &MarkAndSweepBudget,
+    ) -> rusqlite::Result<(MarkAndSweepProgress, u64)> {
+        let started = std::time::Instant::now();
+        let _deletion = DeletionGuard::new(&self.shared);
+        self.require_mark_and_sweep_tables(connection)?;
+        if self.mark_and_sweep.is_none() {
+            info!("Starting a mark-and-sweep garbage collection cycle");
+            connection.execute("DELETE FROM gc_mark", ())?;
+            connection.execute("DELETE FROM gc_pending", ())?;
+            let pruned_history = self.prune_root_history(connection)?;
+            debug!(
+                "Garbage collection pruned {} root history entries",
+                pruned_history
+            );
+            let expired_pins = self.delete_expired_pins(connection)?;
+            debug!("Garbage collection deleted {} expired pins", expired_pins);
+            self.shared.lock().unwrap().newly_referenced = Some(BTreeSet::new());
+            self.add_unmarked_roots_to_pending(connection)?;
+            self.mark_and_sweep = Some(MarkAndSweepCycle {
+                phase: MarkAndSweepPhase::Marking,
+                trees_marked: 0,
+                trees_swept: 0,
+                last_swept_id: i64::MIN,
+            });
+        }
+        let mut cycle = self.mark_and_sweep.take().expect("The cycle was started");
+        let mut trees_processed: u64 = 0;
+        let mut trees_swept: u64 = 0;
+        let result = loop {
+            let newly_referenced = self
+                .shared
+                .lock()
+                .unwrap()
+                .newly_referenced
+                .replace(BTreeSet::new())
+                .unwrap_or_default();
+            if !newly_referenced.is_empty() {
+                let mut statement = connection
+                    .prepare_cached("INSERT OR IGNORE INTO gc_pending (tree_id) VALUES (?1)")?;
+                for tree_id in newly_referenced {
+                    statement.execute((tree_id,))?;
+                }
+                cycle.phase = MarkAndSweepPhase::Marking;
+            }
+            if budget.is_exhausted(started, trees_processed) {
+                break None;
+            }
+            match cycle.phase {
+                MarkAndSweepPhase::Marking => {
+                    let next: Option<i64> = connection
+                        .prepare_cached("SELECT tree_id FROM gc_pending LIMIT 1")?
+                        .query_row((), |row| row.get(0))
+                        .optional()?;
+                    match next {
+                        Some(tree_id) => {
+                            if Self::mark_tree(connection, tree_id)? {
+                                cycle.trees_marked += 1;
+                            }
+                            trees_processed += 1;
+                        }
+                        // Roots may have changed since the cycle started.
+                        None if self.add_unmarked_roots_to_pending(connection)? == 0 => {
+                            debug!("Marked {} trees", cycle.trees_marked);
+                            cycle.phase = MarkAndSweepPhase::Sweeping;
+                        }
+                        None => {}
+                    }
+                }
+                MarkAndSweepPhase::Sweeping => {
+                    let next: Option<i64> = connection
+                        .prepare_cached(
+                            "SELECT id FROM tree
+                            WHERE id > ?1 AND id NOT IN (SELECT tree_id FROM gc_mark)
+                            ORDER BY id LIMIT 1",
+                        )?
+                        .query_row((cycle.last_swept_id,), |row| row.get(0))
+                        .optional()?;
+                    match next {
+                        Some(tree_id) => {
+                            connection
+                                .prepare_cached("DELETE FROM tree WHERE id = ?1")?
+                                .execute((tree_id,))?;
+                            cycle.last_swept_id = tree_id;
+                            cycle.trees_swept += 1;
+                            trees_swept += 1;
+                            trees_processed += 1;
+                        }
+                        None => {
+                            info!(
+                                "Mark-and-sweep garbage collection marked {} and deleted {} trees",
+                                cycle.trees_marked, cycle.trees_swept
+                            );
+                            connection.execute("DELETE FROM gc_mark", ())?;
+                            {
+                                let mut shared = self.shared.lock().unwrap();
+                                shared.newly_referenced = None;
+                                shared.stats.mark_and_sweep_cycles_finished += 1;
+                            }
+                            break Some(MarkAndSweepProgress {
+                                phase: MarkAndSweepPhase::Finished,
+                                trees_marked: cycle.trees_marked,
+                                trees_pending: 0,
+                                trees_swept: cycle.trees_swept,
+                            });
+                        }
+                    }
+                }
+                MarkAndSweepPhase::Finished => unreachable!("Finished cycles are not kept"),
+            }
+        };
+        let progress = match result {
+            Some(finished) => finished,
+            None => {
+                let progress = MarkAndSweepProgress {
+                    phase: cycle.phase,
+                    trees_marked: cycle.trees_marked,
+                    trees_pending: Self::count_pending(connection)?,
+                    trees_swept: cycle.trees_swept,
+                };
+                self.mark_and_sweep = Some(cycle);
+                progress
+            }
+        };
+        self.shared.lock().unwrap().stats.trees_swept += trees_swept;
+        Ok((progress, trees_swept))
+    }
 }
 
 #[derive(Debug)]
@@ -184,6 +743,22 @@ struct SQLiteState {
     connection: rusqlite::Connection,
     transaction: Option<TransactionStats>,
     garbage_collector: GarbageCollector,
+    /// Root updates of the current transaction. They are published when it is committed.
+    uncommitted_root_changes: Vec<RootChange>,
+    codec: BlobCodec,
+    /// All dictionaries in the database. New trees are compressed with the newest one.
+    dictionaries: Arc<CompressionDictionaries>,
+    shared: Arc<std::sync::Mutex<SharedState>>,
+}
+
+impl SQLiteState {
+    fn encode_tree_blob(&self, original_blob: &[u8]) -> EncodedBlob {
+        let dictionary = self
+            .dictionaries
+            .last_key_value()
+            .map(|(id, content)| (*id, content.as_slice()));
+        encode_tree_blob(original_blob, self.codec, dictionary)
+    }
 }
 
 impl SQLiteState {
@@ -203,336 +778,1229 @@ impl SQLiteState {
     }
 }
 
+/// Read-only connections that load trees and roots without waiting for the writer.
+#[derive(Debug)]
+struct ReaderPool {
+    connections: std::sync::Mutex<Vec<rusqlite::Connection>>,
+    available: tokio::sync::Semaphore,
+}
+
 #[derive(Debug)]
 pub struct SQLiteStorage {
     state: tokio::sync::Mutex<SQLiteState>,
+    shared: Arc<std::sync::Mutex<SharedState>>,
+    readers: Option<ReaderPool>,
+    root_changes: tokio::sync::broadcast::Sender<RootChange>,
+    digest_algorithm: DigestAlgorithm,
 }
 
 impl SQLiteStorage {
-    pub fn from(connection: rusqlite::Connection) -> rusqlite::Result<Self> {
+    /// Creates the schema if the database is empty and upgrades older schemas. See [Self::upgrade_schema].
+    pub fn from(connection: rusqlite::Connection) -> std::result::Result<Self, OpenError> {
+        Self::from_with_configuration(connection, SQLiteStorageConfiguration::default())
+    }
+
+    pub fn from_with_configuration(
+        connection: rusqlite::Connection,
+        configuration: SQLiteStorageConfiguration,
+    ) -> std::result::Result<Self, OpenError> {
         Self::configure_connection(&connection)?;
+        Self::upgrade_schema(&connection)?;
+        let dictionaries = Arc::new(load_compression_dictionaries(&connection)?);
+        let readers = match connection.path() {
+            Some(path) if !path.is_empty() && configuration.reader_connections > 0 => {
+                Some(Self::open_readers(path, configuration.reader_connections)?)
+            }
+            _ => None,
+        };
+        let shared = Arc::new(std::sync::Mutex::new(SharedState {
+            additional_roots: BTreeMap::new(),
+            newly_referenced: None,
+            deletion_sequence: 0,
+            uncommitted_trees: BTreeSet::new(),
+            uncommitted_roots: BTreeSet::new(),
+            has_uncommitted_deletions: false,
+            dictionaries: dictionaries.clone(),
+            stats: SQLiteStorageStats::default(),
+        }));
+        let codec = configuration.codec;
+        let digest_algorithm = configuration.digest_algorithm;
         Ok(Self {
             state: Mutex::new(SQLiteState {
                 connection,
                 transaction: None,
-                garbage_collector: GarbageCollector::new(),
+                garbage_collector: GarbageCollector::new(configuration, shared.clone()),
+                uncommitted_root_changes: Vec::new(),
+                codec,
+                dictionaries,
+                shared: shared.clone(),
             }),
+            shared,
+            readers,
+            root_changes: tokio::sync::broadcast::channel(ROOT_CHANGE_CHANNEL_CAPACITY).0,
+            digest_algorithm,
         })
     }
 
-    pub fn configure_connection(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
-        connection.pragma_update(None, "foreign_keys", "on")?;
-        // "The default suggested cache size is -2000, which means the cache size is limited to 2048000 bytes of memory."
-        // https://www.sqlite.org/pragma.html#pragma_cache_size
-        connection.pragma_update(None, "cache_size", "-200000")?;
-        // "The WAL journaling mode uses a write-ahead log instead of a rollback journal to implement transactions. The WAL journaling mode is persistent; after being set it stays in effect across multiple database connections and after closing and reopening the database. A database in WAL journaling mode can only be accessed by SQLite version 3.7.0 (2010-07-21) or later."
-        // https://www.sqlite.org/wal.html
-        connection.pragma_update(None, "journal_mode", "WAL")?;
-        // CREATE TEMP TABLE shall not create a file (https://sqlite.org/tempfiles.html)
-        connection.pragma_update(None, "temp_store", "MEMORY")?;
-        Ok(())
+    fn open_readers(path: &str, count: usize) -> rusqlite::Result<ReaderPool> {
+        let mut connections = Vec::with_capacity(count);
+        for _ in 0..count {
+            let connection = rusqlite::Connection::open_with_flags(
+                path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            connection.pragma_update(None, "cache_size", "-200000")?;
+            connection.pragma_update(None, "query_only", "on")?;
+            connections.push(connection);
+        }
+        debug!("Opened {count} reader connections to {path}");
+        Ok(ReaderPool {
+            connections: std::sync::Mutex::new(connections),
+            available: tokio::sync::Semaphore::new(count),
+        })
     }
 
-    pub fn create_schema(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
-        {
-            // Why are we using format! instead of an SQL parameter here?
-            // Answer is the SQLite error: "parameters prohibited in CHECK constraints" (because why should anything ever work)
-            let query = format!(
-                "CREATE TABLE tree (
-                    id INTEGER PRIMARY KEY NOT NULL,
-                    digest BLOB UNIQUE NOT NULL,
-                    tree_blob BLOB NOT NULL,
-                    is_compressed INTEGER NOT NULL,
-                    CONSTRAINT digest_length_matches_sha3_512 CHECK (LENGTH(digest) == 64),
-                    CONSTRAINT tree_blob_max_length CHECK (LENGTH(tree_blob) <= {TREE_BLOB_MAX_LENGTH}),
-                    CONSTRAINT is_compressed_boolean CHECK (is_compressed IN (0, 1))
-                ) STRICT"
-            );
-            connection
-                .execute(&query, ())
-                .map(|size| assert_eq!(0, size))?;
+    /// Runs `read` on a reader connection, but only if the readers don't see any trees that are already deleted for the
+    /// writer. The caller has to take care of [SharedState::uncommitted_trees] and [SharedState::uncommitted_roots]. Also
+    /// returns the [SharedState::deletion_sequence] from before the read.
+    async fn read<T>(
+        &self,
+        read: impl FnOnce(&rusqlite::Connection, &CompressionDictionaries) -> T,
+    ) -> Option<(T, u64)> {
+        let readers = self.readers.as_ref()?;
+        let _permit = readers
+            .available
+            .acquire()
+            .await
+            .expect("The semaphore is never closed");
+        let (deletion_sequence, dictionaries) = {
+            let shared = self.shared.lock().unwrap();
+            if shared.has_uncommitted_deletions || (shared.deletion_sequence % 2 == 1) {
+                return None;
+            }
+            (shared.deletion_sequence, shared.dictionaries.clone())
+        };
+        let connection = readers
+            .connections
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("Every permit has a connection");
+        let result = read(&connection, &dictionaries);
+        readers.connections.lock().unwrap().push(connection);
+        Some((result, deletion_sequence))
+    }
+
+    /// Returns None if the trees have to be loaded by the writer instead.
+    async fn load_trees_from_reader(
+        &self,
+        references: &[BlobDigest],
+    ) -> Option<std::result::Result<Vec<StrongDelayedHashedTree>, LoadError>> {
+        let (rows, deletion_sequence) = self
+            .read(|connection, dictionaries| read_trees(connection, dictionaries, references))
+            .await?;
+        match rows {
+            Ok(rows) => {
+                let mut shared = self.shared.lock().unwrap();
+                // Something was deleted during the read, so the trees may not exist anymore.
+                if shared.deletion_sequence != deletion_sequence {
+                    return None;
+                }
+                Some(Ok(register_trees(&mut shared, references, rows)))
+            }
+            Err(error) => Some(Err(error)),
         }
-        connection
-            .execute(
-                "CREATE TABLE reference (
+    }
+
+    fn create_root_history_table(
+        connection: &rusqlite::Connection,
+        if_not_exists: bool,
+    ) -> rusqlite::Result<()> {
+        let if_not_exists = if if_not_exists { "IF NOT EXISTS " } else { "" };
+        connection.execute(
+            &format!(
+                "CREATE TABLE {if_not_exists}root_history (
                     id INTEGER PRIMARY KEY NOT NULL,
-                    origin INTEGER NOT NULL REFERENCES tree ON DELETE CASCADE,
-                    zero_based_index INTEGER NOT NULL,
+                    name TEXT NOT NULL,
                     target BLOB NOT NULL,
-                    UNIQUE (origin, zero_based_index),
-                    CONSTRAINT digest_length_matches_sha3_512 CHECK (LENGTH(target) == 64)
+                    updated_at INTEGER NOT NULL,
+                    CONSTRAINT target_is_tagged CHECK ({})
                 ) STRICT",
-                (),
-            )
-            .map(|size| assert_eq!(0, size))?;
-        connection
-            .execute("CREATE INDEX reference_origin ON reference (origin)", ())
-            .map(|size| assert_eq!(0, size))?;
-        connection
-            .execute("CREATE INDEX reference_target ON reference (target)", ())
-            .map(|size| assert_eq!(0, size))?;
-        connection
-            .execute(
-                "CREATE TABLE root (
+                tagged_digest_check("target")
+            ),
+            (),
+        )?;
+        connection.execute(
+            &format!(
+                "CREATE INDEX {if_not_exists}root_history_name ON root_history (name, updated_at)"
+            ),
+            (),
+        )?;
+        connection.execute(
+            &format!("CREATE INDEX {if_not_exists}root_history_target ON root_history (target)"),
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Where [SQLiteStorage::check_integrity] moves broken trees. `children` contains the tagged digests of the children
+    /// one after another.
+    fn create_quarantined_tree_table(
+        connection: &rusqlite::Connection,
+        if_not_exists: bool,
+    ) -> rusqlite::Result<()> {
+        let if_not_exists = if if_not_exists { "IF NOT EXISTS " } else { "" };
+        connection.execute(
+            &format!(
+                "CREATE TABLE {if_not_exists}quarantined_tree (
+                    id INTEGER PRIMARY KEY NOT NULL,
+                    digest BLOB NOT NULL,
+                    tree_blob BLOB NOT NULL,
+                    codec INTEGER NOT NULL,
+                    dictionary INTEGER,
+                    children BLOB NOT NULL,
+                    reason TEXT NOT NULL
+                ) STRICT"
+            ),
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn create_pin_table(
+        connection: &rusqlite::Connection,
+        if_not_exists: bool,
+    ) -> rusqlite::Result<()> {
+        let if_not_exists = if if_not_exists { "IF NOT EXISTS " } else { "" };
+        connection.execute(
+            &format!(
+                "CREATE TABLE {if_not_exists}pin (
                     id INTEGER PRIMARY KEY NOT NULL,
                     name TEXT UNIQUE NOT NULL,
                     target BLOB NOT NULL,
-                    CONSTRAINT target_length_matches_sha3_512 CHECK (LENGTH(target) == 64)
+                    expires_at INTEGER,
+                    CONSTRAINT target_is_tagged CHECK ({})
                 ) STRICT",
-                (),
-            )
-            .map(|size| assert_eq!(0, size))?;
+                tagged_digest_check("target")
+            ),
+            (),
+        )?;
+        connection.execute(
+            &format!("CREATE INDEX {if_not_exists}pin_target ON pin (target)"),
+            (),
+        )?;
         Ok(())
     }
-}
 
-#[async_trait]
-impl StoreTree for SQLiteStorage {
-    //#[instrument(skip_all)]
-    async fn store_tree(
+    fn create_compression_dictionary_table(
+        connection: &rusqlite::Connection,
+        if_not_exists: bool,
+    ) -> rusqlite::Result<()> {
+        let if_not_exists = if if_not_exists { "IF NOT EXISTS " } else { "" };
+        connection.execute(
+            &format!(
+                "CREATE TABLE {if_not_exists}compression_dictionary (
+                    id INTEGER PRIMARY KEY NOT NULL,
+                    content BLOB NOT NULL
+                ) STRICT"
+            ),
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// All recorded updates of the root `name`, oldest first. Entries outside of the retention window disappear during garbage collection.
+    pub async fn root_history(&self, name: &str) -> Result<Vec<RootHistoryEntry>, LoadError> {
+        let state_locked = self.state.lock().await;
+        let mut statement = state_locked
+            .connection
+            .prepare_cached(
+                "SELECT target, updated_at FROM root_history WHERE name = ?1 ORDER BY updated_at ASC, id ASC",
+            )
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+        let rows = statement
+            .query_map((name,), |row| {
+                let target: BlobDigest = row.get(0)?;
+                let updated_at: i64 = row.get(1)?;
+                Ok(RootHistoryEntry {
+                    target,
+                    updated_at: from_unix_micros(updated_at),
+                })
+            })
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+        rows.collect::<rusqlite::Result<_>>()
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))
+    }
+
+    /// Loads the target the root `name` had at `time`, if the history still knows it and the tree hasn't been collected.
+    pub async fn load_root_at(
         &self,
-        tree: &HashedTree,
-    ) -> std::result::Result<StrongReference, StoreError> {
+        name: &str,
+        time: std::time::SystemTime,
+    ) -> Result<Option<StrongReference>, LoadError> {
         let mut state_locked = self.state.lock().await;
-        let digest = *tree.digest();
-        let origin_digest: [u8; 64] = digest.into();
-        {
-            let tree_id: Option<i64> = {
-                let connection_locked = &state_locked.connection;
-                let mut statement = connection_locked
-                    .prepare_cached("SELECT id FROM tree WHERE digest = ?")
-                    .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?;
-                match statement.query_row(
-                    (&origin_digest,),
-                    |row| -> rusqlite::Result<_, rusqlite::Error> { row.get(0) },
-                ) {
-                    Ok(id) => Some(id),
-                    Err(rusqlite::Error::QueryReturnedNoRows) => None,
-                    Err(error) => {
-                        return Err(StoreError::Rusqlite(format!("{}", error)));
+        let state = &mut *state_locked;
+        let target: Option<(BlobDigest, i64)> = state
+            .connection
+            .query_row(
+                "SELECT root_history.target, tree.id FROM root_history, tree
+                WHERE root_history.name = ?1 AND root_history.updated_at <= ?2 AND root_history.target = tree.digest
+                ORDER BY root_history.updated_at DESC, root_history.id DESC LIMIT 1",
+                (&name, to_unix_micros(time)),
+                |row| -> rusqlite::Result<_> {
+                    let target = row.get(0)?;
+                    let tree_id: i64 = row.get(1)?;
+                    Ok((target, tree_id))
+                },
+            )
+            .optional()
+            .map_err(|err| LoadError::Rusqlite(format!("{}", err)))?;
+        match target {
+            Some((digest, tree_id)) => {
+                let reference = state
+                    .garbage_collector
+                    .require_additional_root(&digest, tree_id, &state.connection)
+                    .map_err(|error| LoadError::Rusqlite(error.to_string()))?;
+                Ok(Some(reference))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn configure_connection(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+        connection.pragma_update(None, "foreign_keys", "on")?;
+        // "The default suggested cache size is -2000, which means the cache size is limited to 2048000 bytes of memory."
+        // https://www.sqlite.org/pragma.html#pragma_cache_size
+        connection.pragma_update(None, "cache_size", "-200000")?;
+        // "The WAL journaling mode uses a write-ahead log instead of a rollback journal to implement transactions. The WAL journaling mode is persistent; after being set it stays in effect across multiple database connections and after closing and reopening the database. A database in WAL journaling mode can only be accessed by SQLite version 3.7.0 (2010-07-21) or later."
+        // https://www.sqlite.org/wal.html
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        // CREATE TEMP TABLE shall not create a file (https://sqlite.org/tempfiles.html)
+        connection.pragma_update(None, "temp_store", "MEMORY")?;
+        Ok(())
+    }
+
+    pub fn create_schema(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+        Self::create_compression_dictionary_table(connection, false)?;
+        Self::create_tree_tables(connection)?;
+        Self::create_root_history_table(connection, false)?;
+        Self::create_pin_table(connection, false)?;
+        Self::create_quarantined_tree_table(connection, false)?;
+        Self::set_schema_version(connection, SCHEMA_VERSION)
+    }
+
+    pub fn schema_version(connection: &rusqlite::Connection) -> rusqlite::Result<i32> {
+        connection.query_row("PRAGMA user_version;", [], |row| row.get(0))
+    }
+
+    fn set_schema_version(connection: &rusqlite::Connection, version: i32) -> rusqlite::Result<()> {
+        connection.pragma_update(None, "user_version", version)
+    }
+
+    /// Migrates the schema step by step until it reaches [SCHEMA_VERSION]. An empty database gets the current schema.
+    /// Databases from newer versions of this library are refused because they may contain data we would misinterpret.
+    pub fn upgrade_schema(connection: &rusqlite::Connection) -> std::result::Result<(), OpenError> {
+        loop {
+            let user_version = Self::schema_version(connection)?;
+            match user_version {
+                0 => {
+                    if Self::tree_table_sql(connection)?.is_none() {
+                        info!("Creating the schema of an empty database");
+                        Self::create_schema(connection)?;
+                    } else {
+                        Self::migrate_unversioned_schema(connection)?;
                     }
                 }
-            };
-            if let Some(id) = tree_id {
-                let (connection_locked, garbage_collector) = {
-                    let state = &mut *state_locked;
-                    (&state.connection, &mut state.garbage_collector)
-                };
-                return garbage_collector
-                    .require_additional_root(tree.digest(), id, connection_locked)
-                    .map_err(|error| StoreError::Rusqlite(error.to_string()));
+                1 => {
+                    info!("Migrating the database schema from version 1 to 2");
+                    Self::create_pin_table(connection, true)?;
+                    Self::set_schema_version(connection, 2)?;
+                }
+                2 => {
+                    info!("Migrating the database schema from version 2 to 3");
+                    // Repairing used to create the table when it needed it.
+                    Self::create_quarantined_tree_table(connection, true)?;
+                    Self::set_schema_version(connection, 3)?;
+                }
+                3 => {
+                    // Future migrations go here
+                    return Ok(());
+                }
+                _ => {
+                    error!("Unsupported database schema version: {}", user_version);
+                    return Err(OpenError::UnsupportedSchemaVersion(user_version));
+                }
             }
         }
+    }
 
-        state_locked
-            .require_transaction(1 + tree.tree().children().references().len() as u64)
-            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    /// Databases created before the schema had a version. Depending on their age, they lack some of the features of
+    /// version 1. Every step checks whether it is necessary, so this can safely run again after being interrupted.
+    fn migrate_unversioned_schema(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+        info!("Migrating an unversioned database schema to version 1");
+        Self::create_root_history_table(connection, true)?;
+        Self::create_compression_dictionary_table(connection, true)?;
+        if Self::has_untagged_digests(connection)? {
+            Self::rebuild_tree_tables(connection, true)?;
+        } else if Self::has_is_compressed_column(connection)? {
+            Self::rebuild_tree_tables(connection, false)?;
+        }
+        Self::set_schema_version(connection, 1)
+    }
 
-        let connection_locked = &mut state_locked.connection;
+    fn create_tree_tables(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+        {
+            // Why are we using format! instead of an SQL parameter here?
+            // Answer is the SQLite error: "parameters prohibited in CHECK constraints" (because why should anything ever work)
+            let query = format!(
+                "CREATE TABLE tree (
+                    id INTEGER PRIMARY KEY NOT NULL,
+                    digest BLOB UNIQUE NOT NULL,
+                    tree_blob BLOB NOT NULL,
+                    codec INTEGER NOT NULL,
+                    dictionary INTEGER REFERENCES compression_dictionary,
+                    CONSTRAINT digest_is_tagged CHECK ({}),
+                    CONSTRAINT tree_blob_max_length CHECK (LENGTH(tree_blob) <= {TREE_BLOB_MAX_LENGTH}),
+                    CONSTRAINT codec_is_known CHECK (codec IN (0, 1, 2)),
+                    CONSTRAINT only_zstd_uses_dictionaries CHECK (dictionary IS NULL OR codec == 2)
+                ) STRICT",
+                tagged_digest_check("digest")
+            );
+            connection
+                .execute(&query, ())
+                .map(|size| assert_eq!(0, size))?;
+        }
+        connection
+            .execute(
+                &format!(
+                    "CREATE TABLE reference (
+                        id INTEGER PRIMARY KEY NOT NULL,
+                        origin INTEGER NOT NULL REFERENCES tree ON DELETE CASCADE,
+                        zero_based_index INTEGER NOT NULL,
+                        target BLOB NOT NULL,
+                        UNIQUE (origin, zero_based_index),
+                        CONSTRAINT target_is_tagged CHECK ({})
+                    ) STRICT",
+                    tagged_digest_check("target")
+                ),
+                (),
+            )
+            .map(|size| assert_eq!(0, size))?;
+        connection
+            .execute("CREATE INDEX reference_origin ON reference (origin)", ())
+            .map(|size| assert_eq!(0, size))?;
+        connection
+            .execute("CREATE INDEX reference_target ON reference (target)", ())
+            .map(|size| assert_eq!(0, size))?;
+        connection
+            .execute(
+                &format!(
+                    "CREATE TABLE root (
+                        id INTEGER PRIMARY KEY NOT NULL,
+                        name TEXT UNIQUE NOT NULL,
+                        target BLOB NOT NULL,
+                        CONSTRAINT target_is_tagged CHECK ({})
+                    ) STRICT",
+                    tagged_digest_check("target")
+                ),
+                (),
+            )
+            .map(|size| assert_eq!(0, size))?;
+        Ok(())
+    }
+
+    fn tree_table_sql(connection: &rusqlite::Connection) -> rusqlite::Result<Option<String>> {
+        connection
+            .query_row(
+                "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'tree'",
+                (),
+                |row| row.get(0),
+            )
+            .optional()
+    }
 
-        // Try to compress the blob, but only store compressed if it's beneficial
-        let original_blob = tree.tree().blob().as_slice();
-        let compressed = lz4_flex::compress_prepend_size(original_blob);
+    /// Databases created before digests were tagged with their algorithm only contain untagged SHA3-512 digests.
+    fn has_untagged_digests(connection: &rusqlite::Connection) -> rusqlite::Result<bool> {
+        Ok(Self::tree_table_sql(connection)?
+            .is_some_and(|sql| sql.contains("digest_length_matches_sha3_512")))
+    }
 
-        let (blob_to_store, is_compressed): (&[u8], i32) = if compressed.len() < original_blob.len()
-        {
-            // Compression is beneficial, store compressed
-            (&compressed, 1)
-        } else {
-            // Compression doesn't help, store uncompressed to save CPU time on loading
-            (original_blob, 0)
+    /// Databases created before there were multiple codecs only know whether a tree is compressed with lz4.
+    fn has_is_compressed_column(connection: &rusqlite::Connection) -> rusqlite::Result<bool> {
+        Ok(Self::tree_table_sql(connection)?.is_some_and(|sql| sql.contains("is_compressed")))
+    }
+
+    /// Rebuilds the tree tables of an old database with the current schema. The `is_compressed` column becomes the
+    /// `codec` column, which uses the same values for uncompressed and lz4. With `tag_digests`, the SHA3-512 tag is
+    /// added to every digest.
+    fn rebuild_tree_tables(
+        connection: &rusqlite::Connection,
+        tag_digests: bool,
+    ) -> rusqlite::Result<()> {
+        info!("Upgrading the tree tables of the database (tag_digests: {tag_digests})");
+        // The tables are recreated, so the foreign key from reference to tree can't be enforced in the meantime.
+        // This setting can't be changed inside of a transaction.
+        connection.pragma_update(None, "foreign_keys", "off")?;
+        let result = Self::copy_into_new_tree_tables(connection, tag_digests);
+        if result.is_err() && !connection.is_autocommit() {
+            if let Err(error) = connection.execute("ROLLBACK;", ()) {
+                error!(
+                    "Could not roll back the upgrade of the tree tables: {}",
+                    error
+                );
+            }
+        }
+        // Even after a failure, the connection must not be left without foreign key checks.
+        let foreign_keys_restored = connection.pragma_update(None, "foreign_keys", "on");
+        result.and(foreign_keys_restored)
+    }
+
+    /// The transaction of [SQLiteStorage::rebuild_tree_tables]. It is still open if this fails.
+    fn copy_into_new_tree_tables(
+        connection: &rusqlite::Connection,
+        tag_digests: bool,
+    ) -> rusqlite::Result<()> {
+        let digest = |column: &str| {
+            if tag_digests {
+                format!("CAST(x'14' || {column} AS BLOB)")
+            } else {
+                column.to_string()
+            }
         };
+        connection.execute_batch(
+            "BEGIN TRANSACTION;
+            DROP INDEX reference_origin;
+            DROP INDEX reference_target;
+            ALTER TABLE tree RENAME TO old_tree;
+            ALTER TABLE reference RENAME TO old_reference;
+            ALTER TABLE root RENAME TO old_root;
+            DROP INDEX root_history_name;
+            DROP INDEX root_history_target;
+            ALTER TABLE root_history RENAME TO old_root_history;",
+        )?;
+        Self::create_tree_tables(connection)?;
+        Self::create_root_history_table(connection, false)?;
+        connection.execute_batch(&format!(
+            "INSERT INTO tree (id, digest, tree_blob, codec)
+                SELECT id, {}, tree_blob, is_compressed FROM old_tree;
+            INSERT INTO reference (id, origin, zero_based_index, target)
+                SELECT id, origin, zero_based_index, {} FROM old_reference;
+            INSERT INTO root (id, name, target) SELECT id, name, {} FROM old_root;
+            INSERT INTO root_history (id, name, target, updated_at)
+                SELECT id, name, {}, updated_at FROM old_root_history;
+            DROP TABLE old_reference;
+            DROP TABLE old_tree;
+            DROP TABLE old_root;
+            DROP TABLE old_root_history;",
+            digest("digest"),
+            digest("target"),
+            digest("target"),
+            digest("target"),
+        ))?;
+        upgrade_quarantine(connection, tag_digests)?;
+        connection.execute("COMMIT;", ())?;
+        Ok(())
+    }
 
-        let tree_id: i64 = {
-            // The SAVEPOINT ensures that the trees and references stay consistent even if something fails here.
-            let save_point = connection_locked
-                .savepoint()
+    /// Verifies every tree, reference and root in the database. With `repair`, broken trees are moved into the
+    /// `quarantined_tree` table. Repairing happens inside the current transaction, so it has to be committed.
+    pub async fn check_integrity(&self, repair: bool) -> Result<IntegrityReport, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        if repair {
+            state_locked
+                .require_transaction(0)
                 .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+        }
+        let state = &mut *state_locked;
+        // Repairing deletes the broken trees.
+        let _deletion = repair.then(|| DeletionGuard::new(&self.shared));
+        let report = check_integrity(&state.connection, repair)
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+        if let Some(ref mut stats) = state.transaction {
+            stats.writes += report.quarantined.len() as u64;
+        }
+        for quarantined in &report.quarantined {
+            state.garbage_collector.forget(quarantined);
+        }
+        Ok(report)
+    }
 
-            {
-                let mut statement = save_point
-                    .prepare_cached(
-                        "INSERT INTO tree (digest, tree_blob, is_compressed) VALUES (?1, ?2, ?3)",
-                    )
-                    .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?;
-                let rows_inserted = statement
-                    .execute((&origin_digest, blob_to_store, &is_compressed))
-                    .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
-                assert_eq!(1, rows_inserted);
+    /// Trains a zstd dictionary from up to `max_samples` random trees in the database and stores it. From now on,
+    /// [BlobCodec::Zstd] uses the new dictionary for new trees. Existing trees keep their compression until
+    /// [SQLiteStorage::recompress_trees] is called. The dictionary is stored in the current transaction, so it has to be committed.
+    pub async fn train_compression_dictionary(
+        &self,
+        max_samples: usize,
+        max_size: usize,
+    ) -> Result<i64, DictionaryTrainingError> {
+        let mut state_locked = self.state.lock().await;
+        let state = &mut *state_locked;
+        let rows: Vec<(BlobDigest, Vec<u8>, i64, Option<i64>)> = state
+            .connection
+            .prepare_cached(
+                "SELECT digest, tree_blob, codec, dictionary FROM tree ORDER BY RANDOM() LIMIT ?1",
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_map((max_samples as i64,), |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                    })?
+                    .collect()
+            })
+            .map_err(|error| {
+                DictionaryTrainingError::Store(StoreError::Rusqlite(error.to_string()))
+            })?;
+        let mut samples = Vec::with_capacity(rows.len());
+        for (digest, tree_blob_raw, codec, dictionary) in rows {
+            let sample = decode_tree_blob(tree_blob_raw, codec, dictionary, &state.dictionaries)
+                .map_err(|message| {
+                    DictionaryTrainingError::Store(StoreError::CorruptedStorage(format!(
+                        "{digest}: {message}"
+                    )))
+                })?;
+            if !sample.is_empty() {
+                samples.push(sample);
             }
+        }
+        let content =
+            train_dictionary(&samples, max_size).map_err(DictionaryTrainingError::Training)?;
+        state.require_transaction(1).map_err(|error| {
+            DictionaryTrainingError::Store(StoreError::Rusqlite(error.to_string()))
+        })?;
+        state
+            .connection
+            .execute(
+                "INSERT INTO compression_dictionary (content) VALUES (?1)",
+                (&content,),
+            )
+            .map_err(|error| {
+                DictionaryTrainingError::Store(StoreError::Rusqlite(error.to_string()))
+            })?;
+        let id = state.connection.last_insert_rowid();
+        info!(
+            "Trained compression dictionary {id} with {} bytes from {} samples",
+            content.len(),
+            samples.len()
+        );
+        Arc::make_mut(&mut state.dictionaries).insert(id, content);
+        state.shared.lock().unwrap().dictionaries = state.dictionaries.clone();
+        Ok(id)
+    }
 
-            let tree_id: i64 = {
-                let mut statement = save_point
-                    .prepare_cached("SELECT id FROM tree WHERE digest = ?1")
-                    .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
-                statement
-                    .query_row(
-                        (&origin_digest,),
-                        |row| -> rusqlite::Result<_, rusqlite::Error> { row.get(0) },
-                    )
-                    .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?
-            };
+    pub fn stats(&self) -> SQLiteStorageStats {
+        self.shared.lock().unwrap().stats
+    }
+
+    /// Runs a mark-and-sweep garbage collection cycle until it is finished or the budget is used up. An unfinished cycle is
+    /// continued by the next call. Everything reachable from the roots, the root history and the live [StrongReference]s is kept,
+    /// including trees that get referenced while the cycle is running. Deletions happen inside the current transaction.
+    pub async fn mark_and_sweep(
+        &self,
+        budget: &MarkAndSweepBudget,
+    ) -> Result<MarkAndSweepProgress, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        let state = &mut *state_locked;
+        state
+            .require_transaction(0)
+            .map_err(|error| StoreError::Rusqlite(error.to_string()))?;
+        let (progress, trees_swept) = state
+            .garbage_collector
+            .mark_and_sweep(&state.connection, budget)
+            .map_err(|error| StoreError::Rusqlite(error.to_string()))?;
+        state
+            .require_transaction(trees_swept)
+            .map_err(|error| StoreError::Rusqlite(error.to_string()))?;
+        Ok(progress)
+    }
 
-            if !tree.tree().children().references().is_empty() {
-                let inserted_tree_rowid = save_point.last_insert_rowid();
-                let mut statement = save_point
+    /// Compresses every tree again with the configured codec and the newest dictionary. Trees that already use them are not
+    /// rewritten. This happens inside the current transaction, so it has to be committed.
+    pub async fn recompress_trees(&self) -> Result<RecompressionStats, StoreError> {
+        const BATCH_SIZE: i64 = 1000;
+        let mut state_locked = self.state.lock().await;
+        let state = &mut *state_locked;
+        let mut stats = RecompressionStats::default();
+        let mut last_id = i64::MIN;
+        loop {
+            let rows: Vec<(i64, Vec<u8>, i64, Option<i64>)> = state
+                .connection
+                .prepare_cached(
+                    "SELECT id, tree_blob, codec, dictionary FROM tree WHERE id > ?1 ORDER BY id LIMIT ?2",
+                )
+                .and_then(|mut statement| {
+                    statement
+                        .query_map((last_id, BATCH_SIZE), |row| {
+                            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                        })?
+                        .collect()
+                })
+                .map_err(|error| StoreError::Rusqlite(error.to_string()))?;
+            let Some((id, ..)) = rows.last() else {
+                break;
+            };
+            last_id = *id;
+            for (id, tree_blob_raw, codec, dictionary) in rows {
+                stats.trees_examined += 1;
+                stats.bytes_before += tree_blob_raw.len() as u64;
+                let blob = decode_tree_blob(tree_blob_raw, codec, dictionary, &state.dictionaries)
+                    .map_err(|message| {
+                        StoreError::CorruptedStorage(format!("Tree {id}: {message}"))
+                    })?;
+                let encoded = state.encode_tree_blob(&blob);
+                stats.bytes_after += encoded.data.len() as u64;
+                if (encoded.codec, encoded.dictionary) == (codec, dictionary) {
+                    continue;
+                }
+                state
+                    .require_transaction(1)
+                    .map_err(|error| StoreError::Rusqlite(error.to_string()))?;
+                state
+                    .connection
                     .prepare_cached(
-                        "INSERT INTO reference (origin, zero_based_index, target) SELECT ?1, ?2, ?3 FROM tree WHERE tree.digest = ?3",
+                        "UPDATE tree SET tree_blob = ?2, codec = ?3, dictionary = ?4 WHERE id = ?1",
                     )
-                    .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
-                for (index, reference) in tree.tree().children().references().iter().enumerate() {
-                    let target_digest: [u8; 64] = (*reference.digest()).into();
-                    let rows_inserted = statement
-                        .execute((
-                            &inserted_tree_rowid,
-                            u32::try_from(index).expect("A child index won't be too large"),
-                            &target_digest,
-                        ))
-                        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
-                    match rows_inserted {
-                        0 => {
-                            return Err(StoreError::TreeMissing(LoadError::TreeNotFound(
-                                *reference.digest(),
-                            )))
-                        }
-                        1 => {}
-                        _ =>
-                            return Err(StoreError::CorruptedStorage(
-                                "Multiple rows inserted into reference table for a single child reference, which should be impossible due to the UNIQUE constraint".to_string())),
-                    }
-                }
+                    .and_then(|mut statement| {
+                        statement.execute((id, &encoded.data, encoded.codec, encoded.dictionary))
+                    })
+                    .map_err(|error| StoreError::Rusqlite(error.to_string()))?;
+                stats.trees_recompressed += 1;
+            }
+        }
+        info!("Recompressed trees: {stats:?}");
+        Ok(stats)
+    }
+}
+
+/// Stays below SQLite's historical limit of 999 parameters per statement. Statements with a variable number of parameters
+/// are prepared without the statement cache, because every number would take a slot in the cache.
+const MAX_PARAMETERS_PER_STATEMENT: usize = 900;
+
+/// SQL condition that `column` contains a digest in the format of [BlobDigest::to_tagged_bytes].
+fn tagged_digest_check(column: &str) -> String {
+    [DigestAlgorithm::Sha3_512, DigestAlgorithm::Blake3]
+        .iter()
+        .map(|algorithm| {
+            format!(
+                "(SUBSTR({column}, 1, 1) == x'{:02x}' AND LENGTH({column}) == {})",
+                algorithm.tag(),
+                1 + algorithm.digest_length()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+impl rusqlite::types::ToSql for BlobDigest {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(rusqlite::types::ToSqlOutput::from(self.to_tagged_bytes()))
+    }
+}
+
+impl rusqlite::types::FromSql for BlobDigest {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        let bytes = value.as_blob()?;
+        BlobDigest::from_tagged_bytes(bytes).ok_or_else(|| {
+            rusqlite::types::FromSqlError::Other(
+                format!("Not a tagged digest: {}", hex::encode(bytes)).into(),
+            )
+        })
+    }
+}
+
+fn placeholders(count: usize, columns: usize) -> String {
+    let row = format!("({})", vec!["?"; columns].join(", "));
+    vec![row; count].join(", ")
+}
+
+/// Looks up the IDs of all trees that exist. Digests that are not in the database are missing from the result.
+fn find_tree_ids(
+    connection: &rusqlite::Connection,
+    digests: &[BlobDigest],
+) -> rusqlite::Result<BTreeMap<BlobDigest, i64>> {
+    let mut result = BTreeMap::new();
+    for chunk in digests.chunks(MAX_PARAMETERS_PER_STATEMENT) {
+        let mut statement = connection.prepare(&format!(
+            "SELECT id, digest FROM tree WHERE digest IN ({})",
+            vec!["?"; chunk.len()].join(", ")
+        ))?;
+        let rows = statement.query_map(rusqlite::params_from_iter(chunk), |row| {
+            let id: i64 = row.get(0)?;
+            let digest: BlobDigest = row.get(1)?;
+            Ok((digest, id))
+        })?;
+        for row in rows {
+            let (digest, id) = row?;
+            result.insert(digest, id);
+        }
+    }
+    Ok(result)
+}
+
+pub(crate) fn load_compression_dictionaries(
+    connection: &rusqlite::Connection,
+) -> rusqlite::Result<CompressionDictionaries> {
+    connection
+        .prepare_cached("SELECT id, content FROM compression_dictionary")?
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+fn store_trees_locked(
+    state: &mut SQLiteState,
+    trees: &[HashedTree],
+) -> std::result::Result<Vec<StrongReference>, StoreError> {
+    let digests: Vec<BlobDigest> = trees.iter().map(|tree| *tree.digest()).collect();
+    let mut tree_ids = find_tree_ids(&state.connection, &digests)
+        .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?;
+
+    let mut new_trees: Vec<&HashedTree> = Vec::new();
+    let mut new_digests: BTreeSet<BlobDigest> = BTreeSet::new();
+    for tree in trees {
+        if !tree_ids.contains_key(tree.digest()) && new_digests.insert(*tree.digest()) {
+            new_trees.push(tree);
+        }
+    }
+
+    // Check all children before writing anything so that a missing child doesn't leave half of the batch behind.
+    let mut unknown_children: Vec<BlobDigest> = Vec::new();
+    let mut seen_children: BTreeSet<BlobDigest> = BTreeSet::new();
+    for tree in &new_trees {
+        for child in tree.tree().children().references() {
+            let child = child.digest();
+            if !new_digests.contains(child)
+                && !tree_ids.contains_key(child)
+                && seen_children.insert(*child)
+            {
+                unknown_children.push(*child);
             }
+        }
+    }
+    let existing_children = find_tree_ids(&state.connection, &unknown_children)
+        .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?;
+    if let Some(missing) = unknown_children
+        .iter()
+        .find(|child| !existing_children.contains_key(child))
+    {
+        return Err(StoreError::TreeMissing(LoadError::TreeNotFound(*missing)));
+    }
+
+    if !new_trees.is_empty() {
+        let writes: u64 = new_trees
+            .iter()
+            .map(|tree| 1 + tree.tree().children().references().len() as u64)
+            .sum();
+        state
+            .require_transaction(writes)
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
 
-            save_point
-                .commit()
+        let mut encoded_blobs = new_trees
+            .iter()
+            .map(|tree| state.encode_tree_blob(tree.tree().blob().as_slice()))
+            .collect::<Vec<_>>()
+            .into_iter();
+        // The SAVEPOINT ensures that the trees and references stay consistent even if something fails here.
+        let save_point = state
+            .connection
+            .savepoint()
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+        for chunk in new_trees.chunks(MAX_PARAMETERS_PER_STATEMENT / 4) {
+            let mut parameters: Vec<rusqlite::types::Value> = Vec::with_capacity(chunk.len() * 4);
+            for (tree, encoded) in chunk.iter().zip(encoded_blobs.by_ref()) {
+                parameters.push(rusqlite::types::Value::Blob(
+                    tree.digest().to_tagged_bytes(),
+                ));
+                parameters.push(rusqlite::types::Value::Blob(encoded.data));
+                parameters.push(rusqlite::types::Value::Integer(encoded.codec));
+                parameters.push(encoded.dictionary.into());
+            }
+            let mut statement = save_point
+                .prepare(&format!(
+                    "INSERT INTO tree (digest, tree_blob, codec, dictionary) VALUES {}",
+                    placeholders(chunk.len(), 4)
+                ))
+                .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?;
+            let rows_inserted = statement
+                .execute(rusqlite::params_from_iter(parameters))
                 .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
-            tree_id
-        };
+            assert_eq!(chunk.len(), rows_inserted);
+        }
 
-        let (connection_locked, garbage_collector) = {
-            let state = &mut *state_locked;
-            (&state.connection, &mut state.garbage_collector)
-        };
-        garbage_collector
-            .require_additional_root(&digest, tree_id, connection_locked)
-            .map_err(|error| StoreError::Rusqlite(error.to_string()))
+        let new_digests: Vec<BlobDigest> = new_digests.into_iter().collect();
+        tree_ids.extend(
+            find_tree_ids(&save_point, &new_digests)
+                .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?,
+        );
+
+        let mut references: Vec<(i64, u32, BlobDigest)> = Vec::new();
+        for tree in &new_trees {
+            let origin = tree_ids[tree.digest()];
+            for (index, child) in tree.tree().children().references().iter().enumerate() {
+                references.push((
+                    origin,
+                    u32::try_from(index).expect("A child index won't be too large"),
+                    *child.digest(),
+                ));
+            }
+        }
+        for chunk in references.chunks(MAX_PARAMETERS_PER_STATEMENT / 3) {
+            let mut parameters: Vec<rusqlite::types::Value> = Vec::with_capacity(chunk.len() * 3);
+            for (origin, index, target) in chunk {
+                parameters.push(rusqlite::types::Value::Integer(*origin));
+                parameters.push(rusqlite::types::Value::Integer((*index).into()));
+                parameters.push(rusqlite::types::Value::Blob(target.to_tagged_bytes()));
+            }
+            let mut statement = save_point
+                .prepare(&format!(
+                    "INSERT INTO reference (origin, zero_based_index, target) VALUES {}",
+                    placeholders(chunk.len(), 3)
+                ))
+                .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?;
+            let rows_inserted = statement
+                .execute(rusqlite::params_from_iter(parameters))
+                .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+            assert_eq!(chunk.len(), rows_inserted);
+        }
+
+        save_point
+            .commit()
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+        state
+            .shared
+            .lock()
+            .unwrap()
+            .uncommitted_trees
+            .extend(new_digests);
     }
+
+    // Every tree of the batch has to be protected before an automatic garbage collection can run.
+    let mut result = Vec::with_capacity(trees.len());
+    for tree in trees {
+        result.push(
+            state
+                .garbage_collector
+                .require_additional_root_entry(tree.digest(), tree_ids[tree.digest()]),
+        );
+    }
+    state
+        .garbage_collector
+        .check_automatic_collection(&state.connection)
+        .map_err(|error| StoreError::Rusqlite(error.to_string()))?;
+    Ok(result)
 }
 
-async fn load_tree_impl(
-    state: &tokio::sync::Mutex<SQLiteState>,
-    reference: &BlobDigest,
-) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
-    let mut state_locked = state.lock().await;
-    let (tree_blob, child_digests, root_reference) = {
-        let (connection_locked, garbage_collector) = {
-            let state = &mut *state_locked;
-            (&state.connection, &mut state.garbage_collector)
-        };
-        let digest: [u8; 64] = (*reference).into();
-        let mut statement = connection_locked
-            .prepare_cached("SELECT id, tree_blob, is_compressed FROM tree WHERE digest = ?1")
+/// Overwrites the blob and the references of a tree that already exists. Trees that don't exist yet are stored normally.
+fn replace_tree_locked(
+    state: &mut SQLiteState,
+    tree: &HashedTree,
+) -> std::result::Result<StrongReference, StoreError> {
+    let digest = *tree.digest();
+    let tree_id = match find_tree_ids(&state.connection, std::slice::from_ref(&digest))
+        .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?
+        .get(&digest)
+    {
+        Some(tree_id) => *tree_id,
+        None => {
+            let mut result = store_trees_locked(state, std::slice::from_ref(tree))?;
+            return Ok(result.pop().expect("One reference per tree"));
+        }
+    };
+    let children: Vec<BlobDigest> = tree
+        .tree()
+        .children()
+        .references()
+        .iter()
+        .map(|child| *child.digest())
+        .collect();
+    let existing_children = find_tree_ids(&state.connection, &children)
+        .map_err(|error| StoreError::Rusqlite(format!("{}", error)))?;
+    if let Some(missing) = children
+        .iter()
+        .find(|child| !existing_children.contains_key(child))
+    {
+        return Err(StoreError::TreeMissing(LoadError::TreeNotFound(*missing)));
+    }
+
+    state
+        .require_transaction(1 + children.len() as u64)
+        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    let encoded = state.encode_tree_blob(tree.tree().blob().as_slice());
+    let save_point = state
+        .connection
+        .savepoint()
+        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    let rows_updated = save_point
+        .execute(
+            "UPDATE tree SET tree_blob = ?1, codec = ?2, dictionary = ?3 WHERE id = ?4",
+            (encoded.data, encoded.codec, encoded.dictionary, tree_id),
+        )
+        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    assert_eq!(1, rows_updated);
+    save_point
+        .execute("DELETE FROM reference WHERE origin = ?1", (tree_id,))
+        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    for (index, child) in children.iter().enumerate() {
+        save_point
+            .prepare_cached(
+                "INSERT INTO reference (origin, zero_based_index, target) VALUES (?1, ?2, ?3)",
+            )
+            .and_then(|mut statement| {
+                statement.execute((
+                    tree_id,
+                    i64::try_from(index).expect("A child index won't be too large"),
+                    child.to_tagged_bytes(),
+                ))
+            })
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    }
+    save_point
+        .commit()
+        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    // The readers still see the old content until the transaction is committed.
+    state
+        .shared
+        .lock()
+        .unwrap()
+        .uncommitted_trees
+        .insert(digest);
+    Ok(state
+        .garbage_collector
+        .require_additional_root_entry(&digest, tree_id))
+}
+
+#[async_trait]
+impl StoreTree for SQLiteStorage {
+    //#[instrument(skip_all)]
+    async fn store_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        let mut result = store_trees_locked(&mut state_locked, std::slice::from_ref(tree))?;
+        Ok(result.pop().expect("One reference per tree"))
+    }
+
+    async fn store_trees(
+        &self,
+        trees: &[HashedTree],
+    ) -> std::result::Result<Vec<StrongReference>, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        store_trees_locked(&mut state_locked, trees)
+    }
+
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
+    }
+}
+
+/// A tree as found in the database, before any [StrongReference]s were handed out.
+#[derive(Debug)]
+struct TreeRow {
+    tree_id: i64,
+    blob: TreeBlob,
+    /// Digest and tree ID of each child.
+    children: Vec<(BlobDigest, i64)>,
+}
+
+/// Reads the trees in the order of `references`. Fails if any of them is missing or broken.
+fn read_trees(
+    connection: &rusqlite::Connection,
+    dictionaries: &CompressionDictionaries,
+    references: &[BlobDigest],
+) -> std::result::Result<Vec<TreeRow>, LoadError> {
+    let unique: Vec<BlobDigest> = references
+        .iter()
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let mut rows: BTreeMap<BlobDigest, std::result::Result<(i64, Vec<u8>), LoadError>> =
+        BTreeMap::new();
+    for chunk in unique.chunks(MAX_PARAMETERS_PER_STATEMENT) {
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT id, digest, tree_blob, codec, dictionary FROM tree WHERE digest IN ({})",
+                vec!["?"; chunk.len()].join(", ")
+            ))
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+        let chunk_rows = statement
+            .query_map(
+                rusqlite::params_from_iter(chunk),
+                |row| -> rusqlite::Result<_> {
+                    let id: i64 = row.get(0)?;
+                    let digest: BlobDigest = row.get(1)?;
+                    let tree_blob_raw: Vec<u8> = row.get(2)?;
+                    let codec: i64 = row.get(3)?;
+                    let dictionary: Option<i64> = row.get(4)?;
+                    let decompressed_data =
+                        decode_tree_blob(tree_blob_raw, codec, dictionary, dictionaries)
+                            .map_err(|message| LoadError::Inconsistency(digest, message));
+                    Ok((digest, decompressed_data.map(|data| (id, data))))
+                },
+            )
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+        for row in chunk_rows {
+            let (digest, result) = row.map_err(|sql_error| {
+                error!("Error loading tree from the database: {sql_error:?}");
+                LoadError::Rusqlite(format!("{}", sql_error))
+            })?;
+            rows.insert(digest, result);
+        }
+    }
+
+    let tree_ids: Vec<i64> = rows
+        .values()
+        .filter_map(|row| row.as_ref().ok().map(|(id, _)| *id))
+        .collect();
+    let mut children_by_origin: BTreeMap<i64, Vec<(i64, BlobDigest, i64)>> = BTreeMap::new();
+    for chunk in tree_ids.chunks(MAX_PARAMETERS_PER_STATEMENT) {
+        let mut statement = connection
+            .prepare(&format!(
+                concat!(
+                    "SELECT reference.origin, reference.zero_based_index, reference.target, tree.id FROM reference, tree",
+                    " WHERE reference.origin IN ({}) AND reference.target = tree.digest",
+                    " ORDER BY reference.origin ASC, reference.zero_based_index ASC"
+                ),
+                vec!["?"; chunk.len()].join(", ")
+            ))
             .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
-        let (tree_id, decompressed_data) =
-            match statement.query_row((&digest,), |row| -> rusqlite::Result<_> {
-                let id: i64 = row.get(0)?;
-                let tree_blob_raw: Vec<u8> = row.get(1)?;
-                let is_compressed: i32 = row.get(2)?;
-                // Decompress if needed
-                let decompressed_data = match is_compressed {
-                    1 => match lz4_flex::decompress_size_prepended(&tree_blob_raw) {
-                        Ok(data) => data,
-                        Err(error) => {
-                            let message =
-                                format!("Failed to decompress tree blob using lz4: {error:?}");
-                            return Ok(Err(LoadError::Inconsistency(*reference, message)));
-                        }
-                    },
-                    0 => tree_blob_raw,
-                    _ => {
-                        let message = format!(
-                            "Invalid is_compressed value: {is_compressed}, expected 0 or 1"
-                        );
-                        return Ok(Err(LoadError::Inconsistency(*reference, message)));
-                    }
-                };
-                Ok(Ok((id, decompressed_data)))
-            }) {
-                Ok(maybe_tuple) => maybe_tuple?,
-                Err(rusqlite::Error::QueryReturnedNoRows) => {
-                    error!("No tree found for digest {reference} in the database.");
-                    return Err(LoadError::TreeNotFound(*reference));
-                }
-                Err(sql_error) => {
-                    error!("Error loading tree from the database: {sql_error:?}");
-                    return Err(LoadError::Rusqlite(format!("{}", sql_error)));
-                }
-            };
-        // Keep the parent alive while we load the children to prevent it from being garbage collected in the middle of loading.
-        let root_reference = garbage_collector
-            .require_additional_root(reference, tree_id, connection_locked)
-            .map_err(|error| LoadError::Rusqlite(error.to_string()))?;
-        let tree_blob = TreeBlob::try_from(decompressed_data.into())
-            .map_err(|error| LoadError::Deserialization(*reference, error))?;
-        let mut statement = connection_locked
-        .prepare_cached(concat!(
-            "SELECT reference.zero_based_index, reference.target, tree.id FROM reference, tree",
-            " WHERE reference.origin = ? AND reference.target = tree.digest ORDER BY reference.zero_based_index ASC"
-        ))
-        .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
         let child_results = statement
-            .query_map([&tree_id], |row| {
-                let index: i64 = row.get(0)?;
-                let target: [u8; 64] = row.get(1)?;
-                let child_tree_id: i64 = row.get(2)?;
-                Ok((index, BlobDigest::new(&target), child_tree_id))
+            .query_map(rusqlite::params_from_iter(chunk.iter()), |row| {
+                let origin: i64 = row.get(0)?;
+                let index: i64 = row.get(1)?;
+                let target: BlobDigest = row.get(2)?;
+                let child_tree_id: i64 = row.get(3)?;
+                Ok((origin, index, target, child_tree_id))
             })
             .map_err(|error| LoadError::Rusqlite(format!("{}", &error)))?;
-        let child_digests: Vec<(BlobDigest, i64)> = {
-            let mut result = Vec::new();
-            for (expected_index, maybe_tuple) in child_results.enumerate() {
-                let tuple =
-                    maybe_tuple.map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
-                let target = tuple.1;
-                let actual_index = tuple.0;
-                let child_tree_id = tuple.2;
-                if expected_index as i64 != actual_index {
-                    return Err(LoadError::Inconsistency(
-                        *reference,
-                        format!(
-                            "Expected index {}, but got {}",
-                            expected_index, actual_index
-                        ),
-                    ));
-                }
-                result.push((target, child_tree_id));
+        for child in child_results {
+            let (origin, index, target, child_tree_id) =
+                child.map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+            children_by_origin
+                .entry(origin)
+                .or_default()
+                .push((index, target, child_tree_id));
+        }
+    }
+
+    let mut result = Vec::with_capacity(references.len());
+    for reference in references {
+        let (tree_id, decompressed_data) = match rows.get(reference) {
+            Some(Ok((tree_id, decompressed_data))) => (*tree_id, decompressed_data),
+            Some(Err(error)) => return Err(error.clone()),
+            None => {
+                error!("No tree found for digest {reference} in the database.");
+                return Err(LoadError::TreeNotFound(*reference));
             }
-            result
-        };
-        (tree_blob, child_digests, root_reference)
-    };
-    let mut child_references = Vec::new();
-    for (child_digest, child_tree_id) in child_digests {
-        let (connection_locked, garbage_collector) = {
-            let state = &mut *state_locked;
-            (&state.connection, &mut state.garbage_collector)
         };
-        let child_reference = garbage_collector
-            .require_additional_root(&child_digest, child_tree_id, connection_locked)
-            .map_err(|error| LoadError::Rusqlite(error.to_string()))?;
-        child_references.push(child_reference);
-    }
-    let child_count = child_references.len();
-    let children = match TreeChildren::try_from(child_references) {
-        Some(children) => children,
-        None => {
-            let message = format!("Tree has too many children: {}", child_count);
+        let tree_blob = TreeBlob::try_from(decompressed_data.clone().into())
+            .map_err(|error| LoadError::Deserialization(*reference, error))?;
+        let child_digests = children_by_origin
+            .get(&tree_id)
+            .map(|children| children.as_slice())
+            .unwrap_or_default();
+        for (expected_index, (actual_index, _, _)) in child_digests.iter().enumerate() {
+            if expected_index as i64 != *actual_index {
+                return Err(LoadError::Inconsistency(
+                    *reference,
+                    format!(
+                        "Expected index {}, but got {}",
+                        expected_index, actual_index
+                    ),
+                ));
+            }
+        }
+        if child_digests.len() > TREE_MAX_CHILDREN {
+            let message = format!("Tree has too many children: {}", child_digests.len());
             error!("{}", message);
             return Err(LoadError::Inconsistency(*reference, message));
         }
-    };
-    let tree = DelayedHashedTree::delayed(Arc::new(Tree::new(tree_blob, children)), *reference);
-    Ok(StrongDelayedHashedTree::new(root_reference, tree))
+        result.push(TreeRow {
+            tree_id,
+            blob: tree_blob,
+            children: child_digests
+                .iter()
+                .map(|(_, child_digest, child_tree_id)| (*child_digest, *child_tree_id))
+                .collect(),
+        });
+    }
+    Ok(result)
+}
+
+/// Hands out the [StrongReference]s for trees returned by [read_trees].
+fn register_trees(
+    shared: &mut SharedState,
+    references: &[BlobDigest],
+    rows: Vec<TreeRow>,
+) -> Vec<StrongDelayedHashedTree> {
+    references
+        .iter()
+        .zip(rows)
+        .map(|(reference, row)| {
+            // Keep the parent alive while we load the children to prevent it from being garbage collected in the middle of loading.
+            let root_reference = shared.require_additional_root(reference, row.tree_id);
+            let child_references: Vec<StrongReference> = row
+                .children
+                .iter()
+                .map(|(child_digest, child_tree_id)| {
+                    shared.require_additional_root(child_digest, *child_tree_id)
+                })
+                .collect();
+            let children = TreeChildren::try_from(child_references)
+                .expect("read_trees checks the number of children");
+            let tree =
+                DelayedHashedTree::delayed(Arc::new(Tree::new(row.blob, children)), *reference);
+            StrongDelayedHashedTree::new(root_reference, tree)
+        })
+        .collect()
+}
+
+fn load_trees_locked(
+    state: &mut SQLiteState,
+    references: &[BlobDigest],
+) -> std::result::Result<Vec<StrongDelayedHashedTree>, LoadError> {
+    let rows = read_trees(&state.connection, &state.dictionaries, references)?;
+    let result = register_trees(&mut state.shared.lock().unwrap(), references, rows);
+    state
+        .garbage_collector
+        .check_automatic_collection(&state.connection)
+        .map_err(|error| LoadError::Rusqlite(error.to_string()))?;
+    Ok(result)
+}
+
+fn read_root(
+    connection: &rusqlite::Connection,
+    name: &str,
+) -> std::result::Result<Option<(BlobDigest, i64)>, LoadError> {
+    connection
+        .query_row(
+            "SELECT root.target, tree.id FROM root, tree WHERE root.name = ?1 AND root.target = tree.digest",
+            (&name,),
+            |row| -> rusqlite::Result<_> {
+                let target = row.get(0)?;
+                let tree_id: i64 = row.get(1)?;
+                Ok((target, tree_id))
+            },
+        )
+        .optional()
+        .map_err(|err| LoadError::Rusqlite(format!("{}", err)))
+}
+
+fn count_trees(connection: &rusqlite::Connection) -> std::result::Result<u64, StoreError> {
+    match connection
+        .query_row_and_then(
+            "SELECT COUNT(*) FROM tree",
+            (),
+            |row| -> rusqlite::Result<_> {
+                let count: i64 = row.get(0)?;
+                Ok(count)
+            },
+        )
+        .map_err(|error| StoreError::Rusqlite(format!("{}", error)))
+    {
+        Ok(count) => Ok(u64::try_from(count).expect("COUNT(*) won't be negative")),
+        Err(err) => Err(err),
+    }
 }
 
 #[async_trait]
@@ -541,31 +2009,183 @@ impl LoadTree for SQLiteStorage {
         &self,
         reference: &BlobDigest,
     ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
-        load_tree_impl(&self.state, reference).await
+        let mut result = self.load_trees(std::slice::from_ref(reference)).await?;
+        Ok(result.pop().expect("One tree per reference"))
     }
 
-    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+    async fn load_trees(
+        &self,
+        references: &[BlobDigest],
+    ) -> std::result::Result<Vec<StrongDelayedHashedTree>, LoadError> {
+        // Only the trees stored in the open transaction need the writer, the readers see all of the others.
+        let is_uncommitted: Vec<bool> = {
+            let shared = self.shared.lock().unwrap();
+            references
+                .iter()
+                .map(|reference| shared.uncommitted_trees.contains(reference))
+                .collect()
+        };
+        let mut committed = Vec::new();
+        let mut uncommitted = Vec::new();
+        for (reference, is_uncommitted) in references.iter().zip(&is_uncommitted) {
+            if *is_uncommitted {
+                uncommitted.push(*reference);
+            } else {
+                committed.push(*reference);
+            }
+        }
+        if !committed.is_empty() {
+            if let Some(result) = self.load_trees_from_reader(&committed).await {
+                let from_reader = result?;
+                if uncommitted.is_empty() {
+                    return Ok(from_reader);
+                }
+                let mut from_writer = {
+                    let mut state_locked = self.state.lock().await;
+                    load_trees_locked(&mut state_locked, &uncommitted)?
+                }
+                .into_iter();
+                let mut from_reader = from_reader.into_iter();
+                return Ok(is_uncommitted
+                    .iter()
+                    .map(|is_uncommitted| {
+                        if *is_uncommitted {
+                            from_writer.next()
+                        } else {
+                            from_reader.next()
+                        }
+                        .expect("One tree per reference")
+                    })
+                    .collect());
+            }
+        }
+        let mut state_locked = self.state.lock().await;
+        load_trees_locked(&mut state_locked, references)
+    }
+
+    async fn find_existing_trees(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<bool>, LoadError> {
         let state_locked = self.state.lock().await;
-        let connection_locked = &state_locked.connection;
-        match connection_locked
-            .query_row_and_then(
-                "SELECT COUNT(*) FROM tree",
-                (),
-                |row| -> rusqlite::Result<_> {
-                    let count: i64 = row.get(0)?;
-                    Ok(count)
-                },
-            )
-            .map_err(|error| StoreError::Rusqlite(format!("{}", error)))
-        {
-            Ok(count) => Ok(u64::try_from(count).expect("COUNT(*) won't be negative")),
-            Err(err) => Err(err),
+        let existing = find_tree_ids(&state_locked.connection, digests)
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+        Ok(digests
+            .iter()
+            .map(|digest| existing.contains_key(digest))
+            .collect())
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        let has_uncommitted_trees = !self.shared.lock().unwrap().uncommitted_trees.is_empty();
+        if !has_uncommitted_trees {
+            if let Some((count, _)) = self.read(|connection, _| count_trees(connection)).await {
+                return count;
+            }
         }
+        let state_locked = self.state.lock().await;
+        count_trees(&state_locked.connection)
     }
 }
 
 impl LoadStoreTree for SQLiteStorage {}
 
+#[async_trait]
+impl ReplaceTree for SQLiteStorage {
+    async fn replace_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError> {
+        let mut state_locked = self.state.lock().await;
+        replace_tree_locked(&mut state_locked, tree)
+    }
+}
+
+#[async_trait]
+impl MeasureStoredSize for SQLiteStorage {
+    async fn stored_sizes(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<Option<u64>>, LoadError> {
+        let state_locked = self.state.lock().await;
+        let mut sizes: BTreeMap<BlobDigest, u64> = BTreeMap::new();
+        for chunk in digests.chunks(MAX_PARAMETERS_PER_STATEMENT) {
+            let mut statement = state_locked
+                .connection
+                .prepare(&format!(
+                    "SELECT digest, LENGTH(tree_blob) FROM tree WHERE digest IN ({})",
+                    vec!["?"; chunk.len()].join(", ")
+                ))
+                .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+            let rows = statement
+                .query_map(rusqlite::params_from_iter(chunk), |row| {
+                    let digest: BlobDigest = row.get(0)?;
+                    let size: i64 = row.get(1)?;
+                    Ok((digest, size as u64))
+                })
+                .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+            for row in rows {
+                let (digest, size) =
+                    row.map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+                sizes.insert(digest, size);
+            }
+        }
+        Ok(digests
+            .iter()
+            .map(|digest| sizes.get(digest).copied())
+            .collect())
+    }
+}
+
+fn update_root_locked(
+    state: &mut SQLiteState,
+    name: &str,
+    target: &StrongReference,
+) -> std::result::Result<(), StoreError> {
+    state
+        .require_transaction(1)
+        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    let connection_locked = &state.connection;
+    let target_bytes = target.digest().to_tagged_bytes();
+    let _tree_id = match connection_locked.query_row(
+        "SELECT id FROM tree WHERE digest = ?1",
+        (&target_bytes,),
+        |row| -> rusqlite::Result<i64> { row.get(0) },
+    ) {
+        Ok(id) => id,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(StoreError::TreeMissing(LoadError::TreeNotFound(
+                *target.digest(),
+            )))
+        }
+        Err(err) => return Err(StoreError::Rusqlite(format!("{}", err))),
+    };
+    // TODO: use tree_id as target in the query
+    connection_locked.execute(
+        "INSERT INTO root (name, target) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET target = ?2;",
+        (&name, &target_bytes),
+    )
+    .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    let updated_at = to_unix_micros((state.garbage_collector.configuration.clock)());
+    connection_locked
+        .execute(
+            "INSERT INTO root_history (name, target, updated_at) VALUES (?1, ?2, ?3)",
+            (&name, &target_bytes, updated_at),
+        )
+        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+    state.uncommitted_root_changes.push(RootChange {
+        name: name.to_string(),
+        target: *target.digest(),
+    });
+    state
+        .shared
+        .lock()
+        .unwrap()
+        .uncommitted_roots
+        .insert(name.to_string());
+    Ok(())
+}
+
 #[async_trait]
 impl UpdateRoot for SQLiteStorage {
     //#[instrument(skip_all)]
@@ -576,32 +2196,114 @@ impl UpdateRoot for SQLiteStorage {
     ) -> std::result::Result<(), StoreError> {
         info!("Update root {} to {}", name, target);
         let mut state_locked = self.state.lock().await;
+        update_root_locked(&mut state_locked, name, target)
+    }
+
+    async fn compare_and_swap_root(
+        &self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &StrongReference,
+    ) -> std::result::Result<CompareAndSwapResult, StoreError> {
+        info!("Compare and swap root {} to {}", name, new);
+        let mut state_locked = self.state.lock().await;
+        let actual: Option<BlobDigest> = state_locked
+            .connection
+            .query_row(
+                "SELECT target FROM root WHERE name = ?1",
+                (&name,),
+                |row| -> rusqlite::Result<_> {
+                    let target = row.get(0)?;
+                    Ok(target)
+                },
+            )
+            .optional()
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+        if actual.as_ref() != expected {
+            return Ok(CompareAndSwapResult::Conflict { actual });
+        }
+        update_root_locked(&mut state_locked, name, new)?;
+        Ok(CompareAndSwapResult::Swapped)
+    }
+}
+
+#[async_trait]
+impl PinTrees for SQLiteStorage {
+    async fn pin(
+        &self,
+        name: &str,
+        target: &StrongReference,
+        expires_at: Option<std::time::SystemTime>,
+    ) -> std::result::Result<(), StoreError> {
+        info!("Pin {} to {} until {:?}", name, target, expires_at);
+        let mut state_locked = self.state.lock().await;
         state_locked
             .require_transaction(1)
             .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
-        let connection_locked = &state_locked.connection;
-        let target_array: [u8; 64] = (*target.digest()).into();
-        let _tree_id = match connection_locked.query_row(
-            "SELECT id FROM tree WHERE digest = ?1",
-            (&target_array,),
-            |row| -> rusqlite::Result<i64> { row.get(0) },
-        ) {
-            Ok(id) => id,
-            Err(rusqlite::Error::QueryReturnedNoRows) => {
-                return Err(StoreError::TreeMissing(LoadError::TreeNotFound(
-                    *target.digest(),
-                )))
-            }
-            Err(err) => return Err(StoreError::Rusqlite(format!("{}", err))),
-        };
-        // TODO: use tree_id as target in the query
-        connection_locked.execute(
-            "INSERT INTO root (name, target) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET target = ?2;",
-            (&name, &target_array),
-        )
-        .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+        let target_bytes = target.digest().to_tagged_bytes();
+        let exists = state_locked
+            .connection
+            .query_row(
+                "SELECT 1 FROM tree WHERE digest = ?1",
+                (&target_bytes,),
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?
+            .is_some();
+        if !exists {
+            return Err(StoreError::TreeMissing(LoadError::TreeNotFound(
+                *target.digest(),
+            )));
+        }
+        state_locked
+            .connection
+            .execute(
+                "INSERT INTO pin (name, target, expires_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT(name) DO UPDATE SET target = ?2, expires_at = ?3",
+                (&name, &target_bytes, expires_at.map(to_unix_micros)),
+            )
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
         Ok(())
     }
+
+    async fn unpin(&self, name: &str) -> std::result::Result<bool, StoreError> {
+        info!("Unpin {}", name);
+        let mut state_locked = self.state.lock().await;
+        state_locked
+            .require_transaction(1)
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+        let deleted = state_locked
+            .connection
+            .execute("DELETE FROM pin WHERE name = ?1", (&name,))
+            .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
+        Ok(deleted > 0)
+    }
+
+    async fn list_pins(&self) -> std::result::Result<Vec<Pin>, LoadError> {
+        let state_locked = self.state.lock().await;
+        let now = to_unix_micros((state_locked.garbage_collector.configuration.clock)());
+        let mut statement = state_locked
+            .connection
+            .prepare_cached(
+                "SELECT name, target, expires_at FROM pin
+                WHERE expires_at IS NULL OR expires_at > ?1
+                ORDER BY name ASC",
+            )
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+        let rows = statement
+            .query_map((now,), |row| {
+                let expires_at: Option<i64> = row.get(2)?;
+                Ok(Pin {
+                    name: row.get(0)?,
+                    target: row.get(1)?,
+                    expires_at: expires_at.map(from_unix_micros),
+                })
+            })
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))?;
+        rows.collect::<rusqlite::Result<_>>()
+            .map_err(|error| LoadError::Rusqlite(format!("{}", error)))
+    }
 }
 
 #[async_trait]
@@ -626,20 +2328,24 @@ impl LoadRoot for SQLiteStorage {
         &self,
         name: &str,
     ) -> std::result::Result<Option<StrongReference>, LoadError> {
+        let is_uncommitted = self.shared.lock().unwrap().uncommitted_roots.contains(name);
+        if is_uncommitted {
+            // The readers would still see the old target.
+        } else if let Some((target, deletion_sequence)) =
+            self.read(|connection, _| read_root(connection, name)).await
+        {
+            match target? {
+                Some((digest, tree_id)) => {
+                    let mut shared = self.shared.lock().unwrap();
+                    if shared.deletion_sequence == deletion_sequence {
+                        return Ok(Some(shared.require_additional_root(&digest, tree_id)));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
         let mut state_locked = self.state.lock().await;
-        let connection_locked = &state_locked.connection;
-        let target: Option<(BlobDigest, i64)> = connection_locked
-            .query_row(
-                "SELECT root.target, tree.id FROM root, tree WHERE root.name = ?1 AND root.target = tree.digest",
-                (&name,),
-                |row| -> rusqlite::Result<_> {
-                    let target = row.get(0)?;
-                    let tree_id: i64 = row.get(1)?;
-                    Ok((BlobDigest::new(&target), tree_id))
-                },
-            )
-            .optional()
-            .map_err(|err| LoadError::Rusqlite(format!("{}", err)))?;
+        let target = read_root(&state_locked.connection, name)?;
         match target {
             Some((digest, tree_id)) => {
                 let (connection_locked, garbage_collector) = {
@@ -656,6 +2362,12 @@ impl LoadRoot for SQLiteStorage {
     }
 }
 
+impl WatchRoots for SQLiteStorage {
+    fn watch_roots(&self) -> RootChangeStream {
+        root_change_stream(self.root_changes.subscribe())
+    }
+}
+
 #[async_trait]
 impl CommitChanges for SQLiteStorage {
     #[instrument(skip_all)]
@@ -670,9 +2382,29 @@ impl CommitChanges for SQLiteStorage {
                     .map_err(|err| StoreError::Rusqlite(format!("{}", err)))?;
                 let writes = stats.writes;
                 state_locked.transaction = None;
+                {
+                    let mut shared = state_locked.shared.lock().unwrap();
+                    shared.uncommitted_trees.clear();
+                    shared.uncommitted_roots.clear();
+                    shared.has_uncommitted_deletions = false;
+                    shared.stats.commits += 1;
+                    shared.stats.writes_committed += writes;
+                }
+                for change in state_locked.uncommitted_root_changes.drain(..) {
+                    // Nobody may be subscribed, which is fine.
+                    let _ = self.root_changes.send(change);
+                }
                 Ok(writes)
             }
-            None => Ok(0),
+            None => {
+                // Without a transaction, every deletion has been committed already.
+                state_locked
+                    .shared
+                    .lock()
+                    .unwrap()
+                    .has_uncommitted_deletions = false;
+                Ok(0)
+            }
         }
     }
 }
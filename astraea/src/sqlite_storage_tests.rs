@@ -1,14 +1,26 @@
 use crate::{
-    sqlite_storage::SQLiteStorage,
+    blob_codec::{BlobCodec, ZSTD_DEFAULT_LEVEL},
+    prometheus_text::PrometheusText,
+    sqlite_storage::{
+        Clock, DictionaryTrainingError, MarkAndSweepBudget, MarkAndSweepPhase,
+        MarkAndSweepProgress, OpenError, RecompressionStats, RootHistoryEntry, SQLiteStorage,
+        SQLiteStorageConfiguration, SQLiteStorageStats, SCHEMA_VERSION,
+    },
     storage::{
-        CollectGarbage, CommitChanges, GarbageCollectionStats, LoadError, LoadRoot, LoadTree,
-        StoreError, StoreTree, UpdateRoot,
+        CollectGarbage, CommitChanges, CompareAndSwapResult, GarbageCollectionStats, LoadError,
+        LoadRoot, LoadTree, Pin, PinTrees, RootChange, StoreError, StoreTree, StrongReference,
+        UpdateRoot, WatchRoots,
+    },
+    tree::{
+        BlobDigest, DigestAlgorithm, HashedTree, Tree, TreeBlob, TreeChildren,
+        TREE_BLOB_MAX_LENGTH, TREE_MAX_CHILDREN,
     },
-    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren, TREE_MAX_CHILDREN},
 };
 use bytes::Bytes;
+use futures_util::FutureExt;
 use pretty_assertions::assert_eq;
 use std::sync::Arc;
+use tokio_stream::StreamExt;
 
 #[test_log::test]
 fn test_create_schema() {
@@ -512,10 +524,10 @@ async fn test_compression_load_corrupted_blob() {
     let connection = rusqlite::Connection::open_in_memory().unwrap();
     SQLiteStorage::create_schema(&connection).unwrap();
     let digest = BlobDigest::parse_hex_string("f0140e314ee38d4472393680e7a72a81abb36b134b467d90ea943b7aa1ea03bf2323bc1a2df91f7230a225952e162f6629cf435e53404e9cdd727a2d94e4f909").unwrap();
-    let digest_array: [u8; 64] = digest.into();
+    let digest_array = digest.to_tagged_bytes();
     connection
         .execute(
-            "INSERT INTO tree (digest, is_compressed, tree_blob) VALUES (?1, ?2, ?3)",
+            "INSERT INTO tree (digest, codec, tree_blob) VALUES (?1, ?2, ?3)",
             rusqlite::params![
                 digest_array,
                 1u8,
@@ -572,7 +584,7 @@ async fn test_load_too_many_children() {
     {
         let connection2 = rusqlite::Connection::open(&database_path).unwrap();
         let tree_row_id: i64 = {
-            let stored_array: [u8; 64] = (*stored.digest()).into();
+            let stored_array = stored.digest().to_tagged_bytes();
             let mut statement = connection2
                 .prepare_cached("SELECT id FROM tree WHERE digest = ?1")
                 .unwrap();
@@ -585,7 +597,7 @@ async fn test_load_too_many_children() {
                 )
                 .unwrap()
         };
-        let reference_array: [u8; 64] = reference.into();
+        let reference_array = reference.to_tagged_bytes();
         connection2
             .execute(
                 "INSERT INTO reference (origin, zero_based_index, target) VALUES (?1, ?2, ?3)",
@@ -736,3 +748,1381 @@ async fn test_sql_errors() {
         storage.collect_some_garbage().await
     );
 }
+
+fn make_leaf(index: u32) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::copy_from_slice(&index.to_be_bytes())).unwrap(),
+        TreeChildren::empty(),
+    )))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_trees_and_load_trees() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let existing = make_leaf(0);
+    storage.store_tree(&existing).await.unwrap();
+    assert_eq!(1, storage.commit_changes().await.unwrap());
+
+    let leaf = make_leaf(1);
+    let parent = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::empty(),
+        TreeChildren::try_from(vec![
+            StrongReference::from_weak(*leaf.digest()),
+            StrongReference::from_weak(*existing.digest()),
+        ])
+        .unwrap(),
+    )));
+    let batch = vec![leaf.clone(), parent.clone(), existing.clone(), leaf.clone()];
+    let references = storage.store_trees(&batch).await.unwrap();
+    assert_eq!(
+        batch.iter().map(|tree| *tree.digest()).collect::<Vec<_>>(),
+        references
+            .iter()
+            .map(|reference| *reference.digest())
+            .collect::<Vec<_>>()
+    );
+    // leaf, parent and its two references
+    assert_eq!(4, storage.commit_changes().await.unwrap());
+    assert_eq!(3, storage.approximate_tree_count().await.unwrap());
+
+    let digests = vec![*parent.digest(), *leaf.digest(), *parent.digest()];
+    let loaded: Vec<HashedTree> = storage
+        .load_trees(&digests)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|loaded| loaded.hash().unwrap().hashed_tree().clone())
+        .collect();
+    assert_eq!(vec![parent.clone(), leaf.clone(), parent.clone()], loaded);
+
+    let missing = *make_leaf(2).digest();
+    assert_eq!(
+        vec![true, false, true],
+        storage
+            .find_existing_trees(&[*leaf.digest(), missing, *existing.digest()])
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        LoadError::TreeNotFound(missing),
+        storage
+            .load_trees(&[*leaf.digest(), missing])
+            .await
+            .unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_trees_missing_child_writes_nothing() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let missing = make_leaf(1);
+    let parent = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::empty(),
+        TreeChildren::try_from(vec![StrongReference::from_weak(*missing.digest())]).unwrap(),
+    )));
+    assert_eq!(
+        StoreError::TreeMissing(LoadError::TreeNotFound(*missing.digest())),
+        storage
+            .store_trees(&[make_leaf(0), parent])
+            .await
+            .unwrap_err()
+    );
+    assert_eq!(0, storage.commit_changes().await.unwrap());
+    assert_eq!(0, storage.approximate_tree_count().await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_trees_many() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    // More trees and references than fit into a single statement.
+    let leaves: Vec<HashedTree> = (0..TREE_MAX_CHILDREN as u32).map(make_leaf).collect();
+    let parent = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::empty(),
+        TreeChildren::try_from(
+            leaves
+                .iter()
+                .map(|leaf| StrongReference::from_weak(*leaf.digest()))
+                .collect(),
+        )
+        .unwrap(),
+    )));
+    let mut batch = leaves.clone();
+    batch.push(parent.clone());
+    let references = storage.store_trees(&batch).await.unwrap();
+    assert_eq!(batch.len(), references.len());
+    assert_eq!(
+        2 * TREE_MAX_CHILDREN as u64 + 1,
+        storage.commit_changes().await.unwrap()
+    );
+    let loaded = storage
+        .load_tree(parent.digest())
+        .await
+        .unwrap()
+        .hash()
+        .unwrap();
+    assert_eq!(&parent, loaded.hashed_tree());
+    let digests: Vec<BlobDigest> = leaves.iter().map(|leaf| *leaf.digest()).collect();
+    let loaded_leaves: Vec<HashedTree> = storage
+        .load_trees(&digests)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|loaded| loaded.hash().unwrap().hashed_tree().clone())
+        .collect();
+    assert_eq!(leaves, loaded_leaves);
+}
+
+struct TestClock {
+    seconds: Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl TestClock {
+    fn new() -> Self {
+        Self {
+            seconds: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        }
+    }
+
+    fn set(&self, seconds: u64) {
+        self.seconds
+            .store(seconds, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn clock(&self) -> Clock {
+        let seconds = self.seconds.clone();
+        Arc::new(move || {
+            std::time::UNIX_EPOCH
+                + std::time::Duration::from_secs(seconds.load(std::sync::atomic::Ordering::SeqCst))
+        })
+    }
+}
+
+fn at(seconds: u64) -> std::time::SystemTime {
+    std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds)
+}
+
+async fn store_versions(storage: &SQLiteStorage, clock: &TestClock) -> Vec<BlobDigest> {
+    let mut digests = Vec::new();
+    for (version, seconds) in [(1u32, 10), (2, 20), (3, 30)] {
+        clock.set(seconds);
+        let reference = storage.store_tree(&make_leaf(version)).await.unwrap();
+        storage.update_root("test", &reference).await.unwrap();
+        digests.push(*reference.digest());
+    }
+    digests
+}
+
+#[test_log::test(tokio::test)]
+async fn test_root_history() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let clock = TestClock::new();
+    let storage = SQLiteStorage::from_with_configuration(
+        connection,
+        SQLiteStorageConfiguration {
+            root_history_retention: std::time::Duration::ZERO,
+            clock: clock.clock(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let digests = store_versions(&storage, &clock).await;
+    assert_eq!(
+        vec![
+            RootHistoryEntry {
+                target: digests[0],
+                updated_at: at(10),
+            },
+            RootHistoryEntry {
+                target: digests[1],
+                updated_at: at(20),
+            },
+            RootHistoryEntry {
+                target: digests[2],
+                updated_at: at(30),
+            },
+        ],
+        storage.root_history("test").await.unwrap()
+    );
+    assert_eq!(
+        Vec::<RootHistoryEntry>::new(),
+        storage.root_history("other").await.unwrap()
+    );
+    assert_eq!(None, storage.load_root_at("test", at(9)).await.unwrap());
+    for (seconds, expected) in [(10, 0), (19, 0), (20, 1), (29, 1), (30, 2), (1000, 2)] {
+        assert_eq!(
+            Some(digests[expected]),
+            storage
+                .load_root_at("test", at(seconds))
+                .await
+                .unwrap()
+                .map(|reference| *reference.digest())
+        );
+    }
+
+    // Without retention, the garbage collector only keeps the current target.
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 2 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        vec![RootHistoryEntry {
+            target: digests[2],
+            updated_at: at(30),
+        }],
+        storage.root_history("test").await.unwrap()
+    );
+    assert_eq!(None, storage.load_root_at("test", at(15)).await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_root_history_retention() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let clock = TestClock::new();
+    let storage = SQLiteStorage::from_with_configuration(
+        connection,
+        SQLiteStorageConfiguration {
+            root_history_retention: std::time::Duration::from_secs(15),
+            clock: clock.clock(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let digests = store_versions(&storage, &clock).await;
+
+    // The first version was replaced at 20, which is within the window starting at 15.
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 0 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(3, storage.root_history("test").await.unwrap().len());
+
+    // Now the window starts at 25, and only the first version had been replaced before.
+    clock.set(40);
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 1 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        vec![digests[1], digests[2]],
+        storage
+            .root_history("test")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.target)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        LoadError::TreeNotFound(digests[0]),
+        storage.load_tree(&digests[0]).await.unwrap_err()
+    );
+    assert_eq!(
+        Some(digests[1]),
+        storage
+            .load_root_at("test", at(25))
+            .await
+            .unwrap()
+            .map(|reference| *reference.digest())
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_root_history_table_is_added_to_old_databases() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    {
+        let connection = rusqlite::Connection::open(&database_path).unwrap();
+        SQLiteStorage::create_schema(&connection).unwrap();
+        connection.execute("DROP TABLE root_history", ()).unwrap();
+        // Databases this old didn't have a schema version yet.
+        connection.pragma_update(None, "user_version", 0).unwrap();
+    }
+    let storage = SQLiteStorage::from(rusqlite::Connection::open(&database_path).unwrap()).unwrap();
+    let reference = storage.store_tree(&make_leaf(1)).await.unwrap();
+    storage.update_root("test", &reference).await.unwrap();
+    assert_eq!(1, storage.root_history("test").await.unwrap().len());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_compare_and_swap_root() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let first = storage.store_tree(&make_leaf(1)).await.unwrap();
+    let second = storage.store_tree(&make_leaf(2)).await.unwrap();
+    assert_eq!(
+        CompareAndSwapResult::Conflict { actual: None },
+        storage
+            .compare_and_swap_root("test", Some(first.digest()), &second)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("test", None, &first)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Conflict {
+            actual: Some(*first.digest())
+        },
+        storage
+            .compare_and_swap_root("test", None, &second)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        CompareAndSwapResult::Swapped,
+        storage
+            .compare_and_swap_root("test", Some(first.digest()), &second)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        Some(second.clone()),
+        storage.load_root("test").await.unwrap()
+    );
+    assert_eq!(2, storage.root_history("test").await.unwrap().len());
+
+    let missing = *make_leaf(3).digest();
+    assert_eq!(
+        StoreError::TreeMissing(LoadError::TreeNotFound(missing)),
+        storage
+            .compare_and_swap_root(
+                "test",
+                Some(second.digest()),
+                &StrongReference::from_weak(missing)
+            )
+            .await
+            .unwrap_err()
+    );
+    assert_eq!(Some(second), storage.load_root("test").await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_watch_roots_publishes_on_commit() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let mut changes = storage.watch_roots();
+    let first = storage.store_tree(&make_leaf(1)).await.unwrap();
+    let second = storage.store_tree(&make_leaf(2)).await.unwrap();
+    storage.update_root("a", &first).await.unwrap();
+    storage
+        .compare_and_swap_root("b", None, &second)
+        .await
+        .unwrap();
+    assert!(changes.next().now_or_never().is_none());
+
+    storage.commit_changes().await.unwrap();
+    assert_eq!(
+        Some(Ok(RootChange {
+            name: "a".to_string(),
+            target: *first.digest(),
+        })),
+        changes.next().await
+    );
+    assert_eq!(
+        Some(Ok(RootChange {
+            name: "b".to_string(),
+            target: *second.digest(),
+        })),
+        changes.next().await
+    );
+    assert!(changes.next().now_or_never().is_none());
+
+    drop(storage);
+    assert_eq!(None, changes.next().await);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_store_blake3_tree_with_sha3_child() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let child = make_leaf(1);
+    let child_reference = storage.store_tree(&child).await.unwrap();
+    let parent = HashedTree::from_with_algorithm(
+        Arc::new(Tree::new(
+            TreeBlob::try_from(Bytes::from_static(b"parent")).unwrap(),
+            TreeChildren::try_from(vec![child_reference]).unwrap(),
+        )),
+        DigestAlgorithm::Blake3,
+    );
+    let parent_reference = storage.store_tree(&parent).await.unwrap();
+    assert_eq!(
+        DigestAlgorithm::Blake3,
+        parent_reference.digest().algorithm()
+    );
+    storage
+        .update_root("test", &parent_reference)
+        .await
+        .unwrap();
+    storage.commit_changes().await.unwrap();
+
+    let loaded = storage
+        .load_tree(parent_reference.digest())
+        .await
+        .unwrap()
+        .hash()
+        .unwrap();
+    assert_eq!(&parent, loaded.hashed_tree());
+    assert_eq!(
+        child.digest(),
+        loaded.hashed_tree().tree().children().references()[0].digest()
+    );
+    assert_eq!(
+        Some(parent_reference),
+        storage.load_root("test").await.unwrap()
+    );
+}
+
+/// The schema from before digests were tagged with their algorithm.
+fn create_untagged_schema(connection: &rusqlite::Connection) {
+    connection
+        .execute_batch(
+            "CREATE TABLE tree (
+                id INTEGER PRIMARY KEY NOT NULL,
+                digest BLOB UNIQUE NOT NULL,
+                tree_blob BLOB NOT NULL,
+                is_compressed INTEGER NOT NULL,
+                CONSTRAINT digest_length_matches_sha3_512 CHECK (LENGTH(digest) == 64),
+                CONSTRAINT is_compressed_boolean CHECK (is_compressed IN (0, 1))
+            ) STRICT;
+            CREATE TABLE reference (
+                id INTEGER PRIMARY KEY NOT NULL,
+                origin INTEGER NOT NULL REFERENCES tree ON DELETE CASCADE,
+                zero_based_index INTEGER NOT NULL,
+                target BLOB NOT NULL,
+                UNIQUE (origin, zero_based_index),
+                CONSTRAINT digest_length_matches_sha3_512 CHECK (LENGTH(target) == 64)
+            ) STRICT;
+            CREATE INDEX reference_origin ON reference (origin);
+            CREATE INDEX reference_target ON reference (target);
+            CREATE TABLE root (
+                id INTEGER PRIMARY KEY NOT NULL,
+                name TEXT UNIQUE NOT NULL,
+                target BLOB NOT NULL,
+                CONSTRAINT target_length_matches_sha3_512 CHECK (LENGTH(target) == 64)
+            ) STRICT;
+            CREATE TABLE root_history (
+                id INTEGER PRIMARY KEY NOT NULL,
+                name TEXT NOT NULL,
+                target BLOB NOT NULL,
+                updated_at INTEGER NOT NULL,
+                CONSTRAINT target_length_matches_sha3_512 CHECK (LENGTH(target) == 64)
+            ) STRICT;
+            CREATE INDEX root_history_name ON root_history (name, updated_at);
+            CREATE INDEX root_history_target ON root_history (target);",
+        )
+        .unwrap();
+}
+
+#[test_log::test(tokio::test)]
+async fn test_untagged_digests_are_migrated() {
+    let child = make_leaf(1);
+    let parent = HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from_static(b"parent")).unwrap(),
+        TreeChildren::try_from(vec![StrongReference::from_weak(*child.digest())]).unwrap(),
+    )));
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    create_untagged_schema(&connection);
+    for (id, tree) in [(1, &child), (2, &parent)] {
+        connection
+            .execute(
+                "INSERT INTO tree (id, digest, tree_blob, is_compressed) VALUES (?1, ?2, ?3, 0)",
+                (id, tree.digest().as_bytes(), tree.tree().blob().as_slice()),
+            )
+            .unwrap();
+    }
+    connection
+        .execute(
+            "INSERT INTO reference (origin, zero_based_index, target) VALUES (2, 0, ?1)",
+            (child.digest().as_bytes(),),
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO root (name, target) VALUES ('test', ?1)",
+            (parent.digest().as_bytes(),),
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO root_history (name, target, updated_at) VALUES ('test', ?1, 0)",
+            (parent.digest().as_bytes(),),
+        )
+        .unwrap();
+
+    drop(connection);
+
+    let storage = SQLiteStorage::from(rusqlite::Connection::open(&database_path).unwrap()).unwrap();
+    let root = storage.load_root("test").await.unwrap().unwrap();
+    assert_eq!(parent.digest(), root.digest());
+    let loaded = storage
+        .load_tree(root.digest())
+        .await
+        .unwrap()
+        .hash()
+        .unwrap();
+    assert_eq!(&parent, loaded.hashed_tree());
+    assert_eq!(
+        vec![RootHistoryEntry {
+            target: *parent.digest(),
+            updated_at: std::time::UNIX_EPOCH,
+        }],
+        storage.root_history("test").await.unwrap()
+    );
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 0 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(2, storage.approximate_tree_count().await.unwrap());
+    drop(storage);
+    assert_eq!(
+        SCHEMA_VERSION,
+        SQLiteStorage::schema_version(&rusqlite::Connection::open(&database_path).unwrap())
+            .unwrap()
+    );
+}
+
+#[test_log::test]
+fn test_failed_tree_table_rebuild_is_rolled_back() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    create_untagged_schema(&connection);
+    // The old schema didn't limit the size of a blob, so this tree can't be copied into the new table.
+    connection
+        .execute(
+            "INSERT INTO tree (id, digest, tree_blob, is_compressed) VALUES (1, ?1, ?2, 0)",
+            (
+                BlobDigest::hash(b"too large").as_bytes(),
+                vec![0u8; TREE_BLOB_MAX_LENGTH + 1],
+            ),
+        )
+        .unwrap();
+    drop(connection);
+
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    SQLiteStorage::upgrade_schema(&connection).unwrap_err();
+    assert!(connection.is_autocommit());
+    let foreign_keys: i64 = connection
+        .pragma_query_value(None, "foreign_keys", |row| row.get(0))
+        .unwrap();
+    assert_eq!(1, foreign_keys);
+    let old_trees: i64 = connection
+        .query_row(
+            "SELECT COUNT(*) FROM tree WHERE is_compressed = 0",
+            (),
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(1, old_trees);
+}
+
+fn make_compressible_leaf(index: u32) -> HashedTree {
+    let content =
+        format!("{{\"name\": \"file {index}\", \"size\": {index}, \"kind\": \"regular file\"}}")
+            .repeat(10);
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from(content)).unwrap(),
+        TreeChildren::empty(),
+    )))
+}
+
+fn open_with_codec(database_path: &std::path::Path, codec: BlobCodec) -> SQLiteStorage {
+    SQLiteStorage::from_with_configuration(
+        rusqlite::Connection::open(database_path).unwrap(),
+        SQLiteStorageConfiguration {
+            codec,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+fn count_trees_by_codec(database_path: &std::path::Path) -> Vec<(i64, Option<i64>, i64)> {
+    let connection = rusqlite::Connection::open(database_path).unwrap();
+    let mut statement = connection
+        .prepare("SELECT codec, dictionary, COUNT(*) FROM tree GROUP BY codec, dictionary ORDER BY codec, dictionary")
+        .unwrap();
+    statement
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+}
+
+#[test_log::test(tokio::test)]
+async fn test_zstd_codec() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    SQLiteStorage::create_schema(&rusqlite::Connection::open(&database_path).unwrap()).unwrap();
+    let zstd = BlobCodec::Zstd {
+        level: ZSTD_DEFAULT_LEVEL,
+    };
+    let tree = make_compressible_leaf(1);
+    {
+        let storage = open_with_codec(&database_path, zstd);
+        storage.store_tree(&tree).await.unwrap();
+        storage.store_tree(&make_leaf(2)).await.unwrap();
+        storage.commit_changes().await.unwrap();
+    }
+    assert_eq!(
+        vec![(BlobCodec::None.id(), None, 1), (zstd.id(), None, 1)],
+        count_trees_by_codec(&database_path)
+    );
+    // Loading doesn't depend on the configured codec.
+    let storage = open_with_codec(&database_path, BlobCodec::Lz4);
+    let loaded = storage
+        .load_tree(tree.digest())
+        .await
+        .unwrap()
+        .hash()
+        .unwrap();
+    assert_eq!(&tree, loaded.hashed_tree());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_train_dictionary_and_recompress() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    SQLiteStorage::create_schema(&rusqlite::Connection::open(&database_path).unwrap()).unwrap();
+    let trees: Vec<HashedTree> = (0..200).map(make_compressible_leaf).collect();
+    {
+        let storage = open_with_codec(&database_path, BlobCodec::Lz4);
+        storage.store_trees(&trees).await.unwrap();
+        storage.commit_changes().await.unwrap();
+    }
+    assert_eq!(
+        vec![(BlobCodec::Lz4.id(), None, 200)],
+        count_trees_by_codec(&database_path)
+    );
+
+    let zstd = BlobCodec::Zstd {
+        level: ZSTD_DEFAULT_LEVEL,
+    };
+    {
+        let storage = open_with_codec(&database_path, zstd);
+        let dictionary = storage
+            .train_compression_dictionary(100, 4096)
+            .await
+            .unwrap();
+        let stats = storage.recompress_trees().await.unwrap();
+        assert_eq!(200, stats.trees_examined);
+        assert_eq!(200, stats.trees_recompressed);
+        assert!(stats.bytes_after < stats.bytes_before);
+        storage.commit_changes().await.unwrap();
+        assert_eq!(
+            vec![(zstd.id(), Some(dictionary), 200)],
+            count_trees_by_codec(&database_path)
+        );
+        assert_eq!(
+            RecompressionStats {
+                trees_examined: 200,
+                trees_recompressed: 0,
+                bytes_before: stats.bytes_after,
+                bytes_after: stats.bytes_after,
+            },
+            storage.recompress_trees().await.unwrap()
+        );
+    }
+
+    let storage = open_with_codec(&database_path, BlobCodec::Lz4);
+    let digests: Vec<BlobDigest> = trees.iter().map(|tree| *tree.digest()).collect();
+    let loaded = storage.load_trees(&digests).await.unwrap();
+    for (expected, loaded) in trees.iter().zip(loaded) {
+        assert_eq!(expected, loaded.hash().unwrap().hashed_tree());
+    }
+    // Recompressing with another codec doesn't use the dictionary.
+    assert_eq!(
+        200,
+        storage.recompress_trees().await.unwrap().trees_recompressed
+    );
+    storage.commit_changes().await.unwrap();
+    assert_eq!(
+        vec![(BlobCodec::Lz4.id(), None, 200)],
+        count_trees_by_codec(&database_path)
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_train_dictionary_without_samples() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    assert!(matches!(
+        storage.train_compression_dictionary(100, 4096).await,
+        Err(DictionaryTrainingError::Training(_))
+    ));
+    assert_eq!(0, storage.commit_changes().await.unwrap());
+}
+
+async fn store_chain(storage: &SQLiteStorage, name: &str, length: usize) -> Vec<StrongReference> {
+    let mut chain: Vec<StrongReference> = Vec::new();
+    for index in 0..length {
+        let reference = storage
+            .store_tree(&HashedTree::from(Arc::new(Tree::new(
+                TreeBlob::try_from(Bytes::from(format!("{name} {index}"))).unwrap(),
+                TreeChildren::try_from(chain.last().cloned().into_iter().collect::<Vec<_>>())
+                    .unwrap(),
+            ))))
+            .await
+            .unwrap();
+        chain.push(reference);
+    }
+    chain
+}
+
+#[test_log::test(tokio::test)]
+async fn test_mark_and_sweep_deletes_unreachable_chain() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let dead = store_chain(&storage, "dead", 4).await;
+    let dead_digests: Vec<BlobDigest> = dead.iter().map(|tree| *tree.digest()).collect();
+    storage
+        .update_root("test", dead.last().unwrap())
+        .await
+        .unwrap();
+    let alive = store_chain(&storage, "alive", 2).await;
+    storage
+        .update_root("test", alive.last().unwrap())
+        .await
+        .unwrap();
+    drop(dead);
+    drop(alive);
+    storage.commit_changes().await.unwrap();
+    // The old garbage collector only deletes the top of the chain at a time.
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 1 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        MarkAndSweepProgress {
+            phase: MarkAndSweepPhase::Finished,
+            trees_marked: 2,
+            trees_pending: 0,
+            trees_swept: 3,
+        },
+        storage
+            .mark_and_sweep(&MarkAndSweepBudget::default())
+            .await
+            .unwrap()
+    );
+    assert_eq!(3, storage.commit_changes().await.unwrap());
+    for digest in &dead_digests {
+        assert_eq!(
+            Some(LoadError::TreeNotFound(*digest)),
+            storage.load_tree(digest).await.err()
+        );
+    }
+    let root = storage.load_root("test").await.unwrap().unwrap();
+    let loaded = storage.load_tree(root.digest()).await.unwrap();
+    assert_eq!(
+        1,
+        loaded
+            .hash()
+            .unwrap()
+            .hashed_tree()
+            .tree()
+            .children()
+            .references()
+            .len()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_stats() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    assert_eq!(SQLiteStorageStats::default(), storage.stats());
+    let dead = store_chain(&storage, "dead", 4).await;
+    drop(dead);
+    let first_writes = storage.commit_changes().await.unwrap();
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 1 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    storage
+        .mark_and_sweep(&MarkAndSweepBudget::default())
+        .await
+        .unwrap();
+    let second_writes = storage.commit_changes().await.unwrap();
+    // Nothing to commit.
+    assert_eq!(0, storage.commit_changes().await.unwrap());
+    let stats = storage.stats();
+    assert_eq!(
+        SQLiteStorageStats {
+            commits: 2,
+            writes_committed: first_writes + second_writes,
+            garbage_collections: 1,
+            trees_collected: 1,
+            mark_and_sweep_cycles_finished: 1,
+            trees_swept: 3,
+        },
+        stats
+    );
+    let mut text = PrometheusText::new();
+    stats.write_prometheus(&mut text);
+    let text = text.finish();
+    assert!(text
+        .contains("# TYPE astraea_sqlite_commits_total counter\nastraea_sqlite_commits_total 2\n"));
+    assert!(text.contains("\nastraea_sqlite_trees_swept_total 3\n"));
+}
+
+#[test_log::test(tokio::test)]
+async fn test_mark_and_sweep_incrementally() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let alive = store_chain(&storage, "alive", 3).await;
+    storage
+        .update_root("test", alive.last().unwrap())
+        .await
+        .unwrap();
+    drop(alive);
+    drop(store_chain(&storage, "dead", 3).await);
+    let budget = MarkAndSweepBudget {
+        max_duration: None,
+        max_trees: Some(1),
+    };
+    let mut steps = Vec::new();
+    loop {
+        let progress = storage.mark_and_sweep(&budget).await.unwrap();
+        steps.push((
+            progress.phase,
+            progress.trees_marked,
+            progress.trees_pending,
+            progress.trees_swept,
+        ));
+        if progress.phase == MarkAndSweepPhase::Finished {
+            break;
+        }
+    }
+    assert_eq!(
+        vec![
+            (MarkAndSweepPhase::Marking, 1, 1, 0),
+            (MarkAndSweepPhase::Marking, 2, 1, 0),
+            (MarkAndSweepPhase::Marking, 3, 0, 0),
+            (MarkAndSweepPhase::Sweeping, 3, 0, 1),
+            (MarkAndSweepPhase::Sweeping, 3, 0, 2),
+            (MarkAndSweepPhase::Sweeping, 3, 0, 3),
+            (MarkAndSweepPhase::Finished, 3, 0, 3),
+        ],
+        steps
+    );
+    // The next cycle starts from scratch and has nothing left to delete.
+    assert_eq!(
+        MarkAndSweepProgress {
+            phase: MarkAndSweepPhase::Finished,
+            trees_marked: 3,
+            trees_pending: 0,
+            trees_swept: 0,
+        },
+        storage
+            .mark_and_sweep(&MarkAndSweepBudget::default())
+            .await
+            .unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_mark_and_sweep_keeps_trees_referenced_during_cycle() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let alive = store_chain(&storage, "alive", 2).await;
+    storage
+        .update_root("test", alive.last().unwrap())
+        .await
+        .unwrap();
+    drop(alive);
+    let revived = store_chain(&storage, "revived", 2).await;
+    let revived_digests: Vec<BlobDigest> = revived.iter().map(|tree| *tree.digest()).collect();
+    drop(revived);
+    drop(store_chain(&storage, "dead", 1).await);
+    let budget = MarkAndSweepBudget {
+        max_duration: Some(std::time::Duration::ZERO),
+        max_trees: None,
+    };
+    assert_eq!(
+        MarkAndSweepPhase::Marking,
+        storage.mark_and_sweep(&budget).await.unwrap().phase
+    );
+    // Loading the unreachable chain protects it again.
+    let revived = storage.load_tree(&revived_digests[1]).await.unwrap();
+    let new_tree = store_chain(&storage, "new", 1).await;
+    assert_eq!(
+        MarkAndSweepProgress {
+            phase: MarkAndSweepPhase::Finished,
+            trees_marked: 5,
+            trees_pending: 0,
+            trees_swept: 1,
+        },
+        storage
+            .mark_and_sweep(&MarkAndSweepBudget::default())
+            .await
+            .unwrap()
+    );
+    drop(revived);
+    for digest in &revived_digests {
+        storage.load_tree(digest).await.unwrap();
+    }
+    storage.load_tree(new_tree[0].digest()).await.unwrap();
+}
+
+#[test_log::test(tokio::test)]
+async fn test_reader_connections() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from_with_configuration(
+        connection,
+        SQLiteStorageConfiguration {
+            reader_connections: 2,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    // Uncommitted changes are visible to the callers of the writer.
+    let first = storage.store_tree(&make_leaf(1)).await.unwrap();
+    let first_digest = *first.digest();
+    storage.update_root("test", &first).await.unwrap();
+    assert_eq!(1, storage.approximate_tree_count().await.unwrap());
+    assert_eq!(
+        Some(first_digest),
+        storage
+            .load_root("test")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    storage.load_tree(&first_digest).await.unwrap();
+    assert_eq!(2, storage.commit_changes().await.unwrap());
+
+    // Now the readers can take over.
+    let second = storage.store_tree(&make_leaf(2)).await.unwrap();
+    assert_eq!(1, storage.commit_changes().await.unwrap());
+    let digests = [first_digest, *second.digest()];
+    let loaded =
+        futures_util::future::join_all((0..10).map(|index| storage.load_tree(&digests[index % 2])))
+            .await;
+    for (index, tree) in loaded.into_iter().enumerate() {
+        assert_eq!(
+            &digests[index % 2],
+            tree.unwrap().hash().unwrap().reference().digest()
+        );
+    }
+    assert_eq!(2, storage.approximate_tree_count().await.unwrap());
+    let missing = BlobDigest::hash(b"missing");
+    assert_eq!(
+        LoadError::TreeNotFound(missing),
+        storage.load_tree(&missing).await.unwrap_err()
+    );
+
+    // Trees deleted by the garbage collector disappear for the readers, too.
+    storage.update_root("test", &second).await.unwrap();
+    assert_eq!(1, storage.commit_changes().await.unwrap());
+    drop(first);
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 1 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        LoadError::TreeNotFound(first_digest),
+        storage.load_tree(&first_digest).await.unwrap_err()
+    );
+    assert_eq!(
+        Some(*second.digest()),
+        storage
+            .load_root("test")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    assert_eq!(1, storage.approximate_tree_count().await.unwrap());
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread", worker_threads = 2))]
+async fn test_readers_do_not_wait_for_open_transaction() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    // The writer asks the clock while it updates a root, so the clock can stop the writer in the middle of a transaction.
+    let is_holding = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let (entered_sender, entered_receiver) = std::sync::mpsc::channel::<()>();
+    let (release_sender, release_receiver) = std::sync::mpsc::channel::<()>();
+    let clock: Clock = {
+        let is_holding = is_holding.clone();
+        let entered_sender = std::sync::Mutex::new(entered_sender);
+        let release_receiver = std::sync::Mutex::new(release_receiver);
+        Arc::new(move || {
+            if is_holding.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                entered_sender.lock().unwrap().send(()).unwrap();
+                release_receiver.lock().unwrap().recv().unwrap();
+            }
+            std::time::UNIX_EPOCH
+        })
+    };
+    let storage = Arc::new(
+        SQLiteStorage::from_with_configuration(
+            connection,
+            SQLiteStorageConfiguration {
+                reader_connections: 1,
+                clock,
+                ..Default::default()
+            },
+        )
+        .unwrap(),
+    );
+    let committed = storage.store_tree(&make_leaf(1)).await.unwrap();
+    storage.update_root("committed", &committed).await.unwrap();
+    storage.commit_changes().await.unwrap();
+    let uncommitted = storage.store_tree(&make_leaf(2)).await.unwrap();
+
+    is_holding.store(true, std::sync::atomic::Ordering::SeqCst);
+    let writer = tokio::spawn({
+        let storage = storage.clone();
+        let uncommitted = uncommitted.clone();
+        async move { storage.update_root("uncommitted", &uncommitted).await }
+    });
+    tokio::task::spawn_blocking(move || entered_receiver.recv().unwrap())
+        .await
+        .unwrap();
+    let (loaded, root) = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        (
+            storage.load_tree(committed.digest()).await.unwrap(),
+            storage.load_root("committed").await.unwrap(),
+        )
+    })
+    .await
+    .expect("The readers don't wait for the writer");
+    assert_eq!(
+        committed.digest(),
+        loaded.hash().unwrap().reference().digest()
+    );
+    assert_eq!(Some(*committed.digest()), root.map(|root| *root.digest()));
+    release_sender.send(()).unwrap();
+    writer.await.unwrap().unwrap();
+
+    // The writer still provides what it changed in the open transaction.
+    let loaded = storage
+        .load_trees(&[*uncommitted.digest(), *committed.digest()])
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![*uncommitted.digest(), *committed.digest()],
+        loaded
+            .into_iter()
+            .map(|tree| *tree.hash().unwrap().reference().digest())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        Some(*uncommitted.digest()),
+        storage
+            .load_root("uncommitted")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    storage
+        .update_root("committed", &uncommitted)
+        .await
+        .unwrap();
+    assert_eq!(
+        Some(*uncommitted.digest()),
+        storage
+            .load_root("committed")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+    storage.commit_changes().await.unwrap();
+}
+
+#[test_log::test]
+fn test_schema_version() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    assert_eq!(0, SQLiteStorage::schema_version(&connection).unwrap());
+    SQLiteStorage::create_schema(&connection).unwrap();
+    assert_eq!(
+        SCHEMA_VERSION,
+        SQLiteStorage::schema_version(&connection).unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_empty_database_gets_schema() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    {
+        let storage =
+            SQLiteStorage::from(rusqlite::Connection::open(&database_path).unwrap()).unwrap();
+        let reference = storage.store_tree(&make_leaf(1)).await.unwrap();
+        storage.update_root("test", &reference).await.unwrap();
+        storage.commit_changes().await.unwrap();
+    }
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    assert_eq!(
+        SCHEMA_VERSION,
+        SQLiteStorage::schema_version(&connection).unwrap()
+    );
+    let storage = SQLiteStorage::from(connection).unwrap();
+    assert_eq!(
+        Some(*make_leaf(1).digest()),
+        storage
+            .load_root("test")
+            .await
+            .unwrap()
+            .map(|root| *root.digest())
+    );
+}
+
+#[test_log::test]
+fn test_newer_schema_version_is_refused() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    connection
+        .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+        .unwrap();
+    assert_eq!(
+        Some(OpenError::UnsupportedSchemaVersion(SCHEMA_VERSION + 1)),
+        SQLiteStorage::from(connection).err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_pins() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let chain = store_chain(&storage, "partial import", 3).await;
+    let digests: Vec<BlobDigest> = chain.iter().map(|tree| *tree.digest()).collect();
+    storage
+        .pin("import", chain.last().unwrap(), None)
+        .await
+        .unwrap();
+    drop(chain);
+    assert_eq!(
+        vec![Pin {
+            name: "import".to_string(),
+            target: digests[2],
+            expires_at: None,
+        }],
+        storage.list_pins().await.unwrap()
+    );
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 0 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        MarkAndSweepPhase::Finished,
+        storage
+            .mark_and_sweep(&MarkAndSweepBudget::default())
+            .await
+            .unwrap()
+            .phase
+    );
+    assert_eq!(3, storage.approximate_tree_count().await.unwrap());
+
+    assert!(storage.unpin("import").await.unwrap());
+    assert!(!storage.unpin("import").await.unwrap());
+    assert_eq!(Vec::<Pin>::new(), storage.list_pins().await.unwrap());
+    assert_eq!(
+        MarkAndSweepProgress {
+            phase: MarkAndSweepPhase::Finished,
+            trees_marked: 0,
+            trees_pending: 0,
+            trees_swept: 3,
+        },
+        storage
+            .mark_and_sweep(&MarkAndSweepBudget::default())
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        LoadError::TreeNotFound(digests[0]),
+        storage.load_tree(&digests[0]).await.unwrap_err()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_pin_is_replaced() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let first = storage.store_tree(&make_leaf(1)).await.unwrap();
+    let second = storage.store_tree(&make_leaf(2)).await.unwrap();
+    storage.pin("work", &first, None).await.unwrap();
+    storage
+        .pin("work", &second, Some(at(10_000_000_000)))
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![Pin {
+            name: "work".to_string(),
+            target: *second.digest(),
+            expires_at: Some(at(10_000_000_000)),
+        }],
+        storage.list_pins().await.unwrap()
+    );
+    drop(first);
+    drop(second);
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 1 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_pin_expires() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let clock = TestClock::new();
+    let storage = SQLiteStorage::from_with_configuration(
+        connection,
+        SQLiteStorageConfiguration {
+            clock: clock.clock(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    clock.set(10);
+    let reference = storage.store_tree(&make_leaf(1)).await.unwrap();
+    let digest = *reference.digest();
+    storage
+        .pin("temporary", &reference, Some(at(20)))
+        .await
+        .unwrap();
+    drop(reference);
+    assert_eq!(1, storage.list_pins().await.unwrap().len());
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 0 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+
+    clock.set(20);
+    assert_eq!(Vec::<Pin>::new(), storage.list_pins().await.unwrap());
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 1 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        LoadError::TreeNotFound(digest),
+        storage.load_tree(&digest).await.unwrap_err()
+    );
+    // The garbage collector removed the expired pin.
+    assert!(!storage.unpin("temporary").await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_pin_missing_tree() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = SQLiteStorage::from(connection).unwrap();
+    let missing = BlobDigest::hash(b"missing");
+    assert_eq!(
+        StoreError::TreeMissing(LoadError::TreeNotFound(missing)),
+        storage
+            .pin("missing", &StrongReference::from_weak(missing), None)
+            .await
+            .unwrap_err()
+    );
+    assert_eq!(Vec::<Pin>::new(), storage.list_pins().await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_pins_survive_restart() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    let digest = {
+        let storage =
+            SQLiteStorage::from(rusqlite::Connection::open(&database_path).unwrap()).unwrap();
+        let reference = storage.store_tree(&make_leaf(1)).await.unwrap();
+        storage.pin("import", &reference, None).await.unwrap();
+        storage.commit_changes().await.unwrap();
+        *reference.digest()
+    };
+    let storage = SQLiteStorage::from(rusqlite::Connection::open(&database_path).unwrap()).unwrap();
+    assert_eq!(
+        GarbageCollectionStats { trees_collected: 0 },
+        storage.collect_some_garbage().await.unwrap()
+    );
+    assert_eq!(
+        digest,
+        *storage
+            .load_tree(&digest)
+            .await
+            .unwrap()
+            .reference()
+            .digest()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_pin_table_is_added_to_version_1_databases() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    {
+        let connection = rusqlite::Connection::open(&database_path).unwrap();
+        SQLiteStorage::create_schema(&connection).unwrap();
+        connection.execute("DROP TABLE pin", ()).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+    }
+    let storage = SQLiteStorage::from(rusqlite::Connection::open(&database_path).unwrap()).unwrap();
+    let reference = storage.store_tree(&make_leaf(1)).await.unwrap();
+    storage.pin("test", &reference, None).await.unwrap();
+    assert_eq!(1, storage.list_pins().await.unwrap().len());
+    storage.commit_changes().await.unwrap();
+    drop(storage);
+    assert_eq!(
+        SCHEMA_VERSION,
+        SQLiteStorage::schema_version(&rusqlite::Connection::open(&database_path).unwrap())
+            .unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_quarantined_tree_table_is_added_to_version_2_databases() {
+    let workspace = tempfile::tempdir().unwrap();
+    let database_path = workspace.path().join("database.sqlite");
+    {
+        let connection = rusqlite::Connection::open(&database_path).unwrap();
+        SQLiteStorage::create_schema(&connection).unwrap();
+        connection
+            .execute("DROP TABLE quarantined_tree", ())
+            .unwrap();
+        connection.pragma_update(None, "user_version", 2).unwrap();
+    }
+    drop(SQLiteStorage::from(rusqlite::Connection::open(&database_path).unwrap()).unwrap());
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    assert_eq!(
+        SCHEMA_VERSION,
+        SQLiteStorage::schema_version(&connection).unwrap()
+    );
+    assert_eq!(
+        0,
+        connection
+            .query_row("SELECT COUNT(*) FROM quarantined_tree", (), |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap()
+    );
+}
@@ -1,11 +1,12 @@
 use crate::{
     delayed_hashed_tree::DelayedHashedTree,
-    tree::{BlobDigest, HashedTree, Tree, TreeSerializationError},
+    tree::{BlobDigest, DigestAlgorithm, HashedTree, Tree, TreeSerializationError},
 };
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::{hash::Hash, sync::Arc};
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum StoreError {
     NoSpace,
     Rusqlite(String),
@@ -13,6 +14,8 @@ pub enum StoreError {
     Unrepresentable,
     TreeMissing(LoadError),
     CorruptedStorage(String),
+    Network(String),
+    Io(String),
 }
 
 impl std::fmt::Display for StoreError {
@@ -23,12 +26,14 @@ impl std::fmt::Display for StoreError {
 
 impl std::error::Error for StoreError {}
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum LoadError {
     Rusqlite(String),
     TreeNotFound(BlobDigest),
     Deserialization(BlobDigest, TreeSerializationError),
     Inconsistency(BlobDigest, String),
+    Network(String),
+    Io(String),
 }
 
 impl std::fmt::Display for LoadError {
@@ -143,12 +148,13 @@ pub async fn load_children(
     load_tree: &(dyn LoadTree + Send + Sync),
     parent: &Tree,
 ) -> Result<Vec<StrongDelayedHashedTree>, LoadError> {
-    let mut references = Vec::new();
-    for child_reference in parent.children().references() {
-        let loaded_child = load_tree.load_tree(child_reference.digest()).await?;
-        references.push(loaded_child);
-    }
-    Ok(references)
+    let digests: Vec<BlobDigest> = parent
+        .children()
+        .references()
+        .iter()
+        .map(|child| *child.digest())
+        .collect();
+    load_tree.load_trees(&digests).await
 }
 
 #[derive(Debug, Clone)]
@@ -180,6 +186,25 @@ pub trait StoreTree {
         &self,
         tree: &HashedTree,
     ) -> std::result::Result<StrongReference, StoreError>;
+
+    /// Stores all of the trees and returns their references in the same order. Children have to exist already or be
+    /// part of the batch. Implementations should override this if they can do better than one call per tree.
+    async fn store_trees(
+        &self,
+        trees: &[HashedTree],
+    ) -> std::result::Result<Vec<StrongReference>, StoreError> {
+        let mut result = Vec::with_capacity(trees.len());
+        for tree in trees {
+            result.push(self.store_tree(tree).await?);
+        }
+        Ok(result)
+    }
+
+    /// The algorithm that new trees for this storage should be hashed with. Trees hashed with other algorithms can still be
+    /// stored.
+    fn digest_algorithm(&self) -> DigestAlgorithm {
+        DigestAlgorithm::Sha3_512
+    }
 }
 
 #[async_trait::async_trait]
@@ -189,10 +214,69 @@ pub trait LoadTree: std::fmt::Debug {
         reference: &BlobDigest,
     ) -> std::result::Result<StrongDelayedHashedTree, LoadError>;
     async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError>;
+
+    /// Loads all of the trees and returns them in the same order. Fails if any of them can't be loaded.
+    /// Implementations should override this if they can do better than one call per tree.
+    async fn load_trees(
+        &self,
+        references: &[BlobDigest],
+    ) -> std::result::Result<Vec<StrongDelayedHashedTree>, LoadError> {
+        let mut result = Vec::with_capacity(references.len());
+        for reference in references {
+            result.push(self.load_tree(reference).await?);
+        }
+        Ok(result)
+    }
+
+    /// Answers "which of these trees do you already have?" with one bool per digest in the same order.
+    /// Implementations that talk to a remote host should override this to avoid one round trip per digest.
+    async fn find_existing_trees(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<bool>, LoadError> {
+        let mut result = Vec::with_capacity(digests.len());
+        for digest in digests {
+            match self.load_tree(digest).await {
+                Ok(_) => result.push(true),
+                Err(LoadError::TreeNotFound(_)) => result.push(false),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(result)
+    }
 }
 
 pub trait LoadStoreTree: LoadTree + StoreTree {}
 
+#[async_trait::async_trait]
+pub trait ReplaceTree {
+    /// Stores the tree like [StoreTree::store_tree], but overwrites whatever the storage already has under its digest.
+    /// [StoreTree::store_tree] skips trees that exist, so this is the only way to repair a corrupted copy.
+    async fn replace_tree(
+        &self,
+        tree: &HashedTree,
+    ) -> std::result::Result<StrongReference, StoreError>;
+}
+
+#[async_trait::async_trait]
+pub trait MeasureStoredSize {
+    /// How many bytes the blob of each tree occupies in the storage after compression, or None if the tree doesn't exist.
+    async fn stored_sizes(
+        &self,
+        digests: &[BlobDigest],
+    ) -> std::result::Result<Vec<Option<u64>>, LoadError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompareAndSwapResult {
+    Swapped,
+    /// The root didn't point to the expected tree. `actual` is what it points to instead (`None` if it doesn't exist), so the
+    /// caller can merge and try again.
+    Conflict {
+        actual: Option<BlobDigest>,
+    },
+}
+
 #[async_trait]
 pub trait UpdateRoot {
     async fn update_root(
@@ -200,6 +284,62 @@ pub trait UpdateRoot {
         name: &str,
         target: &StrongReference,
     ) -> std::result::Result<(), StoreError>;
+
+    /// Points the root to `new` only if it currently points to `expected`, or doesn't exist if `expected` is `None`. The check
+    /// and the update happen atomically with respect to other updates of the same storage.
+    async fn compare_and_swap_root(
+        &self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &StrongReference,
+    ) -> std::result::Result<CompareAndSwapResult, StoreError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootChange {
+    pub name: String,
+    pub target: BlobDigest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchRootsError {
+    /// The subscriber didn't keep up and this many changes were dropped. Load the roots again to catch up.
+    Lagged(u64),
+}
+
+impl std::fmt::Display for WatchRootsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for WatchRootsError {}
+
+pub type RootChangeStream =
+    std::pin::Pin<Box<dyn futures_core::Stream<Item = Result<RootChange, WatchRootsError>> + Send>>;
+
+/// How many changes a subscriber can fall behind before it starts missing some.
+pub(crate) const ROOT_CHANGE_CHANNEL_CAPACITY: usize = 1024;
+
+pub(crate) fn root_change_stream(
+    mut receiver: tokio::sync::broadcast::Receiver<RootChange>,
+) -> RootChangeStream {
+    Box::pin(async_stream::stream! {
+        loop {
+            match receiver.recv().await {
+                Ok(change) => yield Ok(change),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(count)) => {
+                    yield Err(WatchRootsError::Lagged(count))
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+pub trait WatchRoots {
+    /// Yields every root update that becomes durable after subscribing. The stream ends when the storage is dropped.
+    fn watch_roots(&self) -> RootChangeStream;
 }
 
 #[async_trait]
@@ -210,6 +350,34 @@ pub trait LoadRoot {
     ) -> std::result::Result<Option<StrongReference>, LoadError>;
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    pub name: String,
+    pub target: BlobDigest,
+    /// After this time, the pin doesn't protect its target anymore and is removed by the next garbage collection.
+    pub expires_at: Option<std::time::SystemTime>,
+}
+
+/// Pins keep trees alive like roots do, but they are meant for temporary protection, for example of partial work that has to
+/// survive a restart. Unlike a [StrongReference], a pin is stored persistently.
+#[async_trait]
+pub trait PinTrees {
+    /// Protects `target` and everything reachable from it under `name` until it is unpinned or `expires_at` has passed.
+    /// An existing pin with the same name is replaced.
+    async fn pin(
+        &self,
+        name: &str,
+        target: &StrongReference,
+        expires_at: Option<std::time::SystemTime>,
+    ) -> std::result::Result<(), StoreError>;
+
+    /// Returns whether the pin existed.
+    async fn unpin(&self, name: &str) -> std::result::Result<bool, StoreError>;
+
+    /// All pins that haven't expired yet, ordered by name.
+    async fn list_pins(&self) -> std::result::Result<Vec<Pin>, LoadError>;
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct GarbageCollectionStats {
     pub trees_collected: u64,
@@ -0,0 +1,152 @@
+use crate::{
+    inclusion_proof::{InclusionProof, InclusionProofError},
+    storage::{CompareAndSwapResult, LoadError, StoreError, StrongReference},
+    tree::{BlobDigest, DigestAlgorithm, Tree, TreeBlob, TreeChildren, TreeSerializationError},
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Upper limit for the size of a single message on the wire. The largest legitimate message is a batch of digests
+/// or a single tree, so this is plenty. The limit prevents a broken or malicious peer from making us allocate arbitrary amounts of memory.
+pub const MAX_MESSAGE_LENGTH: u32 = 16 * 1024 * 1024;
+
+/// A tree as it travels over the network. Children are transmitted as digests only. The receiving side resolves them
+/// to strong references itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SerializedTree {
+    pub blob: Vec<u8>,
+    pub children: Vec<BlobDigest>,
+}
+
+impl SerializedTree {
+    pub fn from_tree(tree: &Tree) -> SerializedTree {
+        SerializedTree {
+            blob: tree.blob().as_slice().to_vec(),
+            children: tree
+                .children()
+                .references()
+                .iter()
+                .map(|reference| *reference.digest())
+                .collect(),
+        }
+    }
+
+    /// Builds the tree using the given strong references for the children. They have to be in the same order as [SerializedTree::children].
+    pub fn to_tree(&self, children: Vec<StrongReference>) -> Result<Tree, TreeSerializationError> {
+        assert_eq!(self.children.len(), children.len());
+        let blob = TreeBlob::try_from(bytes::Bytes::copy_from_slice(&self.blob))?;
+        let children =
+            TreeChildren::try_from(children).ok_or(TreeSerializationError::TooManyChildren)?;
+        Ok(Tree::new(blob, children))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Operation {
+    LoadTree(BlobDigest),
+    StoreTree {
+        tree: SerializedTree,
+        /// The server hashes the tree with the same algorithm as the client.
+        algorithm: DigestAlgorithm,
+    },
+    FindExistingTrees(Vec<BlobDigest>),
+    ApproximateTreeCount,
+    UpdateRoot {
+        name: String,
+        target: BlobDigest,
+    },
+    CompareAndSwapRoot {
+        name: String,
+        expected: Option<BlobDigest>,
+        new: BlobDigest,
+    },
+    LoadRoot(String),
+    ProveInclusion {
+        root: BlobDigest,
+        path: Vec<usize>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Request {
+    /// Digests the client doesn't hold strong references to anymore. The server stops keeping these trees alive on behalf of the client.
+    /// Releases are processed before the operation.
+    pub released: Vec<BlobDigest>,
+    pub operation: Operation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Response {
+    LoadTree(Result<SerializedTree, LoadError>),
+    StoreTree(Result<BlobDigest, StoreError>),
+    FindExistingTrees(Result<Vec<bool>, LoadError>),
+    ApproximateTreeCount(Result<u64, StoreError>),
+    UpdateRoot(Result<(), StoreError>),
+    CompareAndSwapRoot(Result<CompareAndSwapResult, StoreError>),
+    LoadRoot(Result<Option<BlobDigest>, LoadError>),
+    ProveInclusion(Result<InclusionProof, InclusionProofError>),
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    Postcard(postcard::Error),
+    MessageTooLong(u64),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Writes a length-prefixed postcard message. The length is a big-endian u32.
+pub async fn write_message<T, W>(writer: &mut W, message: &T) -> Result<(), ProtocolError>
+where
+    T: Serialize,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let serialized = postcard::to_stdvec(message).map_err(ProtocolError::Postcard)?;
+    let length = u32::try_from(serialized.len())
+        .ok()
+        .filter(|length| *length <= MAX_MESSAGE_LENGTH)
+        .ok_or(ProtocolError::MessageTooLong(serialized.len() as u64))?;
+    writer
+        .write_all(&length.to_be_bytes())
+        .await
+        .map_err(ProtocolError::Io)?;
+    writer
+        .write_all(&serialized)
+        .await
+        .map_err(ProtocolError::Io)?;
+    writer.flush().await.map_err(ProtocolError::Io)?;
+    Ok(())
+}
+
+/// Reads a message written by [write_message]. Returns `Ok(None)` if the peer closed the connection cleanly between two messages.
+pub async fn read_message<T, R>(reader: &mut R) -> Result<Option<T>, ProtocolError>
+where
+    T: serde::de::DeserializeOwned,
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let mut length_bytes = [0u8; 4];
+    match reader.read_exact(&mut length_bytes).await {
+        Ok(_) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(ProtocolError::Io(error)),
+    }
+    let length = u32::from_be_bytes(length_bytes);
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(ProtocolError::MessageTooLong(length as u64));
+    }
+    let mut buffer = vec![0u8; length as usize];
+    reader
+        .read_exact(&mut buffer)
+        .await
+        .map_err(ProtocolError::Io)?;
+    postcard::from_bytes(&buffer)
+        .map(Some)
+        .map_err(ProtocolError::Postcard)
+}
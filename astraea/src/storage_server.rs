@@ -0,0 +1,212 @@
+use crate::{
+    inclusion_proof::prove_inclusion,
+    storage::{
+        CompareAndSwapResult, LoadError, LoadRoot, LoadStoreTree, StoreError, StrongReference,
+        UpdateRoot,
+    },
+    storage_protocol::{
+        read_message, write_message, Operation, ProtocolError, Request, Response, SerializedTree,
+    },
+    tree::{BlobDigest, DigestAlgorithm, HashedTree},
+};
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+/// Per-connection state of the server. The server holds strong references on behalf of the client
+/// for every tree the client has received a reference to, until the client releases them or disconnects.
+struct ClientSession<S> {
+    storage: Arc<S>,
+    held_references: BTreeMap<BlobDigest, StrongReference>,
+}
+
+impl<S> ClientSession<S>
+where
+    S: LoadStoreTree + UpdateRoot + LoadRoot + Send + Sync,
+{
+    fn new(storage: Arc<S>) -> Self {
+        Self {
+            storage,
+            held_references: BTreeMap::new(),
+        }
+    }
+
+    fn hold(&mut self, reference: StrongReference) {
+        self.held_references.insert(*reference.digest(), reference);
+    }
+
+    async fn handle_request(&mut self, request: Request) -> Response {
+        for released in &request.released {
+            self.held_references.remove(released);
+        }
+        match request.operation {
+            Operation::LoadTree(digest) => Response::LoadTree(self.load_tree(&digest).await),
+            Operation::StoreTree { tree, algorithm } => {
+                Response::StoreTree(self.store_tree(&tree, algorithm).await)
+            }
+            Operation::FindExistingTrees(digests) => {
+                Response::FindExistingTrees(self.storage.find_existing_trees(&digests).await)
+            }
+            Operation::ApproximateTreeCount => {
+                Response::ApproximateTreeCount(self.storage.approximate_tree_count().await)
+            }
+            Operation::UpdateRoot { name, target } => {
+                Response::UpdateRoot(self.update_root(&name, &target).await)
+            }
+            Operation::CompareAndSwapRoot {
+                name,
+                expected,
+                new,
+            } => Response::CompareAndSwapRoot(
+                self.compare_and_swap_root(&name, expected.as_ref(), &new)
+                    .await,
+            ),
+            Operation::LoadRoot(name) => Response::LoadRoot(self.load_root(&name).await),
+            Operation::ProveInclusion { root, path } => Response::ProveInclusion(
+                prove_inclusion(self.storage.as_ref(), &root, &path)
+                    .await
+                    .map(|(proof, _target)| proof),
+            ),
+        }
+    }
+
+    async fn load_tree(&mut self, digest: &BlobDigest) -> Result<SerializedTree, LoadError> {
+        let loaded = self.storage.load_tree(digest).await?;
+        let reference = loaded.reference().clone();
+        let hashed = match loaded.hash() {
+            Some(hashed) => hashed,
+            None => {
+                return Err(LoadError::Inconsistency(
+                    *digest,
+                    "Tree hash does not match the digest".to_string(),
+                ))
+            }
+        };
+        let tree = hashed.hashed_tree().tree();
+        for child in tree.children().references() {
+            self.hold(child.clone());
+        }
+        self.hold(reference);
+        Ok(SerializedTree::from_tree(tree))
+    }
+
+    async fn store_tree(
+        &mut self,
+        tree: &SerializedTree,
+        algorithm: DigestAlgorithm,
+    ) -> Result<BlobDigest, StoreError> {
+        let children = tree
+            .children
+            .iter()
+            .map(|digest| match self.held_references.get(digest) {
+                Some(held) => held.clone(),
+                // The storage will check whether the child exists.
+                None => StrongReference::from_weak(*digest),
+            })
+            .collect();
+        let tree = tree
+            .to_tree(children)
+            .map_err(StoreError::TreeSerializationError)?;
+        let reference = self
+            .storage
+            .store_tree(&HashedTree::from_with_algorithm(Arc::new(tree), algorithm))
+            .await?;
+        let digest = *reference.digest();
+        self.hold(reference);
+        Ok(digest)
+    }
+
+    fn held_or_weak(&self, digest: &BlobDigest) -> StrongReference {
+        match self.held_references.get(digest) {
+            Some(held) => held.clone(),
+            None => StrongReference::from_weak(*digest),
+        }
+    }
+
+    async fn update_root(&mut self, name: &str, target: &BlobDigest) -> Result<(), StoreError> {
+        let target = self.held_or_weak(target);
+        self.storage.update_root(name, &target).await
+    }
+
+    async fn compare_and_swap_root(
+        &mut self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &BlobDigest,
+    ) -> Result<CompareAndSwapResult, StoreError> {
+        let new = self.held_or_weak(new);
+        self.storage
+            .compare_and_swap_root(name, expected, &new)
+            .await
+    }
+
+    async fn load_root(&mut self, name: &str) -> Result<Option<BlobDigest>, LoadError> {
+        match self.storage.load_root(name).await? {
+            Some(reference) => {
+                let digest = *reference.digest();
+                self.hold(reference);
+                Ok(Some(digest))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Serves a single client until it disconnects.
+pub async fn serve_storage_connection<S>(
+    stream: TcpStream,
+    storage: Arc<S>,
+) -> Result<(), ProtocolError>
+where
+    S: LoadStoreTree + UpdateRoot + LoadRoot + Send + Sync,
+{
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = ClientSession::new(storage);
+    loop {
+        let request: Request = match read_message(&mut reader).await? {
+            Some(request) => request,
+            None => {
+                debug!(
+                    "Client disconnected, releasing {} references",
+                    session.held_references.len()
+                );
+                return Ok(());
+            }
+        };
+        let response = session.handle_request(request).await;
+        write_message(&mut writer, &response).await?;
+    }
+}
+
+/// Accepts clients forever and serves each of them on a separate task.
+pub async fn serve_storage<S>(listener: TcpListener, storage: Arc<S>) -> std::io::Result<()>
+where
+    S: LoadStoreTree + UpdateRoot + LoadRoot + Send + Sync + 'static,
+{
+    loop {
+        let (stream, remote_endpoint): (TcpStream, SocketAddr) = listener.accept().await?;
+        info!("Storage client connected from {}", &remote_endpoint);
+        // Without TCP_NODELAY every small request could be delayed by Nagle's algorithm.
+        if let Err(error) = stream.set_nodelay(true) {
+            warn!(
+                "Could not set TCP_NODELAY on connection from {}: {:?}",
+                &remote_endpoint, &error
+            );
+        }
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            match serve_storage_connection(stream, storage).await {
+                Ok(_) => info!("Storage client {} disconnected", &remote_endpoint),
+                Err(error) => warn!(
+                    "Error serving storage client {}: {}",
+                    &remote_endpoint, &error
+                ),
+            }
+        });
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{
+    storage::{LoadError, LoadTree},
+    tree::{BlobDigest, HashedTree},
+};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    sync::Arc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraversalOrder {
+    BreadthFirst,
+    DepthFirst,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraversalError {
+    Load(LoadError),
+    HashMismatch(BlobDigest),
+}
+
+impl std::fmt::Display for TraversalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for TraversalError {}
+
+pub type TraversalStream =
+    std::pin::Pin<Box<dyn futures_core::Stream<Item = Result<HashedTree, TraversalError>> + Send>>;
+
+/// Yields every tree reachable from `roots` exactly once, even if it is reachable on multiple paths.
+/// Up to `concurrency` trees are loaded at the same time. The trees are yielded as soon as they are loaded, so `order` is only
+/// followed exactly with a concurrency of 1. The stream ends after the first error.
+pub fn traverse(
+    storage: Arc<dyn LoadTree + Send + Sync>,
+    roots: &[BlobDigest],
+    order: TraversalOrder,
+    concurrency: usize,
+) -> TraversalStream {
+    let mut seen: BTreeSet<BlobDigest> = BTreeSet::new();
+    let mut pending: VecDeque<BlobDigest> = VecDeque::new();
+    for root in roots {
+        if seen.insert(*root) {
+            pending.push_back(*root);
+        }
+    }
+    if order == TraversalOrder::DepthFirst {
+        // The first root is taken from the end first.
+        pending.make_contiguous().reverse();
+    }
+    let concurrency = concurrency.max(1);
+    Box::pin(async_stream::stream! {
+        let mut loading = FuturesUnordered::new();
+        loop {
+            while loading.len() < concurrency {
+                let next = match order {
+                    TraversalOrder::BreadthFirst => pending.pop_front(),
+                    TraversalOrder::DepthFirst => pending.pop_back(),
+                };
+                let Some(digest) = next else {
+                    break;
+                };
+                let storage = storage.clone();
+                loading.push(async move { (digest, storage.load_tree(&digest).await) });
+            }
+            let Some((digest, loaded)) = loading.next().await else {
+                break;
+            };
+            let hashed = match loaded {
+                Ok(loaded) => match loaded.hash() {
+                    Some(hashed) => hashed.hashed_tree().clone(),
+                    None => {
+                        yield Err(TraversalError::HashMismatch(digest));
+                        return;
+                    }
+                },
+                Err(error) => {
+                    yield Err(TraversalError::Load(error));
+                    return;
+                }
+            };
+            let children = hashed.tree().children().references().iter().map(|child| *child.digest());
+            let new_children: Vec<BlobDigest> = children.filter(|child| seen.insert(*child)).collect();
+            match order {
+                TraversalOrder::BreadthFirst => pending.extend(new_children),
+                // The first child is taken from the end first.
+                TraversalOrder::DepthFirst => pending.extend(new_children.into_iter().rev()),
+            }
+            yield Ok(hashed);
+        }
+    })
+}
@@ -0,0 +1,105 @@
+use crate::{
+    in_memory_storage::InMemoryTreeStorage,
+    storage::{LoadError, StoreTree, StrongReference},
+    traversal::{traverse, TraversalError, TraversalOrder},
+    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::{collections::BTreeSet, sync::Arc};
+use tokio_stream::StreamExt;
+
+async fn store(
+    storage: &InMemoryTreeStorage,
+    content: &'static str,
+    children: Vec<StrongReference>,
+) -> StrongReference {
+    storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(Bytes::from(content)).unwrap(),
+            TreeChildren::try_from(children).unwrap(),
+        ))))
+        .await
+        .unwrap()
+}
+
+/// root -> (left -> shared, right -> shared)
+async fn make_diamond(storage: &InMemoryTreeStorage) -> Vec<StrongReference> {
+    let shared = store(storage, "shared", vec![]).await;
+    let left = store(storage, "left", vec![shared.clone()]).await;
+    let right = store(storage, "right", vec![shared.clone()]).await;
+    let root = store(storage, "root", vec![left.clone(), right.clone()]).await;
+    vec![root, left, right, shared]
+}
+
+async fn visit(
+    storage: Arc<InMemoryTreeStorage>,
+    roots: &[BlobDigest],
+    order: TraversalOrder,
+    concurrency: usize,
+) -> Vec<BlobDigest> {
+    traverse(storage, roots, order, concurrency)
+        .map(|tree| *tree.unwrap().digest())
+        .collect()
+        .await
+}
+
+#[test_log::test(tokio::test)]
+async fn test_traverse_breadth_first() {
+    let storage = Arc::new(InMemoryTreeStorage::empty());
+    let trees = make_diamond(&storage).await;
+    let digests: Vec<BlobDigest> = trees.iter().map(|tree| *tree.digest()).collect();
+    assert_eq!(
+        digests,
+        visit(storage, &[digests[0]], TraversalOrder::BreadthFirst, 1).await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_traverse_depth_first() {
+    let storage = Arc::new(InMemoryTreeStorage::empty());
+    let trees = make_diamond(&storage).await;
+    let [root, left, right, shared] = [0, 1, 2, 3].map(|index| *trees[index].digest());
+    assert_eq!(
+        vec![root, left, shared, right],
+        visit(storage.clone(), &[root], TraversalOrder::DepthFirst, 1).await
+    );
+    // Multiple roots are visited in their order. Trees reachable from several roots are only visited once.
+    let other_reference = store(&storage, "other", vec![trees[3].clone()]).await;
+    let other = *other_reference.digest();
+    assert_eq!(
+        vec![right, shared, other, root, left],
+        visit(
+            storage,
+            &[right, other, right, root],
+            TraversalOrder::DepthFirst,
+            1
+        )
+        .await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_traverse_concurrently() {
+    let storage = Arc::new(InMemoryTreeStorage::empty());
+    let trees = make_diamond(&storage).await;
+    let expected: BTreeSet<BlobDigest> = trees.iter().map(|tree| *tree.digest()).collect();
+    for order in [TraversalOrder::BreadthFirst, TraversalOrder::DepthFirst] {
+        let visited = visit(storage.clone(), &[*trees[0].digest()], order, 8).await;
+        assert_eq!(expected.len(), visited.len());
+        assert_eq!(expected, visited.into_iter().collect());
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_traverse_missing_tree() {
+    let storage = Arc::new(InMemoryTreeStorage::empty());
+    let missing = BlobDigest::hash(b"missing");
+    let results: Vec<_> = traverse(storage, &[missing], TraversalOrder::BreadthFirst, 1)
+        .collect()
+        .await;
+    assert_eq!(
+        vec![Err(TraversalError::Load(LoadError::TreeNotFound(missing)))],
+        results
+    );
+}
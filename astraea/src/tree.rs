@@ -3,30 +3,100 @@ use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_512};
 use std::{fmt::Display, sync::Arc};
 
-/// SHA3-512 hash. Supports Serde because we will need this type a lot in network protocols and file formats.
-#[derive(Serialize, Deserialize, PartialEq, PartialOrd, Ord, Eq, Clone, Copy, Hash)]
-pub struct BlobDigest(
-    /// data is split into two parts because Serde doesn't support 64-element arrays
-    pub ([u8; 32], [u8; 32]),
-);
+/// The hash function that produced a [BlobDigest]. The tags are the codes from the multihash table.
+#[derive(Serialize, Deserialize, PartialEq, PartialOrd, Ord, Eq, Clone, Copy, Hash, Debug)]
+pub enum DigestAlgorithm {
+    Sha3_512,
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    pub fn tag(&self) -> u8 {
+        match self {
+            DigestAlgorithm::Sha3_512 => 0x14,
+            DigestAlgorithm::Blake3 => 0x1e,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<DigestAlgorithm> {
+        match tag {
+            0x14 => Some(DigestAlgorithm::Sha3_512),
+            0x1e => Some(DigestAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Length of the digest in bytes, not counting the tag.
+    pub fn digest_length(&self) -> usize {
+        match self {
+            DigestAlgorithm::Sha3_512 => 64,
+            DigestAlgorithm::Blake3 => 32,
+        }
+    }
+}
+
+/// Hash of a tree, tagged with the algorithm that produced it. Display, [BlobDigest::parse_hex_string] and Serde all use the
+/// tag followed by the digest. Supports Serde because we will need this type a lot in network protocols and file formats.
+#[derive(PartialEq, PartialOrd, Ord, Eq, Clone, Copy, Hash)]
+pub struct BlobDigest {
+    algorithm: DigestAlgorithm,
+    /// Shorter digests are padded with zeros.
+    bytes: [u8; 64],
+}
 
 impl BlobDigest {
+    /// SHA3-512 was the only algorithm before digests were tagged, so untagged 64 bytes are always SHA3-512.
     pub fn new(value: &[u8; 64]) -> BlobDigest {
-        let (first, second) = value.split_at(32);
-        BlobDigest((first.try_into().unwrap(), second.try_into().unwrap()))
+        BlobDigest {
+            algorithm: DigestAlgorithm::Sha3_512,
+            bytes: *value,
+        }
+    }
+
+    pub fn blake3(value: &[u8; 32]) -> BlobDigest {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(value);
+        BlobDigest {
+            algorithm: DigestAlgorithm::Blake3,
+            bytes,
+        }
+    }
+
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        self.algorithm
+    }
+
+    /// The digest without the tag.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.algorithm.digest_length()]
     }
 
-    pub fn to_array(&self) -> [u8; 64] {
-        let mut result = [0u8; 64];
-        result[..32].copy_from_slice(&self.0 .0);
-        result[32..].copy_from_slice(&self.0 .1);
+    pub fn to_tagged_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(1 + self.algorithm.digest_length());
+        result.push(self.algorithm.tag());
+        result.extend_from_slice(self.as_bytes());
         result
     }
 
+    pub fn from_tagged_bytes(input: &[u8]) -> Option<BlobDigest> {
+        let (tag, digest) = input.split_first()?;
+        let algorithm = DigestAlgorithm::from_tag(*tag)?;
+        if digest.len() != algorithm.digest_length() {
+            return None;
+        }
+        let mut bytes = [0u8; 64];
+        bytes[..digest.len()].copy_from_slice(digest);
+        Some(BlobDigest { algorithm, bytes })
+    }
+
+    /// Also accepts untagged SHA3-512 digests as they were displayed before digests were tagged.
     pub fn parse_hex_string(input: &str) -> Option<BlobDigest> {
-        let mut result = [0u8; 64];
-        hex::decode_to_slice(input, &mut result).ok()?;
-        Some(BlobDigest::new(&result))
+        if input.len() == 128 {
+            let mut result = [0u8; 64];
+            hex::decode_to_slice(input, &mut result).ok()?;
+            return Some(BlobDigest::new(&result));
+        }
+        BlobDigest::from_tagged_bytes(&hex::decode(input).ok()?)
     }
 
     pub fn hash(input: &[u8]) -> BlobDigest {
@@ -47,16 +117,64 @@ impl std::fmt::Debug for BlobDigest {
 
 impl std::fmt::Display for BlobDigest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}{}", hex::encode(self.0 .0), hex::encode(self.0 .1))
+        write!(
+            f,
+            "{:02x}{}",
+            self.algorithm.tag(),
+            hex::encode(self.as_bytes())
+        )
     }
 }
 
-impl std::convert::From<BlobDigest> for [u8; 64] {
-    fn from(val: BlobDigest) -> Self {
-        let mut result = [0u8; 64];
-        result[..32].copy_from_slice(&val.0 .0);
-        result[32..].copy_from_slice(&val.0 .1);
-        result
+impl Serialize for BlobDigest {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(&self.to_tagged_bytes())
+        }
+    }
+}
+
+struct BlobDigestVisitor;
+
+impl<'de> serde::de::Visitor<'de> for BlobDigestVisitor {
+    type Value = BlobDigest;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a tagged digest")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<BlobDigest, E>
+    where
+        E: serde::de::Error,
+    {
+        BlobDigest::parse_hex_string(value)
+            .ok_or_else(|| E::invalid_value(serde::de::Unexpected::Str(value), &self))
+    }
+
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<BlobDigest, E>
+    where
+        E: serde::de::Error,
+    {
+        BlobDigest::from_tagged_bytes(value)
+            .ok_or_else(|| E::invalid_value(serde::de::Unexpected::Bytes(value), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for BlobDigest {
+    fn deserialize<D>(deserializer: D) -> Result<BlobDigest, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(BlobDigestVisitor)
+        } else {
+            deserializer.deserialize_bytes(BlobDigestVisitor)
+        }
     }
 }
 
@@ -119,7 +237,7 @@ impl std::fmt::Debug for TreeBlob {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum TreeSerializationError {
     Postcard(postcard::Error),
     BlobTooLong,
@@ -234,6 +352,11 @@ impl HashedTree {
         Self { tree, digest }
     }
 
+    pub fn from_with_algorithm(tree: Arc<Tree>, algorithm: DigestAlgorithm) -> HashedTree {
+        let digest = calculate_reference_with(&tree, algorithm);
+        Self { tree, digest }
+    }
+
     pub fn tree(&self) -> &Arc<Tree> {
         &self.tree
     }
@@ -249,6 +372,20 @@ impl Display for HashedTree {
     }
 }
 
+/// SHA3 trees hash every child digest as 64 bytes like before digests were tagged. Shorter digests are tagged and padded,
+/// which can't be confused with a real SHA3-512 digest.
+fn fixed_width_child_digest(digest: &BlobDigest) -> [u8; 64] {
+    match digest.algorithm {
+        DigestAlgorithm::Sha3_512 => digest.bytes,
+        _ => {
+            let mut result = [0u8; 64];
+            let tagged = digest.to_tagged_bytes();
+            result[..tagged.len()].copy_from_slice(&tagged);
+            result
+        }
+    }
+}
+
 pub fn calculate_digest_fixed<D>(referenced: &Tree) -> sha3::digest::Output<D>
 where
     D: sha3::Digest,
@@ -258,8 +395,7 @@ where
     hasher.update(referenced.blob.as_slice());
     hasher.update((referenced.children.references().len() as u64).to_be_bytes());
     for item in referenced.children.references() {
-        hasher.update(item.digest().0 .0);
-        hasher.update(item.digest().0 .1);
+        hasher.update(fixed_width_child_digest(item.digest()));
     }
     hasher.finalize()
 }
@@ -275,13 +411,36 @@ where
     hasher.update(referenced.blob.as_slice());
     hasher.update(&(referenced.children.references().len() as u64).to_be_bytes());
     for item in referenced.children.references() {
-        hasher.update(&item.digest().0 .0);
-        hasher.update(&item.digest().0 .1);
+        hasher.update(&fixed_width_child_digest(item.digest()));
     }
     hasher.finalize_xof()
 }
 
+/// Children are hashed with their tags, so a BLAKE3 tree can refer to existing SHA3-512 trees.
+pub fn calculate_digest_blake3(referenced: &Tree) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&(referenced.blob.len() as u64).to_be_bytes());
+    hasher.update(referenced.blob.as_slice());
+    hasher.update(&(referenced.children.references().len() as u64).to_be_bytes());
+    for item in referenced.children.references() {
+        hasher.update(&item.digest().to_tagged_bytes());
+    }
+    hasher.finalize()
+}
+
+/// Uses SHA3-512. See [calculate_reference_with] for other algorithms.
 pub fn calculate_reference(referenced: &Tree) -> BlobDigest {
-    let result: [u8; 64] = calculate_digest_fixed::<sha3::Sha3_512>(referenced).into();
-    BlobDigest::new(&result)
+    calculate_reference_with(referenced, DigestAlgorithm::Sha3_512)
+}
+
+pub fn calculate_reference_with(referenced: &Tree, algorithm: DigestAlgorithm) -> BlobDigest {
+    match algorithm {
+        DigestAlgorithm::Sha3_512 => {
+            let result: [u8; 64] = calculate_digest_fixed::<sha3::Sha3_512>(referenced).into();
+            BlobDigest::new(&result)
+        }
+        DigestAlgorithm::Blake3 => {
+            BlobDigest::blake3(calculate_digest_blake3(referenced).as_bytes())
+        }
+    }
 }
@@ -0,0 +1,127 @@
+use crate::{
+    storage::{LoadError, LoadTree},
+    tree::BlobDigest,
+};
+use std::sync::Arc;
+
+/// A position in a tree is the list of child indices leading there from the root. The root itself is the empty path.
+pub type TreePath = Vec<usize>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeDifference {
+    /// Only the first tree has a child at this position.
+    OnlyInA { path: TreePath, digest: BlobDigest },
+    /// Only the second tree has a child at this position.
+    OnlyInB { path: TreePath, digest: BlobDigest },
+    /// Both trees have a subtree at this position, but with different digests. The differences inside of these subtrees follow.
+    Changed {
+        path: TreePath,
+        a: BlobDigest,
+        b: BlobDigest,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TreeDiffError {
+    Load(LoadError),
+    HashMismatch(BlobDigest),
+}
+
+impl std::fmt::Display for TreeDiffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for TreeDiffError {}
+
+pub type TreeDiffStream = std::pin::Pin<
+    Box<dyn futures_core::Stream<Item = Result<TreeDifference, TreeDiffError>> + Send>,
+>;
+
+enum Pending {
+    Compare {
+        path: TreePath,
+        a: BlobDigest,
+        b: BlobDigest,
+    },
+    Report(TreeDifference),
+}
+
+async fn load_children(
+    storage: &(dyn LoadTree + Send + Sync),
+    a: &BlobDigest,
+    b: &BlobDigest,
+) -> Result<(Vec<BlobDigest>, Vec<BlobDigest>), TreeDiffError> {
+    let loaded = storage
+        .load_trees(&[*a, *b])
+        .await
+        .map_err(TreeDiffError::Load)?;
+    let mut children = Vec::with_capacity(2);
+    for (digest, tree) in [a, b].into_iter().zip(loaded) {
+        let hashed = tree.hash().ok_or(TreeDiffError::HashMismatch(*digest))?;
+        children.push(
+            hashed
+                .hashed_tree()
+                .tree()
+                .children()
+                .references()
+                .iter()
+                .map(|child| *child.digest())
+                .collect::<Vec<_>>(),
+        );
+    }
+    let b_children = children.pop().expect("Two trees were loaded");
+    let a_children = children.pop().expect("Two trees were loaded");
+    Ok((a_children, b_children))
+}
+
+/// Compares the trees `a` and `b` position by position. Subtrees with the same digest at the same position are skipped
+/// without loading them, so the cost depends on the size of the difference rather than the size of the trees.
+/// The differences are yielded depth first in the order of their paths. The stream ends after the first error.
+pub fn diff_trees(
+    storage: Arc<dyn LoadTree + Send + Sync>,
+    a: BlobDigest,
+    b: BlobDigest,
+) -> TreeDiffStream {
+    Box::pin(async_stream::stream! {
+        let mut pending = vec![Pending::Compare { path: Vec::new(), a, b }];
+        while let Some(next) = pending.pop() {
+            let (path, a, b) = match next {
+                Pending::Compare { path, a, b } => (path, a, b),
+                Pending::Report(difference) => {
+                    yield Ok(difference);
+                    continue;
+                }
+            };
+            if a == b {
+                continue;
+            }
+            yield Ok(TreeDifference::Changed { path: path.clone(), a, b });
+            let (a_children, b_children) = match load_children(storage.as_ref(), &a, &b).await {
+                Ok(children) => children,
+                Err(error) => {
+                    yield Err(error);
+                    return;
+                }
+            };
+            // The stack is processed from the end, so the children are pushed in reverse order.
+            for index in (0..a_children.len().max(b_children.len())).rev() {
+                let mut child_path = path.clone();
+                child_path.push(index);
+                pending.push(match (a_children.get(index), b_children.get(index)) {
+                    (Some(a), Some(b)) => Pending::Compare { path: child_path, a: *a, b: *b },
+                    (Some(digest), None) => Pending::Report(TreeDifference::OnlyInA {
+                        path: child_path,
+                        digest: *digest,
+                    }),
+                    (None, Some(digest)) => Pending::Report(TreeDifference::OnlyInB {
+                        path: child_path,
+                        digest: *digest,
+                    }),
+                    (None, None) => unreachable!("The index is below the length of one of the lists"),
+                });
+            }
+        }
+    })
+}
@@ -0,0 +1,192 @@
+use crate::{
+    in_memory_storage::InMemoryTreeStorage,
+    storage::{
+        LoadError, LoadTree, StoreError, StoreTree, StrongDelayedHashedTree, StrongReference,
+    },
+    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+    tree_diff::{diff_trees, TreeDiffError, TreeDifference},
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::{Arc, Mutex};
+use tokio_stream::StreamExt;
+
+/// Remembers which trees were loaded.
+#[derive(Debug)]
+struct LoadRecorder {
+    storage: InMemoryTreeStorage,
+    loaded: Mutex<Vec<BlobDigest>>,
+}
+
+#[async_trait]
+impl LoadTree for LoadRecorder {
+    async fn load_tree(
+        &self,
+        reference: &BlobDigest,
+    ) -> std::result::Result<StrongDelayedHashedTree, LoadError> {
+        self.loaded.lock().unwrap().push(*reference);
+        self.storage.load_tree(reference).await
+    }
+
+    async fn approximate_tree_count(&self) -> std::result::Result<u64, StoreError> {
+        self.storage.approximate_tree_count().await
+    }
+}
+
+async fn store(
+    storage: &InMemoryTreeStorage,
+    content: &'static str,
+    children: Vec<StrongReference>,
+) -> StrongReference {
+    storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(Bytes::from(content)).unwrap(),
+            TreeChildren::try_from(children).unwrap(),
+        ))))
+        .await
+        .unwrap()
+}
+
+async fn collect_differences(
+    storage: Arc<LoadRecorder>,
+    a: &StrongReference,
+    b: &StrongReference,
+) -> Vec<Result<TreeDifference, TreeDiffError>> {
+    diff_trees(storage, *a.digest(), *b.digest())
+        .collect()
+        .await
+}
+
+#[test_log::test(tokio::test)]
+async fn test_diff_equal_trees() {
+    let storage = Arc::new(LoadRecorder {
+        storage: InMemoryTreeStorage::empty(),
+        loaded: Mutex::new(Vec::new()),
+    });
+    let leaf = store(&storage.storage, "leaf", vec![]).await;
+    let root = store(&storage.storage, "root", vec![leaf]).await;
+    assert_eq!(
+        Vec::<Result<TreeDifference, TreeDiffError>>::new(),
+        collect_differences(storage.clone(), &root, &root).await
+    );
+    assert_eq!(Vec::<BlobDigest>::new(), *storage.loaded.lock().unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_diff_skips_shared_subtrees() {
+    let storage = Arc::new(LoadRecorder {
+        storage: InMemoryTreeStorage::empty(),
+        loaded: Mutex::new(Vec::new()),
+    });
+    let shared_leaf = store(&storage.storage, "shared leaf", vec![]).await;
+    let shared = store(&storage.storage, "shared", vec![shared_leaf]).await;
+    let old_leaf = store(&storage.storage, "old leaf", vec![]).await;
+    let new_leaf = store(&storage.storage, "new leaf", vec![]).await;
+    let old_middle = store(&storage.storage, "middle", vec![old_leaf.clone()]).await;
+    let new_middle = store(&storage.storage, "middle", vec![new_leaf.clone()]).await;
+    let removed = store(&storage.storage, "removed", vec![]).await;
+    let added = store(&storage.storage, "added", vec![]).await;
+    let a = store(
+        &storage.storage,
+        "root",
+        vec![shared.clone(), old_middle.clone(), removed.clone()],
+    )
+    .await;
+    let b = store(
+        &storage.storage,
+        "root",
+        vec![shared.clone(), new_middle.clone()],
+    )
+    .await;
+    let c = store(
+        &storage.storage,
+        "root",
+        vec![
+            shared.clone(),
+            new_middle.clone(),
+            removed.clone(),
+            added.clone(),
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        vec![
+            Ok(TreeDifference::Changed {
+                path: vec![],
+                a: *a.digest(),
+                b: *b.digest()
+            }),
+            Ok(TreeDifference::Changed {
+                path: vec![1],
+                a: *old_middle.digest(),
+                b: *new_middle.digest()
+            }),
+            Ok(TreeDifference::Changed {
+                path: vec![1, 0],
+                a: *old_leaf.digest(),
+                b: *new_leaf.digest()
+            }),
+            Ok(TreeDifference::OnlyInA {
+                path: vec![2],
+                digest: *removed.digest()
+            }),
+        ],
+        collect_differences(storage.clone(), &a, &b).await
+    );
+    // Leaves are loaded to find out that they have no children, but nothing below the shared subtree is.
+    assert_eq!(
+        vec![
+            *a.digest(),
+            *b.digest(),
+            *old_middle.digest(),
+            *new_middle.digest(),
+            *old_leaf.digest(),
+            *new_leaf.digest()
+        ],
+        *storage.loaded.lock().unwrap()
+    );
+
+    assert_eq!(
+        vec![
+            Ok(TreeDifference::Changed {
+                path: vec![],
+                a: *b.digest(),
+                b: *c.digest()
+            }),
+            Ok(TreeDifference::OnlyInB {
+                path: vec![2],
+                digest: *removed.digest()
+            }),
+            Ok(TreeDifference::OnlyInB {
+                path: vec![3],
+                digest: *added.digest()
+            }),
+        ],
+        collect_differences(storage.clone(), &b, &c).await
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_diff_missing_tree() {
+    let storage = Arc::new(LoadRecorder {
+        storage: InMemoryTreeStorage::empty(),
+        loaded: Mutex::new(Vec::new()),
+    });
+    let existing = store(&storage.storage, "existing", vec![]).await;
+    let missing = StrongReference::from_weak(BlobDigest::hash(b"missing"));
+    assert_eq!(
+        vec![
+            Ok(TreeDifference::Changed {
+                path: vec![],
+                a: *existing.digest(),
+                b: *missing.digest()
+            }),
+            Err(TreeDiffError::Load(LoadError::TreeNotFound(
+                *missing.digest()
+            ))),
+        ],
+        collect_differences(storage, &existing, &missing).await
+    );
+}
@@ -0,0 +1,278 @@
+use crate::{
+    deep_tree::{DeepTree, DeepTreeChildren},
+    storage::StrongReference,
+    tree::{ReferenceIndex, Tree, TreeBlob, TreeChildren, TREE_BLOB_MAX_LENGTH, TREE_MAX_CHILDREN},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cell::RefCell;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TreeSerdeError {
+    Postcard(postcard::Error),
+    BlobTooLong {
+        length: usize,
+    },
+    TooManyChildren,
+    /// A [StrongReference] or [DeepTree] was serialized or deserialized without one of the functions of this module.
+    ChildOutsideOfTree,
+    /// A [StrongReference] was used with a [DeepTree] or the other way around.
+    WrongChildKind,
+    NoSuchChild(ReferenceIndex),
+    /// Every child of a tree has to be deserialized, otherwise the tree doesn't fit the type.
+    UnusedChild(ReferenceIndex),
+    /// The blob is longer than the value.
+    TrailingBytes(usize),
+}
+
+impl std::fmt::Display for TreeSerdeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for TreeSerdeError {}
+
+#[derive(Debug)]
+enum Children {
+    References(Vec<StrongReference>),
+    DeepTrees(Vec<DeepTree>),
+}
+
+impl Children {
+    fn len(&self) -> usize {
+        match self {
+            Children::References(references) => references.len(),
+            Children::DeepTrees(trees) => trees.len(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Context {
+    children: Children,
+    used: Vec<bool>,
+    /// postcard drops the message of custom errors, so the actual error is kept here.
+    error: Option<TreeSerdeError>,
+}
+
+thread_local! {
+    // A stack because a Serialize implementation may call one of the functions of this module itself.
+    static CONTEXTS: RefCell<Vec<Context>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Makes a context available to the Serialize and Deserialize implementations below until it is popped, even on panic.
+struct ContextGuard {
+    depth: usize,
+}
+
+impl ContextGuard {
+    fn push(children: Children) -> Self {
+        let used = vec![false; children.len()];
+        let depth = CONTEXTS.with_borrow_mut(|contexts| {
+            contexts.push(Context {
+                children,
+                used,
+                error: None,
+            });
+            contexts.len()
+        });
+        Self { depth }
+    }
+
+    fn pop(self) -> Context {
+        let context = CONTEXTS.with_borrow_mut(|contexts| {
+            assert_eq!(self.depth, contexts.len());
+            contexts.pop().expect("The context was pushed")
+        });
+        std::mem::forget(self);
+        context
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXTS.with_borrow_mut(|contexts| contexts.truncate(self.depth - 1));
+    }
+}
+
+/// Runs `change` on the innermost context and remembers its error.
+fn with_context<T>(
+    change: impl FnOnce(&mut Context) -> Result<T, TreeSerdeError>,
+) -> Result<T, TreeSerdeError> {
+    CONTEXTS.with_borrow_mut(|contexts| {
+        let context = contexts
+            .last_mut()
+            .ok_or(TreeSerdeError::ChildOutsideOfTree)?;
+        let result = change(context);
+        if let Err(error) = &result {
+            context.error.get_or_insert(error.clone());
+        }
+        result
+    })
+}
+
+fn add_child(context: &mut Context) -> Result<ReferenceIndex, TreeSerdeError> {
+    let index = context.children.len();
+    if index >= TREE_MAX_CHILDREN {
+        return Err(TreeSerdeError::TooManyChildren);
+    }
+    Ok(ReferenceIndex(index as u64))
+}
+
+fn use_child(context: &mut Context, index: ReferenceIndex) -> Result<usize, TreeSerdeError> {
+    let position = index.0 as usize;
+    match context.used.get_mut(position) {
+        Some(used) => {
+            *used = true;
+            Ok(position)
+        }
+        None => Err(TreeSerdeError::NoSuchChild(index)),
+    }
+}
+
+impl Serialize for StrongReference {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let index = with_context(|context| {
+            let index = add_child(context)?;
+            match &mut context.children {
+                Children::References(references) => references.push(self.clone()),
+                Children::DeepTrees(_) => return Err(TreeSerdeError::WrongChildKind),
+            }
+            Ok(index)
+        })
+        .map_err(serde::ser::Error::custom)?;
+        index.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StrongReference {
+    fn deserialize<D>(deserializer: D) -> Result<StrongReference, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let index = ReferenceIndex::deserialize(deserializer)?;
+        with_context(|context| {
+            let position = use_child(context, index)?;
+            match &context.children {
+                Children::References(references) => Ok(references[position].clone()),
+                Children::DeepTrees(_) => Err(TreeSerdeError::WrongChildKind),
+            }
+        })
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for DeepTree {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let index = with_context(|context| {
+            let index = add_child(context)?;
+            match &mut context.children {
+                Children::DeepTrees(trees) => trees.push(self.clone()),
+                Children::References(_) => return Err(TreeSerdeError::WrongChildKind),
+            }
+            Ok(index)
+        })
+        .map_err(serde::ser::Error::custom)?;
+        index.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeepTree {
+    fn deserialize<D>(deserializer: D) -> Result<DeepTree, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let index = ReferenceIndex::deserialize(deserializer)?;
+        with_context(|context| {
+            let position = use_child(context, index)?;
+            match &context.children {
+                Children::DeepTrees(trees) => Ok(trees[position].clone()),
+                Children::References(_) => Err(TreeSerdeError::WrongChildKind),
+            }
+        })
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+fn serialize_with_children<T: Serialize + ?Sized>(
+    value: &T,
+    children: Children,
+) -> Result<(TreeBlob, Children), TreeSerdeError> {
+    let guard = ContextGuard::push(children);
+    let result = postcard::to_allocvec(value);
+    let context = guard.pop();
+    if let Some(error) = context.error {
+        return Err(error);
+    }
+    let blob = result.map_err(TreeSerdeError::Postcard)?;
+    if blob.len() > TREE_BLOB_MAX_LENGTH {
+        return Err(TreeSerdeError::BlobTooLong { length: blob.len() });
+    }
+    Ok((
+        TreeBlob::try_from(bytes::Bytes::from(blob)).expect("The length was checked above"),
+        context.children,
+    ))
+}
+
+fn deserialize_with_children<T: DeserializeOwned>(
+    blob: &TreeBlob,
+    children: Children,
+) -> Result<T, TreeSerdeError> {
+    let guard = ContextGuard::push(children);
+    let result = postcard::take_from_bytes(blob.as_slice());
+    let context = guard.pop();
+    if let Some(error) = context.error {
+        return Err(error);
+    }
+    let (value, rest) = result.map_err(TreeSerdeError::Postcard)?;
+    if !rest.is_empty() {
+        return Err(TreeSerdeError::TrailingBytes(rest.len()));
+    }
+    if let Some(unused) = context.used.iter().position(|used| !used) {
+        return Err(TreeSerdeError::UnusedChild(ReferenceIndex(unused as u64)));
+    }
+    Ok(value)
+}
+
+/// Serializes `value` into the blob in the postcard format. Every [StrongReference] in it becomes a child of the tree and is
+/// written into the blob as the [ReferenceIndex] of that child. [StrongReference]s can't be serialized in any other way.
+pub fn to_tree<T: Serialize + ?Sized>(value: &T) -> Result<Tree, TreeSerdeError> {
+    match serialize_with_children(value, Children::References(Vec::new()))? {
+        (blob, Children::References(references)) => Ok(Tree::new(
+            blob,
+            TreeChildren::try_from(references).expect("The child count was checked while adding"),
+        )),
+        (_, Children::DeepTrees(_)) => unreachable!("The kind of children doesn't change"),
+    }
+}
+
+pub fn from_tree<T: DeserializeOwned>(tree: &Tree) -> Result<T, TreeSerdeError> {
+    deserialize_with_children(
+        tree.blob(),
+        Children::References(tree.children().references().to_vec()),
+    )
+}
+
+/// Like [to_tree], but the [DeepTree]s in `value` become the children.
+pub fn to_deep_tree<T: Serialize + ?Sized>(value: &T) -> Result<DeepTree, TreeSerdeError> {
+    match serialize_with_children(value, Children::DeepTrees(Vec::new()))? {
+        (blob, Children::DeepTrees(trees)) => Ok(DeepTree::new(
+            blob,
+            DeepTreeChildren::try_from(trees).expect("The child count was checked while adding"),
+        )),
+        (_, Children::References(_)) => unreachable!("The kind of children doesn't change"),
+    }
+}
+
+pub fn from_deep_tree<T: DeserializeOwned>(tree: &DeepTree) -> Result<T, TreeSerdeError> {
+    deserialize_with_children(
+        tree.blob(),
+        Children::DeepTrees(tree.children().references().to_vec()),
+    )
+}
@@ -0,0 +1,189 @@
+use crate::{
+    deep_tree::{DeepTree, DeepTreeChildren},
+    in_memory_storage::InMemoryTreeStorage,
+    storage::{LoadTree, StoreTree, StrongReference},
+    tree::{
+        HashedTree, ReferenceIndex, Tree, TreeBlob, TreeChildren, TREE_BLOB_MAX_LENGTH,
+        TREE_MAX_CHILDREN,
+    },
+    tree_serde::{from_deep_tree, from_tree, to_deep_tree, to_tree, TreeSerdeError},
+};
+use pretty_assertions::assert_eq;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Directory {
+    name: String,
+    size: u64,
+    first: StrongReference,
+    others: Vec<(String, StrongReference)>,
+    parent: Option<StrongReference>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum Expression {
+    Literal(DeepTree),
+    Apply {
+        function: DeepTree,
+        arguments: Vec<DeepTree>,
+    },
+}
+
+fn leaf(content: &'static str) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(bytes::Bytes::from(content)).unwrap(),
+        TreeChildren::empty(),
+    )))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_round_trip_through_storage() {
+    let storage = InMemoryTreeStorage::empty();
+    let a = storage.store_tree(&leaf("a")).await.unwrap();
+    let b = storage.store_tree(&leaf("b")).await.unwrap();
+    let original = Directory {
+        name: "home".to_string(),
+        size: 1234,
+        first: a.clone(),
+        others: vec![
+            ("b".to_string(), b.clone()),
+            ("a again".to_string(), a.clone()),
+        ],
+        parent: None,
+    };
+    let tree = to_tree(&original).unwrap();
+    assert_eq!(
+        vec![*a.digest(), *b.digest(), *a.digest()],
+        tree.children()
+            .references()
+            .iter()
+            .map(|child| *child.digest())
+            .collect::<Vec<_>>()
+    );
+    let reference = storage
+        .store_tree(&HashedTree::from(Arc::new(tree)))
+        .await
+        .unwrap();
+    let loaded = storage
+        .load_tree(reference.digest())
+        .await
+        .unwrap()
+        .hash()
+        .unwrap();
+    let deserialized: Directory = from_tree(loaded.hashed_tree().tree()).unwrap();
+    assert_eq!(original, deserialized);
+}
+
+#[test_log::test]
+fn test_plain_fields_are_postcard() {
+    let tree = to_tree(&("text", 7u8)).unwrap();
+    assert_eq!(
+        postcard::to_allocvec(&("text", 7u8)).unwrap(),
+        tree.blob().as_slice()
+    );
+    assert!(tree.children().references().is_empty());
+    assert_eq!(Ok(("text".to_string(), 7u8)), from_tree(&tree));
+}
+
+#[test_log::test]
+fn test_deep_tree_round_trip() {
+    let literal = |content: &'static str| {
+        DeepTree::new(
+            TreeBlob::try_from(bytes::Bytes::from(content)).unwrap(),
+            DeepTreeChildren::empty(),
+        )
+    };
+    let inner = Expression::Literal(literal("1"));
+    let original = Expression::Apply {
+        function: to_deep_tree(&inner).unwrap(),
+        arguments: vec![literal("2"), literal("3")],
+    };
+    let tree = to_deep_tree(&original).unwrap();
+    assert_eq!(3, tree.children().references().len());
+    assert_eq!(Ok(original), from_deep_tree(&tree));
+    let function: Expression = match from_deep_tree(&tree).unwrap() {
+        Expression::Apply { function, .. } => from_deep_tree(&function).unwrap(),
+        Expression::Literal(_) => panic!(),
+    };
+    assert_eq!(inner, function);
+}
+
+#[test_log::test]
+fn test_limits() {
+    let references: Vec<StrongReference> = (0..=TREE_MAX_CHILDREN)
+        .map(|_| StrongReference::from_weak(*leaf("").digest()))
+        .collect();
+    assert_eq!(
+        Some(TreeSerdeError::TooManyChildren),
+        to_tree(&references).err()
+    );
+    assert_eq!(
+        TREE_MAX_CHILDREN,
+        to_tree(&references[1..])
+            .unwrap()
+            .children()
+            .references()
+            .len()
+    );
+
+    let long = vec![0u8; TREE_BLOB_MAX_LENGTH];
+    assert_eq!(
+        Some(TreeSerdeError::BlobTooLong {
+            length: postcard::to_allocvec(&long).unwrap().len()
+        }),
+        to_tree(&long).err()
+    );
+}
+
+#[test_log::test]
+fn test_children_outside_of_tree() {
+    let reference = StrongReference::from_weak(*leaf("").digest());
+    assert!(postcard::to_allocvec(&reference).is_err());
+    assert_eq!(
+        Some(TreeSerdeError::WrongChildKind),
+        to_deep_tree(&reference).err()
+    );
+    assert_eq!(
+        Some(TreeSerdeError::WrongChildKind),
+        to_tree(&DeepTree::empty()).err()
+    );
+}
+
+#[test_log::test]
+fn test_tree_does_not_fit_type() {
+    let reference = StrongReference::from_weak(*leaf("").digest());
+    let index_without_child = Tree::new(
+        TreeBlob::try_from(bytes::Bytes::from(
+            postcard::to_allocvec(&ReferenceIndex(1)).unwrap(),
+        ))
+        .unwrap(),
+        TreeChildren::try_from(vec![reference.clone()]).unwrap(),
+    );
+    assert_eq!(
+        Some(TreeSerdeError::NoSuchChild(ReferenceIndex(1))),
+        from_tree::<StrongReference>(&index_without_child).err()
+    );
+
+    let unused_child = Tree::new(
+        to_tree(&reference).unwrap().blob().clone(),
+        TreeChildren::try_from(vec![reference.clone(), reference.clone()]).unwrap(),
+    );
+    assert_eq!(
+        Some(TreeSerdeError::UnusedChild(ReferenceIndex(1))),
+        from_tree::<StrongReference>(&unused_child).err()
+    );
+
+    let trailing_bytes = to_tree(&(1u8, 2u8, 3u8)).unwrap();
+    assert_eq!(
+        Some(TreeSerdeError::TrailingBytes(2)),
+        from_tree::<u8>(&trailing_bytes).err()
+    );
+
+    assert_eq!(
+        Some(TreeSerdeError::Postcard(
+            postcard::Error::DeserializeUnexpectedEnd
+        )),
+        from_tree::<u64>(&Tree::empty()).err()
+    );
+}
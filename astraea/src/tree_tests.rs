@@ -3,8 +3,9 @@ use std::sync::Arc;
 use crate::{
     storage::StrongReference,
     tree::{
-        calculate_reference, BlobDigest, HashedTree, ReferenceIndex, Tree, TreeBlob, TreeChildren,
-        TreeDeserializationError, TreeSerializationError, TREE_BLOB_MAX_LENGTH,
+        calculate_reference, calculate_reference_with, BlobDigest, DigestAlgorithm, HashedTree,
+        ReferenceIndex, Tree, TreeBlob, TreeChildren, TreeDeserializationError,
+        TreeSerializationError, TREE_BLOB_MAX_LENGTH,
     },
 };
 use pretty_assertions::assert_eq;
@@ -78,7 +79,7 @@ fn test_display_tree_deserialization_error() {
             "{}",
             TreeDeserializationError::Load(crate::storage::LoadError::TreeNotFound(BlobDigest::new(&[0u8; 64])))
         ),
-        "Load(TreeNotFound(BlobDigest(\"00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\")))"
+        "Load(TreeNotFound(BlobDigest(\"1400000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\")))"
     );
 }
 
@@ -119,9 +120,9 @@ fn test_calculate_reference_blob_yes_references_0() {
 fn test_calculate_reference_blob_no_references_1() {
     let tree = Arc::new(Tree::new(
         TreeBlob::empty(),
-        TreeChildren::try_from(vec![StrongReference::from_weak(BlobDigest((
-            [0u8; 32], [0u8; 32],
-        )))])
+        TreeChildren::try_from(vec![StrongReference::from_weak(BlobDigest::new(
+            &[0u8; 64],
+        ))])
         .unwrap(),
     ));
     let reference = calculate_reference(&tree);
@@ -135,9 +136,9 @@ fn test_calculate_reference_blob_no_references_1() {
 fn test_calculate_reference_blob_yes_references_1() {
     let tree = Arc::new(Tree::new(
         TreeBlob::try_from(bytes::Bytes::from("Hello, world!")).unwrap(),
-        TreeChildren::try_from(vec![StrongReference::from_weak(BlobDigest((
-            [0u8; 32], [0u8; 32],
-        )))])
+        TreeChildren::try_from(vec![StrongReference::from_weak(BlobDigest::new(
+            &[0u8; 64],
+        ))])
         .unwrap(),
     ));
     let reference = calculate_reference(&tree);
@@ -152,8 +153,8 @@ fn test_calculate_reference_blob_no_references_2() {
     let tree = Arc::new(Tree::new(
         TreeBlob::empty(),
         TreeChildren::try_from(vec![
-            StrongReference::from_weak(BlobDigest(([0u8; 32], [0u8; 32]))),
-            StrongReference::from_weak(BlobDigest(([1u8; 32], [1u8; 32]))),
+            StrongReference::from_weak(BlobDigest::new(&[0u8; 64])),
+            StrongReference::from_weak(BlobDigest::new(&[1u8; 64])),
         ])
         .unwrap(),
     ));
@@ -169,8 +170,8 @@ fn test_calculate_reference_blob_yes_references_2() {
     let tree = Arc::new(Tree::new(
         TreeBlob::try_from(bytes::Bytes::from("Hello, world!")).unwrap(),
         TreeChildren::try_from(vec![
-            StrongReference::from_weak(BlobDigest(([0u8; 32], [0u8; 32]))),
-            StrongReference::from_weak(BlobDigest(([1u8; 32], [1u8; 32]))),
+            StrongReference::from_weak(BlobDigest::new(&[0u8; 64])),
+            StrongReference::from_weak(BlobDigest::new(&[1u8; 64])),
         ])
         .unwrap(),
     ));
@@ -180,3 +181,82 @@ fn test_calculate_reference_blob_yes_references_2() {
         BlobDigest::parse_hex_string(
             "ed2f76ba42ecee524b9cbdd10a8eedd879b0a2a1a8f51f633c40a8293fee31f2d75c8b07b95f1f4696ddb3b9aef71b9a1fe45e04347224f2ae405b6bb3a96124").unwrap());
 }
+
+#[test_log::test]
+fn blob_digest_display_and_parse() {
+    let sha3 = BlobDigest::new(&[0xab; 64]);
+    let sha3_string = format!("14{}", "ab".repeat(64));
+    assert_eq!(sha3_string, sha3.to_string());
+    assert_eq!(Some(sha3), BlobDigest::parse_hex_string(&sha3_string));
+    assert_eq!(
+        Some(sha3),
+        BlobDigest::parse_hex_string(&"ab".repeat(64)),
+        "untagged digests are SHA3-512"
+    );
+
+    let blake3 = BlobDigest::blake3(&[0xcd; 32]);
+    let blake3_string = format!("1e{}", "cd".repeat(32));
+    assert_eq!(blake3_string, blake3.to_string());
+    assert_eq!(Some(blake3), BlobDigest::parse_hex_string(&blake3_string));
+    assert_eq!(DigestAlgorithm::Blake3, blake3.algorithm());
+    assert_eq!(&[0xcd; 32][..], blake3.as_bytes());
+
+    // Unknown tag
+    assert_eq!(
+        None,
+        BlobDigest::parse_hex_string(&format!("1f{}", "cd".repeat(32)))
+    );
+    // Length doesn't match the tag
+    assert_eq!(
+        None,
+        BlobDigest::parse_hex_string(&format!("1e{}", "cd".repeat(64)))
+    );
+}
+
+#[test_log::test]
+fn blob_digest_serde_round_trip() {
+    for digest in [BlobDigest::new(&[7; 64]), BlobDigest::blake3(&[8; 32])] {
+        let serialized = postcard::to_allocvec(&digest).unwrap();
+        assert_eq!(digest.to_tagged_bytes().len() + 1, serialized.len());
+        assert_eq!(digest, postcard::from_bytes(&serialized).unwrap());
+    }
+}
+
+#[test_log::test]
+fn test_calculate_reference_blake3() {
+    let tree = Arc::new(Tree::empty());
+    let reference = calculate_reference_with(&tree, DigestAlgorithm::Blake3);
+    assert_eq!(
+        BlobDigest::blake3(blake3::hash(&[0u8; 16]).as_bytes()),
+        reference
+    );
+    assert_eq!(
+        &reference,
+        HashedTree::from_with_algorithm(tree, DigestAlgorithm::Blake3).digest()
+    );
+}
+
+#[test_log::test]
+fn test_calculate_reference_mixed_algorithms() {
+    let blake3_child = BlobDigest::blake3(&[1; 32]);
+    let sha3_child = BlobDigest::new(&[2; 64]);
+    let tree = Tree::new(
+        TreeBlob::empty(),
+        TreeChildren::try_from(vec![
+            StrongReference::from_weak(blake3_child),
+            StrongReference::from_weak(sha3_child),
+        ])
+        .unwrap(),
+    );
+    let sha3 = calculate_reference(&tree);
+    let blake3 = calculate_reference_with(&tree, DigestAlgorithm::Blake3);
+    assert_eq!(DigestAlgorithm::Sha3_512, sha3.algorithm());
+    assert_eq!(DigestAlgorithm::Blake3, blake3.algorithm());
+
+    let mut expected = blake3::Hasher::new();
+    expected.update(&0u64.to_be_bytes());
+    expected.update(&2u64.to_be_bytes());
+    expected.update(&blake3_child.to_tagged_bytes());
+    expected.update(&sha3_child.to_tagged_bytes());
+    assert_eq!(BlobDigest::blake3(expected.finalize().as_bytes()), blake3);
+}
@@ -0,0 +1,153 @@
+use crate::{
+    storage::{LoadError, LoadTree, MeasureStoredSize},
+    traversal::{traverse, TraversalError, TraversalOrder},
+    tree::BlobDigest,
+};
+use futures_util::StreamExt;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    pub trees: u64,
+    /// Sum of the blob sizes before compression.
+    pub blob_bytes: u64,
+    /// Sum of the sizes reported by [MeasureStoredSize].
+    pub stored_bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, tree: &TreeUsage) {
+        self.trees += 1;
+        self.blob_bytes += tree.blob_bytes;
+        self.stored_bytes += tree.stored_bytes;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootUsage {
+    pub root: BlobDigest,
+    /// Every tree reachable from the root, counted once.
+    pub total: Usage,
+    /// The part of `total` that is also reachable from other roots.
+    pub shared: Usage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageReport {
+    /// One entry per distinct root in the order they were passed in.
+    pub roots: Vec<RootUsage>,
+    /// Everything reachable from any of the roots, counting every tree once.
+    pub combined: Usage,
+    /// Trees reachable from more than one root.
+    pub shared: Usage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsageReportError {
+    Traversal(TraversalError),
+    Load(LoadError),
+    /// The storage doesn't know the size of a tree that was just loaded from it.
+    StoredSizeMissing(BlobDigest),
+}
+
+impl std::fmt::Display for UsageReportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for UsageReportError {}
+
+#[derive(Debug)]
+struct TreeUsage {
+    blob_bytes: u64,
+    stored_bytes: u64,
+    root_count: u32,
+}
+
+/// Answers "how much does each of these roots keep alive?". Every root is traversed on its own, so trees shared between
+/// roots are loaded once per root. Up to `concurrency` trees are loaded at the same time.
+pub async fn usage_report<S>(
+    storage: Arc<S>,
+    roots: &[BlobDigest],
+    concurrency: usize,
+) -> Result<UsageReport, UsageReportError>
+where
+    S: LoadTree + MeasureStoredSize + Send + Sync + 'static,
+{
+    let mut distinct_roots: Vec<BlobDigest> = Vec::new();
+    let mut seen_roots: BTreeSet<BlobDigest> = BTreeSet::new();
+    for root in roots {
+        if seen_roots.insert(*root) {
+            distinct_roots.push(*root);
+        }
+    }
+
+    let mut trees: BTreeMap<BlobDigest, TreeUsage> = BTreeMap::new();
+    let mut trees_by_root: Vec<Vec<BlobDigest>> = Vec::with_capacity(distinct_roots.len());
+    for root in &distinct_roots {
+        let mut reachable = Vec::new();
+        let mut stream = traverse(
+            storage.clone(),
+            std::slice::from_ref(root),
+            TraversalOrder::BreadthFirst,
+            concurrency,
+        );
+        while let Some(tree) = stream.next().await {
+            let tree = tree.map_err(UsageReportError::Traversal)?;
+            trees
+                .entry(*tree.digest())
+                .or_insert_with(|| TreeUsage {
+                    blob_bytes: tree.tree().blob().len() as u64,
+                    stored_bytes: 0,
+                    root_count: 0,
+                })
+                .root_count += 1;
+            reachable.push(*tree.digest());
+        }
+        trees_by_root.push(reachable);
+    }
+
+    let digests: Vec<BlobDigest> = trees.keys().copied().collect();
+    let stored_sizes = storage
+        .stored_sizes(&digests)
+        .await
+        .map_err(UsageReportError::Load)?;
+    for (digest, stored_size) in digests.iter().zip(stored_sizes) {
+        trees
+            .get_mut(digest)
+            .expect("Every digest is known")
+            .stored_bytes = stored_size.ok_or(UsageReportError::StoredSizeMissing(*digest))?;
+    }
+
+    let mut report = UsageReport {
+        roots: Vec::with_capacity(distinct_roots.len()),
+        combined: Usage::default(),
+        shared: Usage::default(),
+    };
+    for tree in trees.values() {
+        report.combined.add(tree);
+        if tree.root_count > 1 {
+            report.shared.add(tree);
+        }
+    }
+    for (root, reachable) in distinct_roots.into_iter().zip(trees_by_root) {
+        let mut usage = RootUsage {
+            root,
+            total: Usage::default(),
+            shared: Usage::default(),
+        };
+        for digest in reachable {
+            let tree = &trees[&digest];
+            usage.total.add(tree);
+            if tree.root_count > 1 {
+                usage.shared.add(tree);
+            }
+        }
+        report.roots.push(usage);
+    }
+    Ok(report)
+}
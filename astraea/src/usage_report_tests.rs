@@ -0,0 +1,119 @@
+use crate::{
+    blob_codec::BlobCodec,
+    in_memory_storage::InMemoryTreeStorage,
+    sqlite_storage::{SQLiteStorage, SQLiteStorageConfiguration},
+    storage::LoadError,
+    storage::{StoreTree, StrongReference},
+    traversal::TraversalError,
+    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
+    usage_report::{usage_report, RootUsage, Usage, UsageReport, UsageReportError},
+};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+fn make_tree(content: &str, children: Vec<StrongReference>) -> HashedTree {
+    HashedTree::from(Arc::new(Tree::new(
+        TreeBlob::try_from(Bytes::from(content.to_string())).unwrap(),
+        TreeChildren::try_from(children).unwrap(),
+    )))
+}
+
+fn usage(trees: u64, bytes: u64) -> Usage {
+    Usage {
+        trees,
+        blob_bytes: bytes,
+        stored_bytes: bytes,
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_usage_report() {
+    let storage = Arc::new(InMemoryTreeStorage::empty());
+    let shared = storage
+        .store_tree(&make_tree("shared", vec![]))
+        .await
+        .unwrap();
+    let a = storage
+        .store_tree(&make_tree("a", vec![shared.clone(), shared.clone()]))
+        .await
+        .unwrap();
+    let b_only = storage
+        .store_tree(&make_tree("b only", vec![]))
+        .await
+        .unwrap();
+    let b = storage
+        .store_tree(&make_tree("root b", vec![shared.clone(), b_only]))
+        .await
+        .unwrap();
+    let unrelated = storage
+        .store_tree(&make_tree("unrelated", vec![]))
+        .await
+        .unwrap();
+    assert_eq!(
+        UsageReport {
+            roots: vec![
+                RootUsage {
+                    root: *a.digest(),
+                    total: usage(2, 1 + 6),
+                    shared: usage(1, 6),
+                },
+                RootUsage {
+                    root: *b.digest(),
+                    total: usage(3, 6 + 6 + 6),
+                    shared: usage(1, 6),
+                },
+                RootUsage {
+                    root: *unrelated.digest(),
+                    total: usage(1, 9),
+                    shared: usage(0, 0),
+                },
+            ],
+            combined: usage(5, 1 + 6 + 6 + 6 + 9),
+            shared: usage(1, 6),
+        },
+        usage_report(
+            storage.clone(),
+            &[*a.digest(), *b.digest(), *a.digest(), *unrelated.digest()],
+            4
+        )
+        .await
+        .unwrap()
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_usage_report_stored_bytes() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = Arc::new(
+        SQLiteStorage::from_with_configuration(
+            connection,
+            SQLiteStorageConfiguration {
+                codec: BlobCodec::Lz4,
+                ..Default::default()
+            },
+        )
+        .unwrap(),
+    );
+    let content = "compressible ".repeat(100);
+    let root = storage
+        .store_tree(&make_tree(&content, vec![]))
+        .await
+        .unwrap();
+    let report = usage_report(storage, &[*root.digest()], 1).await.unwrap();
+    assert_eq!(1, report.combined.trees);
+    assert_eq!(content.len() as u64, report.combined.blob_bytes);
+    assert!(report.combined.stored_bytes < report.combined.blob_bytes);
+    assert_eq!(report.combined, report.roots[0].total);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_usage_report_missing_root() {
+    let storage = Arc::new(InMemoryTreeStorage::empty());
+    let missing = BlobDigest::hash(b"missing");
+    assert_eq!(
+        UsageReportError::Traversal(TraversalError::Load(LoadError::TreeNotFound(missing))),
+        usage_report(storage, &[missing], 1).await.unwrap_err()
+    );
+}
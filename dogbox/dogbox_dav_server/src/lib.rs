@@ -1,7 +1,9 @@
 use astraea::{
-    sqlite_storage::SQLiteStorage,
+    instrumented_storage::InstrumentedStorage,
+    prometheus_text::PrometheusText,
+    sqlite_storage::{SQLiteStorage, SQLiteStorageConfiguration},
     storage::{CollectGarbage, CommitChanges, LoadRoot, UpdateRoot},
-    tree::TREE_BLOB_MAX_LENGTH,
+    tree::{DigestAlgorithm, TREE_BLOB_MAX_LENGTH},
 };
 use dav_server::{fakels::FakeLs, DavHandler};
 use dogbox_tree_editor::{
@@ -28,6 +30,81 @@ mod file_system_tests;
 #[cfg(test)]
 mod lib_tests;
 
+/// Served at [METRICS_PATH] in the Prometheus text format. The metrics have their own listener, so that every path of the
+/// DAV server remains available for files.
+struct Metrics {
+    storage: Arc<InstrumentedStorage>,
+    database: Arc<SQLiteStorage>,
+}
+
+const METRICS_PATH: &str = "/metrics";
+
+impl Metrics {
+    fn render(&self) -> String {
+        let mut text = PrometheusText::new();
+        self.storage.metrics().write_prometheus("sqlite", &mut text);
+        self.database.stats().write_prometheus(&mut text);
+        text.finish()
+    }
+}
+
+async fn serve_metrics_connection(
+    stream: TcpStream,
+    remote_endpoint: &SocketAddr,
+    metrics: Arc<Metrics>,
+) {
+    let make_service = move |request: Request<body::Incoming>| {
+        debug!("Metrics request from {}: {:?}", remote_endpoint, &request);
+        let metrics = metrics.clone();
+        async move {
+            let response =
+                if request.method() == hyper::Method::GET && request.uri().path() == METRICS_PATH {
+                    hyper::Response::builder()
+                        .header("Content-Type", "text/plain; version=0.0.4")
+                        .body(dav_server::body::Body::from(metrics.render()))
+                        .unwrap()
+                } else {
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::NOT_FOUND)
+                        .body(dav_server::body::Body::empty())
+                        .unwrap()
+                };
+            Ok::<_, Infallible>(response)
+        }
+    };
+    match http1::Builder::new()
+        .serve_connection(
+            TokioIo::new(stream),
+            hyper::service::service_fn(make_service),
+        )
+        .await
+    {
+        Ok(_) => {
+            debug!("Successfully served metrics connection {}", remote_endpoint);
+        }
+        Err(err) => {
+            info!(
+                "Error serving metrics connection {}: {:?}",
+                remote_endpoint, err
+            );
+        }
+    }
+}
+
+async fn handle_metrics_connections(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        let (stream, remote_endpoint) = listener.accept().await?;
+        debug!("Incoming metrics connection from {}", &remote_endpoint);
+        let metrics = metrics.clone();
+        tokio::task::spawn(async move {
+            serve_metrics_connection(stream, &remote_endpoint, metrics).await
+        });
+    }
+}
+
 async fn serve_connection(
     stream: TcpStream,
     remote_endpoint: &SocketAddr,
@@ -318,12 +395,15 @@ async fn persist_root_on_change(
     }
 }
 
+/// Metrics are served at `/metrics` of `metrics_listener` if there is one.
 pub async fn run_dav_server(
     listener: TcpListener,
+    metrics_listener: Option<TcpListener>,
     database_file_name: &Path,
     modified_default: std::time::SystemTime,
     clock: WallClock,
     auto_save_interval: std::time::Duration,
+    digest_algorithm: DigestAlgorithm,
 ) -> Result<
     (
         tokio::sync::mpsc::Receiver<SaveStatus>,
@@ -365,7 +445,23 @@ pub async fn run_dav_server(
         }
         debug!("Created SQL schema in {}", &database_file_name.display());
     }
-    let blob_storage_database = Arc::new(SQLiteStorage::from(sqlite_connection)?);
+    let blob_storage_database = Arc::new(SQLiteStorage::from_with_configuration(
+        sqlite_connection,
+        SQLiteStorageConfiguration {
+            // Lets many clients read files while the tree is being saved.
+            reader_connections: 4,
+            // New files and directories get digests of this algorithm. Existing trees keep theirs.
+            digest_algorithm,
+            ..Default::default()
+        },
+    )?);
+    let instrumented_storage = Arc::new(InstrumentedStorage::with_dedup_hits(
+        blob_storage_database.clone(),
+    ));
+    let metrics = Arc::new(Metrics {
+        storage: instrumented_storage.clone(),
+        database: blob_storage_database.clone(),
+    });
     let root_name = "latest";
     let open_file_write_buffer_in_blocks = DEFAULT_WRITE_BUFFER_IN_BLOCKS;
     let root_path = std::path::PathBuf::from("/");
@@ -373,11 +469,11 @@ pub async fn run_dav_server(
         Some(found) => {
             OpenDirectory::load_directory(
                 root_path,
-                blob_storage_database.clone(), &found, modified_default, clock, open_file_write_buffer_in_blocks).await.unwrap(/*TODO*/)
+                instrumented_storage.clone(), &found, modified_default, clock, open_file_write_buffer_in_blocks).await.unwrap(/*TODO*/)
         }
         None => {
             let dir = Arc::new(
-                OpenDirectory::create_directory(root_path,blob_storage_database.clone(), clock,
+                OpenDirectory::create_directory(root_path,instrumented_storage.clone(), clock,
                 open_file_write_buffer_in_blocks)
                 .await
                 .unwrap(/*TODO*/),
@@ -441,6 +537,14 @@ pub async fn run_dav_server(
                 async move {
                     handle_tcp_connections(listener, dav_server).await.unwrap();
                     Ok(())
+                },
+                async move {
+                    if let Some(metrics_listener) = metrics_listener {
+                        handle_metrics_connections(metrics_listener, metrics)
+                            .await
+                            .unwrap();
+                    }
+                    Ok(())
                 }
             );
             join_result.map(|_| ())
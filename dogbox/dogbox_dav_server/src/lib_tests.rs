@@ -1,5 +1,5 @@
 use crate::run_dav_server;
-use astraea::tree::TREE_BLOB_MAX_LENGTH;
+use astraea::tree::{DigestAlgorithm, TREE_BLOB_MAX_LENGTH};
 use dav_server::fs::DavMetaData;
 use dogbox_tree_editor::{OpenDirectory, WallClock};
 use pretty_assertions::assert_eq;
@@ -73,11 +73,13 @@ async fn run_dav_server_instance<'t>(
     let server_url = format!("http://{actual_address}");
     let (mut save_status_receiver, server, root_directory) = run_dav_server(
         listener,
+        None,
         database_file_name,
         modified_default,
         clock,
         // don't waste time with the tests (more than 0 seconds to avoid wasting too many CPU cycles)
         std::time::Duration::from_millis(1),
+        DigestAlgorithm::Sha3_512,
     )
     .await
     .unwrap();
@@ -381,6 +383,68 @@ async fn test_file_not_found() {
     test_fresh_dav_server(None, &verify_changes, clock).await
 }
 
+#[test_log::test(tokio::test)]
+async fn test_metrics_are_served_separately() {
+    let temporary_directory = tempfile::tempdir().unwrap();
+    let database_file_name = temporary_directory.path().join("dogbox_dav_server.sqlite");
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let server_url = format!("http://{}", listener.local_addr().unwrap());
+    let metrics_listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let metrics_url = format!("http://{}/metrics", metrics_listener.local_addr().unwrap());
+    let clock = make_simple_clock();
+    let (_save_status_receiver, server, _root_directory) = run_dav_server(
+        listener,
+        Some(metrics_listener),
+        &database_file_name,
+        clock(),
+        clock,
+        std::time::Duration::from_millis(1),
+        DigestAlgorithm::Sha3_512,
+    )
+    .await
+    .unwrap();
+    let testing = async {
+        // The same path on the DAV server is an ordinary file.
+        let client = create_client(server_url);
+        client
+            .put("/metrics", b"file content".to_vec())
+            .await
+            .unwrap();
+        let response = client.get("/metrics").await.unwrap();
+        assert_eq!(&b"file content"[..], &response.bytes().await.unwrap()[..]);
+        client.put("/copy", b"file content".to_vec()).await.unwrap();
+
+        let agent = reqwest::Client::builder()
+            .use_rustls_tls()
+            .tls_certs_only([])
+            .build()
+            .unwrap();
+        let response = agent.get(metrics_url).send().await.unwrap();
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+        let text = response.text().await.unwrap();
+        assert!(
+            text.contains("astraea_storage_calls_total{storage=\"sqlite\",operation=\"store\"}"),
+            "{text}"
+        );
+        // The second file has the same content as the first one, so its blocks already exist.
+        assert!(
+            !text.contains("astraea_storage_dedup_hits_total{storage=\"sqlite\"} 0\n"),
+            "{text}"
+        );
+    };
+    tokio::select! {
+        result = server => {
+            panic!("Server isn't expected to exit: {result:?}");
+        }
+        _ = testing => {
+        }
+    };
+}
+
 async fn test_create_file(content: Vec<u8>) {
     let clock_simulator = simulate_clock();
     let file_name = "test.txt";
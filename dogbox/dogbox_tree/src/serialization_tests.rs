@@ -3,6 +3,7 @@ use crate::serialization::{
     FileName, FileNameContent, FileNameError,
 };
 use astraea::{
+    fault_injecting_storage::{Fault, FaultInjectingStorage, FaultOperation, FaultRule},
     in_memory_storage::InMemoryTreeStorage,
     storage::{StoreTree, StrongReference},
     tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren, TREE_MAX_CHILDREN},
@@ -151,3 +152,47 @@ async fn test_deserialize_directory() {
         .unwrap();
     assert_eq!(original, deserialized);
 }
+
+#[test_log::test(tokio::test)]
+async fn test_deserialize_directory_with_storage_faults() {
+    let storage = FaultInjectingStorage::new(Arc::new(InMemoryTreeStorage::empty()));
+    let file = storage
+        .store_tree(&HashedTree::from(Arc::new(Tree::new(
+            TreeBlob::try_from(bytes::Bytes::from("content")).unwrap(),
+            TreeChildren::empty(),
+        ))))
+        .await
+        .unwrap();
+    let entries = BTreeMap::from([(
+        FileName::try_from("file".to_string()).unwrap(),
+        (
+            DirectoryEntryMetaData::new(
+                DirectoryEntryKind::File(7),
+                std::time::SystemTime::UNIX_EPOCH,
+            ),
+            file,
+        ),
+    )]);
+    let directory = serialize_directory(&entries, &storage).await.unwrap();
+    for fault in [Fault::Fail, Fault::NotFound, Fault::Corrupt] {
+        storage.add_rule(FaultRule::once(
+            FaultOperation::Load,
+            *directory.digest(),
+            fault,
+        ));
+        assert!(
+            deserialize_directory(&storage, directory.digest())
+                .await
+                .is_err(),
+            "{fault:?}"
+        );
+    }
+    assert_eq!(
+        entries.into_iter().collect::<Vec<_>>(),
+        deserialize_directory(&storage, directory.digest())
+            .await
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>()
+    );
+}
@@ -142,6 +142,7 @@ fn read_large_file<S: Fn() -> Arc<dyn LoadStoreTree + Send + Sync>>(
 
 const UNREALISTICALLY_LARGE_READ_SIZE: usize = usize::MAX;
 const WINDOWS_WEBDAV_READ_SIZE: usize = 16384;
+const LOAD_CACHE_MAX_BYTES: u64 = 64 * 1024 * 1024;
 
 fn make_single_threaded_runtime() -> Runtime {
     Builder::new_current_thread().build().unwrap()
@@ -213,7 +214,10 @@ fn read_large_file_sqlite_in_memory_storage_cold_realistic_read_size(c: &mut Cri
 }
 
 fn read_large_file_sqlite_in_memory_storage_cold_with_load_cache_hot(c: &mut Criterion) {
-    let storage = Arc::new(LoadCache::new(make_sqlite_in_memory_storage(), 1000));
+    let storage = Arc::new(LoadCache::new(
+        make_sqlite_in_memory_storage(),
+        LOAD_CACHE_MAX_BYTES,
+    ));
     read_large_file(
         c,
         "read_large_file_sqlite_in_memory_storage_cold_with_load_cache_hot",
@@ -231,7 +235,7 @@ fn read_large_file_sqlite_in_memory_storage_cold_with_load_cache_cold(c: &mut Cr
         "read_large_file_sqlite_in_memory_storage_cold_with_load_cache_cold",
         false,
         UNREALISTICALLY_LARGE_READ_SIZE,
-        || Arc::new(LoadCache::new(storage.clone(), 1000)),
+        || Arc::new(LoadCache::new(storage.clone(), LOAD_CACHE_MAX_BYTES)),
         make_multi_threaded_runtime(),
     );
 }
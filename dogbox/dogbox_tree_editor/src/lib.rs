@@ -14,7 +14,9 @@ mod sqlite_tests;
 use crate::segmented_blob::{load_segmented_blob, save_segmented_blob};
 use astraea::{
     storage::{LoadStoreTree, StoreError, StrongHashedTree, StrongReference},
-    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren, TREE_BLOB_MAX_LENGTH},
+    tree::{
+        BlobDigest, DigestAlgorithm, HashedTree, Tree, TreeBlob, TreeChildren, TREE_BLOB_MAX_LENGTH,
+    },
 };
 use async_stream::stream;
 use bytes::Buf;
@@ -1754,10 +1756,13 @@ impl OpenFileContentBlock {
                             return Ok(None);
                         }
                         debug!("Calculating unknown digest of size {}", vec.len());
-                        HashedTree::from(Arc::new(Tree::new(
-                            TreeBlob::try_from( bytes::Bytes::from(vec.clone() /*TODO: avoid clone*/)).unwrap(/*TODO*/),
-                            TreeChildren::empty(),
-                        )))
+                        HashedTree::from_with_algorithm(
+                            Arc::new(Tree::new(
+                                TreeBlob::try_from( bytes::Bytes::from(vec.clone() /*TODO: avoid clone*/)).unwrap(/*TODO*/),
+                                TreeChildren::empty(),
+                            )),
+                            storage.digest_algorithm(),
+                        )
                     }
                 };
                 let size = hashed_tree.tree().blob().len();
@@ -2153,10 +2158,14 @@ impl OpenFileContentBufferLoaded {
                 .await?;
         }
         if new_number_of_blocks > self.blocks.len() {
-            let filler_hashed_tree = HashedTree::from(Arc::new(Tree::new(
-                TreeBlob::try_from(bytes::Bytes::from(vec![0u8; TREE_BLOB_MAX_LENGTH])).unwrap(),
-                TreeChildren::empty(),
-            )));
+            let filler_hashed_tree = HashedTree::from_with_algorithm(
+                Arc::new(Tree::new(
+                    TreeBlob::try_from(bytes::Bytes::from(vec![0u8; TREE_BLOB_MAX_LENGTH]))
+                        .unwrap(),
+                    TreeChildren::empty(),
+                )),
+                storage.digest_algorithm(),
+            );
             let filler_reference = storage
                 .store_tree(&filler_hashed_tree)
                 .await
@@ -2217,8 +2226,17 @@ impl OptimizedWriteBuffer {
         &self.suffix
     }
 
-    //#[instrument(skip(content))]
+    /// Hashes the full blocks with SHA3-512. See [Self::from_bytes_with_algorithm].
     pub async fn from_bytes(write_position: u64, content: bytes::Bytes) -> OptimizedWriteBuffer {
+        Self::from_bytes_with_algorithm(write_position, content, DigestAlgorithm::Sha3_512).await
+    }
+
+    //#[instrument(skip(content))]
+    pub async fn from_bytes_with_algorithm(
+        write_position: u64,
+        content: bytes::Bytes,
+        algorithm: DigestAlgorithm,
+    ) -> OptimizedWriteBuffer {
         let first_block_offset = (write_position % TREE_BLOB_MAX_LENGTH as u64) as usize;
         let first_block_capacity = TREE_BLOB_MAX_LENGTH - first_block_offset;
         let mut block_aligned_content = content.clone();
@@ -2256,11 +2274,14 @@ impl OptimizedWriteBuffer {
 
             // Calculating the SHA-3 digest of 64 KB of data can take surprisingly long, especially in Debug mode.
             // Parallelizing the computations should save a lot of time.
-            let blocking_task = tokio::task::spawn_blocking(|| {
-                HashedTree::from(Arc::new(Tree::new(
-                    TreeBlob::try_from(next).unwrap(),
-                    TreeChildren::empty(),
-                )))
+            let blocking_task = tokio::task::spawn_blocking(move || {
+                HashedTree::from_with_algorithm(
+                    Arc::new(Tree::new(
+                        TreeBlob::try_from(next).unwrap(),
+                        TreeChildren::empty(),
+                    )),
+                    algorithm,
+                )
             });
             full_block_hashing.push(blocking_task);
         }
@@ -2602,11 +2623,14 @@ impl OpenFileContentBuffer {
             }
             if first_block_index > (loaded.blocks.len() as u64) {
                 // We only need to calculate this block once and can use it many times in the loop below.
-                let filler_hashed_tree = HashedTree::from(Arc::new(Tree::new(
-                    TreeBlob::try_from(bytes::Bytes::from(vec![0u8; TREE_BLOB_MAX_LENGTH]))
-                        .unwrap(),
-                    TreeChildren::empty(),
-                )));
+                let filler_hashed_tree = HashedTree::from_with_algorithm(
+                    Arc::new(Tree::new(
+                        TreeBlob::try_from(bytes::Bytes::from(vec![0u8; TREE_BLOB_MAX_LENGTH]))
+                            .unwrap(),
+                        TreeChildren::empty(),
+                    )),
+                    storage.digest_algorithm(),
+                );
                 let filler_reference = storage
                     .store_tree(&filler_hashed_tree)
                     .await
@@ -2945,7 +2969,16 @@ impl OpenFile {
         self.assert_write_permission(write_permission);
         debug!("Write at {}: {} bytes", position, buf.len());
         Box::pin(async move {
-            let write_buffer = OptimizedWriteBuffer::from_bytes(position, buf).await;
+            // The lock isn't held while hashing so that reads can continue in the meantime.
+            let algorithm = match self.state.lock().await.storage.as_ref() {
+                Some(storage) => storage.digest_algorithm(),
+                None => {
+                    warn!("Cannot write to a removed file");
+                    return Err(Error::FileRemoved);
+                }
+            };
+            let write_buffer =
+                OptimizedWriteBuffer::from_bytes_with_algorithm(position, buf, algorithm).await;
             let mut state_locked = self.state.lock().await;
             let storage = match state_locked.storage.as_ref() {
                 Some(storage) => storage.clone(),
@@ -3184,10 +3217,10 @@ impl TreeEditor {
     ) -> Result<StrongReference> {
         debug!("Storing empty file");
         match storage
-            .store_tree(&HashedTree::from(Arc::new(Tree::new(
-                TreeBlob::empty(),
-                TreeChildren::empty(),
-            ))))
+            .store_tree(&HashedTree::from_with_algorithm(
+                Arc::new(Tree::new(TreeBlob::empty(), TreeChildren::empty())),
+                storage.digest_algorithm(),
+            ))
             .await
         {
             Ok(success) => Ok(success),
@@ -5,8 +5,9 @@ use crate::{
     OpenFileContentBuffer, OpenFileContentBufferLoaded, OpenFileStats, OptimizedWriteBuffer,
     Prefetcher, StoreChanges, StreakDirection, TreeEditor, WallClock,
 };
+use astraea::fault_injecting_storage::{Fault, FaultInjectingStorage, FaultOperation, FaultRule};
 use astraea::in_memory_storage::InMemoryTreeStorage;
-use astraea::sqlite_storage::SQLiteStorage;
+use astraea::sqlite_storage::{SQLiteStorage, SQLiteStorageConfiguration};
 use astraea::storage::{
     CollectGarbage, GarbageCollectionStats, LoadError, LoadTree, StoreError, StoreTree,
     StrongDelayedHashedTree, StrongHashedTree, StrongReference, UpdateRoot,
@@ -14,7 +15,7 @@ use astraea::storage::{
 use astraea::tree::{TreeChildren, TREE_MAX_CHILDREN};
 use astraea::{
     storage::LoadStoreTree,
-    tree::{BlobDigest, HashedTree, Tree, TreeBlob, TREE_BLOB_MAX_LENGTH},
+    tree::{BlobDigest, DigestAlgorithm, HashedTree, Tree, TreeBlob, TREE_BLOB_MAX_LENGTH},
 };
 use async_trait::async_trait;
 use derivative::Derivative;
@@ -749,6 +750,146 @@ async fn test_read_file_after_garbage_collection() {
     }
 }
 
+#[test_log::test(tokio::test)]
+async fn test_flush_fails_while_storage_fails() {
+    let modified = test_clock();
+    let storage = Arc::new(FaultInjectingStorage::new(Arc::new(
+        InMemoryTreeStorage::empty(),
+    )));
+    let empty_file_reference = TreeEditor::store_empty_file(storage.clone()).await.unwrap();
+    let directory = Arc::new(OpenDirectory::new(
+        std::path::PathBuf::from("/"),
+        DigestStatus::new(empty_file_reference.clone(), false),
+        BTreeMap::new(),
+        storage.clone(),
+        modified,
+        Arc::new(test_clock),
+        1,
+    ));
+    let file_name = FileName::try_from("test.txt".to_string()).unwrap();
+    let opened = directory
+        .clone()
+        .open_file(
+            &file_name,
+            FileCreationMode::create_new(empty_file_reference, 0),
+        )
+        .await
+        .unwrap();
+    let write_permission = opened.get_write_permission();
+    let read_permission = opened.get_read_permission();
+    let file_content = random_bytes(TREE_BLOB_MAX_LENGTH * 2, 456);
+    opened
+        .write_bytes(
+            &write_permission,
+            0,
+            bytes::Bytes::copy_from_slice(&file_content[..]),
+        )
+        .await
+        .unwrap();
+
+    storage.add_rule(FaultRule::always(FaultOperation::Store, Fault::Fail));
+    let error = opened.flush().await.unwrap_err();
+    assert!(
+        matches!(error, Error::Storage(StoreError::Io(_))),
+        "Unexpected error: {error:?}"
+    );
+    assert!(directory.request_save().await.is_err());
+
+    // Nothing was lost, so everything can be saved once the storage works again.
+    storage.clear_rules();
+    opened.flush().await.unwrap();
+    let status = directory.request_save().await.unwrap();
+    assert!(status.digest.is_digest_up_to_date);
+    // Reads end at block boundaries.
+    let mut read = Vec::new();
+    while read.len() < file_content.len() {
+        let bytes = opened
+            .read_bytes(
+                &read_permission,
+                read.len() as u64,
+                file_content.len() - read.len(),
+            )
+            .await
+            .unwrap();
+        assert!(!bytes.is_empty());
+        read.extend_from_slice(&bytes);
+    }
+    assert_eq!(file_content, read);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_new_trees_use_digest_algorithm_of_storage() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    SQLiteStorage::create_schema(&connection).unwrap();
+    let storage = Arc::new(
+        SQLiteStorage::from_with_configuration(
+            connection,
+            SQLiteStorageConfiguration {
+                digest_algorithm: DigestAlgorithm::Blake3,
+                ..Default::default()
+            },
+        )
+        .unwrap(),
+    );
+    let directory = Arc::new(
+        OpenDirectory::create_directory(
+            std::path::PathBuf::from("/"),
+            storage.clone(),
+            Arc::new(test_clock),
+            1,
+        )
+        .await
+        .unwrap(),
+    );
+    let empty_file_reference = TreeEditor::store_empty_file(storage.clone()).await.unwrap();
+    let file = directory
+        .clone()
+        .open_file(
+            &FileName::try_from("test.txt".to_string()).unwrap(),
+            FileCreationMode::create_new(empty_file_reference, 0),
+        )
+        .await
+        .unwrap();
+    let write_permission = file.get_write_permission();
+    // Full blocks, a partial block, and zero-filled blocks before the second write.
+    file.write_bytes(
+        &write_permission,
+        0,
+        bytes::Bytes::from(random_bytes(TREE_BLOB_MAX_LENGTH * 2 + 100, 123)),
+    )
+    .await
+    .unwrap();
+    file.write_bytes(
+        &write_permission,
+        TREE_BLOB_MAX_LENGTH as u64 * 5,
+        bytes::Bytes::from_static(b"end"),
+    )
+    .await
+    .unwrap();
+    file.flush().await.unwrap();
+    let status = directory.request_save().await.unwrap();
+    let mut digests = Vec::new();
+    let mut pending = vec![*status.digest.last_known_digest.digest()];
+    while let Some(digest) = pending.pop() {
+        let loaded = storage.load_tree(&digest).await.unwrap().hash().unwrap();
+        pending.extend(
+            loaded
+                .hashed_tree()
+                .tree()
+                .children()
+                .references()
+                .iter()
+                .map(|child| *child.digest()),
+        );
+        digests.push(digest);
+    }
+    // The directory, the file, its segmented blob, and at least one block of each kind.
+    assert!(digests.len() >= 5, "{digests:?}");
+    for digest in digests {
+        assert_eq!(DigestAlgorithm::Blake3, digest.algorithm());
+    }
+}
+
 #[test_log::test(tokio::test)]
 async fn test_get_meta_data_after_file_write() {
     let modified = test_clock();
@@ -90,7 +90,10 @@ async fn save_segmented_blob_impl(
                 children,
             );
             let reference = storage
-                .store_tree(&HashedTree::from(Arc::new(tree)))
+                .store_tree(&HashedTree::from_with_algorithm(
+                    Arc::new(tree),
+                    storage.digest_algorithm(),
+                ))
                 .await?;
             Ok(reference)
         }
@@ -127,7 +127,12 @@ async fn download_file_impl(
         open_file_content_buffer
             .write(
                 total_bytes_read,
-                OptimizedWriteBuffer::from_bytes(total_bytes_read, Bytes::from(buffer)).await,
+                OptimizedWriteBuffer::from_bytes_with_algorithm(
+                    total_bytes_read,
+                    Bytes::from(buffer),
+                    storage.digest_algorithm(),
+                )
+                .await,
                 storage.clone(),
             )
             .await
@@ -1,7 +1,7 @@
 use crate::file_cache::{FileCache, FileCacheMap, PersistentFileCacheMap};
 use astraea::{
     in_memory_storage::InMemoryTreeStorage,
-    storage::{StoreError, StoreTree, StrongReference, UpdateRoot},
+    storage::{CompareAndSwapResult, StoreError, StoreTree, StrongReference, UpdateRoot},
     tree::{BlobDigest, HashedTree, Tree, TreeBlob, TreeChildren},
 };
 use async_trait::async_trait;
@@ -212,6 +212,22 @@ impl UpdateRoot for PersistentSaveAndLoadNonEmptyUpdateRoot {
         *current_value = Some(target.clone());
         Ok(())
     }
+
+    async fn compare_and_swap_root(
+        &self,
+        name: &str,
+        expected: Option<&BlobDigest>,
+        new: &StrongReference,
+    ) -> std::result::Result<CompareAndSwapResult, StoreError> {
+        assert_eq!(name, "test_root");
+        let mut current_value = self.current_value.lock().await;
+        let actual = current_value.as_ref().map(|current| *current.digest());
+        if actual.as_ref() != expected {
+            return Ok(CompareAndSwapResult::Conflict { actual });
+        }
+        *current_value = Some(new.clone());
+        Ok(CompareAndSwapResult::Swapped)
+    }
 }
 
 #[test_log::test(tokio::test)]
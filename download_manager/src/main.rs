@@ -36,7 +36,7 @@ mod dropbox_tests;
 fn upgrade_schema(
     connection: &rusqlite::Connection,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    for _ in 0..4 {
+    for _ in 0..5 {
         let user_version =
             connection.query_row("PRAGMA user_version;", [], |row| row.get::<_, i32>(0))?;
         match user_version {
@@ -89,6 +89,42 @@ fn upgrade_schema(
                 assert_eq!(0, connection.execute("PRAGMA user_version = 3;", ())?);
             }
             3 => {
+                // Replace result_file.sha3_512_digest with result_file.digest, which starts with the tag of the digest
+                // algorithm, so that results can have digests of any algorithm. All existing digests are SHA3-512.
+                // The check constraint can't be altered, so the table is copied.
+                let transaction = connection.unchecked_transaction()?;
+                transaction.execute_batch(
+                    "CREATE TABLE result_file_tagged (
+                        id INTEGER PRIMARY KEY NOT NULL,
+                        download_job_id INTEGER NOT NULL,
+                        digest BLOB NOT NULL,
+                        FOREIGN KEY(download_job_id) REFERENCES download_job(id)
+                    ) STRICT;",
+                )?;
+                {
+                    let mut select = transaction
+                        .prepare("SELECT id, download_job_id, sha3_512_digest FROM result_file")?;
+                    let mut insert = transaction.prepare(
+                        "INSERT INTO result_file_tagged (id, download_job_id, digest) VALUES (?1, ?2, ?3)",
+                    )?;
+                    let mut rows = select.query(())?;
+                    while let Some(row) = rows.next()? {
+                        let digest = BlobDigest::new(&row.get::<_, [u8; 64]>(2)?);
+                        insert.execute(rusqlite::params![
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, i64>(1)?,
+                            digest.to_tagged_bytes()
+                        ])?;
+                    }
+                }
+                transaction.execute_batch(
+                    "DROP TABLE result_file;
+                    ALTER TABLE result_file_tagged RENAME TO result_file;
+                    PRAGMA user_version = 4;",
+                )?;
+                transaction.commit()?;
+            }
+            4 => {
                 // Future migrations go here
                 return Ok(());
             }
@@ -143,17 +179,23 @@ fn load_downloaded_urls_from_database(
     connection: &mut rusqlite::Connection,
 ) -> rusqlite::Result<Vec<(String, BlobDigest)>> {
     let mut statement = connection.prepare(
-        "SELECT download_job.url AS url, result_file.sha3_512_digest AS digest FROM download_job, result_file WHERE download_job.id = result_file.download_job_id ORDER BY url, digest ASC",
+        "SELECT download_job.url AS url, result_file.digest AS digest FROM download_job, result_file WHERE download_job.id = result_file.download_job_id ORDER BY url, digest ASC",
     )?;
     let url_iter = statement.query_map([], |row| {
         let url = row.get::<_, String>(0)?;
-        let digest = row.get::<_, [u8; 64]>(1)?;
-        Ok((url, digest))
+        let digest = row.get::<_, Vec<u8>>(1)?;
+        match BlobDigest::from_tagged_bytes(&digest) {
+            Some(digest) => Ok((url, digest)),
+            None => Err(rusqlite::Error::FromSqlConversionFailure(
+                1,
+                rusqlite::types::Type::Blob,
+                Box::from(format!("Invalid tagged digest for URL {}", url)),
+            )),
+        }
     })?;
     let mut urls = Vec::new();
     for url_result in url_iter {
-        let (url, digest) = url_result?;
-        urls.push((url, BlobDigest::new(&digest)));
+        urls.push(url_result?);
     }
     Ok(urls)
 }
@@ -216,9 +258,9 @@ fn set_download_job_digests(
         );
     }
     for digest in digests {
-        let digest_bytes = digest.to_array();
+        let digest_bytes = digest.to_tagged_bytes();
         let rows_updated = transaction.execute(
-            "INSERT OR IGNORE INTO result_file (download_job_id, sha3_512_digest) VALUES(?1, ?2)",
+            "INSERT OR IGNORE INTO result_file (download_job_id, digest) VALUES(?1, ?2)",
             rusqlite::params![download_job_id, digest_bytes],
         )?;
         match rows_updated {
@@ -10,7 +10,7 @@ use crate::{
     telegram_bot::{HandleTelegramBotRequests, TelegramBot},
     upgrade_schema, Download, SetDownloadJobDigestOutcome,
 };
-use astraea::tree::BlobDigest;
+use astraea::tree::{calculate_reference_with, BlobDigest, DigestAlgorithm, Tree};
 use pretty_assertions::assert_eq;
 use std::sync::Arc;
 use tracing::info;
@@ -25,10 +25,68 @@ fn test_upgrade_schema() {
         let user_version = connection
             .query_row("PRAGMA user_version;", [], |row| row.get::<_, i32>(0))
             .expect("Failed to get user_version from database");
-        assert_eq!(3, user_version);
+        assert_eq!(4, user_version);
     }
 }
 
+#[test_log::test]
+fn test_upgrade_schema_tags_existing_digests() {
+    let mut connection =
+        rusqlite::Connection::open_in_memory().expect("Failed to open in-memory database");
+    // The tables as of schema version 3.
+    connection
+        .execute_batch(
+            "CREATE TABLE download_job (
+                id INTEGER PRIMARY KEY NOT NULL,
+                url TEXT UNIQUE NOT NULL,
+                fail_count INTEGER NOT NULL DEFAULT 0,
+                remaining_attempts INTEGER NOT NULL DEFAULT 1
+            ) STRICT;
+            CREATE TABLE result_file (
+                id INTEGER PRIMARY KEY NOT NULL,
+                download_job_id INTEGER NOT NULL,
+                sha3_512_digest BLOB NOT NULL,
+                FOREIGN KEY(download_job_id) REFERENCES download_job(id),
+                CONSTRAINT sha3_512_digest_length_check CHECK (LENGTH(sha3_512_digest) == 64)
+            ) STRICT;
+            PRAGMA user_version = 3;",
+        )
+        .unwrap();
+    let digest = BlobDigest::hash(b"test data");
+    connection
+        .execute(
+            "INSERT INTO download_job (id, url) VALUES (1, 'http://example.com/file1')",
+            (),
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO result_file (download_job_id, sha3_512_digest) VALUES (1, ?1)",
+            rusqlite::params![digest.as_bytes()],
+        )
+        .unwrap();
+    upgrade_schema(&connection).expect("Failed to upgrade schema");
+    assert_eq!(
+        vec![("http://example.com/file1".to_string(), digest)],
+        load_downloaded_urls_from_database(&mut connection).unwrap()
+    );
+    // Digests of other algorithms can be stored now.
+    let blake3_digest = calculate_reference_with(&Tree::empty(), DigestAlgorithm::Blake3);
+    assert_eq!(
+        SetDownloadJobDigestOutcome::Success,
+        set_download_job_digests(
+            &mut connection,
+            "http://example.com/file1",
+            &[blake3_digest]
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        vec![("http://example.com/file1".to_string(), blake3_digest)],
+        load_downloaded_urls_from_database(&mut connection).unwrap()
+    );
+}
+
 #[test_log::test]
 fn test_upgrade_schema_for_unknown_user_version() {
     let unsupported_version = 23;
@@ -310,7 +310,12 @@ pub async fn serialize_shallow(
         Ok(success) => success,
         Err(error) => return Err(StoreError::TreeSerializationError(error)),
     };
-    storage.store_tree(&HashedTree::from(Arc::new(tree))).await
+    storage
+        .store_tree(&HashedTree::from_with_algorithm(
+            Arc::new(tree),
+            storage.digest_algorithm(),
+        ))
+        .await
 }
 
 pub async fn serialize_recursively(
@@ -375,10 +380,13 @@ impl Closure {
         let closure_blob = ClosureBlob::new();
         let closure_blob_bytes = postcard::to_allocvec(&closure_blob).unwrap(/*TODO*/);
         store_tree
-            .store_tree(&HashedTree::from(Arc::new(Tree::new(
-                TreeBlob::try_from(bytes::Bytes::from_owner(closure_blob_bytes)).unwrap(/*TODO*/),
-                children,
-            ))))
+            .store_tree(&HashedTree::from_with_algorithm(
+                Arc::new(Tree::new(
+                    TreeBlob::try_from(bytes::Bytes::from_owner(closure_blob_bytes)).unwrap(/*TODO*/),
+                    children,
+                )),
+                store_tree.digest_algorithm(),
+            ))
             .await
     }
 
@@ -557,10 +565,10 @@ pub async fn evaluate(
                 }
             };
             store_tree
-                .store_tree(&HashedTree::from(Arc::new(Tree::new(
-                    TreeBlob::empty(),
-                    children,
-                ))))
+                .store_tree(&HashedTree::from_with_algorithm(
+                    Arc::new(Tree::new(TreeBlob::empty(), children)),
+                    store_tree.digest_algorithm(),
+                ))
                 .await
         }
         Expression::GetChild { parent, index } => {
@@ -63,7 +63,7 @@ async fn test_print_expression_strong_reference() {
         .unwrap();
     let mut writer = String::new();
     reference.print(&mut writer, 0).unwrap();
-    assert_eq!("9be8213097a391e7b693a99d6645d11297b72113314f5e9ef98704205a7c795e41819a670fb10a60b4ca6aa92b4abd8a50932503ec843df6c40219d49f08a623", writer.as_str());
+    assert_eq!("149be8213097a391e7b693a99d6645d11297b72113314f5e9ef98704205a7c795e41819a670fb10a60b4ca6aa92b4abd8a50932503ec843df6c40219d49f08a623", writer.as_str());
 }
 
 #[test_log::test(tokio::test)]
@@ -88,6 +88,6 @@ async fn print_shallow_expression() {
     let mut writer = String::new();
     expression.print(&mut writer, 0).unwrap();
     assert_eq!(
-        "literal(StrongReference(9be8213097a391e7b693a99d6645d11297b72113314f5e9ef98704205a7c795e41819a670fb10a60b4ca6aa92b4abd8a50932503ec843df6c40219d49f08a623))",
+        "literal(StrongReference(149be8213097a391e7b693a99d6645d11297b72113314f5e9ef98704205a7c795e41819a670fb10a60b4ca6aa92b4abd8a50932503ec843df6c40219d49f08a623))",
         writer.as_str());
 }
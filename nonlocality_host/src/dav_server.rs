@@ -1,3 +1,4 @@
+use astraea::tree::DigestAlgorithm;
 use dogbox_dav_server::run_dav_server;
 use std::{net::SocketAddr, sync::Arc};
 use tokio::net::TcpListener;
@@ -5,18 +6,30 @@ use tracing::info;
 
 pub async fn dav_server_main(
     database_file_name: &std::path::Path,
+    digest_algorithm: DigestAlgorithm,
+    metrics_address: Option<SocketAddr>,
 ) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
     let address = SocketAddr::from(([0, 0, 0, 0], 4918));
     let listener = TcpListener::bind(address).await?;
     info!("Serving DAV on http://{}", address);
+    let metrics_listener = match metrics_address {
+        Some(metrics_address) => {
+            let metrics_listener = TcpListener::bind(metrics_address).await?;
+            info!("Serving metrics on http://{}/metrics", metrics_address);
+            Some(metrics_listener)
+        }
+        None => None,
+    };
     let clock = Arc::new(std::time::SystemTime::now);
     let modified_default = clock();
     let (mut save_status_receiver, server, root_directory) = run_dav_server(
         listener,
+        metrics_listener,
         database_file_name,
         modified_default,
         clock,
         std::time::Duration::from_secs(5),
+        digest_algorithm,
     )
     .await?;
     tokio::try_join!(server, async move {
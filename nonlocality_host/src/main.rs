@@ -2,8 +2,13 @@ use crate::{
     dav_server::dav_server_main,
     operating_system::{file_exists, Directory, LinuxOperatingSystem, OperatingSystem},
 };
-use astraea::sqlite_storage::SQLiteStorage;
-use clap::{Parser, Subcommand};
+use astraea::{
+    blob_codec::{BlobCodec, ZSTD_DEFAULT_LEVEL},
+    sqlite_storage::{SQLiteStorage, SQLiteStorageConfiguration},
+    storage::CommitChanges,
+    tree::DigestAlgorithm,
+};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::{ffi::OsStr, path::Path, sync::Arc};
 use tracing::{error, info, warn};
 use tracing_subscriber::fmt::format::FmtSpan;
@@ -37,9 +42,60 @@ enum Commands {
         /// Directory containing the NonlocalityOS installation
         #[arg(value_name = "NONLOCALITY_DIRECTORY", value_parser = clap::value_parser!(std::path::PathBuf))]
         nonlocality_directory: std::path::PathBuf,
+        /// Hash function for new trees. Trees that already exist keep their digests.
+        #[arg(long, value_enum, default_value_t = DigestArgument::Blake3)]
+        digest: DigestArgument,
+        /// Serve Prometheus metrics at /metrics on this address, for example 127.0.0.1:4919
+        #[arg(long, value_name = "ADDRESS")]
+        metrics_address: Option<std::net::SocketAddr>,
+    },
+    /// Compress all trees in the database of an installation again
+    Recompress {
+        /// Directory containing the NonlocalityOS installation
+        #[arg(value_name = "NONLOCALITY_DIRECTORY", value_parser = clap::value_parser!(std::path::PathBuf))]
+        nonlocality_directory: std::path::PathBuf,
+        #[arg(long, value_enum, default_value_t = CodecArgument::Zstd)]
+        codec: CodecArgument,
+        /// Train a new zstd dictionary from the trees in the database before recompressing
+        #[arg(long)]
+        train_dictionary: bool,
     },
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum CodecArgument {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl From<CodecArgument> for BlobCodec {
+    fn from(value: CodecArgument) -> Self {
+        match value {
+            CodecArgument::None => BlobCodec::None,
+            CodecArgument::Lz4 => BlobCodec::Lz4,
+            CodecArgument::Zstd => BlobCodec::Zstd {
+                level: ZSTD_DEFAULT_LEVEL,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DigestArgument {
+    Sha3_512,
+    Blake3,
+}
+
+impl From<DigestArgument> for DigestAlgorithm {
+    fn from(value: DigestArgument) -> Self {
+        match value {
+            DigestArgument::Sha3_512 => DigestAlgorithm::Sha3_512,
+            DigestArgument::Blake3 => DigestAlgorithm::Blake3,
+        }
+    }
+}
+
 pub const SERVICE_FILE_NAME: &str = "nonlocalityos_host.service";
 pub const SYSTEMD_SERVICES_DIRECTORY: &str = "/etc/systemd/system";
 
@@ -186,7 +242,11 @@ async fn uninstall(operating_system: &dyn OperatingSystem) -> std::io::Result<()
     Ok(())
 }
 
-async fn run(nonlocality_directory: &Path) -> std::io::Result<()> {
+async fn run(
+    nonlocality_directory: &Path,
+    digest_algorithm: DigestAlgorithm,
+    metrics_address: Option<std::net::SocketAddr>,
+) -> std::io::Result<()> {
     info!("Running host in {}", nonlocality_directory.display());
     match std::fs::create_dir_all(nonlocality_directory) {
         Ok(_) => {}
@@ -203,7 +263,7 @@ async fn run(nonlocality_directory: &Path) -> std::io::Result<()> {
         "Using database file for DAV server: {}",
         database_file_name.display()
     );
-    match dav_server_main(&database_file_name).await {
+    match dav_server_main(&database_file_name, digest_algorithm, metrics_address).await {
         Ok(_) => {
             warn!("DAV server exited without an error");
             Ok(())
@@ -215,6 +275,52 @@ async fn run(nonlocality_directory: &Path) -> std::io::Result<()> {
     }
 }
 
+const DICTIONARY_TRAINING_SAMPLES: usize = 10_000;
+const DICTIONARY_MAX_SIZE: usize = 100_000;
+
+async fn recompress(
+    nonlocality_directory: &Path,
+    codec: BlobCodec,
+    train_dictionary: bool,
+) -> std::io::Result<()> {
+    let database_file_name = make_installed_database_path(nonlocality_directory);
+    info!(
+        "Recompressing {} with {:?}",
+        database_file_name.display(),
+        codec
+    );
+    let connection =
+        rusqlite::Connection::open(&database_file_name).map_err(std::io::Error::other)?;
+    let storage = SQLiteStorage::from_with_configuration(
+        connection,
+        SQLiteStorageConfiguration {
+            codec,
+            ..Default::default()
+        },
+    )
+    .map_err(std::io::Error::other)?;
+    if train_dictionary {
+        let dictionary = storage
+            .train_compression_dictionary(DICTIONARY_TRAINING_SAMPLES, DICTIONARY_MAX_SIZE)
+            .await
+            .map_err(std::io::Error::other)?;
+        info!("Trained compression dictionary {dictionary}");
+    }
+    let stats = storage
+        .recompress_trees()
+        .await
+        .map_err(std::io::Error::other)?;
+    storage
+        .commit_changes()
+        .await
+        .map_err(std::io::Error::other)?;
+    info!(
+        "Recompressed {} of {} trees, {} bytes before, {} bytes after",
+        stats.trees_recompressed, stats.trees_examined, stats.bytes_before, stats.bytes_after
+    );
+    Ok(())
+}
+
 async fn handle_command_line(
     host_binary_name: &OsStr,
     operating_system: &dyn OperatingSystem,
@@ -233,13 +339,20 @@ async fn handle_command_line(
         Commands::Uninstall => uninstall(operating_system).await,
         Commands::Run {
             nonlocality_directory,
+            digest,
+            metrics_address,
         } => {
             info!(
                 "Nonlocality directory for running: {}",
                 nonlocality_directory.display()
             );
-            run(&nonlocality_directory).await
+            run(&nonlocality_directory, digest.into(), metrics_address).await
         }
+        Commands::Recompress {
+            nonlocality_directory,
+            codec,
+            train_dictionary,
+        } => recompress(&nonlocality_directory, codec.into(), train_dictionary).await,
     }
 }
 
@@ -77,7 +77,7 @@ async fn test_install() {
         ),
         (
             std::path::PathBuf::from("home/nonlocality/.nonlocality/database.sqlite3"),
-            FakeDirectoryEntry::File(36864),
+            FakeDirectoryEntry::File(69632),
         ),
         (
             std::path::PathBuf::from("tmp"),
@@ -177,7 +177,10 @@ pub async fn store_node<Key: Serialize + Ord, Value: NodeValue>(
         Err(error) => return Err(StoreError::TreeSerializationError(error)),
     };
     store_tree
-        .store_tree(&HashedTree::from(std::sync::Arc::new(tree)))
+        .store_tree(&HashedTree::from_with_algorithm(
+            std::sync::Arc::new(tree),
+            store_tree.digest_algorithm(),
+        ))
         .await
 }
 
@@ -552,7 +552,7 @@ async fn test_load_node_error() {
         .await
         .unwrap_err();
     assert_eq!(
-        "TreeNotFound(BlobDigest(\"f0140e314ee38d4472393680e7a72a81abb36b134b467d90ea943b7aa1ea03bf2323bc1a2df91f7230a225952e162f6629cf435e53404e9cdd727a2d94e4f909\"))".to_string(),
+        "TreeNotFound(BlobDigest(\"14f0140e314ee38d4472393680e7a72a81abb36b134b467d90ea943b7aa1ea03bf2323bc1a2df91f7230a225952e162f6629cf435e53404e9cdd727a2d94e4f909\"))".to_string(),
         error.to_string());
 }
 